@@ -0,0 +1,386 @@
+//! Cable models for cable delay corrections.
+//!
+//! By default, cable delays are derived from the electrical lengths in the `Length` column of the
+//! metafits `TILEDATA` table. The metafits also records the flavour of each rfinput's cable in the
+//! `Flavors` column, e.g. `RG6_90` is 90m of RG6. Different cable types have different velocity
+//! factors, and attenuation which varies with frequency, so deriving the delays from the flavour
+//! can be more accurate than assuming a single velocity factor for all cables.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use marlu::{
+    fitsio::{self, FitsFile},
+    mwalib::MetafitsContext,
+};
+use thiserror::Error;
+
+/// The frequency at which cable attenuation is specified.
+pub const ATTENUATION_REF_FREQ_HZ: f64 = 100e6;
+
+/// Errors when reading cable flavours or a cable flavour table.
+#[derive(Error, Debug)]
+pub enum CableError {
+    #[error("Could not read cable flavour table {path}: {source}")]
+    /// The flavour table could not be read
+    Unreadable {
+        /// The path to the flavour table
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error(
+        "{path}:{line_num}: expected <FLAVOUR> <VELOCITY_FACTOR> <ATTENUATION>, found {line:?}"
+    )]
+    /// A line of the flavour table could not be parsed
+    BadTableLine {
+        /// The path to the flavour table
+        path: String,
+        /// The line number (starting from 1)
+        line_num: usize,
+        /// The contents of the line
+        line: String,
+    },
+
+    #[error("Invalid cable flavour {flavour:?}, expected <TYPE>_<LENGTH>, e.g. RG6_90")]
+    /// A flavour from the metafits could not be parsed
+    BadFlavour {
+        /// The flavour string
+        flavour: String,
+    },
+
+    #[error("Unknown cable type {cable_type} for flavour {flavour}. Known types: {known:?}")]
+    /// The cable type of a flavour is not in the flavour table
+    UnknownCableType {
+        /// The cable type
+        cable_type: String,
+        /// The flavour string
+        flavour: String,
+        /// The cable types in the table
+        known: Vec<String>,
+    },
+
+    #[error("No cable flavour for antenna {ant} in the metafits")]
+    /// An antenna in the metafits doesn't have a flavour for both of its rfinputs
+    MissingFlavour {
+        /// The antenna number
+        ant: u32,
+    },
+
+    #[error("Could not read cable flavours from metafits {path}: {source}")]
+    /// The `TILEDATA` table of the metafits could not be read
+    Metafits {
+        /// The path to the metafits
+        path: String,
+        /// The underlying fitsio error
+        source: fitsio::errors::Error,
+    },
+}
+
+/// The properties of a type of cable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CableType {
+    /// The speed of signals in the cable as a fraction of the speed of light
+    pub velocity_factor: f64,
+    /// The attenuation of the cable in dB per 100m at [`ATTENUATION_REF_FREQ_HZ`]. Attenuation is
+    /// assumed to be proportional to the square root of frequency.
+    pub attenuation_db_per_100m: f64,
+}
+
+/// A table of cable types, keyed by the name used in metafits flavours.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CableTypeTable(pub BTreeMap<String, CableType>);
+
+impl Default for CableTypeTable {
+    /// The nominal properties of the cable types used by the MWA.
+    fn default() -> Self {
+        Self(BTreeMap::from([
+            (
+                "RG6".into(),
+                CableType {
+                    velocity_factor: 0.83,
+                    attenuation_db_per_100m: 6.6,
+                },
+            ),
+            (
+                "LMR400".into(),
+                CableType {
+                    velocity_factor: 0.85,
+                    attenuation_db_per_100m: 3.9,
+                },
+            ),
+        ]))
+    }
+}
+
+impl CableTypeTable {
+    /// Read cable types from a whitespace separated text file with the columns
+    /// `<TYPE> <VELOCITY_FACTOR> <ATTENUATION>`, where attenuation is in dB per 100m at 100MHz.
+    /// Blank lines and lines starting with `#` are ignored. These types are added to the
+    /// default table, replacing any types with the same name.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`CableError`] if the file can't be read or parsed.
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, CableError> {
+        let path_str = path.as_ref().display().to_string();
+        let contents = fs::read_to_string(&path).map_err(|source| CableError::Unreadable {
+            path: path_str.clone(),
+            source,
+        })?;
+        let mut table = Self::default();
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || CableError::BadTableLine {
+                path: path_str.clone(),
+                line_num: line_idx + 1,
+                line: line.into(),
+            };
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (name, velocity_factor, attenuation_db_per_100m) = match fields[..] {
+                [name, velocity_factor, attenuation] => (
+                    name,
+                    velocity_factor.parse::<f64>().map_err(|_| bad_line())?,
+                    attenuation.parse::<f64>().map_err(|_| bad_line())?,
+                ),
+                _ => return Err(bad_line()),
+            };
+            if !(velocity_factor > 0. && velocity_factor <= 1.) {
+                return Err(bad_line());
+            }
+            table.0.insert(
+                name.into(),
+                CableType {
+                    velocity_factor,
+                    attenuation_db_per_100m,
+                },
+            );
+        }
+        Ok(table)
+    }
+
+    /// Look up the cable type and physical length in metres of a metafits flavour, e.g. `RG6_90`.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`CableError::BadFlavour`] if the flavour can't be parsed, or
+    /// [`CableError::UnknownCableType`] if the cable type isn't in the table.
+    pub fn lookup(&self, flavour: &str) -> Result<(CableType, f64), CableError> {
+        let (cable_type, length_m) = flavour
+            .trim()
+            .rsplit_once('_')
+            .and_then(|(cable_type, length)| Some((cable_type, length.parse::<f64>().ok()?)))
+            .ok_or_else(|| CableError::BadFlavour {
+                flavour: flavour.into(),
+            })?;
+        match self.0.get(cable_type) {
+            Some(&properties) => Ok((properties, length_m)),
+            None => Err(CableError::UnknownCableType {
+                cable_type: cable_type.into(),
+                flavour: flavour.into(),
+                known: self.0.keys().cloned().collect(),
+            }),
+        }
+    }
+}
+
+/// Read the cable flavours of the `[X, Y]` rfinputs of each antenna from the `Flavors` column of
+/// the metafits `TILEDATA` table, indexed by mwalib antenna index.
+///
+/// # Errors
+///
+/// Will return a [`CableError`] if the table can't be read, or an antenna is missing a flavour.
+pub fn read_metafits_flavours(meta_ctx: &MetafitsContext) -> Result<Vec<[String; 2]>, CableError> {
+    let to_error = |source| CableError::Metafits {
+        path: meta_ctx.metafits_filename.clone(),
+        source,
+    };
+    let mut fptr = FitsFile::open(&meta_ctx.metafits_filename).map_err(to_error)?;
+    let hdu = fptr.hdu(1).map_err(to_error)?;
+    let ants: Vec<u32> = hdu.read_col(&mut fptr, "Antenna").map_err(to_error)?;
+    let pols: Vec<String> = hdu.read_col(&mut fptr, "Pol").map_err(to_error)?;
+    let flavours: Vec<String> = hdu.read_col(&mut fptr, "Flavors").map_err(to_error)?;
+
+    let mut ant_flavours = vec![[None, None]; meta_ctx.antennas.len()];
+    for (ant, pol, flavour) in itertools::izip!(ants, pols, flavours) {
+        let ant_idx = meta_ctx
+            .antennas
+            .iter()
+            .position(|antenna| antenna.ant == ant);
+        let pol_idx = match pol.trim() {
+            "X" => 0,
+            "Y" => 1,
+            _ => continue,
+        };
+        if let Some(ant_idx) = ant_idx {
+            ant_flavours[ant_idx][pol_idx] = Some(flavour.trim().to_string());
+        }
+    }
+    ant_flavours
+        .into_iter()
+        .zip(meta_ctx.antennas.iter())
+        .map(|(flavours, antenna)| match flavours {
+            [Some(x), Some(y)] => Ok([x, y]),
+            _ => Err(CableError::MissingFlavour { ant: antenna.ant }),
+        })
+        .collect()
+}
+
+/// The cables of each antenna's rfinputs, used for cable delay corrections.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CableModel {
+    /// The electrical length in metres of the `[X, Y]` rfinputs of each mwalib antenna index.
+    pub electrical_lengths_m: Vec<[f64; 2]>,
+    /// The attenuation in dB at [`ATTENUATION_REF_FREQ_HZ`] of the `[X, Y]` rfinputs of each
+    /// mwalib antenna index. If provided, the slope of this attenuation over frequency is
+    /// corrected.
+    pub attenuations_db: Option<Vec<[f64; 2]>>,
+    /// The frequency where amplitudes are left unchanged by attenuation corrections.
+    pub attenuation_norm_freq_hz: f64,
+}
+
+impl CableModel {
+    /// The electrical lengths from the metafits `Length` column, with no attenuation.
+    pub fn from_metafits(meta_ctx: &MetafitsContext) -> Self {
+        Self {
+            electrical_lengths_m: meta_ctx
+                .antennas
+                .iter()
+                .map(|antenna| {
+                    [
+                        antenna.rfinput_x.electrical_length_m,
+                        antenna.rfinput_y.electrical_length_m,
+                    ]
+                })
+                .collect(),
+            attenuations_db: None,
+            attenuation_norm_freq_hz: meta_ctx.centre_freq_hz as f64,
+        }
+    }
+
+    /// Derive electrical lengths, and optionally attenuation, from the cable flavour of each
+    /// rfinput (see [`read_metafits_flavours`]).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use birli::cables::{CableModel, CableTypeTable};
+    ///
+    /// let flavours = vec![["RG6_90".to_string(), "LMR400_400".to_string()]];
+    /// let model =
+    ///     CableModel::from_flavours(&flavours, &CableTypeTable::default(), true, 150e6).unwrap();
+    ///
+    /// assert!((model.electrical_lengths_m[0][0] - 90. / 0.83).abs() < 1e-9);
+    /// assert!((model.attenuations_db.unwrap()[0][1] - 4. * 3.9).abs() < 1e-9);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`CableError`] if a flavour can't be found in `table`.
+    pub fn from_flavours(
+        flavours: &[[String; 2]],
+        table: &CableTypeTable,
+        correct_attenuation: bool,
+        attenuation_norm_freq_hz: f64,
+    ) -> Result<Self, CableError> {
+        let mut electrical_lengths_m = Vec::with_capacity(flavours.len());
+        let mut attenuations_db = Vec::with_capacity(flavours.len());
+        for ant_flavours in flavours {
+            let mut lengths = [0.; 2];
+            let mut attenuations = [0.; 2];
+            for (flavour, length, attenuation) in
+                itertools::izip!(ant_flavours, lengths.iter_mut(), attenuations.iter_mut())
+            {
+                let (cable_type, length_m) = table.lookup(flavour)?;
+                *length = length_m / cable_type.velocity_factor;
+                *attenuation = cable_type.attenuation_db_per_100m * length_m / 100.;
+            }
+            electrical_lengths_m.push(lengths);
+            attenuations_db.push(attenuations);
+        }
+        Ok(Self {
+            electrical_lengths_m,
+            attenuations_db: if correct_attenuation {
+                Some(attenuations_db)
+            } else {
+                None
+            },
+            attenuation_norm_freq_hz,
+        })
+    }
+
+    /// The factor to multiply a voltage by at `freq_hz` to correct for the slope of an
+    /// attenuation of `attenuation_db` at [`ATTENUATION_REF_FREQ_HZ`].
+    pub fn attenuation_gain(&self, attenuation_db: f64, freq_hz: f64) -> f64 {
+        let scale = (freq_hz / ATTENUATION_REF_FREQ_HZ).sqrt()
+            - (self.attenuation_norm_freq_hz / ATTENUATION_REF_FREQ_HZ).sqrt();
+        10_f64.powf(attenuation_db * scale / 20.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{read_metafits_flavours, CableError, CableModel, CableTypeTable};
+    use crate::test_common::get_mwax_context;
+
+    #[test]
+    fn test_read_metafits_flavours() {
+        let corr_ctx = get_mwax_context();
+        let flavours = read_metafits_flavours(&corr_ctx.metafits_context).unwrap();
+        assert_eq!(flavours.len(), corr_ctx.metafits_context.num_ants);
+        assert_eq!(flavours[0], ["RG6_90".to_string(), "RG6_90".to_string()]);
+        assert_eq!(
+            flavours[1],
+            ["LMR400_400".to_string(), "LMR400_400".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_cable_type_table_from_file() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("cables.txt");
+        fs::write(&path, "# type vf atten\nRG6 0.5 10\n\nHELIAX 0.9 1.0\n").unwrap();
+        let table = CableTypeTable::from_file(&path).unwrap();
+        assert_eq!(table.lookup("RG6_90").unwrap().0.velocity_factor, 0.5);
+        assert_eq!(table.lookup("HELIAX_10").unwrap().1, 10.);
+        assert!(table.lookup("LMR400_400").is_ok());
+
+        fs::write(&path, "RG6 0.5\n").unwrap();
+        assert!(matches!(
+            CableTypeTable::from_file(&path),
+            Err(CableError::BadTableLine { line_num: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_cable_model_from_flavours_errors() {
+        let table = CableTypeTable::default();
+        for (flavour, is_bad) in [("RG6", true), ("RG6_abc", true), ("COAX_90", false)] {
+            let result =
+                CableModel::from_flavours(&[[flavour.into(), "RG6_90".into()]], &table, false, 0.);
+            if is_bad {
+                assert!(matches!(result, Err(CableError::BadFlavour { .. })));
+            } else {
+                assert!(matches!(result, Err(CableError::UnknownCableType { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn test_attenuation_gain() {
+        let model = CableModel {
+            attenuation_norm_freq_hz: 100e6,
+            ..CableModel::default()
+        };
+        assert!((model.attenuation_gain(6., 100e6) - 1.).abs() < 1e-12);
+        // 6dB at 100MHz is 12dB at 400MHz, so 6dB more attenuation to correct.
+        assert!((model.attenuation_gain(6., 400e6) - 10_f64.powf(6. / 20.)).abs() < 1e-12);
+    }
+}
@@ -29,6 +29,17 @@ pub enum CalibrationError {
         /// The shape that was received instead
         received: String,
     },
+
+    #[error(transparent)]
+    /// When the calibration solutions can't be read.
+    ReadSolutions(#[from] crate::io::error::ReadSolutionsError),
+
+    #[error("The provided calibration solution has {num_timeblocks} timeblocks, but only 1 timeblock is supported")]
+    /// When the calibration solutions have more than one timeblock.
+    TimeblockCount {
+        /// The number of timeblocks in the calibration solution
+        num_timeblocks: usize,
+    },
 }
 
 /// apply a direction independent calibration solution for a single timeblock to the given
@@ -1,521 +1,42 @@
 //! Command Line Interface helpers for Birli
 
 use crate::{
+    cables::{read_metafits_flavours, CableModel, CableTypeTable},
+    corrections::{drift_phase_centre, ScrunchType},
+    ephemeris::{Ephemeris, NearFieldFrame, NearFieldTrack},
     error::{BirliError, BirliError::DryRun, CLIError::InvalidCommandLineArgument},
-    flags::FlagContext,
-    io::{aocal::AOCalSols, IOContext},
+    flags::{AmplitudeThreshold, FlagContext, FlagExtension},
+    io::{read_oversampled, IOContext, PhasedOutput},
     marlu::{
-        built_info::PKG_VERSION as MARLU_PKG_VERSION,
         constants::{
             COTTER_MWA_HEIGHT_METRES, COTTER_MWA_LATITUDE_RADIANS, COTTER_MWA_LONGITUDE_RADIANS,
         },
         hifitime::{self, Epoch, Unit},
-        io::{error::BadArrayShape, ms::MeasurementSetWriter, uvfits::UvfitsWriter, VisWrite},
-        mwalib,
-        ndarray::s,
-        precession::{precess_time, PrecessionInfo},
-        History, Jones, LatLngHeight, MwaObsContext, ObsContext, RADec, VisContext, ENH,
+        mwalib, AzEl, LatLngHeight, RADec,
     },
-    passband_gains::{PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ},
-    with_increment_duration, Axis, Complex, FlagFileSet, PreprocessContext, VisSelection,
+    passband_gains::{
+        read_passband_gains, PassbandGainsError, PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ,
+    },
+    tiles::TileOverrides,
+    PreprocessContext, VisSelection,
 };
 use cfg_if::cfg_if;
 use clap::{arg, command, ErrorKind::ArgumentNotFound, PossibleValue, ValueHint::FilePath};
-use itertools::{izip, Itertools};
+use itertools::Itertools;
 use log::{debug, info, trace, warn};
-use mwalib::{
-    built_info::PKG_VERSION as MWALIB_PKG_VERSION, fitsio_sys::CFITSIO_VERSION, CableDelaysApplied,
-    CorrelatorContext, GeometricDelaysApplied,
-};
-use prettytable::{format as prettyformat, row, table};
-use std::{
-    collections::HashMap,
-    convert::Into,
-    env,
-    ffi::OsString,
-    fmt::{Debug, Display},
-    time::Duration,
-};
+use mwalib::{CableDelaysApplied, CorrelatorContext, GeometricDelaysApplied, MWAVersion};
+use std::{convert::Into, env, f64::consts::FRAC_PI_2, ffi::OsString, fmt::Debug, path::PathBuf};
 
 cfg_if! {
     if #[cfg(feature = "aoflagger")] {
-        use aoflagger_sys::{cxx_aoflagger_new};
-    }
-}
-
-const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
-const PKG_NAME: &str = env!("CARGO_PKG_NAME");
-
-/// Args for preprocessing a correlator context.
-pub struct BirliContext<'a> {
-    /// mwalib::CorrelatorContext
-    pub corr_ctx: CorrelatorContext,
-    /// Preprocessing parameters
-    pub prep_ctx: PreprocessContext<'a>,
-    /// selected visibility indices
-    pub vis_sel: VisSelection,
-    /// Flagging Parameters
-    pub flag_ctx: FlagContext,
-    /// Input / output paths
-    pub io_ctx: IOContext,
-    /// temporal averaging factor
-    pub avg_time: usize,
-    /// spectral averaging factor
-    pub avg_freq: usize,
-    /// temporal chunking factor
-    pub num_timesteps_per_chunk: Option<usize>,
-    /// Are we ignoring DUT1?
-    pub ignore_dut1: bool,
-}
-
-// Add build-time information from the "built" crate.
-include!(concat!(env!("OUT_DIR"), "/built.rs"));
-
-/// stolen from hyperdrive
-/// Write many info-level log lines of how this executable was compiled.
-///
-/// # Errors
-///
-/// propagates writeln! fails
-pub fn fmt_build_info(f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    match GIT_HEAD_REF {
-        Some(hr) => {
-            let dirty = GIT_DIRTY.unwrap_or(false);
-            writeln!(
-                f,
-                "Compiled on git commit hash: {}{}",
-                GIT_COMMIT_HASH.unwrap(),
-                if dirty { " (dirty)" } else { "" }
-            )?;
-            writeln!(f, "            git head ref: {}", hr)?;
-        }
-        None => writeln!(f, "Compiled on git commit hash: <no git info>")?,
-    }
-    writeln!(f, "            {}", BUILT_TIME_UTC)?;
-    writeln!(f, "         with compiler {}", RUSTC_VERSION)?;
-    writeln!(f, "libraries:")?;
-    writeln!(f, "- marlu v{}", MARLU_PKG_VERSION)?;
-    writeln!(f, "- mwalib v{}", MWALIB_PKG_VERSION)?;
-    writeln!(f, "- cfitsio (bindings) v{}", CFITSIO_VERSION)?;
-
-    cfg_if! {
-        if #[cfg(feature = "aoflagger")] {
-            use std::os::raw::c_short;
-            let mut major: c_short = -1;
-            let mut minor: c_short = -1;
-            let mut sub_minor: c_short = -1;
-            let aoflagger = unsafe { cxx_aoflagger_new() };
-            aoflagger.GetVersion(&mut major, &mut minor, &mut sub_minor);
-            assert!(major >= 3);
-            assert!(minor >= 0);
-            assert!(sub_minor >= 0);
-            writeln!(f, "- aoflagger v{}.{}.{}", major, minor, sub_minor)?;
-        }
+        use crate::strategies::{validate_strategy_file, AOFlaggerStrategy, BundledStrategy};
     }
-    writeln!(f)?;
-    Ok(())
-}
-
-fn time_details(
-    gps_time_ms: u64,
-    dut1: hifitime::Duration,
-    phase_centre: RADec,
-    array_pos: LatLngHeight,
-) -> (String, String, f64, PrecessionInfo) {
-    let epoch = Epoch::from_gpst_seconds(gps_time_ms as f64 / 1e3);
-    let (y, mo, d, h, mi, s, ms) = epoch.as_gregorian_utc();
-    let precession_info = precess_time(
-        array_pos.longitude_rad,
-        array_pos.latitude_rad,
-        phase_centre,
-        epoch,
-        dut1,
-    );
-    (
-        format!("{:02}-{:02}-{:02}", y, mo, d),
-        format!(
-            "{:02}:{:02}:{:02}.{:03}",
-            h,
-            mi,
-            s,
-            (ms as f64 / 1e6).round()
-        ),
-        epoch.as_mjd_utc_seconds(),
-        precession_info,
-    )
 }
 
-impl Display for BirliContext<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{} version {}", PKG_NAME, PKG_VERSION,)?;
-
-        fmt_build_info(f)?;
-
-        writeln!(
-            f,
-            "observation name:     {}",
-            self.corr_ctx.metafits_context.obs_name
-        )?;
-
-        writeln!(f, "Array position:       {}", &self.prep_ctx.array_pos)?;
-        writeln!(f, "Phase centre:         {}", &self.prep_ctx.phase_centre)?;
-        let pointing_centre = RADec::from_mwalib_tile_pointing(&self.corr_ctx.metafits_context);
-        if pointing_centre != self.prep_ctx.phase_centre {
-            writeln!(f, "Pointing centre:      {}", &pointing_centre)?;
-        }
-
-        let coarse_chan_flag_idxs: Vec<usize> = self
-            .flag_ctx
-            .coarse_chan_flags
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
-            .collect();
-        // TODO: actually display this.
-        let _fine_chan_flag_idxs: Vec<usize> = self
-            .flag_ctx
-            .fine_chan_flags
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
-            .collect();
-        let timestep_flag_idxs: Vec<usize> = self
-            .flag_ctx
-            .timestep_flags
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
-            .collect();
-        let ant_pairs = self.vis_sel.get_ant_pairs(&self.corr_ctx.metafits_context);
-        #[allow(clippy::needless_collect)]
-        let baseline_flag_idxs: Vec<usize> = self
-            .flag_ctx
-            .get_baseline_flags(&ant_pairs)
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
-            .collect();
-
-        let dut1 = if self.ignore_dut1 {
-            hifitime::Duration::from_total_nanoseconds(0)
-        } else {
-            hifitime::Duration::from_f64(
-                self.corr_ctx.metafits_context.dut1.unwrap_or(0.0),
-                Unit::Second,
-            )
-        };
-        let (sched_start_date, sched_start_time, sched_start_mjd_s, sched_start_prec) =
-            time_details(
-                self.corr_ctx.metafits_context.sched_start_gps_time_ms,
-                dut1,
-                self.prep_ctx.phase_centre,
-                self.prep_ctx.array_pos,
-            );
-        writeln!(
-            f,
-            "Scheduled start:      {} {} UTC, unix={:.3}, gps={:.3}, mjd={:.3}, lmst={:7.4}°, lmst2k={:7.4}°, lat2k={:7.4}°",
-            sched_start_date, sched_start_time,
-            self.corr_ctx.metafits_context.sched_start_unix_time_ms as f64 / 1e3,
-            self.corr_ctx.metafits_context.sched_start_gps_time_ms as f64 / 1e3,
-            sched_start_mjd_s,
-            sched_start_prec.lmst.to_degrees(),
-            sched_start_prec.lmst_j2000.to_degrees(),
-            sched_start_prec.array_latitude_j2000.to_degrees(),
-        )?;
-        let (sched_end_date, sched_end_time, sched_end_mjd_s, sched_end_prec) = time_details(
-            self.corr_ctx.metafits_context.sched_end_gps_time_ms,
-            dut1,
-            self.prep_ctx.phase_centre,
-            self.prep_ctx.array_pos,
-        );
-        writeln!(
-            f,
-
-            "Scheduled end:        {} {} UTC, unix={:.3}, gps={:.3}, mjd={:.3}, lmst={:7.4}°, lmst2k={:7.4}°, lat2k={:7.4}°",
-            sched_end_date, sched_end_time,
-            self.corr_ctx.metafits_context.sched_end_unix_time_ms as f64 / 1e3,
-            self.corr_ctx.metafits_context.sched_end_gps_time_ms as f64 / 1e3,
-            sched_end_mjd_s,
-            sched_end_prec.lmst.to_degrees(),
-            sched_end_prec.lmst_j2000.to_degrees(),
-            sched_end_prec.array_latitude_j2000.to_degrees(),
-        )?;
-        let int_time_s = self.corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
-        let sched_duration_s = self.corr_ctx.metafits_context.sched_duration_ms as f64 / 1e3;
-        writeln!(
-            f,
-            "Scheduled duration:   {:.3}s = {:3} * {:.3}s",
-            sched_duration_s,
-            (sched_duration_s / int_time_s).ceil(),
-            int_time_s
-        )?;
-        let quack_duration_s = self.corr_ctx.metafits_context.quack_time_duration_ms as f64 / 1e3;
-        writeln!(
-            f,
-            "Quack duration:       {:.3}s = {:3} * {:.3}s",
-            quack_duration_s,
-            (quack_duration_s / int_time_s).ceil(),
-            int_time_s
-        )?;
-        let num_avg_timesteps =
-            (self.vis_sel.timestep_range.len() as f64 / self.avg_time as f64).ceil() as usize;
-        let avg_int_time_s = int_time_s * self.avg_time as f64;
-        writeln!(
-            f,
-            "Output duration:      {:.3}s = {:3} * {:.3}s{}",
-            num_avg_timesteps as f64 * avg_int_time_s,
-            num_avg_timesteps,
-            avg_int_time_s,
-            if self.avg_time == 1 {
-                "".into()
-            } else {
-                format!(" ({}x)", self.avg_time)
-            }
-        )?;
-
-        let total_bandwidth_mhz = self.corr_ctx.metafits_context.obs_bandwidth_hz as f64 / 1e6;
-        let fine_chan_width_khz =
-            self.corr_ctx.metafits_context.corr_fine_chan_width_hz as f64 / 1e3;
-        let fine_chans_per_coarse = self
-            .corr_ctx
-            .metafits_context
-            .num_corr_fine_chans_per_coarse;
-
-        writeln!(
-            f,
-            "Scheduled Bandwidth:  {:.3}MHz = {:3} * {:3} * {:.3}kHz",
-            total_bandwidth_mhz,
-            self.corr_ctx.metafits_context.num_metafits_coarse_chans,
-            fine_chans_per_coarse,
-            fine_chan_width_khz
-        )?;
-
-        let out_bandwidth_mhz = self.vis_sel.coarse_chan_range.len() as f64
-            * fine_chans_per_coarse as f64
-            * fine_chan_width_khz
-            / 1e3;
-        let num_avg_chans = (self.vis_sel.coarse_chan_range.len() as f64
-            * fine_chans_per_coarse as f64
-            / self.avg_freq as f64)
-            .ceil() as usize;
-        let avg_fine_chan_width_khz = fine_chan_width_khz * self.avg_freq as f64;
-        writeln!(
-            f,
-            "Output Bandwidth:     {:.3}MHz = {:9} * {:.3}kHz{}",
-            out_bandwidth_mhz,
-            num_avg_chans,
-            avg_fine_chan_width_khz,
-            if self.avg_freq == 1 {
-                "".into()
-            } else {
-                format!(" ({}x)", self.avg_freq)
-            }
-        )?;
-
-        let first_epoch =
-            Epoch::from_gpst_seconds(self.corr_ctx.timesteps[0].gps_time_ms as f64 / 1e3);
-        let (y, mo, d, ..) = first_epoch.as_gregorian_utc();
-
-        let mut timestep_table = table!([
-            "",
-            format!("{:02}-{:02}-{:02} UTC +", y, mo, d),
-            "unix [s]",
-            "gps [s]",
-            "p",
-            "c",
-            "g",
-            "s",
-            "f"
-        ]);
-        timestep_table.set_format(*prettyformat::consts::FORMAT_CLEAN);
-
-        let provided_timestep_indices = &self.corr_ctx.provided_timestep_indices;
-        let common_timestep_indices = &self.corr_ctx.common_timestep_indices;
-        let common_good_timestep_indices = &self.corr_ctx.common_good_timestep_indices;
-        for (timestep_idx, timestep) in self.corr_ctx.timesteps.iter().enumerate() {
-            let provided = provided_timestep_indices.contains(&timestep_idx);
-            let selected = self.vis_sel.timestep_range.contains(&timestep_idx);
-            let common = common_timestep_indices.contains(&timestep_idx);
-            let good = common_good_timestep_indices.contains(&timestep_idx);
-            let flagged = timestep_flag_idxs.contains(&timestep_idx);
-
-            let (_, time, ..) = time_details(
-                timestep.gps_time_ms,
-                dut1,
-                self.prep_ctx.phase_centre,
-                self.prep_ctx.array_pos,
-            );
-            let row = row![r =>
-                format!("ts{}:", timestep_idx),
-                time,
-                format!("{:.3}", timestep.unix_time_ms as f64 / 1e3),
-                format!("{:.3}", timestep.gps_time_ms as f64 / 1e3),
-                if provided {"p"} else {""},
-                if common {"c"} else {""},
-                if good {"g"} else {""},
-                if selected {"s"} else {""},
-                if flagged {"f"} else {""}
-            ];
-            timestep_table.add_row(row);
-        }
-
-        writeln!(
-            f,
-            "Timestep details (all={}, provided={}, common={}, good={}, select={}, flag={}):\n{}",
-            self.corr_ctx.num_timesteps,
-            self.corr_ctx.num_provided_timesteps,
-            self.corr_ctx.num_common_timesteps,
-            self.corr_ctx.num_common_good_timesteps,
-            self.vis_sel.timestep_range.len(),
-            timestep_flag_idxs.len(),
-            timestep_table
-        )?;
-
-        let mut coarse_chan_table = table!([
-            "",
-            "gpu",
-            "corr",
-            "rec",
-            "cen [MHz]",
-            "p",
-            "c",
-            "g",
-            "s",
-            "f"
-        ]);
-        coarse_chan_table.set_format(*prettyformat::consts::FORMAT_CLEAN);
-        // coarse_chan_table
-        let provided_coarse_chan_indices = &self.corr_ctx.provided_coarse_chan_indices;
-        let common_coarse_chan_indices = &self.corr_ctx.common_coarse_chan_indices;
-        let common_good_coarse_chan_indices = &self.corr_ctx.common_good_coarse_chan_indices;
-        for (chan_idx, chan) in self.corr_ctx.coarse_chans.iter().enumerate() {
-            let provided = provided_coarse_chan_indices.contains(&chan_idx);
-            let selected = self.vis_sel.coarse_chan_range.contains(&chan_idx);
-            let common = common_coarse_chan_indices.contains(&chan_idx);
-            let good = common_good_coarse_chan_indices.contains(&chan_idx);
-            let flagged = coarse_chan_flag_idxs.contains(&chan_idx);
-            let row = row![r =>
-                format!("cc{}:", chan_idx),
-                chan.gpubox_number,
-                chan.corr_chan_number,
-                chan.rec_chan_number,
-                format!("{:.4}", chan.chan_centre_hz as f64 / 1e6),
-                if provided {"p"} else {""},
-                if common {"c"} else {""},
-                if good {"g"} else {""},
-                if selected {"s"} else {""},
-                if flagged {"f"} else {""}
-            ];
-            coarse_chan_table.add_row(row);
-        }
-
-        writeln!(
-            f,
-            "Coarse channel details (metafits={}, provided={}, common={}, good={}, select={}, flag={}):\n{}",
-            self.corr_ctx.num_coarse_chans,
-            self.corr_ctx.num_provided_coarse_chans,
-            self.corr_ctx.num_common_coarse_chans,
-            self.corr_ctx.num_common_good_coarse_chans,
-            self.vis_sel.coarse_chan_range.len(),
-            coarse_chan_flag_idxs.len(),
-            coarse_chan_table
-        )?;
-
-        writeln!(
-            f,
-            "Antenna details (all={}, flag={})",
-            self.corr_ctx.metafits_context.num_ants,
-            self.flag_ctx
-                .antenna_flags
-                .iter()
-                .enumerate()
-                .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
-                .count(),
-            // format!("\n{}", ant_table)
-        )?;
-
-        writeln!(
-            f,
-            "Baseline Details (all={}, auto={}, select={}, flag={}):",
-            self.corr_ctx.metafits_context.num_baselines,
-            self.corr_ctx.metafits_context.num_ants,
-            self.vis_sel.baseline_idxs.len(),
-            baseline_flag_idxs.len(),
-        )?;
-
-        // TODO: show free memory with https://docs.rs/sys-info/latest/sys_info/fn.mem_info.html
-
-        let num_sel_timesteps = self.vis_sel.timestep_range.len();
-        let num_sel_chans = self.vis_sel.coarse_chan_range.len() * fine_chans_per_coarse;
-        let num_sel_baselines = self.vis_sel.baseline_idxs.len();
-        let num_sel_pols = self.corr_ctx.metafits_context.num_visibility_pols;
-        let mem_selected_bytes = self.vis_sel.estimate_bytes_best(fine_chans_per_coarse);
-        let mem_per_timestep_gib =
-            mem_selected_bytes as f64 / num_sel_timesteps as f64 / 1024.0_f64.powi(3);
-
-        writeln!(
-            f,
-            "Estimated memory usage per timestep =           {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
-            num_sel_chans,
-            num_sel_baselines,
-            std::mem::size_of::<Jones<f32>>(),
-            std::mem::size_of::<f32>(),
-            std::mem::size_of::<bool>(),
-            mem_per_timestep_gib,
-        )?;
-
-        if let Some(num_timesteps) = self.num_timesteps_per_chunk {
-            writeln!(
-                f,
-                "Estimated memory per chunk          = {:5}ts * {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
-                num_timesteps,
-                num_sel_chans,
-                num_sel_baselines,
-                std::mem::size_of::<Jones<f32>>(),
-                std::mem::size_of::<f32>(),
-                std::mem::size_of::<bool>(),
-                mem_per_timestep_gib * num_timesteps as f64,
-            )?;
-        }
-
-        writeln!(
-            f,
-            "Estimated memory selected           = {:5}ts * {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
-            num_sel_timesteps,
-            num_sel_chans,
-            num_sel_baselines,
-            std::mem::size_of::<Jones<f32>>(),
-            std::mem::size_of::<f32>(),
-            std::mem::size_of::<bool>(),
-            mem_per_timestep_gib * num_sel_timesteps as f64,
-        )?;
-
-        let avg_mem_per_timestep_gib = (num_avg_chans
-            * num_sel_baselines
-            * num_sel_pols
-            * (std::mem::size_of::<Complex<f32>>()
-                + std::mem::size_of::<f32>()
-                + std::mem::size_of::<bool>())) as f64
-            / 1024.0_f64.powi(3);
-
-        writeln!(
-            f,
-            "Estimated output size               = {:5}ts * {:6}ch * {:6}bl * {:1}pol * ({}<c32> + {}<f32> + {}<bool>) = {:7.02} GiB",
-            num_avg_timesteps,
-            num_avg_chans,
-            num_sel_baselines,
-            num_sel_pols,
-            std::mem::size_of::<Complex<f32>>(),
-            std::mem::size_of::<f32>(),
-            std::mem::size_of::<bool>(),
-            avg_mem_per_timestep_gib * num_avg_timesteps as f64,
-        )?;
-
-        writeln!(f, "Preprocessing Context: \n{}", &self.prep_ctx)?;
+pub use crate::pipeline::{fmt_build_info, BirliContext, BirliContextBuilder};
 
-        Ok(())
-    }
-}
+/// Default threshold for `--flag-bad-tiles`, in robust standard deviations.
+const DEFAULT_BAD_TILE_SIGMA: f32 = 5.;
 
 impl<'a> BirliContext<'a> {
     // TODO: try struct instead of builder
@@ -549,8 +70,39 @@ impl<'a> BirliContext<'a> {
                     .required(false),
                 arg!(--"pointing-centre" "Use pointing instead phase centre")
                     .conflicts_with("phase-centre"),
+                arg!(--"ephemeris" <PATH> "Track a moving target from an ephemeris file")
+                    .value_hint(FilePath)
+                    .required(false)
+                    .conflicts_with_all(&["phase-centre", "pointing-centre"]),
+                arg!(--"near-field-azel" <PATH> "Correct delays to a near-field target at az, el, range")
+                    .value_hint(FilePath)
+                    .required(false)
+                    .conflicts_with("ephemeris"),
+                arg!(--"near-field-ecef" <PATH> "Correct delays to a near-field target at ECEF positions")
+                    .value_hint(FilePath)
+                    .required(false)
+                    .conflicts_with_all(&["ephemeris", "near-field-azel"]),
+                arg!(--"drift" <TARGET> "Phase each timestep to a fixed az/el: zenith or pointing")
+                    .required(false)
+                    .possible_values(["zenith", "pointing"])
+                    .hide_possible_values(true)
+                    .conflicts_with_all(&[
+                        "phase-centre",
+                        "pointing-centre",
+                        "ephemeris",
+                        "near-field-azel",
+                        "near-field-ecef",
+                    ]),
                 arg!(--"ignore-dut1" "Do not use the DUT1 value, if available, in the metafits"),
                 arg!(--"emulate-cotter" "Use Cotter's array position, not MWAlib's"),
+                arg!(--"array-position" "Override the array position (degrees, metres)")
+                    .value_names(&["LON", "LAT", "H"])
+                    .allow_hyphen_values(true)
+                    .required(false)
+                    .conflicts_with("emulate-cotter"),
+                arg!(--"tile-overrides" <PATH> "Override tile cable delays and positions from a file")
+                    .value_hint(FilePath)
+                    .required(false),
                 arg!(--"dry-run" "Just print the summary and exit"),
                 arg!(--"no-draw-progress" "do not show progress bars"),
 
@@ -611,6 +163,9 @@ impl<'a> BirliContext<'a> {
                     .help_heading("FLAGGING")
                     .conflicts_with("flag-edge-width")
                     .required(false),
+                arg!(--"flag-passband-gain" <GAIN> "Flag fine chans with a passband gain below <GAIN>")
+                    .help_heading("FLAGGING")
+                    .required(false),
                 arg!(--"flag-fine-chans" <CHANS>... "Flag fine chan indices in each coarse chan")
                     .help_heading("FLAGGING")
                     .multiple_values(true)
@@ -628,21 +183,77 @@ impl<'a> BirliContext<'a> {
                     .help_heading("FLAGGING")
                     .multiple_values(true)
                     .required(false),
+                arg!(--"flag-bad-tiles" "Flag tiles with anomalous autocorrelation power, shape or X/Y ratio")
+                    .help_heading("FLAGGING"),
+                arg!(--"bad-tile-sigma" <SIGMA> "Deviation from the array median above which a tile is bad [default: 5]")
+                    .help_heading("FLAGGING")
+                    .requires("flag-bad-tiles")
+                    .required(false),
                 // -> baselines
                 arg!(--"flag-autos" "[WIP] Flag auto correlations")
                     .help_heading("FLAGGING"),
+                // -> visibilities
+                arg!(--"flag-sanity" "Flag NaN, infinite and zero visibilities before corrections")
+                    .help_heading("FLAGGING"),
+                arg!(--"flag-amp-max" <AMP> "Flag visibility amplitudes above <AMP> (implies --flag-sanity)")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-amp-mad" <SIGMA> "Flag amplitudes <SIGMA> MAD-derived std devs above the baseline median (implies --flag-sanity)")
+                    .help_heading("FLAGGING")
+                    .conflicts_with("flag-amp-max")
+                    .required(false),
+                // -> extension
+                arg!(--"flag-dilate-time" <STEPS> "Flag samples within <STEPS> timesteps of a flag after RFI flagging")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-dilate-freq" <CHANS> "Flag samples within <CHANS> fine chans of a flag after RFI flagging")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-timestep-occupancy" <FRAC> "Flag timesteps with more than <FRAC> of samples flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-chan-occupancy" <FRAC> "Flag fine chans with more than <FRAC> of samples flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-baseline-occupancy" <FRAC> "Flag baselines with more than <FRAC> of samples flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-antenna-occupancy" <FRAC> "Flag antennas with more than <FRAC> of baselines flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
 
                 // corrections
                 arg!(--"no-cable-delay" "Do not perform cable length corrections")
                     .help_heading("CORRECTION"),
+                arg!(--"force-cable-delay" "Perform cable length corrections even if the correlator applied them")
+                    .help_heading("CORRECTION")
+                    .conflicts_with("no-cable-delay"),
+                arg!(--"cable-flavours" "Derive cable delays from the cable flavours in the metafits")
+                    .help_heading("CORRECTION")
+                    .conflicts_with("no-cable-delay"),
+                arg!(--"cable-flavour-table" <PATH> "Override the velocity factor and attenuation of cable types")
+                    .help_heading("CORRECTION")
+                    .value_hint(FilePath)
+                    .requires("cable-flavours")
+                    .required(false),
+                arg!(--"cable-attenuation" "Also correct the attenuation slope of each cable flavour")
+                    .help_heading("CORRECTION")
+                    .requires("cable-flavours"),
                 arg!(--"no-geometric-delay" "Do not perform geometric corrections")
                     .help_heading("CORRECTION")
                     .alias("no-geom"),
+                arg!(--"force-geometric-delay" "Perform geometric corrections without reversing those applied by the correlator")
+                    .help_heading("CORRECTION")
+                    .conflicts_with("no-geometric-delay"),
                 arg!(--"no-digital-gains" "Do not perform digital gains corrections")
                     .help_heading("CORRECTION"),
+                arg!(--"no-van-vleck" "Do not correct Van Vleck quantisation effects of legacy data")
+                    .help_heading("CORRECTION"),
                 arg!(--"passband-gains" <TYPE> "Type of PFB passband filter gains correction to apply")
                     .required(false)
                     .possible_values([
+                        PossibleValue::new("auto")
+                            .help("jake, or none if the coarse channels are oversampled"),
                         PossibleValue::new("none").help("No passband gains correction (unitary)"),
                         PossibleValue::new("cotter")
                             .help(
@@ -652,10 +263,20 @@ impl<'a> BirliContext<'a> {
                             ),
                         PossibleValue::new("jake")
                             .help("see: PFB_JAKE_2022_200HZ in src/passband_gains.rs"),
+                        PossibleValue::new("empirical")
+                            .help("Estimate the passband from the data, see: estimate_passband_gains"),
                     ])
-                    .default_value("jake")
+                    .default_value("auto")
                     .alias("pfb-gains")
                     .help_heading("CORRECTION"),
+                arg!(--"passband-gains-file" <PATH> "Text or FITS file of ultrafine PFB passband gains to apply")
+                    .help_heading("CORRECTION")
+                    .value_hint(FilePath)
+                    .required(false)
+                    .conflicts_with("passband-gains"),
+                arg!(--"step-order" <STEPS> "Order of preprocessing steps [default: cable,digital,passband,flag,geometry,calibrate]")
+                    .help_heading("CORRECTION")
+                    .required(false),
 
                 // calibration
                 arg!(--"apply-di-cal" <PATH> "Apply DI calibration solutions before averaging")
@@ -690,6 +311,17 @@ impl<'a> BirliContext<'a> {
                 arg!(-M --"ms-out" <PATH> "Path for measurement set output")
                     .help_heading("OUTPUT")
                     .required(false),
+                arg!(--"passband-gains-out" <PATH> "Path for text or FITS passband gains estimated from the data")
+                    .help_heading("OUTPUT")
+                    .value_hint(FilePath)
+                    .required(false),
+                arg!(--"phase-centre-out" "Also write a .uvfits or .ms output phased to <RA> <DEC> (degrees)")
+                    .help_heading("OUTPUT")
+                    .value_names(&["RA", "DEC", "PATH"])
+                    .number_of_values(3)
+                    .multiple_occurrences(true)
+                    .allow_hyphen_values(true)
+                    .required(false),
             ]);
         cfg_if! {
             if #[cfg(feature = "aoflagger")] {
@@ -698,6 +330,9 @@ impl<'a> BirliContext<'a> {
                         .help_heading("AOFLAGGER"),
                     arg!(--"aoflagger-strategy" <PATH> "Strategy to use for RFI Flagging")
                         .value_hint(FilePath)
+                        .help_heading("AOFLAGGER")
+                        .required(false),
+                    arg!(--"rfi-halo" <STEPS> "Flag each --time-chunk with <STEPS> extra timesteps of context either side")
                         .help_heading("AOFLAGGER")
                         .required(false)
                 ]);
@@ -721,14 +356,59 @@ impl<'a> BirliContext<'a> {
             uvfits_out: matches.value_of("uvfits-out").map(Into::into),
             ms_out: matches.value_of("ms-out").map(Into::into),
             flag_template: matches.value_of("flag-template").map(Into::into),
+            passband_gains_out: matches.value_of("passband-gains-out").map(Into::into),
+            phased_outs: vec![],
         }
     }
 
+    fn parse_phased_out_matches(
+        matches: &clap::ArgMatches,
+    ) -> Result<Vec<PhasedOutput>, BirliError> {
+        let values = match matches.values_of("phase-centre-out") {
+            Some(values) => values.collect_vec(),
+            None => return Ok(vec![]),
+        };
+        // each occurrence has exactly 3 values, enforced by clap
+        values
+            .chunks(3)
+            .map(|chunk| {
+                let (ra, dec, path) = (chunk[0], chunk[1], PathBuf::from(chunk[2]));
+                let phase_centre = match (ra.parse::<f64>(), dec.parse::<f64>()) {
+                    (Ok(ra), Ok(dec)) => RADec::new(ra.to_radians(), dec.to_radians()),
+                    _ => {
+                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                            option: "--phase-centre-out <RA> <DEC> <PATH>".into(),
+                            expected: "<RA> and <DEC> in degrees".into(),
+                            received: format!("{} {}", ra, dec),
+                        }))
+                    }
+                };
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("uvfits") => Ok(PhasedOutput {
+                        phase_centre,
+                        uvfits_out: Some(path),
+                        ms_out: None,
+                    }),
+                    Some("ms") => Ok(PhasedOutput {
+                        phase_centre,
+                        uvfits_out: None,
+                        ms_out: Some(path),
+                    }),
+                    _ => Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--phase-centre-out <RA> <DEC> <PATH>".into(),
+                        expected: "a <PATH> ending in .uvfits or .ms".into(),
+                        received: path.display().to_string(),
+                    })),
+                }
+            })
+            .collect()
+    }
+
     fn parse_vis_sel_matches(
         corr_ctx: &CorrelatorContext,
         matches: &clap::ArgMatches,
     ) -> Result<VisSelection, BirliError> {
-        let mut vis_sel = VisSelection::from_mwalib(corr_ctx).unwrap();
+        let mut vis_sel = VisSelection::from_mwalib(corr_ctx)?;
         match matches
             .values_of_t::<usize>("sel-time")
             .map(|v| (v[0], v[1]))
@@ -754,119 +434,118 @@ impl<'a> BirliContext<'a> {
     fn parse_flag_matches(
         corr_ctx: &CorrelatorContext,
         matches: &clap::ArgMatches,
+        oversampled: bool,
     ) -> Result<FlagContext, BirliError> {
         let mut flag_ctx = FlagContext::from_mwalib(corr_ctx);
-        match matches.values_of_t::<usize>("flag-times") {
-            Ok(timestep_idxs) => {
-                for (value_idx, &timestep_idx) in timestep_idxs.iter().enumerate() {
-                    if timestep_idx >= corr_ctx.num_timesteps {
-                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
-                            option: "--flag-times <TIMESTEPS>...".into(),
-                            expected: format!(
-                                "timestep_idx < num_timesteps={}",
-                                corr_ctx.num_timesteps
-                            ),
-                            received: format!(
-                                "timestep_idxs[{}]={}. all:{:?}",
-                                value_idx, timestep_idx, timestep_idxs
-                            ),
-                        }));
-                    }
-                    flag_ctx.timestep_flags[timestep_idx] = true;
-                }
-            }
-            Err(err) => match err.kind() {
-                ArgumentNotFound { .. } => {}
-                _ => return Err(err.into()),
-            },
-        };
-        match matches.values_of_t::<usize>("flag-coarse-chans") {
-            Ok(coarse_chan_idxs) => {
-                for (value_idx, &coarse_chan_idx) in coarse_chan_idxs.iter().enumerate() {
-                    if coarse_chan_idx >= corr_ctx.num_coarse_chans {
-                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
-                            option: "--flag-coarse-chans <CHANS>...".into(),
-                            expected: format!(
-                                "coarse_chan_idx < num_coarse_chans={}",
-                                corr_ctx.num_coarse_chans
-                            ),
-                            received: format!(
-                                "coarse_chan_idxs[{}]={}. all:{:?}",
-                                value_idx, coarse_chan_idx, coarse_chan_idxs
-                            ),
-                        }));
-                    }
-                    flag_ctx.coarse_chan_flags[coarse_chan_idx] = true;
-                }
-            }
-            Err(err) => match err.kind() {
-                ArgumentNotFound { .. } => {}
-                _ => return Err(err.into()),
-            },
-        };
         let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
-        match matches.values_of_t::<usize>("flag-fine-chans") {
-            Ok(fine_chan_idxs) => {
-                for (value_idx, &fine_chan_idx) in fine_chan_idxs.iter().enumerate() {
-                    if fine_chan_idx >= fine_chans_per_coarse {
-                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
-                            option: "--flag-fine-chans <CHANS>...".into(),
-                            expected: format!(
-                                "fine_chan_idx < num_fine_chans={}",
-                                fine_chans_per_coarse
-                            ),
-                            received: format!(
-                                "fine_chan_idxs[{}]={}. all:{:?}",
-                                value_idx, fine_chan_idx, fine_chan_idxs
-                            ),
-                        }));
-                    }
-                    flag_ctx.fine_chan_flags[fine_chan_idx] = true;
-                }
-            }
-            Err(err) => match err.kind() {
-                ArgumentNotFound { .. } => {}
-                _ => return Err(err.into()),
-            },
-        };
+        for timestep_idx in Self::parse_flag_idx_matches(
+            matches,
+            "flag-times",
+            "--flag-times <TIMESTEPS>...",
+            ("timestep", "num_timesteps", corr_ctx.num_timesteps),
+        )? {
+            flag_ctx.timestep_flags[timestep_idx] = true;
+        }
+        for coarse_chan_idx in Self::parse_flag_idx_matches(
+            matches,
+            "flag-coarse-chans",
+            "--flag-coarse-chans <CHANS>...",
+            ("coarse_chan", "num_coarse_chans", corr_ctx.num_coarse_chans),
+        )? {
+            flag_ctx.coarse_chan_flags[coarse_chan_idx] = true;
+        }
+        for fine_chan_idx in Self::parse_flag_idx_matches(
+            matches,
+            "flag-fine-chans",
+            "--flag-fine-chans <CHANS>...",
+            ("fine_chan", "num_fine_chans", fine_chans_per_coarse),
+        )? {
+            flag_ctx.fine_chan_flags[fine_chan_idx] = true;
+        }
         if matches.is_present("no-flag-metafits") {
             info!("Ignoring antenna flags from metafits.");
             // set antenna flags to all false
             flag_ctx.antenna_flags = vec![false; flag_ctx.antenna_flags.len()];
         }
-        match matches.values_of_t::<usize>("flag-antennas") {
-            Ok(antenna_idxs) => {
-                for (value_idx, &antenna_idx) in antenna_idxs.iter().enumerate() {
-                    if antenna_idx >= corr_ctx.metafits_context.num_ants {
-                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
-                            option: "--flag-antennas <ANTS>...".into(),
-                            expected: format!(
-                                "antenna_idx < num_ants={}",
-                                corr_ctx.metafits_context.num_ants
-                            ),
-                            received: format!(
-                                "antenna_idxs[{}]={}. all:{:?}",
-                                value_idx, antenna_idx, antenna_idxs
-                            ),
-                        }));
-                    }
-                    flag_ctx.antenna_flags[antenna_idx] = true;
-                }
+        for antenna_idx in Self::parse_flag_idx_matches(
+            matches,
+            "flag-antennas",
+            "--flag-antennas <ANTS>...",
+            ("antenna", "num_ants", corr_ctx.metafits_context.num_ants),
+        )? {
+            flag_ctx.antenna_flags[antenna_idx] = true;
+        }
+        if matches.is_present("flag-autos") {
+            flag_ctx.autos = true;
+        }
+        if matches.is_present("flag-bad-tiles") {
+            if flag_ctx.autos {
+                warn!("--flag-bad-tiles has no effect when autocorrelations are flagged");
+            }
+            flag_ctx.bad_tile_threshold = Some(DEFAULT_BAD_TILE_SIGMA);
+        }
+        flag_ctx.extension = Self::parse_flag_extension_matches(matches)?;
+        match matches.value_of_t::<f32>("bad-tile-sigma") {
+            Ok(sigma) if sigma > 0. => {
+                flag_ctx.bad_tile_threshold = Some(sigma);
+            }
+            Ok(sigma) => {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--bad-tile-sigma <SIGMA>".into(),
+                    expected: "a positive number".into(),
+                    received: format!("{}", sigma),
+                }));
             }
             Err(err) => match err.kind() {
-                ArgumentNotFound { .. } => {}
+                ArgumentNotFound => {}
                 _ => return Err(err.into()),
             },
         };
-        if matches.is_present("flag-autos") {
-            flag_ctx.autos = true;
-        }
         if matches.is_present("flag-dc") {
             flag_ctx.flag_dc = true;
         }
         if matches.is_present("no-flag-dc") {
             flag_ctx.flag_dc = false;
         }
+        Self::parse_flag_edge_matches(matches, corr_ctx, oversampled, &mut flag_ctx)?;
+        Self::parse_flag_time_matches(matches, corr_ctx, &mut flag_ctx)?;
+        Ok(flag_ctx)
+    }
+
+    /// Parse the indices given to the flagging option `name`, which must be less than `len`,
+    /// the number of `{item}`s, named `len_name` in errors.
+    fn parse_flag_idx_matches(
+        matches: &clap::ArgMatches,
+        name: &str,
+        option: &str,
+        (item, len_name, len): (&str, &str, usize),
+    ) -> Result<Vec<usize>, BirliError> {
+        let idxs = match matches.values_of_t::<usize>(name) {
+            Ok(idxs) => idxs,
+            Err(err) => match err.kind() {
+                ArgumentNotFound { .. } => return Ok(vec![]),
+                _ => return Err(err.into()),
+            },
+        };
+        if let Some((value_idx, idx)) = idxs.iter().enumerate().find(|(_, &idx)| idx >= len) {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: option.into(),
+                expected: format!("{}_idx < {}={}", item, len_name, len),
+                received: format!("{}_idxs[{}]={}. all:{:?}", item, value_idx, idx, idxs),
+            }));
+        }
+        Ok(idxs)
+    }
+
+    /// Flag the fine channels on the edges of each coarse channel with `--flag-edge-chans` or
+    /// `--flag-edge-width`. These are applied to oversampled observations too, even though the
+    /// edges of their coarse channels are not attenuated by the PFB.
+    fn parse_flag_edge_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        oversampled: bool,
+        flag_ctx: &mut FlagContext,
+    ) -> Result<(), BirliError> {
         match matches.value_of_t::<usize>("flag-edge-chans") {
             Ok(n) => {
                 if n >= flag_ctx.fine_chan_flags.len() / 2 {
@@ -908,6 +587,24 @@ impl<'a> BirliContext<'a> {
                 _ => return Err(err.into()),
             },
         };
+        if oversampled
+            && (matches.is_present("flag-edge-chans") || matches.is_present("flag-edge-width"))
+        {
+            info!(
+                "The coarse channels of this observation are oversampled, so their edges are not \
+                attenuated by the PFB, but they will be flagged as requested."
+            );
+        }
+        Ok(())
+    }
+
+    /// Flag the start and end of the observation with `--flag-init`, `--flag-end`,
+    /// `--flag-init-steps` and `--flag-end-steps`.
+    fn parse_flag_time_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        flag_ctx: &mut FlagContext,
+    ) -> Result<(), BirliError> {
         match matches.value_of_t::<f32>("flag-init") {
             Ok(init_time) => {
                 let d = corr_ctx.metafits_context.corr_int_time_ms as f32 / 1000.0;
@@ -964,7 +661,189 @@ impl<'a> BirliContext<'a> {
                 _ => return Err(err.into()),
             },
         };
-        Ok(flag_ctx)
+        Ok(())
+    }
+
+    fn parse_flag_extension_matches(
+        matches: &clap::ArgMatches,
+    ) -> Result<FlagExtension, BirliError> {
+        let mut extension = FlagExtension::default();
+        for (option, dilate) in [
+            ("flag-dilate-time", &mut extension.dilate_time),
+            ("flag-dilate-freq", &mut extension.dilate_freq),
+        ] {
+            match matches.value_of_t::<usize>(option) {
+                Ok(radius) => *dilate = radius,
+                Err(err) => match err.kind() {
+                    ArgumentNotFound => {}
+                    _ => return Err(err.into()),
+                },
+            };
+        }
+        for (option, occupancy) in [
+            ("flag-timestep-occupancy", &mut extension.timestep_occupancy),
+            ("flag-chan-occupancy", &mut extension.channel_occupancy),
+            ("flag-baseline-occupancy", &mut extension.baseline_occupancy),
+            ("flag-antenna-occupancy", &mut extension.antenna_occupancy),
+        ] {
+            match matches.value_of_t::<f32>(option) {
+                Ok(frac) if (0. ..1.).contains(&frac) => *occupancy = Some(frac),
+                Ok(frac) => {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: format!("--{} <FRAC>", option),
+                        expected: "a fraction in [0, 1)".into(),
+                        received: format!("{}", frac),
+                    }));
+                }
+                Err(err) => match err.kind() {
+                    ArgumentNotFound => {}
+                    _ => return Err(err.into()),
+                },
+            };
+        }
+        Ok(extension)
+    }
+
+    /// Flag fine channels whose passband gain is below `--flag-passband-gain`, which depends on
+    /// the passband gains selected in `prep_ctx`.
+    fn parse_passband_flag_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        prep_ctx: &PreprocessContext,
+        flag_ctx: &mut FlagContext,
+    ) -> Result<(), BirliError> {
+        let threshold = match matches.value_of_t::<f64>("flag-passband-gain") {
+            Ok(threshold) if threshold > 0. => threshold,
+            Ok(threshold) => {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--flag-passband-gain <GAIN>".into(),
+                    expected: "a positive number".into(),
+                    received: format!("{}", threshold),
+                }));
+            }
+            Err(err) => match err.kind() {
+                ArgumentNotFound => return Ok(()),
+                _ => return Err(err.into()),
+            },
+        };
+        if let Some(passband_gains) = prep_ctx.passband_gains.as_deref() {
+            let num_flagged = flag_ctx.flag_low_gain_chans(
+                passband_gains,
+                &ScrunchType::from_mwa_version(corr_ctx.mwa_version)?,
+                threshold,
+            )?;
+            info!(
+                "flagging {} fine chans in each coarse chan with a passband gain below {}",
+                num_flagged, threshold
+            );
+        } else {
+            warn!(
+                "--flag-passband-gain has no effect without a passband gains table, use \
+                --passband-gains or --passband-gains-file to select one"
+            );
+        }
+        Ok(())
+    }
+
+    /// Track the target of `--ephemeris`, which must cover the selected timesteps, labelling the
+    /// outputs with its position in the middle of the selection.
+    fn parse_ephemeris_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        vis_sel: &VisSelection,
+        prep_ctx: &mut PreprocessContext,
+    ) -> Result<(), BirliError> {
+        let ephemeris = match matches.value_of("ephemeris") {
+            Some(path) => Ephemeris::from_file(path)?,
+            None => return Ok(()),
+        };
+        if !prep_ctx.correct_geometry {
+            warn!("--ephemeris has no effect with --no-geometric-delay");
+        }
+        let (first, last) = Self::selected_centroids(corr_ctx, vis_sel);
+        ephemeris.radec_at(first)?;
+        ephemeris.radec_at(last)?;
+        prep_ctx.phase_centre = ephemeris.radec_at(first + (last - first) / 2)?;
+        info!(
+            "tracking an ephemeris of {} positions, labelling outputs with {}",
+            ephemeris.entries().len(),
+            prep_ctx.phase_centre
+        );
+        prep_ctx.ephemeris = Some(ephemeris);
+        Ok(())
+    }
+
+    /// Correct for the delays to the near-field target of `--near-field-azel` or
+    /// `--near-field-ecef`, which must cover the selected timesteps.
+    fn parse_near_field_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        vis_sel: &VisSelection,
+        prep_ctx: &mut PreprocessContext,
+    ) -> Result<(), BirliError> {
+        let near_field = match (
+            matches.value_of("near-field-azel"),
+            matches.value_of("near-field-ecef"),
+        ) {
+            (Some(path), _) => NearFieldTrack::from_file(path, NearFieldFrame::AzElRange)?,
+            (_, Some(path)) => NearFieldTrack::from_file(path, NearFieldFrame::Ecef)?,
+            _ => return Ok(()),
+        };
+        if !prep_ctx.correct_geometry {
+            warn!("near-field corrections have no effect with --no-geometric-delay");
+        }
+        let (first, last) = Self::selected_centroids(corr_ctx, vis_sel);
+        near_field.position_at(first, prep_ctx.array_pos)?;
+        near_field.position_at(last, prep_ctx.array_pos)?;
+        info!(
+            "correcting for the delays to a near-field target at {} positions, labelling outputs with {}",
+            near_field.entries().len(),
+            prep_ctx.phase_centre
+        );
+        prep_ctx.near_field = Some(near_field);
+        Ok(())
+    }
+
+    /// Phase each timestep to the fixed azimuth and elevation of `--drift`, labelling the outputs
+    /// with its J2000 position in the middle of the selection.
+    fn parse_drift_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        vis_sel: &VisSelection,
+        prep_ctx: &mut PreprocessContext,
+    ) {
+        let meta_ctx = &corr_ctx.metafits_context;
+        let azel = match matches.value_of("drift") {
+            Some("zenith") => AzEl::new(0., FRAC_PI_2),
+            Some("pointing") => AzEl::new_degrees(meta_ctx.az_deg, meta_ctx.alt_deg),
+            _ => return,
+        };
+        if !prep_ctx.correct_geometry {
+            warn!("--drift has no effect with --no-geometric-delay");
+        }
+        let (first, last) = Self::selected_centroids(corr_ctx, vis_sel);
+        let dut1 = hifitime::Duration::from_f64(meta_ctx.dut1.unwrap_or(0.0), Unit::Second);
+        prep_ctx.phase_centre =
+            drift_phase_centre(azel, first + (last - first) / 2, prep_ctx.array_pos, dut1);
+        info!(
+            "drifting at az/el {}, labelling outputs with {}",
+            azel, prep_ctx.phase_centre
+        );
+        prep_ctx.drift = Some(azel);
+    }
+
+    /// The centroids of the first and last selected timesteps.
+    fn selected_centroids(corr_ctx: &CorrelatorContext, vis_sel: &VisSelection) -> (Epoch, Epoch) {
+        let int_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
+        let centroid = |timestep_idx: usize| {
+            Epoch::from_gpst_seconds(
+                corr_ctx.timesteps[timestep_idx].gps_time_ms as f64 / 1e3 + int_time_s / 2.,
+            )
+        };
+        (
+            centroid(vis_sel.timestep_range.start),
+            centroid(vis_sel.timestep_range.end - 1),
+        )
     }
 
     fn flag_edge_channels(n: usize, channels: &mut [bool]) {
@@ -1060,6 +939,7 @@ impl<'a> BirliContext<'a> {
         matches: &clap::ArgMatches,
         avg_time: usize,
         vis_sel: &VisSelection,
+        num_halo_timesteps: usize,
     ) -> Result<Option<usize>, BirliError> {
         let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
         let num_timesteps_per_chunk: Option<usize> = match (
@@ -1097,15 +977,21 @@ impl<'a> BirliContext<'a> {
                 let bytes_selected = vis_sel.estimate_bytes_best(fine_chans_per_coarse);
                 let bytes_per_timestep = bytes_selected / vis_sel.timestep_range.len();
                 let bytes_per_avg_time = bytes_per_timestep * avg_time;
+                // the halo either side of each chunk also needs to fit in memory
+                let bytes_per_halo = bytes_per_timestep * 2 * num_halo_timesteps;
                 if max_mem_bytes < bytes_selected as f64 {
-                    if max_mem_bytes < bytes_per_avg_time as f64 {
+                    if max_mem_bytes < (bytes_per_avg_time + bytes_per_halo) as f64 {
                         return Err(BirliError::CLIError(InvalidCommandLineArgument {
                             option: "--max-memory <GIBIBYTES>".into(),
                             expected: format!("at least enough memory for an averaged timestep ({} * {:.02} = {:.02} GiB)", avg_time, bytes_per_timestep as f64 / 1024.0_f64.powi(3), bytes_per_avg_time as f64 / 1024.0_f64.powi(3)),
                             received: format!("{}GiB", max_mem_bytes as f64 / 1024.0_f64.powi(3)),
                         }));
                     }
-                    Some((max_mem_bytes / bytes_per_avg_time as f64).floor() as usize * avg_time)
+                    Some(
+                        ((max_mem_bytes - bytes_per_halo as f64) / bytes_per_avg_time as f64)
+                            .floor() as usize
+                            * avg_time,
+                    )
                 } else {
                     None
                 }
@@ -1121,26 +1007,131 @@ impl<'a> BirliContext<'a> {
         Ok(num_timesteps_per_chunk)
     }
 
-    fn parse_prep_matches(
+    #[allow(unused_variables)]
+    fn parse_halo_matches(
         matches: &clap::ArgMatches,
-        corr_ctx: &CorrelatorContext,
+        prep_ctx: &PreprocessContext,
+        flag_ctx: &FlagContext,
+    ) -> Result<usize, BirliError> {
+        cfg_if! {
+            if #[cfg(feature = "aoflagger")] {
+                match matches.value_of_t::<usize>("rfi-halo") {
+                    Ok(_) if prep_ctx.aoflagger_strategy.is_none() && flag_ctx.extension.is_noop() => {
+                        warn!("--rfi-halo has no effect without RFI flagging or flag extension");
+                        Ok(0)
+                    }
+                    Ok(_) if !matches.is_present("time-chunk")
+                        && !matches.is_present("max-memory") => {
+                        warn!("--rfi-halo has no effect without --time-chunk or --max-memory");
+                        Ok(0)
+                    }
+                    Ok(steps) => Ok(steps),
+                    Err(err) => match err.kind() {
+                        ArgumentNotFound => Ok(0),
+                        _ => Err(err.into()),
+                    },
+                }
+            } else {
+                Ok(0)
+            }
+        }
+    }
+
+    fn parse_prep_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        oversampled: bool,
     ) -> Result<PreprocessContext<'a>, BirliError> {
         let mut prep_ctx = PreprocessContext {
             draw_progress: !matches.is_present("no-draw-progress"),
             ..PreprocessContext::default()
         };
-        prep_ctx.array_pos = if matches.is_present("emulate-cotter") {
-            info!("Using array position from Cotter.");
-            LatLngHeight {
-                longitude_rad: COTTER_MWA_LONGITUDE_RADIANS,
-                latitude_rad: COTTER_MWA_LATITUDE_RADIANS,
-                height_metres: COTTER_MWA_HEIGHT_METRES,
+        prep_ctx.array_pos = Self::parse_array_pos_matches(matches)?;
+        prep_ctx.phase_centre = Self::parse_phase_centre_matches(matches, corr_ctx)?;
+        prep_ctx.amp_threshold = Self::parse_amp_threshold_matches(matches)?;
+        prep_ctx.flag_sanity =
+            matches.is_present("flag-sanity") || prep_ctx.amp_threshold.is_some();
+        Self::parse_cable_matches(matches, corr_ctx, &mut prep_ctx)?;
+        prep_ctx.tile_overrides = matches
+            .value_of("tile-overrides")
+            .map(|path| TileOverrides::from_file(path, &corr_ctx.metafits_context))
+            .transpose()?;
+        prep_ctx.correct_digital_gains = !matches.is_present("no-digital-gains");
+        // only the legacy correlator quantises to 4 bits before correlating.
+        prep_ctx.correct_van_vleck = matches!(
+            corr_ctx.metafits_context.mwa_version,
+            Some(MWAVersion::CorrLegacy | MWAVersion::CorrOldLegacy)
+        ) && !matches.is_present("no-van-vleck");
+        Self::parse_passband_matches(matches, corr_ctx, oversampled, &mut prep_ctx)?;
+        Self::parse_geometry_matches(matches, corr_ctx, &mut prep_ctx);
+        cfg_if! {
+            if #[cfg(feature = "aoflagger")] {
+                prep_ctx.aoflagger_strategy = if matches.is_present("no-rfi") {
+                    None
+                } else {
+                    match matches.value_of_t::<String>("aoflagger-strategy") {
+                        Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+                        Ok(strategy) => {
+                            // aoflagger aborts if it can't load the strategy, so check it before
+                            // any data is read.
+                            validate_strategy_file(&strategy)?;
+                            Some(AOFlaggerStrategy::File(strategy))
+                        }
+                        Err(_) => {
+                            let strategy = BundledStrategy::MwaDefault;
+                            info!("Using aoflagger strategy {}", strategy);
+                            Some(AOFlaggerStrategy::Bundled(strategy))
+                        }
+                    }
+                };
+            }
+        }
+        if let Some(step_order) = matches.value_of("step-order") {
+            prep_ctx.step_order = step_order.parse()?;
+        }
+        Ok(prep_ctx)
+    }
+
+    fn parse_array_pos_matches(matches: &clap::ArgMatches) -> Result<LatLngHeight, BirliError> {
+        let array_pos = match (
+            matches
+                .values_of_t::<f64>("array-position")
+                .map(|v| (v[0], v[1], v[2])),
+            matches.is_present("emulate-cotter"),
+        ) {
+            (Err(err), _) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            (Ok(_), true) => {
+                unreachable!("--array-position conflicts with --emulate-cotter, enforced by clap");
+            }
+            (Ok((lon, lat, height)), _) => {
+                info!("Using array position from --array-position.");
+                LatLngHeight {
+                    longitude_rad: lon.to_radians(),
+                    latitude_rad: lat.to_radians(),
+                    height_metres: height,
+                }
+            }
+            (_, true) => {
+                info!("Using array position from Cotter.");
+                LatLngHeight {
+                    longitude_rad: COTTER_MWA_LONGITUDE_RADIANS,
+                    latitude_rad: COTTER_MWA_LATITUDE_RADIANS,
+                    height_metres: COTTER_MWA_HEIGHT_METRES,
+                }
+            }
+            _ => {
+                info!("Using default MWA array position.");
+                LatLngHeight::new_mwa()
             }
-        } else {
-            info!("Using default MWA array position.");
-            LatLngHeight::new_mwa()
         };
-        prep_ctx.phase_centre = match (
+        Ok(array_pos)
+    }
+
+    fn parse_phase_centre_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+    ) -> Result<RADec, BirliError> {
+        let phase_centre = match (
             matches
                 .values_of_t::<f64>("phase-centre")
                 .map(|v| (v[0], v[1])),
@@ -1154,6 +1145,52 @@ impl<'a> BirliContext<'a> {
             (_, true) => RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context),
             _ => RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context),
         };
+        Ok(phase_centre)
+    }
+
+    fn parse_amp_threshold_matches(
+        matches: &clap::ArgMatches,
+    ) -> Result<Option<AmplitudeThreshold>, BirliError> {
+        let amp_threshold = match (
+            matches.value_of_t::<f32>("flag-amp-max"),
+            matches.value_of_t::<f32>("flag-amp-mad"),
+        ) {
+            // filter any errors other than ArgumentNotFound
+            (Err(err), _) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            (_, Err(err)) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            (Ok(_), Ok(_)) => {
+                unreachable!("--flag-amp-max conflicts with --flag-amp-mad, enforced by clap")
+            }
+            (Ok(amp), _) => {
+                if amp.is_nan() || amp <= 0. {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--flag-amp-max <AMP>".into(),
+                        expected: "a positive amplitude".into(),
+                        received: format!("{}", amp),
+                    }));
+                }
+                Some(AmplitudeThreshold::Absolute(amp))
+            }
+            (_, Ok(sigma)) => {
+                if sigma.is_nan() || sigma <= 0. {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--flag-amp-mad <SIGMA>".into(),
+                        expected: "a positive number of standard deviations".into(),
+                        received: format!("{}", sigma),
+                    }));
+                }
+                Some(AmplitudeThreshold::Mad(sigma))
+            }
+            _ => None,
+        };
+        Ok(amp_threshold)
+    }
+
+    fn parse_cable_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        prep_ctx: &mut PreprocessContext,
+    ) -> Result<(), BirliError> {
         prep_ctx.correct_cable_lengths = {
             let cable_delays_disabled = matches.is_present("no-cable-delay");
             let cable_delays_applied = corr_ctx.metafits_context.cable_delays_applied;
@@ -1161,44 +1198,174 @@ impl<'a> BirliContext<'a> {
                 "cable corrections: applied={}, disabled={}",
                 cable_delays_applied, cable_delays_disabled
             );
-            matches!(
-                cable_delays_applied,
-                CableDelaysApplied::NoCableDelaysApplied
-            ) && !cable_delays_disabled
+            match cable_delays_applied {
+                CableDelaysApplied::NoCableDelaysApplied => !cable_delays_disabled,
+                _ if cable_delays_disabled => false,
+                _ if matches.is_present("force-cable-delay") => {
+                    warn!(
+                        "The correlator has already applied cable delays ({}), applying them \
+                        again with --force-cable-delay will double-correct the data!",
+                        cable_delays_applied
+                    );
+                    true
+                }
+                _ => {
+                    info!(
+                        "Skipping cable corrections, the correlator has already applied them ({}).",
+                        cable_delays_applied
+                    );
+                    false
+                }
+            }
         };
-        prep_ctx.correct_digital_gains = !matches.is_present("no-digital-gains");
-        prep_ctx.passband_gains = match matches.value_of("passband-gains") {
-            None | Some("none") => None,
-            Some("jake") => Some(PFB_JAKE_2022_200HZ),
-            Some("cotter") => Some(PFB_COTTER_2014_10KHZ),
-            Some(option) => panic!("unknown option for --passband-gains: {}", option),
+        if !matches!(
+            corr_ctx.metafits_context.cable_delays_applied,
+            CableDelaysApplied::NoCableDelaysApplied
+        ) {
+            prep_ctx.correlator_cable_delays = Some(corr_ctx.metafits_context.cable_delays_applied);
+        }
+        if matches.is_present("cable-flavours") {
+            let table = match matches.value_of("cable-flavour-table") {
+                Some(path) => CableTypeTable::from_file(path)?,
+                None => CableTypeTable::default(),
+            };
+            let flavours = read_metafits_flavours(&corr_ctx.metafits_context)?;
+            prep_ctx.cable_model = Some(CableModel::from_flavours(
+                &flavours,
+                &table,
+                matches.is_present("cable-attenuation"),
+                corr_ctx.metafits_context.centre_freq_hz as f64,
+            )?);
+        }
+        Ok(())
+    }
+
+    fn parse_passband_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        oversampled: bool,
+        prep_ctx: &mut PreprocessContext,
+    ) -> Result<(), BirliError> {
+        prep_ctx.passband_gains = if let Some(path) = matches.value_of("passband-gains-file") {
+            let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+            let coarse_chan_width_hz = corr_ctx.metafits_context.coarse_chan_width_hz as f64;
+            let gains = match read_passband_gains(path) {
+                Err(PassbandGainsError::Empty { .. }) => {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--passband-gains-file".into(),
+                        expected: format!("at least {} gains", fine_chans_per_coarse),
+                        received: "no gains".into(),
+                    }))
+                }
+                Err(PassbandGainsError::BadGain { index, gain, .. }) => {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--passband-gains-file".into(),
+                        expected: "finite, positive gains".into(),
+                        received: format!("a gain of {} at index {}", gain, index),
+                    }))
+                }
+                result => result?,
+            };
+            // each fine channel is scrunched from a whole number of gains.
+            let fscrunch = gains.len() / fine_chans_per_coarse;
+            if fscrunch == 0 || gains.len() % fine_chans_per_coarse != 0 {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--passband-gains-file".into(),
+                    expected: format!(
+                        "a non-zero multiple of {} gains per coarse channel, for a resolution \
+                        which divides the fine channel width of {}Hz",
+                        fine_chans_per_coarse, corr_ctx.metafits_context.corr_fine_chan_width_hz
+                    ),
+                    received: format!(
+                        "{} gains, for a resolution of {}Hz",
+                        gains.len(),
+                        coarse_chan_width_hz / gains.len() as f64
+                    ),
+                }));
+            }
+            info!(
+                "Using {} passband gains from {}, at a resolution of {}Hz.",
+                gains.len(),
+                path,
+                coarse_chan_width_hz / gains.len() as f64
+            );
+            Some(gains.into())
+        } else {
+            match matches.value_of("passband-gains") {
+                Some("auto") if oversampled => {
+                    info!(
+                        "The coarse channels of this observation are oversampled, the retained \
+                        band is flat so no passband gains correction will be applied."
+                    );
+                    None
+                }
+                None | Some("none") => None,
+                Some("empirical") => {
+                    prep_ctx.empirical_passband = true;
+                    None
+                }
+                Some(option) => {
+                    if oversampled {
+                        warn!(
+                            "The coarse channels of this observation are oversampled, but the {} \
+                            passband gains model a critically sampled PFB.",
+                            option
+                        );
+                    }
+                    match option {
+                        "auto" | "jake" => Some(PFB_JAKE_2022_200HZ.into()),
+                        "cotter" => Some(PFB_COTTER_2014_10KHZ.into()),
+                        _ => {
+                            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                                option: "--passband-gains <TYPE>".into(),
+                                expected: "auto, none, cotter, jake or empirical".into(),
+                                received: option.into(),
+                            }))
+                        }
+                    }
+                }
+            }
         };
+        Ok(())
+    }
+
+    fn parse_geometry_matches(
+        matches: &clap::ArgMatches,
+        corr_ctx: &CorrelatorContext,
+        prep_ctx: &mut PreprocessContext,
+    ) {
         prep_ctx.correct_geometry = {
             let geometric_delays_disabled = matches.is_present("no-geometric-delay");
             let geometric_delays_applied = corr_ctx.metafits_context.geometric_delays_applied;
             debug!(
                 "geometric corrections: applied={:?}, disabled={}",
-                geometric_delays_applied, !geometric_delays_disabled
+                geometric_delays_applied, geometric_delays_disabled
             );
-            matches!(geometric_delays_applied, GeometricDelaysApplied::No)
-                && !geometric_delays_disabled
-        };
-        cfg_if! {
-            if #[cfg(feature = "aoflagger")] {
-                prep_ctx.aoflagger_strategy = if matches.is_present("no-rfi") {
-                    None
-                } else {
-                    match matches.value_of_t("aoflagger-strategy") {
-                        Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
-                        Ok(strategy) => Some(strategy),
-                        Err(_) => Some(unsafe {
-                            cxx_aoflagger_new().FindStrategyFileMWA()
-                        }),
-                    }
-                };
+            match geometric_delays_applied {
+                GeometricDelaysApplied::No => {}
+                _ if geometric_delays_disabled => {}
+                _ if matches.is_present("force-geometric-delay") => {
+                    warn!(
+                        "The correlator has already applied geometric delays ({}), applying \
+                        geometric corrections on top of these with --force-geometric-delay will \
+                        double-correct the data!",
+                        geometric_delays_applied
+                    );
+                }
+                _ => {
+                    info!(
+                        "Reversing geometric delays applied by the correlator ({}) before \
+                        geometric corrections.",
+                        geometric_delays_applied
+                    );
+                    prep_ctx.reverse_geometric_delays = true;
+                }
             }
-        }
-        Ok(prep_ctx)
+            if !matches!(geometric_delays_applied, GeometricDelaysApplied::No) {
+                prep_ctx.correlator_geometric_delays = Some(geometric_delays_applied);
+            }
+            !geometric_delays_disabled
+        };
     }
 
     /// Parse an iterator of arguments, `args` into a `BirliContext`.
@@ -1220,11 +1387,13 @@ impl<'a> BirliContext<'a> {
         trace!("arg matches:\n{:?}", &matches);
 
         for unimplemented_option in &["no-sel-autos", "no-sel-flagged-ants", "sel-ants"] {
-            assert!(
-                !matches.is_present(unimplemented_option),
-                "option not yet implemented: --{}",
-                unimplemented_option
-            );
+            if matches.is_present(unimplemented_option) {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: format!("--{}", unimplemented_option),
+                    expected: "an implemented option".into(),
+                    received: "an option which is not yet implemented".into(),
+                }));
+            }
         }
 
         for untested_option in &[
@@ -1244,27 +1413,34 @@ impl<'a> BirliContext<'a> {
             }
         }
 
-        let io_ctx = Self::parse_io_matches(&matches);
-        let corr_ctx = io_ctx.get_corr_ctx()?;
-        debug!("mwalib correlator context:\n{}", &corr_ctx);
-        let vis_sel = Self::parse_vis_sel_matches(&corr_ctx, &matches)?;
-        let mut flag_ctx = Self::parse_flag_matches(&corr_ctx, &matches)?;
-        let prep_ctx = Self::parse_prep_matches(&matches, &corr_ctx)?;
-        let (avg_time, avg_freq) = Self::parse_avg_matches(&matches, &corr_ctx)?;
+        let mut io_ctx = Self::parse_io_matches(&matches);
+        io_ctx.phased_outs = Self::parse_phased_out_matches(&matches)?;
+        let builder = BirliContextBuilder::new(io_ctx)?;
+        let corr_ctx = builder.corr_ctx();
+        debug!("mwalib correlator context:\n{}", corr_ctx);
+        let vis_sel = Self::parse_vis_sel_matches(corr_ctx, &matches)?;
+        let oversampled = read_oversampled(&corr_ctx.metafits_context)?;
+        debug!("oversampled coarse channels: {}", oversampled);
+        let mut flag_ctx = Self::parse_flag_matches(corr_ctx, &matches, oversampled)?;
+        let mut prep_ctx = Self::parse_prep_matches(&matches, corr_ctx, oversampled)?;
+        Self::parse_ephemeris_matches(&matches, corr_ctx, &vis_sel, &mut prep_ctx)?;
+        Self::parse_near_field_matches(&matches, corr_ctx, &vis_sel, &mut prep_ctx)?;
+        Self::parse_drift_matches(&matches, corr_ctx, &vis_sel, &mut prep_ctx);
+        let (avg_time, avg_freq) = Self::parse_avg_matches(&matches, corr_ctx)?;
+        Self::parse_passband_flag_matches(&matches, corr_ctx, &prep_ctx, &mut flag_ctx)?;
+        let num_halo_timesteps = Self::parse_halo_matches(&matches, &prep_ctx, &flag_ctx)?;
         let num_timesteps_per_chunk =
-            Self::parse_chunk_matches(&corr_ctx, &matches, avg_time, &vis_sel)?;
-        flag_ctx.finalise_flag_settings(&corr_ctx);
-        let result = Self {
-            corr_ctx,
-            prep_ctx,
-            vis_sel,
-            flag_ctx,
-            io_ctx,
-            avg_time,
-            avg_freq,
-            num_timesteps_per_chunk,
-            ignore_dut1: matches.is_present("ignore-dut1"),
-        };
+            Self::parse_chunk_matches(corr_ctx, &matches, avg_time, &vis_sel, num_halo_timesteps)?;
+        let result = builder
+            .vis_sel(vis_sel)
+            .flag_ctx(flag_ctx)
+            .prep_ctx(prep_ctx)
+            .avg_time(avg_time)
+            .avg_freq(avg_freq)
+            .num_timesteps_per_chunk(num_timesteps_per_chunk)
+            .num_halo_timesteps(num_halo_timesteps)
+            .ignore_dut1(matches.is_present("ignore-dut1"))
+            .build()?;
 
         info!("{}", &result);
 
@@ -1274,358 +1450,6 @@ impl<'a> BirliContext<'a> {
 
         Ok(result)
     }
-
-    /// Read, Preprocess and write corrected visibilities chunks.
-    ///
-    /// # Errors
-    ///
-    /// can raise:
-    /// - `BadArrayShape` if the shape of the calibration solutions
-    ///     is incompatible with the visibility shape.
-    /// - preprocessing errors
-    pub fn run(self) -> Result<HashMap<String, Duration>, BirliError> {
-        let Self {
-            corr_ctx,
-            mut prep_ctx,
-            vis_sel,
-            flag_ctx,
-            io_ctx,
-            avg_time,
-            avg_freq,
-            num_timesteps_per_chunk,
-            ignore_dut1,
-        } = self;
-
-        // ////////// //
-        // Prepare IO //
-        // ////////// //
-
-        let vis_ctx = VisContext::from_mwalib(
-            &corr_ctx,
-            &vis_sel.timestep_range,
-            &vis_sel.coarse_chan_range,
-            &vis_sel.baseline_idxs,
-            avg_time,
-            avg_freq,
-        );
-
-        // TODO: move phase_centre, array_pos out of prep_ctx
-        let obs_ctx = ObsContext {
-            phase_centre: prep_ctx.phase_centre,
-            array_pos: prep_ctx.array_pos,
-            ..ObsContext::from_mwalib(&corr_ctx.metafits_context)
-        };
-
-        let mwa_ctx = MwaObsContext::from_mwalib(&corr_ctx.metafits_context);
-
-        prep_ctx.calsols = if let Some(ref calsol_file) = io_ctx.aocalsols_in {
-            let calsols = AOCalSols::read_andre_binary(calsol_file).unwrap();
-            assert!(
-                calsols.di_jones.dim().0 == 1,
-                "only 1 timeblock must be supplied for calsols. \
-                Instead found {} timeblocks. dimensions {:?}",
-                calsols.di_jones.dim().1,
-                calsols.di_jones.dim()
-            );
-            let calsol_chans = calsols.di_jones.dim().2;
-            if calsol_chans % corr_ctx.num_coarse_chans != 0 {
-                return Err(BirliError::BadArrayShape(BadArrayShape {
-                    argument: "input AO calibration solutions",
-                    function: "BirliContext::run",
-                    expected: format!(
-                        "a multiple of metafits_num_coarse_chans={}",
-                        corr_ctx.metafits_context.num_metafits_coarse_chans
-                    ),
-                    received: format!("{}", calsol_chans),
-                }));
-            }
-            let num_calsol_fine_chans_per_coarse = calsol_chans / corr_ctx.num_coarse_chans;
-            Some(
-                calsols
-                    .di_jones
-                    .index_axis(Axis(0), 0)
-                    .slice(s![
-                        ..,
-                        (vis_sel.coarse_chan_range.start * num_calsol_fine_chans_per_coarse)
-                            ..(vis_sel.coarse_chan_range.end * num_calsol_fine_chans_per_coarse)
-                    ])
-                    .to_owned(),
-            )
-        } else {
-            None
-        };
-
-        let args_strings = env::args().collect_vec();
-        let cmd_line = shlex::join(args_strings.iter().map(String::as_str));
-        let application = format!("{} {}", PKG_NAME, PKG_VERSION);
-        let message = prep_ctx.as_comment();
-        let history = History {
-            cmd_line: Some(&cmd_line),
-            application: Some(&application),
-            message: Some(&message),
-        };
-        let (s_lat, c_lat) = obs_ctx.array_pos.latitude_rad.sin_cos();
-        let (antenna_names, antenna_positions): (Vec<String>, Vec<marlu::XyzGeodetic>) = corr_ctx
-            .metafits_context
-            .antennas
-            .iter()
-            .map(|a| {
-                let enh = ENH {
-                    e: a.east_m,
-                    n: a.north_m,
-                    h: a.height_m,
-                };
-                let xyz = enh.to_xyz_inner(s_lat, c_lat);
-                (a.tile_name.clone(), xyz)
-            })
-            .unzip();
-        let dut1 = if ignore_dut1 {
-            hifitime::Duration::from_total_nanoseconds(0)
-        } else {
-            hifitime::Duration::from_f64(
-                corr_ctx.metafits_context.dut1.unwrap_or(0.0),
-                Unit::Second,
-            )
-        };
-        let mut uvfits_writer = io_ctx.uvfits_out.map(|uvfits_out| {
-            with_increment_duration!("init", {
-                UvfitsWriter::from_marlu(
-                    uvfits_out,
-                    &vis_ctx,
-                    obs_ctx.array_pos,
-                    obs_ctx.phase_centre,
-                    dut1,
-                    obs_ctx.name.as_deref(),
-                    antenna_names,
-                    antenna_positions.clone(),
-                    Some(&history),
-                )
-                .expect("unable to initialize uvfits writer")
-            })
-        });
-        let mut ms_writer = io_ctx.ms_out.map(|ms_out| {
-            let writer = MeasurementSetWriter::new(
-                ms_out,
-                obs_ctx.phase_centre,
-                obs_ctx.array_pos,
-                antenna_positions,
-                dut1,
-            );
-            with_increment_duration!("init", {
-                writer
-                    .initialize_mwa(
-                        &vis_ctx,
-                        &obs_ctx,
-                        &mwa_ctx,
-                        Some(&history),
-                        &vis_sel.coarse_chan_range,
-                    )
-                    .expect("unable to initialize ms writer");
-            });
-            writer
-        });
-
-        #[cfg(feature = "aoflagger")]
-        let (aoflagger_version, aoflagger_strategy) = {
-            let mut major = 0;
-            let mut minor = 0;
-            let mut subminor = 0;
-            unsafe {
-                aoflagger_sys::cxx_aoflagger_new().GetVersion(
-                    &mut major,
-                    &mut minor,
-                    &mut subminor,
-                );
-            }
-            (
-                Some(format!("v{major}.{minor}.{subminor}")),
-                prep_ctx.aoflagger_strategy.clone(),
-            )
-        };
-        #[cfg(not(feature = "aoflagger"))]
-        let (aoflagger_version, aoflagger_strategy) = (None, None);
-
-        let mut flag_file_set = io_ctx.flag_template.map(|flag_template| {
-            FlagFileSet::new(
-                &flag_template,
-                &corr_ctx,
-                &vis_sel,
-                aoflagger_version,
-                aoflagger_strategy,
-            )
-            .expect("cannot create flag file writer")
-        });
-
-        // //////// //
-        // Chunking //
-        // //////// //
-
-        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
-        let chunk_size = if let Some(steps) = num_timesteps_per_chunk {
-            steps
-        } else {
-            vis_sel.timestep_range.len()
-        };
-
-        // Allocate our big arrays once, reuse them for each chunk unless the chunk shape changes
-        let chunk_vis_sel = VisSelection {
-            timestep_range: (vis_sel.timestep_range.start
-                ..vis_sel.timestep_range.start + chunk_size),
-            ..vis_sel.clone()
-        };
-        let mut jones_array = chunk_vis_sel.allocate_jones(fine_chans_per_coarse)?;
-        let mut flag_array = chunk_vis_sel.allocate_flags(fine_chans_per_coarse)?;
-        let mut weight_array = chunk_vis_sel.allocate_weights(fine_chans_per_coarse)?;
-
-        for mut timestep_chunk in &vis_sel.timestep_range.clone().chunks(chunk_size) {
-            let chunk_first_timestep = timestep_chunk.next().expect("zero-sized chunk");
-            let chunk_vis_sel = VisSelection {
-                timestep_range: (chunk_first_timestep
-                    ..(timestep_chunk.last().unwrap_or(chunk_first_timestep) + 1)),
-                ..vis_sel.clone()
-            };
-            if num_timesteps_per_chunk.is_some() {
-                info!(
-                    "processing timestep chunk {:?} of {:?} % {}",
-                    chunk_vis_sel.timestep_range,
-                    vis_sel.timestep_range.clone(),
-                    chunk_size
-                );
-            }
-
-            // only reallocate arrays if the chunk dimensions have changed.
-            let chunk_dims = chunk_vis_sel.get_shape(fine_chans_per_coarse);
-            let (mut jones_array, mut flag_array, mut weight_array) = if jones_array.dim()
-                == chunk_dims
-            {
-                (
-                    jones_array.view_mut(),
-                    flag_array.view_mut(),
-                    weight_array.view_mut(),
-                )
-            } else {
-                (
-                    jones_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
-                    flag_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
-                    weight_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
-                )
-            };
-
-            // populate flags
-            flag_ctx.set_flags(
-                flag_array.view_mut(),
-                &chunk_vis_sel.timestep_range,
-                &chunk_vis_sel.coarse_chan_range,
-                &chunk_vis_sel.get_ant_pairs(&corr_ctx.metafits_context),
-            )?;
-
-            // populate visibilities
-            with_increment_duration!(
-                "read",
-                chunk_vis_sel.read_mwalib(
-                    &corr_ctx,
-                    jones_array.view_mut(),
-                    flag_array.view_mut(),
-                    prep_ctx.draw_progress,
-                )?
-            );
-
-            // populate weights
-            weight_array.fill(vis_ctx.weight_factor() as f32);
-
-            prep_ctx.preprocess(
-                &corr_ctx,
-                jones_array.view_mut(),
-                weight_array.view_mut(),
-                flag_array.view_mut(),
-                &chunk_vis_sel,
-            )?;
-
-            // output flags (before averaging)
-            if let Some(flag_file_set) = flag_file_set.as_mut() {
-                with_increment_duration!(
-                    "write",
-                    flag_file_set
-                        .write_flag_array(flag_array.view(), prep_ctx.draw_progress)
-                        .expect("unable to write flags")
-                );
-            }
-
-            // bake flags into weights
-            for (weight, flag) in izip!(weight_array.iter_mut(), flag_array.iter()) {
-                *weight = if *flag {
-                    -(*weight).abs()
-                } else {
-                    (*weight).abs()
-                } as f32;
-            }
-
-            let chunk_vis_ctx = VisContext::from_mwalib(
-                &corr_ctx,
-                &chunk_vis_sel.timestep_range,
-                &chunk_vis_sel.coarse_chan_range,
-                &chunk_vis_sel.baseline_idxs,
-                avg_time,
-                avg_freq,
-            );
-
-            // output uvfits
-            if let Some(uvfits_writer) = uvfits_writer.as_mut() {
-                with_increment_duration!(
-                    "write",
-                    uvfits_writer
-                        .write_vis(
-                            jones_array.view(),
-                            weight_array.view(),
-                            &chunk_vis_ctx,
-                            prep_ctx.draw_progress,
-                        )
-                        .expect("unable to write uvfits")
-                );
-            }
-
-            // output ms
-            if let Some(ms_writer) = ms_writer.as_mut() {
-                with_increment_duration!(
-                    "write",
-                    ms_writer
-                        .write_vis(
-                            jones_array.view(),
-                            weight_array.view(),
-                            &chunk_vis_ctx,
-                            prep_ctx.draw_progress,
-                        )
-                        .expect("unable to write ms")
-                );
-            }
-        }
-
-        // Finalise the uvfits writer.
-        if let Some(uvfits_writer) = uvfits_writer.as_mut() {
-            with_increment_duration!(
-                "write",
-                uvfits_writer
-                    .finalise()
-                    .expect("couldn't write antenna table to uvfits")
-            );
-        };
-
-        // Finalise the MS writer.
-        if let Some(ms_writer) = ms_writer.as_mut() {
-            with_increment_duration!("write", ms_writer.finalise().expect("couldn't finalise MS"));
-        };
-
-        // Finalise the mwaf files.
-        if let Some(flag_file_set) = flag_file_set {
-            flag_file_set
-                .finalise()
-                .expect("couldn't finalise mwaf files");
-        }
-
-        // Copy the global durations out to the caller.
-        let durations = crate::DURATIONS.lock().unwrap().clone();
-        Ok(durations)
-    }
 }
 
 #[cfg(test)]
@@ -1940,7 +1764,23 @@ mod tests {
 
 #[cfg(test)]
 mod argparse_tests {
-    use crate::{error::BirliError, test_common::get_1254670392_avg_paths, BirliContext};
+    use std::f64::consts::FRAC_PI_2;
+
+    use approx::assert_abs_diff_eq;
+    use marlu::{
+        constants::VEL_C, fitsio::FitsFile, fitsio_sys, HADec, LatLngHeight, XyzGeodetic, UVW,
+    };
+    use tempfile::tempdir;
+
+    use super::{InvalidCommandLineArgument, DEFAULT_BAD_TILE_SIGMA};
+    use crate::{
+        error::BirliError,
+        flags::{AmplitudeThreshold, FlagExtension},
+        passband_gains::read_passband_gains,
+        preprocessing::PreprocessStep,
+        test_common::{get_1254670392_avg_paths, get_mwax_context, get_mwax_data_paths},
+        BirliContext,
+    };
 
     #[test]
     fn test_parse_missing_input() {
@@ -2022,6 +1862,27 @@ mod argparse_tests {
         ));
     }
 
+    #[test]
+    fn test_parse_unimplemented_selection() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        for unimplemented_args in [
+            vec!["--no-sel-autos"],
+            vec!["--no-sel-flagged-ants"],
+            vec!["--sel-ants", "0", "1"],
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path];
+            args.extend_from_slice(&gpufits_paths);
+            args.extend_from_slice(&unimplemented_args);
+
+            assert!(matches!(
+                BirliContext::from_args(&args),
+                Err(BirliError::CLIError(InvalidCommandLineArgument { option, .. }))
+                    if option == unimplemented_args[0]
+            ));
+        }
+    }
+
     #[test]
     fn test_parse_valid_time_selection() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
@@ -2087,223 +1948,1084 @@ mod argparse_tests {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
         #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-init-steps", "1", "--"];
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-init-steps", "1", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.timestep_flags[0]);
+        assert!(!flag_ctx.timestep_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_flag_init_invalid_indivisible() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-init", "1",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_flag_end_time() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-init", "0", "--flag-end", "2", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext {
+            corr_ctx, flag_ctx, ..
+        } = BirliContext::from_args(&args).unwrap();
+        let n = corr_ctx.num_common_timesteps;
+
+        assert!(flag_ctx.timestep_flags[n - 1]);
+        assert!(!flag_ctx.timestep_flags[n - 2]);
+    }
+
+    #[test]
+    fn test_parse_flag_end_steps() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-init", "0", "--flag-end-steps", "1", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext {
+            corr_ctx, flag_ctx, ..
+        } = BirliContext::from_args(&args).unwrap();
+        let n = corr_ctx.num_common_timesteps;
+
+        assert!(flag_ctx.timestep_flags[n - 1]);
+        assert!(!flag_ctx.timestep_flags[n - 2]);
+    }
+
+    #[test]
+    fn test_parse_flag_end_invalid_indivisible() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-end", "1",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_coarse_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-coarse-chans", "0", "9999",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_valid_coarse_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-coarse-chans", "2",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.coarse_chan_flags[2]);
+        assert!(!flag_ctx.coarse_chan_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_invalid_fine_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-fine-chans", "0", "9999",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_valid_fine_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-fine-chans", "2", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.fine_chan_flags[2]);
+        assert!(!flag_ctx.fine_chan_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_invalid_antenna_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-antennas", "0", "9999",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_valid_antenna_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-antennas", "2", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.antenna_flags[2]);
+        assert!(!flag_ctx.antenna_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_no_flag_metafits_and_valid_antenna_flag() {
+        let (_, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            // in this metafits file, all ants are flagged.
+            "-m", "tests/data/1254670392_avg/1254670392.metafits",
+            "--no-flag-metafits",
+            "--flag-antennas", "2",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.antenna_flags[2]);
+        assert!(!flag_ctx.antenna_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_flag_autos() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-autos"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.autos);
+    }
+
+    #[test]
+    fn test_parse_flag_bad_tiles() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-bad-tiles"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(flag_ctx.bad_tile_threshold, Some(DEFAULT_BAD_TILE_SIGMA));
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-bad-tiles", "--bad-tile-sigma", "3"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(flag_ctx.bad_tile_threshold, Some(3.));
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-bad-tiles", "--bad-tile-sigma", "0"];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_flag_extension() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--flag-dilate-time", "1",
+            "--flag-dilate-freq", "2",
+            "--flag-timestep-occupancy", "0.5",
+            "--flag-chan-occupancy", "0.6",
+            "--flag-baseline-occupancy", "0.7",
+            "--flag-antenna-occupancy", "0.8",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(
+            flag_ctx.extension,
+            FlagExtension {
+                dilate_time: 1,
+                dilate_freq: 2,
+                timestep_occupancy: Some(0.5),
+                channel_occupancy: Some(0.6),
+                baseline_occupancy: Some(0.7),
+                antenna_occupancy: Some(0.8),
+            }
+        );
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-chan-occupancy", "1.5"];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_cable_flavours() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(prep_ctx.cable_model.is_none());
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--cable-flavours", "--cable-attenuation"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let cable_model = prep_ctx.cable_model.unwrap();
+        assert_eq!(cable_model.electrical_lengths_m.len(), 2);
+        assert!(cable_model.attenuations_db.is_some());
+
+        // the table must exist
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--cable-flavours", "--cable-flavour-table", "/nonexistent/cables.txt",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CableError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_array_position_and_tile_overrides() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let overrides_path = tmp_dir.path().join("tiles.txt");
+        let corr_ctx = get_mwax_context();
+        let tile_name = &corr_ctx.metafits_context.antennas[1].tile_name;
+        std::fs::write(&overrides_path, format!("{tile_name} delay_m 100\n")).unwrap();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--array-position", "116.67", "-26.7", "377.8",
+            "--tile-overrides", overrides_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_abs_diff_eq!(prep_ctx.array_pos.longitude_rad, 116.67_f64.to_radians());
+        assert_abs_diff_eq!(prep_ctx.array_pos.latitude_rad, (-26.7_f64).to_radians());
+        assert_abs_diff_eq!(prep_ctx.array_pos.height_metres, 377.8);
+        let tile_overrides = prep_ctx.tile_overrides.unwrap();
+        assert_eq!(tile_overrides.electrical_lengths_m[1], Some([100., 100.]));
+
+        // --array-position conflicts with --emulate-cotter
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--array-position", "116.67", "-26.7", "377.8",
+            "--emulate-cotter",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
+
+        // tiles must be in the metafits
+        std::fs::write(&overrides_path, "NotATile delay_m 100\n").unwrap();
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--tile-overrides", overrides_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::TileOverrideError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_passband_gains_file() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--passband-gains-file", "tests/data/subband-passband-32ch-cotter.txt",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let passband_gains = prep_ctx.passband_gains.unwrap();
+        assert_eq!(passband_gains.len(), 32);
+        assert_abs_diff_eq!(passband_gains[0], 0.50828905083);
+
+        // the number of gains must be a non-zero multiple of the fine channels per coarse channel,
+        // and the gains must be finite and positive.
+        let tmp_dir = tempdir().unwrap();
+        let gains_path = tmp_dir.path().join("gains.txt");
+        for contents in [
+            "0.5\n1.0\n0.5\n",
+            "1.0\n",
+            "# no gains\n",
+            "0.5\nNaN\n",
+            "0.5\ninf\n",
+            "0.5\n-1.0\n",
+            "0.5\n0\n",
+        ] {
+            std::fs::write(&gains_path, contents).unwrap();
+            #[rustfmt::skip]
+            let mut args = vec![
+                "birli", "-m", metafits_path,
+                "--passband-gains-file", gains_path.to_str().unwrap(),
+            ];
+            args.extend_from_slice(&gpufits_paths);
+            assert!(
+                matches!(
+                    BirliContext::from_args(&args),
+                    Err(BirliError::CLIError(InvalidCommandLineArgument { .. }))
+                ),
+                "{:?} should be rejected",
+                contents
+            );
+        }
+
+        // conflicts with an explicit --passband-gains
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--passband-gains-file", "tests/data/subband-passband-32ch-cotter.txt",
+            "--passband-gains", "none",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_flag_passband_gain() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        // the scrunched jake gains are ~0.81 and ~1.0 at 2 fine chans per coarse.
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--flag-passband-gain", "0.9",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(flag_ctx.fine_chan_flags, vec![true, false]);
+
+        // no effect without a passband table
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--flag-passband-gain", "0.9",
+            "--passband-gains", "none",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(flag_ctx.fine_chan_flags, vec![false, false]);
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--flag-passband-gain", "0",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_empirical_passband_gains_out() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let gains_path = tmp_dir.path().join("gains.txt");
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--passband-gains", "empirical",
+            "--passband-gains-out", gains_path.to_str().unwrap(),
+            "--no-draw-progress",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        assert!(birli_ctx.prep_ctx.empirical_passband);
+        assert_eq!(birli_ctx.prep_ctx.passband_gains, None);
+        assert_eq!(
+            birli_ctx.io_ctx.passband_gains_out,
+            Some(gains_path.clone())
+        );
+        assert!(birli_ctx
+            .prep_ctx
+            .as_comment()
+            .contains("empirical pfb gains"));
+
+        birli_ctx.run().unwrap();
+
+        let gains = read_passband_gains(&gains_path).unwrap();
+        assert_eq!(gains.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_phase_centre_out() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--phase-centre-out", "10", "-20", "a.uvfits",
+            "--phase-centre-out", "30", "40", "b.ms",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { io_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(io_ctx.phased_outs.len(), 2);
+        assert_abs_diff_eq!(
+            io_ctx.phased_outs[0].phase_centre.dec,
+            (-20_f64).to_radians()
+        );
+        assert_eq!(io_ctx.phased_outs[0].uvfits_out, Some("a.uvfits".into()));
+        assert_eq!(io_ctx.phased_outs[0].ms_out, None);
+        assert_abs_diff_eq!(io_ctx.phased_outs[1].phase_centre.ra, 30_f64.to_radians());
+        assert_eq!(io_ctx.phased_outs[1].uvfits_out, None);
+        assert_eq!(io_ctx.phased_outs[1].ms_out, Some("b.ms".into()));
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--phase-centre-out", "10", "-20", "a.txt",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(InvalidCommandLineArgument { .. }))
+        ));
+    }
+
+    /// An output phased to another centre should be identical to the output of phasing to that
+    /// centre on its own.
+    #[test]
+    fn test_phase_centre_out_matches_phase_centre() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let main_path = tmp_dir.path().join("main.uvfits");
+        let extra_path = tmp_dir.path().join("extra.uvfits");
+        let alone_path = tmp_dir.path().join("alone.uvfits");
+        let default_path = tmp_dir.path().join("default.uvfits");
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--no-draw-progress",
+            "-u", main_path.to_str().unwrap(),
+            "--phase-centre-out", "10", "20", extra_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        BirliContext::from_args(&args).unwrap().run().unwrap();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--no-draw-progress",
+            "-u", alone_path.to_str().unwrap(),
+            "--phase-centre", "10", "20",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        BirliContext::from_args(&args).unwrap().run().unwrap();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--no-draw-progress",
+            "-u", default_path.to_str().unwrap(),
+        ];
         args.extend_from_slice(&gpufits_paths);
-
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
-
-        assert!(flag_ctx.timestep_flags[0]);
-        assert!(!flag_ctx.timestep_flags[1]);
+        BirliContext::from_args(&args).unwrap().run().unwrap();
+
+        // compare the primary HDU, which has the visibilities. The comments of the antenna table
+        // header can contain junk.
+        let read = |path| {
+            let bytes = std::fs::read(path).unwrap();
+            let antenna_table_start = bytes
+                .windows(8)
+                .position(|window| window == b"XTENSION")
+                .unwrap();
+            bytes[..antenna_table_start].to_vec()
+        };
+        assert!(read(&extra_path) == read(&alone_path));
+        assert!(read(&main_path) == read(&default_path));
+        assert!(read(&main_path) != read(&extra_path));
     }
 
     #[test]
-    fn test_parse_flag_init_invalid_indivisible() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_step_order() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
 
         #[rustfmt::skip]
         let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-init", "1",
-            "--",
+            "birli", "-m", metafits_path,
+            "--step-order", "cable,digital,geometry,calibrate,flag,passband",
         ];
         args.extend_from_slice(&gpufits_paths);
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        assert_eq!(
+            birli_ctx.prep_ctx.step_order.phased_steps(),
+            &[
+                PreprocessStep::Geometry,
+                PreprocessStep::Calibration,
+                PreprocessStep::Flag,
+                PreprocessStep::PassbandGains
+            ]
+        );
+        assert!(format!("{}", birli_ctx.prep_ctx).contains(
+            "Will preprocess in the order cable,digital,geometry,calibrate,flag,passband"
+        ));
 
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--step-order", "cable,digital,geometry",
+        ];
+        args.extend_from_slice(&gpufits_paths);
         assert!(matches!(
             BirliContext::from_args(&args),
-            Err(BirliError::CLIError(_))
+            Err(BirliError::StepOrderError(_))
+        ));
+
+        // flags can't depend on the phase centre when there are several
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--step-order", "cable,digital,passband,geometry,flag,calibrate",
+            "--phase-centre-out", "10", "20", "a.uvfits",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::PipelineError(_))
         ));
     }
 
     #[test]
-    fn test_parse_flag_end_time() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_passband_gains_oversampled() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
 
-        #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-init", "0", "--flag-end", "2", "--"];
+        // critically sampled observations use the jake gains by default
+        let mut args = vec!["birli", "-m", metafits_path];
         args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.passband_gains.unwrap().len(), 6400);
 
-        let BirliContext {
-            corr_ctx, flag_ctx, ..
-        } = BirliContext::from_args(&args).unwrap();
-        let n = corr_ctx.num_common_timesteps;
+        let tmp_dir = tempdir().unwrap();
+        let tmp_metafits_path = tmp_dir.path().join("1297526432.metafits");
+        std::fs::copy(metafits_path, &tmp_metafits_path).unwrap();
+        {
+            let mut fptr = FitsFile::edit(&tmp_metafits_path).unwrap();
+            let hdu = fptr.primary_hdu().unwrap();
+            hdu.write_key(&mut fptr, "OVERSAMP", 1).unwrap();
+        }
 
-        assert!(flag_ctx.timestep_flags[n - 1]);
-        assert!(!flag_ctx.timestep_flags[n - 2]);
+        // oversampled observations have no passband gains correction by default
+        let mut args = vec!["birli", "-m", tmp_metafits_path.to_str().unwrap()];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.passband_gains, None);
+
+        // unless they are explicitly requested
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", tmp_metafits_path.to_str().unwrap(),
+            "--passband-gains", "jake",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.passband_gains.unwrap().len(), 6400);
     }
 
     #[test]
-    fn test_parse_flag_end_steps() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
-
-        #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-init", "0", "--flag-end-steps", "1", "--"];
+    fn test_parse_van_vleck() {
+        // MWAX data is not corrected
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let mut args = vec!["birli", "-m", metafits_path];
         args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(!prep_ctx.correct_van_vleck);
 
-        let BirliContext {
-            corr_ctx, flag_ctx, ..
-        } = BirliContext::from_args(&args).unwrap();
-        let n = corr_ctx.num_common_timesteps;
+        // legacy data is corrected by default
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", "tests/data/1196175296_mwa_ord/1196175296.metafits",
+            "tests/data/1196175296_mwa_ord/1196175296_20171201145440_gpubox01_00.fits",
+        ];
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(prep_ctx.correct_van_vleck);
 
-        assert!(flag_ctx.timestep_flags[n - 1]);
-        assert!(!flag_ctx.timestep_flags[n - 2]);
+        // unless disabled
+        args.push("--no-van-vleck");
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(!prep_ctx.correct_van_vleck);
     }
 
     #[test]
-    fn test_parse_flag_end_invalid_indivisible() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_ephemeris() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = get_mwax_context();
+        let int_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
+        let first_s = corr_ctx.timesteps[corr_ctx.common_timestep_indices[0]].gps_time_ms as f64
+            / 1e3
+            + int_time_s / 2.;
+        let last_s = corr_ctx.timesteps[*corr_ctx.common_timestep_indices.last().unwrap()]
+            .gps_time_ms as f64
+            / 1e3
+            + int_time_s / 2.;
+        let mid_s = (first_s + last_s) / 2.;
+
+        let tmp_dir = tempdir().unwrap();
+        let covering_path = tmp_dir.path().join("covering.txt");
+        std::fs::write(
+            &covering_path,
+            format!(
+                "# gps ra dec\n{} 10 -20\n{} 20 -20\n",
+                mid_s - 100.,
+                mid_s + 100.
+            ),
+        )
+        .unwrap();
 
         #[rustfmt::skip]
         let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-end", "1",
-            "--",
+            "birli", "-m", metafits_path,
+            "--ephemeris", covering_path.to_str().unwrap(),
         ];
         args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.ephemeris.unwrap().entries().len(), 2);
+        // outputs are labelled with the position in the middle of the selection
+        assert_abs_diff_eq!(
+            prep_ctx.phase_centre.ra,
+            15_f64.to_radians(),
+            epsilon = 1e-9
+        );
+        assert_abs_diff_eq!(
+            prep_ctx.phase_centre.dec,
+            (-20_f64).to_radians(),
+            epsilon = 1e-9
+        );
 
+        // the ephemeris must cover the whole selection
+        let short_path = tmp_dir.path().join("short.txt");
+        std::fs::write(
+            &short_path,
+            format!("{} 10 -20\n{} 20 -20\n", mid_s - 100., mid_s),
+        )
+        .unwrap();
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--ephemeris", short_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
         assert!(matches!(
             BirliContext::from_args(&args),
-            Err(BirliError::CLIError(_))
+            Err(BirliError::EphemerisError(_))
         ));
-    }
-
-    #[test]
-    fn test_parse_invalid_coarse_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
+        // a fixed phase centre can't be given as well
         #[rustfmt::skip]
         let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-coarse-chans", "0", "9999",
-            "--",
+            "birli", "-m", metafits_path,
+            "--ephemeris", covering_path.to_str().unwrap(),
+            "--phase-centre", "10", "20",
         ];
         args.extend_from_slice(&gpufits_paths);
-
         assert!(matches!(
             BirliContext::from_args(&args),
-            Err(BirliError::CLIError(_))
+            Err(BirliError::ClapError(_))
         ));
     }
 
     #[test]
-    fn test_parse_valid_coarse_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_near_field() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = get_mwax_context();
+        let first_s =
+            corr_ctx.timesteps[corr_ctx.common_timestep_indices[0]].gps_time_ms as f64 / 1e3;
+        let last_s = corr_ctx.timesteps[*corr_ctx.common_timestep_indices.last().unwrap()]
+            .gps_time_ms as f64
+            / 1e3
+            + corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
+
+        let tmp_dir = tempdir().unwrap();
+        let track_path = tmp_dir.path().join("track.txt");
+        std::fs::write(
+            &track_path,
+            format!("{} 10 20 5e5\n{} 11 21 5e5\n", first_s, last_s),
+        )
+        .unwrap();
 
         #[rustfmt::skip]
         let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-coarse-chans", "2",
-            "--",
+            "birli", "-m", metafits_path,
+            "--near-field-azel", track_path.to_str().unwrap(),
+            "--phase-centre", "10", "20",
         ];
         args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.near_field.unwrap().entries().len(), 2);
+        // outputs are still labelled with the phase centre
+        assert_abs_diff_eq!(prep_ctx.phase_centre.ra, 10_f64.to_radians());
+
+        // the track must cover the whole selection
+        std::fs::write(
+            &track_path,
+            format!("{} 10 20 5e5\n{} 11 21 5e5\n", first_s, first_s + 1.),
+        )
+        .unwrap();
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--near-field-ecef", track_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::EphemerisError(_))
+        ));
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
-
-        assert!(flag_ctx.coarse_chan_flags[2]);
-        assert!(!flag_ctx.coarse_chan_flags[1]);
+        // only one target can be tracked
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--near-field-ecef", track_path.to_str().unwrap(),
+            "--near-field-azel", track_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
     }
 
     #[test]
-    fn test_parse_invalid_fine_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_drift() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = get_mwax_context();
+        let latitude_rad = LatLngHeight::new_mwa().latitude_rad;
+
+        let mut args = vec!["birli", "-m", metafits_path, "--drift", "zenith"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_abs_diff_eq!(prep_ctx.drift.unwrap().el, FRAC_PI_2);
+        // outputs are labelled with the zenith in the middle of the observation, which is only
+        // precessed away from the latitude.
+        assert_abs_diff_eq!(prep_ctx.phase_centre.dec, latitude_rad, epsilon = 1e-2);
+
+        let mut args = vec!["birli", "-m", metafits_path, "--drift", "pointing"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_abs_diff_eq!(
+            prep_ctx.drift.unwrap().el,
+            corr_ctx.metafits_context.alt_deg.to_radians()
+        );
 
+        // the phase centre of a drift scan can't also be given
         #[rustfmt::skip]
         let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-fine-chans", "0", "9999",
-            "--",
+            "birli", "-m", metafits_path,
+            "--drift", "zenith",
+            "--phase-centre", "10", "20",
         ];
         args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
 
+        let mut args = vec!["birli", "-m", metafits_path, "--drift", "horizon"];
+        args.extend_from_slice(&gpufits_paths);
         assert!(matches!(
             BirliContext::from_args(&args),
-            Err(BirliError::CLIError(_))
+            Err(BirliError::ClapError(_))
         ));
     }
 
+    /// The UVWs of a drift scan to zenith are rewritten for each timestep, so w is always the
+    /// height difference of the tiles.
     #[test]
-    fn test_parse_valid_fine_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_drift_uvfits_uvws() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = get_mwax_context();
+        let tmp_dir = tempdir().unwrap();
+        let uvfits_path = tmp_dir.path().join("drift.uvfits");
 
         #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-fine-chans", "2", "--"];
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--no-draw-progress",
+            "-u", uvfits_path.to_str().unwrap(),
+            "--drift", "zenith",
+        ];
         args.extend_from_slice(&gpufits_paths);
+        BirliContext::from_args(&args).unwrap().run().unwrap();
+
+        let array_pos = LatLngHeight::new_mwa();
+        let zenith = HADec::new(0., array_pos.latitude_rad);
+        let tiles_xyz_geod =
+            XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
+        let baselines = &corr_ctx.metafits_context.baselines;
+        let num_rows = corr_ctx.num_common_timesteps * baselines.len();
+
+        let mut fptr = FitsFile::open(&uvfits_path).unwrap();
+        for row_idx in 0..num_rows {
+            let mut uvw_s = [0_f32; 3];
+            let mut status = 0;
+            unsafe {
+                // ffggpe = fits_read_grppar_flt
+                fitsio_sys::ffggpe(
+                    fptr.as_raw(),
+                    1 + row_idx as i64,
+                    1,
+                    3,
+                    uvw_s.as_mut_ptr(),
+                    &mut status,
+                );
+            }
+            assert_eq!(status, 0);
+            let baseline = &baselines[row_idx % baselines.len()];
+            let expected = UVW::from_xyz(
+                tiles_xyz_geod[baseline.ant1_index] - tiles_xyz_geod[baseline.ant2_index],
+                zenith,
+            );
+            assert_abs_diff_eq!(uvw_s[2] as f64 * VEL_C, expected.w, epsilon = 1e-3);
+        }
+    }
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+    /// The weights of each cross-correlation in a uvfits file written with `extra_args`.
+    fn flag_bad_tiles_cross_weights(extra_args: &[&str]) -> Vec<f32> {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = get_mwax_context();
+        let tmp_dir = tempdir().unwrap();
+        let uvfits_path = tmp_dir.path().join("bad_tiles.uvfits");
 
-        assert!(flag_ctx.fine_chan_flags[2]);
-        assert!(!flag_ctx.fine_chan_flags[1]);
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--no-draw-progress",
+            "-u", uvfits_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra_args);
+        args.extend_from_slice(&gpufits_paths);
+        BirliContext::from_args(&args).unwrap().run().unwrap();
+
+        let baselines = &corr_ctx.metafits_context.baselines;
+        let num_rows = corr_ctx.num_common_timesteps * baselines.len();
+        let num_chans = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse
+            * corr_ctx.num_common_coarse_chans;
+        let mut fptr = FitsFile::open(&uvfits_path).unwrap();
+        let mut weights = vec![];
+        for row_idx in 0..num_rows {
+            let baseline = &baselines[row_idx % baselines.len()];
+            if baseline.ant1_index == baseline.ant2_index {
+                continue;
+            }
+            let mut vis = vec![0_f32; num_chans * 4 * 3];
+            let mut status = 0;
+            let mut anynul = 0;
+            unsafe {
+                // ffgpve = fits_read_img_flt
+                fitsio_sys::ffgpve(
+                    fptr.as_raw(),
+                    1 + row_idx as i64,
+                    1,
+                    vis.len() as i64,
+                    0.,
+                    vis.as_mut_ptr(),
+                    &mut anynul,
+                    &mut status,
+                );
+            }
+            assert_eq!(status, 0);
+            weights.extend(vis.chunks(3).map(|vis| vis[2]));
+        }
+        weights
     }
 
+    /// With only two tiles, each deviates from the median by 0.67 robust standard deviations in
+    /// any statistic which differs between them, so a lower threshold flags both, and the default
+    /// flags neither.
     #[test]
-    fn test_parse_invalid_antenna_flag() {
+    fn test_flag_bad_tiles_uvfits() {
+        let unflagged = flag_bad_tiles_cross_weights(&[]);
+        assert!(unflagged.iter().any(|&w| w > 0.));
+        assert_eq!(
+            flag_bad_tiles_cross_weights(&["--flag-bad-tiles"]),
+            unflagged
+        );
+        assert!(
+            flag_bad_tiles_cross_weights(&["--flag-bad-tiles", "--bad-tile-sigma", "0.5"])
+                .iter()
+                .all(|&w| w <= 0.)
+        );
+    }
+
+    #[cfg(feature = "aoflagger")]
+    #[test]
+    fn test_parse_invalid_aoflagger_strategy() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
         #[rustfmt::skip]
         let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-antennas", "0", "9999",
-            "--",
+            "birli", "-m", metafits_path,
+            "--aoflagger-strategy", "tests/data/README.md",
         ];
         args.extend_from_slice(&gpufits_paths);
 
         assert!(matches!(
             BirliContext::from_args(&args),
-            Err(BirliError::CLIError(_))
+            Err(BirliError::StrategyError(_))
         ));
     }
 
     #[test]
-    fn test_parse_valid_antenna_flag() {
+    fn test_parse_flag_sanity() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
         #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-antennas", "2", "--"];
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-sanity"];
         args.extend_from_slice(&gpufits_paths);
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
 
-        assert!(flag_ctx.antenna_flags[2]);
-        assert!(!flag_ctx.antenna_flags[1]);
+        assert!(prep_ctx.flag_sanity);
+        assert_eq!(prep_ctx.amp_threshold, None);
     }
 
     #[test]
-    fn test_parse_no_flag_metafits_and_valid_antenna_flag() {
-        let (_, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_valid_flag_amp() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
         #[rustfmt::skip]
-        let mut args = vec![
-            "birli",
-            // in this metafits file, all ants are flagged.
-            "-m", "tests/data/1254670392_avg/1254670392.metafits",
-            "--no-flag-metafits",
-            "--flag-antennas", "2",
-            "--",
-        ];
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-amp-max", "1e6"];
         args.extend_from_slice(&gpufits_paths);
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
 
-        assert!(flag_ctx.antenna_flags[2]);
-        assert!(!flag_ctx.antenna_flags[1]);
+        assert!(prep_ctx.flag_sanity);
+        assert_eq!(
+            prep_ctx.amp_threshold,
+            Some(AmplitudeThreshold::Absolute(1e6))
+        );
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-amp-mad", "5"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(prep_ctx.flag_sanity);
+        assert_eq!(prep_ctx.amp_threshold, Some(AmplitudeThreshold::Mad(5.)));
     }
 
     #[test]
-    fn test_parse_flag_autos() {
+    fn test_parse_invalid_flag_amp() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
         #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-autos"];
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-amp-max", "0"];
         args.extend_from_slice(&gpufits_paths);
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
 
-        assert!(flag_ctx.autos);
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-amp-mad", "0"];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
     }
 
     #[test]
@@ -2480,6 +3202,8 @@ mod argparse_tests {
 /// module, then I don't get the coverage. :(
 /// All these tests require aoflagger because they either require flagging or
 /// use the --no-rfi option.
+/// Cotter did not correct Van Vleck effects, so comparisons with cotter's output use
+/// `--no-van-vleck`. The default Van Vleck corrections are tested in [`crate::preprocessing`].
 /// TODO: get unit test coverage to the point where this can be moved to a unit
 /// test module.
 #[cfg(test)]
@@ -2515,6 +3239,7 @@ mod tests_aoflagger {
             "-u", uvfits_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--no-cable-delay",
             "--no-geometric-delay",
@@ -2567,6 +3292,7 @@ mod tests_aoflagger {
             "-u", uvfits_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--no-cable-delay",
             "--no-geometric-delay",
@@ -2622,6 +3348,7 @@ mod tests_aoflagger {
             "-u", uvfits_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--no-flag-dc",
@@ -2703,6 +3430,7 @@ mod tests_aoflagger {
             "--no-digital-gains",
             "--phase-centre", "0.0", "0.0",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--no-flag-dc",
@@ -2785,6 +3513,7 @@ mod tests_aoflagger {
             "--no-digital-gains",
             "--pointing-centre",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--sel-time", "0", "1",
@@ -2869,6 +3598,7 @@ mod tests_aoflagger {
             "-M", ms_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--no-cable-delay",
             "--no-geometric-delay",
@@ -2925,6 +3655,7 @@ mod tests_aoflagger {
             "-M", ms_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--no-cable-delay",
             "--no-geometric-delay",
@@ -3005,6 +3736,7 @@ mod tests_aoflagger {
             "-m", metafits_path,
             "-M", ms_path.to_str().unwrap(),
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--no-cable-delay",
             "--no-geometric-delay",
@@ -3096,6 +3828,7 @@ mod tests_aoflagger {
             "-m", metafits_path,
             "-M", ms_path.to_str().unwrap(),
             "--no-draw-progress",
+            "--no-van-vleck",
             "--no-cable-delay",
             "--no-geometric-delay",
             "--no-rfi",
@@ -3183,6 +3916,7 @@ mod tests_aoflagger {
             "-M", ms_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--no-flag-dc",
@@ -3280,6 +4014,7 @@ mod tests_aoflagger {
             "-M", ms_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--no-cable-delay",
@@ -3340,6 +4075,7 @@ mod tests_aoflagger {
             "-M", ms_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--no-cable-delay",
@@ -3399,6 +4135,7 @@ mod tests_aoflagger {
             "-M", ms_path.to_str().unwrap(),
             "--no-digital-gains",
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--emulate-cotter",
             "--no-cable-delay",
@@ -3449,7 +4186,8 @@ mod tests_aoflagger {
 
 #[cfg(test)]
 #[cfg(feature = "aoflagger")]
-/// Tests which require the use of the aoflagger feature
+/// Tests which require the use of the aoflagger feature. Cotter did not correct Van Vleck
+/// effects, so comparisons with cotter's flags use `--no-van-vleck`.
 mod tests_aoflagger_flagset {
     use crate::{io::mwaf::FlagFileSet, BirliContext};
     use itertools::izip;
@@ -3523,6 +4261,7 @@ mod tests_aoflagger_flagset {
             "birli",
             "-m", metafits_path,
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "-f", mwaf_path_template.to_str().unwrap(),
             "--no-flag-dc",
@@ -3619,6 +4358,7 @@ mod tests_aoflagger_flagset {
             "birli",
             "-m", metafits_path,
             "--no-draw-progress",
+            "--no-van-vleck",
             "--pfb-gains", "none",
             "--time-chunk", "1",
             "--sel-time", "1", "2",
@@ -3695,4 +4435,56 @@ mod tests_aoflagger_flagset {
             disk_flags_ts2.slice(s![.., ..20, ..])
         );
     }
+
+    #[test]
+    /// With a halo covering the whole selection, each chunk is flagged with the same context as
+    /// an unchunked run, so the flags should be identical.
+    fn aoflagger_outputs_flags_chunked_halo() {
+        let tmp_dir = tempdir().unwrap();
+        let metafits_path = "tests/data/1247842824_flags/1247842824.metafits";
+        let gpufits_paths =
+            vec!["tests/data/1247842824_flags/1247842824_20190722150008_gpubox01_00.fits"];
+
+        let run_flags = |name: &str, extra_args: &[&str]| {
+            let mwaf_path_template = tmp_dir.path().join(format!("Flagfile{}%%.mwaf", name));
+            #[rustfmt::skip]
+            let mut args = vec![
+                "birli",
+                "-m", metafits_path,
+                "--no-draw-progress",
+                "--no-van-vleck",
+                "--pfb-gains", "none",
+                "--sel-time", "1", "2",
+                "--flag-init-steps", "0",
+                "-f", mwaf_path_template.to_str().unwrap(),
+            ];
+            args.extend_from_slice(extra_args);
+            args.extend_from_slice(&gpufits_paths);
+
+            let birli_ctx = BirliContext::from_args(&args).unwrap();
+            let corr_ctx =
+                CorrelatorContext::new(&birli_ctx.io_ctx.metafits_in, &birli_ctx.io_ctx.gpufits_in)
+                    .unwrap();
+            birli_ctx.run().unwrap();
+
+            let gpubox_ids: Vec<usize> = corr_ctx
+                .common_coarse_chan_indices
+                .iter()
+                .map(|&chan| corr_ctx.coarse_chans[chan].gpubox_number)
+                .collect();
+            FlagFileSet::open(
+                mwaf_path_template.to_str().unwrap(),
+                &gpubox_ids,
+                corr_ctx.mwa_version,
+            )
+            .unwrap()
+            .read_flags()
+            .unwrap()
+        };
+
+        let unchunked_flags = run_flags("unchunked", &[]);
+        let halo_flags = run_flags("halo", &["--time-chunk", "1", "--rfi-halo", "3"]);
+
+        assert_eq!(unchunked_flags, halo_flags);
+    }
 }
@@ -1,20 +1,28 @@
 //! Corrections that can be performed on visibility data
 use crate::{
+    cables::CableModel,
+    ephemeris::{Ephemeris, EphemerisError, NearFieldTrack},
     ndarray::{parallel::prelude::*, prelude::*},
     BirliError, Jones,
 };
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use itertools::{izip, Itertools};
-use log::trace;
+use log::{trace, warn};
 use marlu::{
     constants::VEL_C,
     hifitime::{Duration, Epoch, Unit},
     io::error::BadArrayShape,
-    mwalib::{CorrelatorContext, MWAVersion},
+    mwalib::{
+        CorrelatorContext, GeometricDelaysApplied, MWAVersion, MWALIB_MWA_ALTITUDE_METRES,
+        MWALIB_MWA_LATITUDE_RADIANS, MWALIB_MWA_LONGITUDE_RADIANS,
+    },
     precession::precess_time,
-    Complex, LatLngHeight, RADec, XyzGeodetic, UVW,
+    AzEl, Complex, LatLngHeight, RADec, XyzGeodetic, UVW,
+};
+use std::{
+    f64::consts::{FRAC_PI_2, PI, SQRT_2, TAU},
+    ops::Range,
 };
-use std::{f64::consts::TAU, ops::Range};
 use thiserror::Error;
 
 /// Perform cable length corrections, given an observation's
@@ -65,7 +73,7 @@ use thiserror::Error;
 /// Cotter.
 pub fn correct_cable_lengths(
     corr_ctx: &CorrelatorContext,
-    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    jones_array: ArrayViewMut3<Jones<f32>>,
     coarse_chan_range: &Range<usize>,
     // TODO: allow subset of baselines
     // baseline_idxs: &[usize],
@@ -73,6 +81,34 @@ pub fn correct_cable_lengths(
 ) {
     trace!("start correct_cable_lengths");
 
+    correct_cable_model(
+        corr_ctx,
+        jones_array,
+        coarse_chan_range,
+        &CableModel::from_metafits(&corr_ctx.metafits_context),
+        draw_progress,
+    );
+
+    trace!("end correct_cable_lengths");
+}
+
+/// Perform cable corrections using the electrical lengths in a [`CableModel`], and correct the
+/// slope of each cable's attenuation over frequency if the model has attenuations.
+///
+/// This is the same as [`correct_cable_lengths`], except that the cable model can come from
+/// somewhere other than the metafits lengths, e.g. [`CableModel::from_flavours`].
+///
+/// Autocorrelations are only corrected for attenuation; no delay is applied to any of their
+/// polarisations.
+pub fn correct_cable_model(
+    corr_ctx: &CorrelatorContext,
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    coarse_chan_range: &Range<usize>,
+    cable_model: &CableModel,
+    draw_progress: bool,
+) {
+    trace!("start correct_cable_model");
+
     let meta_ctx = &corr_ctx.metafits_context;
 
     let all_freqs_hz =
@@ -83,6 +119,9 @@ pub fn correct_cable_lengths(
         .map(|b| (b.ant1_index, b.ant2_index))
         .collect::<Vec<_>>();
 
+    // the rfinput of each antenna which contributes to each jones element.
+    let pol_inputs = [(0, 0), (1, 1), (0, 1), (1, 0)];
+
     let draw_target = if draw_progress {
         ProgressDrawTarget::stderr()
     } else {
@@ -107,28 +146,44 @@ pub fn correct_cable_lengths(
         .into_par_iter()
         .zip_eq(&ant_pairs)
         .for_each(|(mut jones_array, &(ant1_idx, ant2_idx))| {
-            if ant1_idx == ant2_idx {
+            let lengths1 = cable_model.electrical_lengths_m[ant1_idx];
+            let lengths2 = cable_model.electrical_lengths_m[ant2_idx];
+            let attenuations = cable_model
+                .attenuations_db
+                .as_ref()
+                .map(|attenuations| (attenuations[ant1_idx], attenuations[ant2_idx]));
+
+            // autocorrelations have no delay, but are still attenuated. The cross-pols of an
+            // autocorrelation are left unphased, since the cable delay is also not applied to
+            // them in other tools.
+            let is_auto = ant1_idx == ant2_idx;
+            if is_auto && attenuations.is_none() {
+                correction_progress.inc(1);
                 return;
             }
 
-            let ant1 = &meta_ctx.antennas[ant1_idx];
-            let ant2 = &meta_ctx.antennas[ant2_idx];
-
-            let pol_lengths = [
-                ant2.rfinput_x.electrical_length_m - ant1.rfinput_x.electrical_length_m,
-                ant2.rfinput_y.electrical_length_m - ant1.rfinput_y.electrical_length_m,
-                ant2.rfinput_y.electrical_length_m - ant1.rfinput_x.electrical_length_m,
-                ant2.rfinput_x.electrical_length_m - ant1.rfinput_y.electrical_length_m,
-            ];
+            let pol_lengths = if is_auto {
+                [0.; 4]
+            } else {
+                pol_inputs.map(|(pol1, pol2)| lengths2[pol2] - lengths1[pol1])
+            };
 
             for (mut jones_array, &freq_hz) in
                 jones_array.axis_iter_mut(Axis(1)).zip_eq(&all_freqs_hz)
             {
+                let pol_gains = attenuations.map_or([1.; 4], |(attens1, attens2)| {
+                    pol_inputs.map(|(pol1, pol2)| {
+                        cable_model.attenuation_gain(attens1[pol1], freq_hz)
+                            * cable_model.attenuation_gain(attens2[pol2], freq_hz)
+                    })
+                });
                 for jones in jones_array.iter_mut() {
                     // promote, correct, demote
                     let mut corrected = Jones::<f64>::from(*jones);
-                    for (complex, length) in corrected.iter_mut().zip_eq(&pol_lengths) {
-                        *complex *= Complex::from_polar(1., -TAU * length * freq_hz / VEL_C);
+                    for (complex, length, gain) in
+                        izip!(corrected.iter_mut(), &pol_lengths, &pol_gains)
+                    {
+                        *complex *= Complex::from_polar(*gain, -TAU * length * freq_hz / VEL_C);
                     }
                     *jones = Jones::<f32>::from(corrected);
                 }
@@ -138,7 +193,7 @@ pub fn correct_cable_lengths(
 
     correction_progress.finish();
 
-    trace!("end correct_cable_lengths");
+    trace!("end correct_cable_model");
 }
 
 /// Perform geometric corrections, given an observation's
@@ -205,7 +260,7 @@ pub fn correct_cable_lengths(
 #[allow(clippy::too_many_arguments)]
 pub fn correct_geometry(
     corr_ctx: &CorrelatorContext,
-    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    jones_array: ArrayViewMut3<Jones<f32>>,
     timestep_range: &Range<usize>,
     coarse_chan_range: &Range<usize>,
     // TODO: allow subset of baselines
@@ -214,8 +269,6 @@ pub fn correct_geometry(
     phase_centre: Option<RADec>,
     draw_progress: bool,
 ) {
-    trace!("start correct_geometry");
-
     let array_pos = match array_pos {
         Some(pos) => pos,
         None => {
@@ -227,39 +280,297 @@ pub fn correct_geometry(
         }
     };
 
-    let timesteps = &corr_ctx.timesteps[timestep_range.clone()];
-
-    let baselines = &corr_ctx.metafits_context.baselines;
-
-    let all_freqs_hz =
-        corr_ctx.get_fine_chan_freqs_hz_array(&coarse_chan_range.clone().collect::<Vec<_>>());
-    let jones_dims = jones_array.dim();
-
-    let integration_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1000.0;
-
     let phase_centre = match phase_centre {
         Some(pc) => pc,
         None => RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context),
     };
     let tiles_xyz_geod = XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
 
-    let ant_pairs = baselines
+    correct_geometry_with_tiles(
+        corr_ctx,
+        jones_array,
+        timestep_range,
+        coarse_chan_range,
+        array_pos,
+        phase_centre,
+        &tiles_xyz_geod,
+        draw_progress,
+    );
+}
+
+/// Perform geometric corrections like [`correct_geometry`], with the geodetic position of each
+/// mwalib antenna given by `tiles_xyz_geod`, e.g. from
+/// [`TileOverrides::get_tiles`](crate::tiles::TileOverrides::get_tiles).
+#[allow(clippy::too_many_arguments)]
+pub fn correct_geometry_with_tiles(
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    array_pos: LatLngHeight,
+    phase_centre: RADec,
+    tiles_xyz_geod: &[XyzGeodetic],
+    draw_progress: bool,
+) {
+    let centroid_timestamps = get_centroid_timestamps(corr_ctx, timestep_range);
+    let phase_centres = vec![phase_centre; centroid_timestamps.len()];
+    let part_uvws = calc_phased_part_uvws(
+        corr_ctx,
+        array_pos,
+        &centroid_timestamps,
+        &phase_centres,
+        tiles_xyz_geod,
+    );
+    _correct_geometry(
+        corr_ctx,
+        jones_array,
+        coarse_chan_range,
+        part_uvws.view(),
+        draw_progress,
+    );
+}
+
+/// Perform geometric corrections like [`correct_geometry_with_tiles`], tracking a moving target,
+/// e.g. a planet, whose phase centre at the centroid of each timestep is interpolated from
+/// `ephemeris`.
+///
+/// # Errors
+///
+/// Will return [`EphemerisError::OutOfRange`] if the centroid of a timestep in `timestep_range`
+/// is not covered by `ephemeris`.
+#[allow(clippy::too_many_arguments)]
+pub fn correct_geometry_ephemeris(
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    array_pos: LatLngHeight,
+    ephemeris: &Ephemeris,
+    tiles_xyz_geod: &[XyzGeodetic],
+    draw_progress: bool,
+) -> Result<(), EphemerisError> {
+    let centroid_timestamps = get_centroid_timestamps(corr_ctx, timestep_range);
+    let phase_centres = centroid_timestamps
         .iter()
-        .map(|b| (b.ant1_index, b.ant2_index))
+        .map(|&epoch| ephemeris.radec_at(epoch))
+        .collect::<Result<Vec<_>, _>>()?;
+    let part_uvws = calc_phased_part_uvws(
+        corr_ctx,
+        array_pos,
+        &centroid_timestamps,
+        &phase_centres,
+        tiles_xyz_geod,
+    );
+    _correct_geometry(
+        corr_ctx,
+        jones_array,
+        coarse_chan_range,
+        part_uvws.view(),
+        draw_progress,
+    );
+    Ok(())
+}
+
+/// Perform geometric corrections for a target in the near field, e.g. a satellite, whose
+/// position at the centroid of each timestep is interpolated from `track`.
+///
+/// Rather than the plane wave of [`correct_geometry_with_tiles`], each visibility is
+/// phase-shifted by the difference in the distances from the target to the baseline's tiles,
+/// relative to the array position. This tends to the plane wave correction as the target gets
+/// further away.
+///
+/// # Errors
+///
+/// Will return [`EphemerisError::OutOfRange`] if the centroid of a timestep in `timestep_range`
+/// is not covered by `track`.
+#[allow(clippy::too_many_arguments)]
+pub fn correct_geometry_near_field(
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    array_pos: LatLngHeight,
+    track: &NearFieldTrack,
+    tiles_xyz_geod: &[XyzGeodetic],
+    draw_progress: bool,
+) -> Result<(), EphemerisError> {
+    let centroid_timestamps = get_centroid_timestamps(corr_ctx, timestep_range);
+    let mut part_uvws = Array2::from_elem(
+        (centroid_timestamps.len(), tiles_xyz_geod.len()),
+        UVW::default(),
+    );
+    for (mut part_uvws, &epoch) in part_uvws.outer_iter_mut().zip_eq(&centroid_timestamps) {
+        let target = track.position_at(epoch, array_pos)?;
+        let range_m = xyz_norm(target);
+        for (part_uvw, &xyz) in part_uvws.iter_mut().zip_eq(tiles_xyz_geod) {
+            // how much closer the target is to the tile than the array position, which is the
+            // w of a plane wave from the direction of the target. This is the difference of the
+            // two ranges, rearranged to avoid cancellation when the target is far away.
+            let dot = target.x * xyz.x + target.y * xyz.y + target.z * xyz.z;
+            part_uvw.w = (2. * dot - xyz_norm(xyz).powi(2)) / (range_m + xyz_norm(target - xyz));
+        }
+    }
+    _correct_geometry(
+        corr_ctx,
+        jones_array,
+        coarse_chan_range,
+        part_uvws.view(),
+        draw_progress,
+    );
+    Ok(())
+}
+
+/// Perform geometric corrections for a drift scan, phasing each timestep to a fixed azimuth and
+/// elevation, e.g. zenith, or a tile pointing which is fixed in hour angle. The phase centre of
+/// each timestep is the J2000 position of `azel` at its centroid (see [`drift_phase_centre`]),
+/// which should also be used for the UVWs of each timestep in the outputs (see
+/// [`rewrite_uvfits_uvws`](crate::io::rewrite_uvfits_uvws)).
+#[allow(clippy::too_many_arguments)]
+pub fn correct_geometry_drift(
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    array_pos: LatLngHeight,
+    azel: AzEl,
+    tiles_xyz_geod: &[XyzGeodetic],
+    draw_progress: bool,
+) {
+    let centroid_timestamps = get_centroid_timestamps(corr_ctx, timestep_range);
+    let dut1 = Duration::from_f64(corr_ctx.metafits_context.dut1.unwrap_or(0.0), Unit::Second);
+    let phase_centres = centroid_timestamps
+        .iter()
+        .map(|&epoch| drift_phase_centre(azel, epoch, array_pos, dut1))
         .collect::<Vec<_>>();
-    let centroid_timestamps = timesteps
+    let part_uvws = calc_phased_part_uvws(
+        corr_ctx,
+        array_pos,
+        &centroid_timestamps,
+        &phase_centres,
+        tiles_xyz_geod,
+    );
+    _correct_geometry(
+        corr_ctx,
+        jones_array,
+        coarse_chan_range,
+        part_uvws.view(),
+        draw_progress,
+    );
+}
+
+/// The J2000 phase centre which is at `azel` from `array_pos` at `epoch`.
+///
+/// This is the phase centre which [`precess_time`] precesses and aberrates onto `azel`, so that
+/// the w of a baseline phased to it is the delay of a plane wave from `azel`.
+pub fn drift_phase_centre(
+    azel: AzEl,
+    epoch: Epoch,
+    array_pos: LatLngHeight,
+    dut1: Duration,
+) -> RADec {
+    let hadec = azel.to_hadec(array_pos.latitude_rad);
+    let (sin_ha, cos_ha) = hadec.ha.sin_cos();
+    let (sin_dec, cos_dec) = hadec.dec.sin_cos();
+    // the direction of azel in the same frame as the tiles, which precess_xyz_parallel rotates to
+    // the frame of the J2000 meridian.
+    let direction = XyzGeodetic {
+        x: cos_dec * cos_ha,
+        y: -cos_dec * sin_ha,
+        z: sin_dec,
+    };
+    let precess = |phase_centre| {
+        precess_time(
+            array_pos.longitude_rad,
+            array_pos.latitude_rad,
+            phase_centre,
+            epoch,
+            dut1,
+        )
+    };
+    let prec = precess(RADec::new(0., 0.));
+    let direction = prec.precess_xyz_parallel(&[direction])[0];
+    let target = RADec::new(
+        prec.lmst_j2000 - (-direction.y).atan2(direction.x),
+        direction.z.asin(),
+    );
+    // precess_time also aberrates the phase centre, which is almost a constant offset this close
+    // to the target, so a few iterations are enough to undo it.
+    let mut phase_centre = target;
+    for _ in 0..3 {
+        let achieved = precess(phase_centre).hadec_j2000;
+        let ra_error = (target.ra - (prec.lmst_j2000 - achieved.ha) + PI).rem_euclid(TAU) - PI;
+        phase_centre = RADec::new(
+            phase_centre.ra + ra_error,
+            phase_centre.dec + target.dec - achieved.dec,
+        );
+    }
+    RADec::new(phase_centre.ra.rem_euclid(TAU), phase_centre.dec)
+}
+
+/// The length of a geodetic position vector in metres.
+fn xyz_norm(xyz: XyzGeodetic) -> f64 {
+    (xyz.x * xyz.x + xyz.y * xyz.y + xyz.z * xyz.z).sqrt()
+}
+
+/// The centroid of each timestep in `timestep_range`.
+fn get_centroid_timestamps(
+    corr_ctx: &CorrelatorContext,
+    timestep_range: &Range<usize>,
+) -> Vec<Epoch> {
+    let integration_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1000.0;
+    corr_ctx.timesteps[timestep_range.clone()]
         .iter()
         .map(|t| Epoch::from_gpst_seconds(t.gps_time_ms as f64 / 1000.0 + integration_time_s / 2.0))
+        .collect()
+}
+
+/// The partial UVWs of each antenna, phased to the corresponding phase centre in `phase_centres`,
+/// at the corresponding time in `centroid_timestamps`.
+fn calc_phased_part_uvws(
+    corr_ctx: &CorrelatorContext,
+    array_pos: LatLngHeight,
+    centroid_timestamps: &[Epoch],
+    phase_centres: &[RADec],
+    tiles_xyz_geod: &[XyzGeodetic],
+) -> Array2<UVW> {
+    let ant_pairs = corr_ctx
+        .metafits_context
+        .baselines
+        .iter()
+        .map(|b| (b.ant1_index, b.ant2_index))
         .collect::<Vec<_>>();
     let dut1 = Duration::from_f64(corr_ctx.metafits_context.dut1.unwrap_or(0.0), Unit::Second);
-    let part_uvws = calc_part_uvws(
+    calc_part_uvws(
         &ant_pairs,
-        &centroid_timestamps,
+        centroid_timestamps,
         dut1,
-        phase_centre,
+        phase_centres,
         array_pos,
-        &tiles_xyz_geod,
-    );
+        tiles_xyz_geod,
+    )
+}
+
+/// Phase each timestep of `jones_array` by the w of each baseline, from the partial UVWs of its
+/// antennas at that timestep in `part_uvws`.
+fn _correct_geometry(
+    corr_ctx: &CorrelatorContext,
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    coarse_chan_range: &Range<usize>,
+    part_uvws: ArrayView2<UVW>,
+    draw_progress: bool,
+) {
+    trace!("start correct_geometry");
+
+    let baselines = &corr_ctx.metafits_context.baselines;
+
+    let all_freqs_hz =
+        corr_ctx.get_fine_chan_freqs_hz_array(&coarse_chan_range.clone().collect::<Vec<_>>());
+    let jones_dims = jones_array.dim();
+
+    let ant_pairs = baselines
+        .iter()
+        .map(|b| (b.ant1_index, b.ant2_index))
+        .collect::<Vec<_>>();
 
     // Create a progress bar to show the status of the correction
     let draw_target = if draw_progress {
@@ -304,6 +615,321 @@ pub fn correct_geometry(
     trace!("end correct_geometry");
 }
 
+/// Reverse the geometric delays which were applied by the MWAX correlator, as described by the
+/// [`GEODEL`](https://wiki.mwatelescope.org/display/MP/MWAX+Metafits+Changes) key in the metafits,
+/// so that the visibilities can then be phased to any phase centre with [`correct_geometry`].
+///
+/// - `Zenith` and `Tile Pointing` delays are for a fixed azimuth and elevation, so they are the
+///   same for every timestep.
+/// - `Az/El Tracking` delays track the tile pointing centre.
+///
+/// The delays are always computed from the MWA position given by mwalib and the metafits tile
+/// positions, since these are what the correlator used, regardless of the array position used to
+/// re-phase the visibilities.
+///
+/// This does nothing if no geometric delays were applied.
+pub fn reverse_geometric_delays(
+    corr_ctx: &CorrelatorContext,
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+) {
+    trace!("start reverse_geometric_delays");
+
+    let meta_ctx = &corr_ctx.metafits_context;
+    let array_pos = LatLngHeight {
+        longitude_rad: MWALIB_MWA_LONGITUDE_RADIANS,
+        latitude_rad: MWALIB_MWA_LATITUDE_RADIANS,
+        height_metres: MWALIB_MWA_ALTITUDE_METRES,
+    };
+    let tiles_xyz_geod = XyzGeodetic::get_tiles(meta_ctx, array_pos.latitude_rad);
+    let ant_pairs = meta_ctx
+        .baselines
+        .iter()
+        .map(|b| (b.ant1_index, b.ant2_index))
+        .collect::<Vec<_>>();
+
+    let fixed_azel = match meta_ctx.geometric_delays_applied {
+        GeometricDelaysApplied::No => return,
+        GeometricDelaysApplied::Zenith => Some(AzEl::new(0., FRAC_PI_2)),
+        GeometricDelaysApplied::TilePointing => {
+            Some(AzEl::new_degrees(meta_ctx.az_deg, meta_ctx.alt_deg))
+        }
+        GeometricDelaysApplied::AzElTracking => None,
+    };
+    let part_uvws = fixed_azel.map_or_else(
+        || {
+            let centroid_timestamps = get_centroid_timestamps(corr_ctx, timestep_range);
+            let dut1 = Duration::from_f64(meta_ctx.dut1.unwrap_or(0.0), Unit::Second);
+            let phase_centres =
+                vec![RADec::from_mwalib_tile_pointing(meta_ctx); centroid_timestamps.len()];
+            calc_part_uvws(
+                &ant_pairs,
+                &centroid_timestamps,
+                dut1,
+                &phase_centres,
+                array_pos,
+                &tiles_xyz_geod,
+            )
+        },
+        |azel| {
+            let hadec = azel.to_hadec(array_pos.latitude_rad);
+            let tile_uvws = tiles_xyz_geod
+                .iter()
+                .map(|&xyz| UVW::from_xyz(xyz, hadec))
+                .collect::<Vec<_>>();
+            Array2::from_shape_fn((timestep_range.len(), tile_uvws.len()), |(_, ant_idx)| {
+                tile_uvws[ant_idx]
+            })
+        },
+    );
+
+    let all_freqs_hz =
+        corr_ctx.get_fine_chan_freqs_hz_array(&coarse_chan_range.clone().collect::<Vec<_>>());
+
+    jones_array
+        .outer_iter_mut()
+        .into_par_iter()
+        .zip_eq(part_uvws.outer_iter())
+        .for_each(|(mut jones_array, part_uvws)| {
+            for (mut jones_array, &(ant1, ant2)) in
+                jones_array.axis_iter_mut(Axis(1)).zip_eq(&ant_pairs)
+            {
+                let uvw = part_uvws[[ant1]] - part_uvws[[ant2]];
+
+                for (jones, freq_hz) in jones_array.iter_mut().zip_eq(&all_freqs_hz) {
+                    // the opposite of the rotation in correct_geometry
+                    let mut corrected = Jones::<f64>::from(*jones);
+                    corrected *= Complex::from_polar(1., TAU * uvw.w * freq_hz / VEL_C);
+                    *jones = Jones::<f32>::from(corrected);
+                }
+            }
+        });
+
+    trace!("end reverse_geometric_delays");
+}
+
+#[derive(Error, Debug)]
+/// Error for Van Vleck Corrections
+pub enum VanVleckCorrection {
+    #[error(transparent)]
+    /// Error for bad array shape in provided argument
+    BadArrayShape(#[from] BadArrayShape),
+}
+
+/// The largest magnitude of the legacy correlator's 4 bit samples, which are integers in `-7..=7`.
+const VAN_VLECK_MAX_LEVEL: i32 = 7;
+
+/// The complementary error function, with a fractional error of less than 1.2e-7.
+///
+/// From Numerical Recipes in C, 2nd edition, section 6.2.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let result = t
+        * (-z * z - 1.265_512_23
+            + t * (1.000_023_68
+                + t * (0.374_091_96
+                    + t * (0.096_784_18
+                        + t * (-0.186_288_06
+                            + t * (0.278_868_07
+                                + t * (-1.135_203_98
+                                    + t * (1.488_515_87
+                                        + t * (-0.822_152_23 + t * 0.170_872_77)))))))))
+            .exp();
+    if x >= 0. {
+        result
+    } else {
+        2. - result
+    }
+}
+
+/// The expected power `<q²>` of a zero-mean Gaussian signal with standard deviation `sigma`,
+/// after it has been quantised to the nearest integer in `-7..=7` by the legacy correlator.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::corrections::quantised_power;
+///
+/// // when the signal is comparable to the quantisation step, the power is biased by 1/12
+/// // (Sheppard's correction).
+/// assert!((quantised_power(1.) - (1. + 1. / 12.)).abs() < 1e-3);
+/// // but most samples of a weak signal are quantised to zero, so its power is underestimated.
+/// assert!(quantised_power(0.2) < 0.5 * 0.2 * 0.2);
+/// ```
+pub fn quantised_power(sigma: f64) -> f64 {
+    // each threshold at k - 1/2 increases q² from (k - 1)² to k², either side of zero.
+    (1..=VAN_VLECK_MAX_LEVEL)
+        .map(|k| f64::from(2 * k - 1) * erfc((f64::from(k) - 0.5) / (sigma * SQRT_2)))
+        .sum()
+}
+
+/// The standard deviation of a zero-mean Gaussian signal whose [`quantised_power`] is `power`,
+/// or `None` if `power` is not within the range of the quantiser, `0 < power < 49`.
+pub fn van_vleck_sigma(power: f64) -> Option<f64> {
+    let max_power = f64::from(VAN_VLECK_MAX_LEVEL * VAN_VLECK_MAX_LEVEL);
+    if !(power > 0. && power < max_power) {
+        return None;
+    }
+    let mut high = 1.;
+    while quantised_power(high) < power {
+        high *= 2.;
+        if !high.is_finite() {
+            return None;
+        }
+    }
+    let mut low = 0.;
+    for _ in 0..64 {
+        let mid = (low + high) / 2.;
+        if quantised_power(mid) < power {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some((low + high) / 2.)
+}
+
+/// The rate of change of the quantised cross power of two signals with their true covariance,
+/// for a signal with standard deviation `sigma`, at zero correlation. The quantised cross power
+/// of two weakly correlated signals is the true covariance multiplied by the gain of each signal.
+///
+/// By Price's theorem, this is the probability density of the signal at each threshold.
+fn quantised_cross_gain(sigma: f64) -> f64 {
+    (1..=VAN_VLECK_MAX_LEVEL)
+        .map(|k| {
+            let x = (f64::from(k) - 0.5) / sigma;
+            2. * (-x * x / 2.).exp() / (sigma * (TAU).sqrt())
+        })
+        .sum()
+}
+
+/// Correct the quantisation (Van Vleck) effects of the legacy correlator's 4 bit samples.
+///
+/// The standard deviation of each input is recovered from its quantised power in the
+/// autocorrelations with [`van_vleck_sigma`], which corrects the autocorrelations. The crosses,
+/// including the cross-polarisations of the autocorrelations, are corrected to first order in the
+/// correlation coefficient, which is accurate for the weak correlations between different tiles.
+///
+/// Visibilities are assumed to be the sum of `2 * fine_chan_width * int_time` real valued
+/// products of 4 bit samples. Visibilities whose autocorrelations are zero or out of range, e.g.
+/// flagged or missing data, are not corrected. The inputs can't be recovered without their
+/// autocorrelations, so if the autocorrelations of any selected antenna are not selected, no
+/// visibilities are corrected and a warning is logged.
+///
+/// This should only be applied to legacy correlator data
+/// ([`MWAVersion::CorrLegacy`] or [`MWAVersion::CorrOldLegacy`]), before any other
+/// corrections which scale the visibilities.
+///
+/// # Arguments
+///
+/// - `corr_ctx` - The correlator [`marlu::mwalib::CorrelatorContext`].
+/// - `jones_array` - The array of Jones matrices to be corrected, [timestep][channel][baseline].
+/// - `ant_pairs` - a slice of tuples of antenna indices for each baseline in the visibilities.
+///
+/// # Errors
+///
+/// Will throw [`VanVleckCorrection::BadArrayShape`] if `jones_array.dim().2 != ant_pairs.len()`
+pub fn correct_van_vleck(
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    ant_pairs: &[(usize, usize)],
+) -> Result<(), VanVleckCorrection> {
+    let num_samples = 2.
+        * corr_ctx.metafits_context.corr_fine_chan_width_hz as f64
+        * corr_ctx.metafits_context.corr_int_time_ms as f64
+        / 1000.;
+    _correct_van_vleck(jones_array, ant_pairs, num_samples)
+}
+
+fn _correct_van_vleck(
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    ant_pairs: &[(usize, usize)],
+    num_samples: f64,
+) -> Result<(), VanVleckCorrection> {
+    let vis_dims = jones_array.dim();
+    if vis_dims.2 != ant_pairs.len() {
+        return Err(VanVleckCorrection::BadArrayShape(BadArrayShape {
+            argument: "ant_pairs",
+            function: "_correct_van_vleck",
+            expected: format!("vis_dims.2={}", vis_dims.2),
+            received: format!("{:?}", ant_pairs.len()),
+        }));
+    }
+
+    // the baseline index of each antenna's autocorrelations
+    let num_ants = ant_pairs
+        .iter()
+        .map(|&(ant1, ant2)| ant1.max(ant2) + 1)
+        .max()
+        .unwrap_or(0);
+    let mut auto_idxs = vec![None; num_ants];
+    for (bl_idx, &(ant1, ant2)) in ant_pairs.iter().enumerate() {
+        if ant1 == ant2 {
+            auto_idxs[ant1] = Some(bl_idx);
+        }
+    }
+    if let Some(&ant_idx) = ant_pairs
+        .iter()
+        .flat_map(|(ant1, ant2)| [ant1, ant2])
+        .find(|&&ant_idx| auto_idxs[ant_idx].is_none())
+    {
+        warn!(
+            "skipping Van Vleck corrections, which need the autocorrelations of antenna {}, which are not selected",
+            ant_idx
+        );
+        return Ok(());
+    }
+
+    // the standard deviation and cross gain of the [X, Y] inputs of each antenna.
+    let mut inputs = Array3::from_elem((vis_dims.0, vis_dims.1, num_ants), [None; 2]);
+    inputs
+        .axis_iter_mut(Axis(2))
+        .into_par_iter()
+        .zip_eq(auto_idxs)
+        .for_each(|(mut inputs, auto_idx)| {
+            if let Some(auto_idx) = auto_idx {
+                for (input, jones) in izip!(
+                    inputs.iter_mut(),
+                    jones_array.index_axis(Axis(2), auto_idx).iter()
+                ) {
+                    *input = [0, 3].map(|pol_idx| {
+                        van_vleck_sigma(f64::from(jones[pol_idx].re) / num_samples)
+                            .map(|sigma| (sigma, quantised_cross_gain(sigma)))
+                    });
+                }
+            }
+        });
+
+    jones_array
+        .axis_iter_mut(Axis(2))
+        .into_par_iter()
+        .zip_eq(ant_pairs)
+        .for_each(|(mut jones_array, &(ant1, ant2))| {
+            for ((timestep_idx, chan_idx), jones) in jones_array.indexed_iter_mut() {
+                let inputs1 = inputs[(timestep_idx, chan_idx, ant1)];
+                let inputs2 = inputs[(timestep_idx, chan_idx, ant2)];
+                let mut corrected = Jones::<f64>::from(*jones);
+                for (pol_idx, vis) in corrected.iter_mut().enumerate() {
+                    let (pol1, pol2) = (pol_idx / 2, pol_idx % 2);
+                    match (inputs1[pol1], inputs2[pol2]) {
+                        (Some((sigma, _)), _) if ant1 == ant2 && pol1 == pol2 => {
+                            *vis = Complex::new(num_samples * sigma * sigma, 0.);
+                        }
+                        (Some((_, gain1)), Some((_, gain2))) if gain1 * gain2 > 0. => {
+                            *vis /= gain1 * gain2;
+                        }
+                        _ => {}
+                    }
+                }
+                *jones = Jones::<f32>::from(corrected);
+            }
+        });
+
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 /// Error for Passband Corrections
 pub enum DigitalGainCorrection {
@@ -660,7 +1286,8 @@ pub fn scrunch_gains(
     }
 }
 
-// Calculate partial uvw components for each antenna and timestep.
+// Calculate partial uvw components for each antenna and timestep, phased to the phase centre of
+// each timestep.
 //
 // UVWs are in units of meters. To get the UVWs in units of wavelengths, divide by the wavelength.
 // uvw at ts, (ant1, ant2) = part_uvw[ant1] - part_uvw[ant2]
@@ -668,13 +1295,14 @@ fn calc_part_uvws(
     ant_pairs: &[(usize, usize)],
     centroid_timestamps: &[Epoch],
     dut1: Duration,
-    phase_centre: RADec,
+    phase_centres: &[RADec],
     array_pos: LatLngHeight,
     tile_xyzs: &[XyzGeodetic],
 ) -> Array2<UVW> {
     let max_ant = ant_pairs.iter().map(|&(a, b)| a.max(b)).max().unwrap();
     let mut part_uvws = Array2::from_elem((centroid_timestamps.len(), max_ant + 1), UVW::default());
-    for (t, &epoch) in centroid_timestamps.iter().enumerate() {
+    for (t, (&epoch, &phase_centre)) in centroid_timestamps.iter().zip_eq(phase_centres).enumerate()
+    {
         let prec = precess_time(
             array_pos.longitude_rad,
             array_pos.latitude_rad,
@@ -696,23 +1324,32 @@ fn calc_part_uvws(
 mod tests {
 
     use super::{
-        _correct_digital_gains, correct_cable_lengths, correct_coarse_passband_gains,
-        correct_digital_gains, correct_geometry, scrunch_gains, VEL_C,
+        _correct_digital_gains, _correct_van_vleck, correct_cable_lengths, correct_cable_model,
+        correct_coarse_passband_gains, correct_digital_gains, correct_geometry,
+        correct_geometry_drift, correct_geometry_ephemeris, correct_geometry_near_field,
+        correct_geometry_with_tiles, drift_phase_centre, get_centroid_timestamps, quantised_power,
+        reverse_geometric_delays, scrunch_gains, van_vleck_sigma, VEL_C,
     };
     use float_cmp::assert_approx_eq;
-    use itertools::izip;
+    use itertools::{izip, Itertools};
     use marlu::{
         hifitime::{Duration, Epoch, Unit},
+        mwalib::GeometricDelaysApplied,
         precession::precess_time,
-        Complex, Jones, LatLngHeight, RADec, XyzGeodetic, UVW,
+        AzEl, Complex, HADec, Jones, LatLngHeight, RADec, XyzGeodetic, UVW,
     };
     use ndarray::{s, Array2, Array3, Axis};
-    use std::f64::consts::PI;
+    use std::f64::consts::{PI, TAU};
 
     use crate::{
         approx::assert_abs_diff_eq,
+        cables::CableModel,
         compare_jones,
-        corrections::{DigitalGainCorrection, PassbandCorrection, ScrunchType},
+        corrections::{DigitalGainCorrection, PassbandCorrection, ScrunchType, VanVleckCorrection},
+        ephemeris::{
+            Ephemeris, EphemerisEntry, EphemerisError, NearFieldEntry, NearFieldPosition,
+            NearFieldTrack,
+        },
         test_common::{get_mwa_ord_context, get_mwax_context},
         VisSelection,
     };
@@ -856,6 +1493,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cable_model_autos_attenuation_only() {
+        let corr_ctx = get_mwax_context();
+        let meta_ctx = &corr_ctx.metafits_context;
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = meta_ctx.num_corr_fine_chans_per_coarse;
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        jones_array.fill(Jones::from([1., 0., 0.5, 0.5, 0.5, -0.5, 1., 0.]));
+        let original = jones_array.clone();
+
+        // X and Y cables of different lengths, so the cross-pols of autos would pick up a delay.
+        let cable_model = CableModel {
+            electrical_lengths_m: vec![[100., 150.]; meta_ctx.num_ants],
+            attenuations_db: Some(vec![[6., 3.]; meta_ctx.num_ants]),
+            attenuation_norm_freq_hz: meta_ctx.centre_freq_hz as f64,
+        };
+        correct_cable_model(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.coarse_chan_range,
+            &cable_model,
+            false,
+        );
+
+        let all_freqs_hz = corr_ctx
+            .get_fine_chan_freqs_hz_array(&vis_sel.coarse_chan_range.clone().collect::<Vec<_>>());
+        let attens = [(6., 6.), (3., 3.), (6., 3.), (3., 6.)];
+        for (bl_idx, baseline) in meta_ctx.baselines.iter().enumerate() {
+            if baseline.ant1_index != baseline.ant2_index {
+                continue;
+            }
+            for (chan_idx, &freq_hz) in all_freqs_hz.iter().enumerate() {
+                let corrected = jones_array[(0, chan_idx, bl_idx)];
+                let expected = original[(0, chan_idx, bl_idx)];
+                for (pol_idx, &(atten1, atten2)) in attens.iter().enumerate() {
+                    let gain = cable_model.attenuation_gain(atten1, freq_hz)
+                        * cable_model.attenuation_gain(atten2, freq_hz);
+                    assert_abs_diff_eq!(
+                        corrected[pol_idx],
+                        expected[pol_idx] * gain as f32,
+                        epsilon = 1e-5
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     #[allow(clippy::unnecessary_cast)]
     fn test_cable_length_corrections_ord() {
@@ -1290,6 +1974,366 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reverse_geometric_delays_tracking() {
+        let mut corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        vis_sel
+            .read_mwalib(
+                &corr_ctx,
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                false,
+            )
+            .unwrap();
+        let raw_jones_array = jones_array.clone();
+
+        // no delays applied, nothing to reverse.
+        reverse_geometric_delays(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+        );
+        assert_eq!(jones_array, raw_jones_array);
+
+        // pretend the correlator tracked the pointing centre.
+        correct_geometry(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            None,
+            Some(RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context)),
+            false,
+        );
+        assert_ne!(jones_array[(0, 0, 1)], raw_jones_array[(0, 0, 1)]);
+        corr_ctx.metafits_context.geometric_delays_applied = GeometricDelaysApplied::AzElTracking;
+        reverse_geometric_delays(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+        );
+
+        // visibilities are ~4e6, so allow for f32 rounding in both rotations.
+        for (jones, raw_jones) in izip!(jones_array.iter(), raw_jones_array.iter()) {
+            assert_abs_diff_eq!(*jones, *raw_jones, epsilon = 2.);
+        }
+    }
+
+    #[test]
+    fn test_reverse_geometric_delays_zenith() {
+        let mut corr_ctx = get_mwax_context();
+        corr_ctx.metafits_context.geometric_delays_applied = GeometricDelaysApplied::Zenith;
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        jones_array.fill(Jones::identity());
+
+        reverse_geometric_delays(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+        );
+
+        // towards zenith, w is the difference in tile heights.
+        let coarse_chan_indices: Vec<_> = vis_sel.coarse_chan_range.clone().collect();
+        let all_freqs_hz = corr_ctx.get_fine_chan_freqs_hz_array(&coarse_chan_indices);
+        let antennas = &corr_ctx.metafits_context.antennas;
+        let w = antennas[0].rfinput_x.height_m - antennas[1].rfinput_x.height_m;
+        for (ts_idx, (chan_idx, freq_hz)) in
+            (0..vis_sel.timestep_range.len()).cartesian_product(all_freqs_hz.iter().enumerate())
+        {
+            let expected = Complex::<f64>::from_polar(1., 2. * PI * w * freq_hz / VEL_C);
+            let jones = Jones::<f64>::from(jones_array[(ts_idx, chan_idx, 1)]);
+            assert_approx_eq!(f64, jones[0].re, expected.re, epsilon = 1e-5);
+            assert_approx_eq!(f64, jones[0].im, expected.im, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_correct_geometry_ephemeris() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        jones_array.fill(Jones::identity());
+        let raw_jones_array = jones_array.clone();
+
+        let array_pos = LatLngHeight::new_mwa();
+        let tiles_xyz_geod =
+            XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
+        let centroids = get_centroid_timestamps(&corr_ctx, &vis_sel.timestep_range);
+        let pointing = RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context);
+        // a target moving by a degree in RA and Dec over the observation.
+        let entry = |epoch, offset_deg: f64| EphemerisEntry {
+            epoch,
+            radec: RADec::new(
+                pointing.ra + offset_deg.to_radians(),
+                pointing.dec + offset_deg.to_radians(),
+            ),
+        };
+        let ephemeris = Ephemeris::new(vec![
+            entry(centroids[0] - Duration::from_f64(1., Unit::Second), 0.),
+            entry(centroids[centroids.len() - 1], 1.),
+        ])
+        .unwrap();
+
+        correct_geometry_ephemeris(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            array_pos,
+            &ephemeris,
+            &tiles_xyz_geod,
+            false,
+        )
+        .unwrap();
+
+        // each timestep is phased to the position of the target at that time.
+        for (ts_idx, (timestep_idx, &centroid)) in
+            vis_sel.timestep_range.clone().zip(&centroids).enumerate()
+        {
+            let mut expected = raw_jones_array
+                .slice(s![ts_idx..=ts_idx, .., ..])
+                .to_owned();
+            correct_geometry_with_tiles(
+                &corr_ctx,
+                expected.view_mut(),
+                &(timestep_idx..timestep_idx + 1),
+                &vis_sel.coarse_chan_range,
+                array_pos,
+                ephemeris.radec_at(centroid).unwrap(),
+                &tiles_xyz_geod,
+                false,
+            );
+            assert_eq!(jones_array.slice(s![ts_idx..=ts_idx, .., ..]), expected);
+        }
+
+        // timesteps outside of the ephemeris are an error.
+        let ephemeris = Ephemeris::new(vec![
+            entry(centroids[0], 0.),
+            entry(centroids[0] + Duration::from_f64(1., Unit::Second), 1.),
+        ])
+        .unwrap();
+        assert!(matches!(
+            correct_geometry_ephemeris(
+                &corr_ctx,
+                jones_array.view_mut(),
+                &vis_sel.timestep_range,
+                &vis_sel.coarse_chan_range,
+                array_pos,
+                &ephemeris,
+                &tiles_xyz_geod,
+                false,
+            ),
+            Err(EphemerisError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_correct_geometry_near_field() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let array_pos = LatLngHeight::new_mwa();
+        let tiles_xyz_geod =
+            XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
+        let all_freqs_hz = corr_ctx
+            .get_fine_chan_freqs_hz_array(&vis_sel.coarse_chan_range.clone().collect::<Vec<_>>());
+        let ant_pairs = corr_ctx
+            .metafits_context
+            .baselines
+            .iter()
+            .map(|b| (b.ant1_index, b.ant2_index))
+            .collect::<Vec<_>>();
+        let centroids = get_centroid_timestamps(&corr_ctx, &vis_sel.timestep_range);
+        let azel = AzEl::new_degrees(60., 45.);
+        let track = |range_m| {
+            let entry = |epoch| NearFieldEntry {
+                epoch,
+                position: NearFieldPosition::AzElRange { azel, range_m },
+            };
+            NearFieldTrack::new(vec![
+                entry(centroids[0]),
+                entry(centroids[centroids.len() - 1]),
+            ])
+            .unwrap()
+        };
+
+        // visibilities of a satellite 500km away are brought into phase.
+        let range_m = 500e3;
+        let target = NearFieldPosition::AzElRange { azel, range_m }
+            .to_geodetic(array_pos)
+            .unwrap();
+        let dist = |xyz: XyzGeodetic| {
+            let d = target - xyz;
+            (d.x * d.x + d.y * d.y + d.z * d.z).sqrt()
+        };
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        for mut jones_array in jones_array.outer_iter_mut() {
+            for (mut jones_array, &freq_hz) in jones_array.outer_iter_mut().zip_eq(&all_freqs_hz) {
+                for (jones, &(ant1, ant2)) in jones_array.iter_mut().zip_eq(&ant_pairs) {
+                    let path_diff_m = dist(tiles_xyz_geod[ant2]) - dist(tiles_xyz_geod[ant1]);
+                    *jones = Jones::<f32>::from(
+                        Jones::<f64>::identity()
+                            * Complex::from_polar(1., TAU * path_diff_m * freq_hz / VEL_C),
+                    );
+                }
+            }
+        }
+        correct_geometry_near_field(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            array_pos,
+            &track(range_m),
+            &tiles_xyz_geod,
+            false,
+        )
+        .unwrap();
+        for jones in jones_array.iter() {
+            assert_abs_diff_eq!(jones[0], Complex::new(1., 0.), epsilon = 1e-3);
+        }
+
+        // a very distant target is corrected like a plane wave from its direction.
+        let hadec = azel.to_hadec(array_pos.latitude_rad);
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        jones_array.fill(Jones::identity());
+        correct_geometry_near_field(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            array_pos,
+            &track(1e15),
+            &tiles_xyz_geod,
+            false,
+        )
+        .unwrap();
+        for jones_array in jones_array.outer_iter() {
+            for (jones_array, &freq_hz) in jones_array.outer_iter().zip_eq(&all_freqs_hz) {
+                for (jones, &(ant1, ant2)) in jones_array.iter().zip_eq(&ant_pairs) {
+                    let uvw = UVW::from_xyz(tiles_xyz_geod[ant1], hadec)
+                        - UVW::from_xyz(tiles_xyz_geod[ant2], hadec);
+                    let expected = Complex::from_polar(1., -TAU * uvw.w * freq_hz / VEL_C);
+                    assert_abs_diff_eq!(
+                        Complex::<f64>::new(jones[0].re as _, jones[0].im as _),
+                        expected,
+                        epsilon = 1e-3
+                    );
+                }
+            }
+        }
+
+        // timesteps outside of the track are an error.
+        let track = NearFieldTrack::new(vec![
+            NearFieldEntry {
+                epoch: centroids[0] - Duration::from_f64(2., Unit::Second),
+                position: NearFieldPosition::AzElRange { azel, range_m },
+            },
+            NearFieldEntry {
+                epoch: centroids[0] - Duration::from_f64(1., Unit::Second),
+                position: NearFieldPosition::AzElRange { azel, range_m },
+            },
+        ])
+        .unwrap();
+        assert!(matches!(
+            correct_geometry_near_field(
+                &corr_ctx,
+                jones_array.view_mut(),
+                &vis_sel.timestep_range,
+                &vis_sel.coarse_chan_range,
+                array_pos,
+                &track,
+                &tiles_xyz_geod,
+                false,
+            ),
+            Err(EphemerisError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_drift_phase_centre() {
+        let array_pos = LatLngHeight::new_mwa();
+        let epoch = Epoch::from_gpst_seconds(1_297_526_432.);
+        let dut1 = Duration::from_f64(-0.1, Unit::Second);
+        for azel in [AzEl::new(0., PI / 2.), AzEl::new_degrees(60., 45.)] {
+            let phase_centre = drift_phase_centre(azel, epoch, array_pos, dut1);
+            // the phase centre is precessed back onto the same direction as azel.
+            let prec = precess_time(
+                array_pos.longitude_rad,
+                array_pos.latitude_rad,
+                phase_centre,
+                epoch,
+                dut1,
+            );
+            let tile = XyzGeodetic {
+                x: 100.,
+                y: -200.,
+                z: 300.,
+            };
+            let w = UVW::from_xyz(prec.precess_xyz_parallel(&[tile])[0], prec.hadec_j2000).w;
+            assert_abs_diff_eq!(
+                w,
+                UVW::from_xyz(tile, azel.to_hadec(array_pos.latitude_rad)).w,
+                epsilon = 1e-6
+            );
+        }
+    }
+
+    #[test]
+    fn test_correct_geometry_drift() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let array_pos = LatLngHeight::new_mwa();
+        let tiles_xyz_geod =
+            XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
+        let all_freqs_hz = corr_ctx
+            .get_fine_chan_freqs_hz_array(&vis_sel.coarse_chan_range.clone().collect::<Vec<_>>());
+
+        // phasing to zenith delays each baseline by the height difference of its tiles in every
+        // timestep.
+        let zenith = HADec::new(0., array_pos.latitude_rad);
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        jones_array.fill(Jones::identity());
+        correct_geometry_drift(
+            &corr_ctx,
+            jones_array.view_mut(),
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            array_pos,
+            AzEl::new(0., PI / 2.),
+            &tiles_xyz_geod,
+            false,
+        );
+        for jones_array in jones_array.outer_iter() {
+            for (jones_array, &freq_hz) in jones_array.outer_iter().zip_eq(&all_freqs_hz) {
+                for (jones, baseline) in jones_array
+                    .iter()
+                    .zip_eq(&corr_ctx.metafits_context.baselines)
+                {
+                    let uvw = UVW::from_xyz(tiles_xyz_geod[baseline.ant1_index], zenith)
+                        - UVW::from_xyz(tiles_xyz_geod[baseline.ant2_index], zenith);
+                    let expected = Complex::from_polar(1., -TAU * uvw.w * freq_hz / VEL_C);
+                    assert_abs_diff_eq!(
+                        Complex::<f64>::new(jones[0].re as _, jones[0].im as _),
+                        expected,
+                        epsilon = 1e-3
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_correct_digital_gains() {
         let corr_ctx = get_mwa_ord_context();
@@ -1662,4 +2706,95 @@ mod tests {
             Err(PassbandCorrection::BadArrayShape { .. })
         ));
     }
+
+    #[test]
+    fn test_van_vleck_sigma_inverts_quantised_power() {
+        for sigma in [0.3, 0.5, 1., 2., 5.] {
+            let power = quantised_power(sigma);
+            assert_abs_diff_eq!(van_vleck_sigma(power).unwrap(), sigma, epsilon = 1e-9);
+        }
+        assert_eq!(van_vleck_sigma(0.), None);
+        assert_eq!(van_vleck_sigma(-1.), None);
+        assert_eq!(van_vleck_sigma(49.), None);
+        assert_eq!(van_vleck_sigma(f64::NAN), None);
+    }
+
+    /// Quantise weakly correlated Gaussian samples like the legacy correlator, and check that
+    /// the corrected visibilities match the statistics of the unquantised samples.
+    #[test]
+    fn test_correct_van_vleck_simulated() {
+        let num_samples = 200_000;
+        let (sigma_x, sigma_y, rho) = (0.5_f64, 0.8_f64, 0.1_f64);
+
+        // a xorshift generator and Box-Muller transform for reproducible Gaussian samples.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut uniform = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1_u64 << 53) as f64
+        };
+        let mut gaussian = || {
+            let (u1, u2) = (uniform().max(f64::MIN_POSITIVE), uniform());
+            (-2. * u1.ln()).sqrt() * (TAU * u2).cos()
+        };
+        let quantise = |x: f64| x.round().clamp(-7., 7.);
+
+        let (mut auto_x, mut auto_y, mut cross, mut true_cross) = (0., 0., 0., 0.);
+        for _ in 0..num_samples {
+            let (g1, g2) = (gaussian(), gaussian());
+            let x = sigma_x * g1;
+            let y = sigma_y * (rho * g1 + (1. - rho * rho).sqrt() * g2);
+            let (qx, qy) = (quantise(x), quantise(y));
+            auto_x += qx * qx;
+            auto_y += qy * qy;
+            cross += qx * qy;
+            true_cross += x * y;
+        }
+
+        // antenna 0 is the X input, and antenna 1 is the Y input, in the XX polarisation.
+        let ant_pairs = vec![(0, 0), (0, 1), (1, 1)];
+        let vis = |power: f64| {
+            Jones::<f32>::from([
+                Complex::new(power as f32, 0.),
+                Complex::default(),
+                Complex::default(),
+                Complex::default(),
+            ])
+        };
+        let mut jones_array =
+            Array3::from_shape_vec((1, 1, 3), vec![vis(auto_x), vis(cross), vis(auto_y)]).unwrap();
+
+        // the quantised power of the weak signal is biased.
+        assert!(auto_x / num_samples as f64 > 1.2 * sigma_x * sigma_x);
+
+        _correct_van_vleck(jones_array.view_mut(), &ant_pairs, num_samples as f64).unwrap();
+
+        let num_samples = num_samples as f64;
+        let corrected_auto_x = f64::from(jones_array[(0, 0, 0)][0].re) / num_samples;
+        let corrected_auto_y = f64::from(jones_array[(0, 0, 2)][0].re) / num_samples;
+        let corrected_cross = f64::from(jones_array[(0, 0, 1)][0].re) / num_samples;
+        assert_abs_diff_eq!(corrected_auto_x, sigma_x * sigma_x, epsilon = 0.01);
+        assert_abs_diff_eq!(corrected_auto_y, sigma_y * sigma_y, epsilon = 0.01);
+        assert_abs_diff_eq!(corrected_cross, true_cross / num_samples, epsilon = 0.002);
+    }
+
+    #[test]
+    fn test_correct_van_vleck_missing_autos() {
+        let mut jones_array = Array3::from_elem((1, 1, 2), Jones::<f32>::identity());
+        _correct_van_vleck(jones_array.view_mut(), &[(0, 0), (0, 1)], 1.).unwrap();
+        assert_eq!(
+            jones_array,
+            Array3::from_elem((1, 1, 2), Jones::<f32>::identity())
+        );
+    }
+
+    #[test]
+    fn test_correct_van_vleck_errors() {
+        let mut jones_array = Array3::from_elem((1, 1, 2), Jones::<f32>::identity());
+        assert!(matches!(
+            _correct_van_vleck(jones_array.view_mut(), &[(0, 0)], 1.),
+            Err(VanVleckCorrection::BadArrayShape(_))
+        ));
+    }
 }
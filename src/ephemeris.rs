@@ -0,0 +1,569 @@
+//! Ephemerides of moving targets, e.g. planets, comets and satellites.
+//!
+//! Geometric corrections usually phase to a fixed position on the sky. To track a moving target,
+//! the phase centre of each timestep can instead be interpolated from an ephemeris file, a
+//! whitespace separated text file with one position per line, in order of time:
+//!
+//! ```text
+//! # <TIME> <RA_DEG> <DEC_DEG> [<DISTANCE_M>]
+//! 1297526432 10.12 -20.34 7.8e11
+//! 1297526492 10.13 -20.35 7.8e11
+//! 2021-02-16T16:03:14 UTC 10.14 -20.36 7.8e11
+//! ```
+//!
+//! Where `<TIME>` is either GPS seconds, or a Gregorian date and time followed by its time system
+//! (see [`Epoch::from_gregorian_str`]), e.g. `2021-02-16T16:03:14 UTC`. Positions are J2000 RA
+//! and Dec in degrees, e.g. astrometric positions from JPL Horizons. The optional distance to the
+//! target is ignored, since these targets are far enough away that only their direction matters.
+//!
+//! Targets in the near field, e.g. satellites, are instead tracked by their position relative to
+//! the array (see [`NearFieldTrack`]), in the same format with either horizon coordinates and
+//! range, or earth-centred, earth-fixed (ECEF) coordinates:
+//!
+//! ```text
+//! # <TIME> <AZ_DEG> <EL_DEG> <RANGE_M>
+//! 1297526432 120.5 45.2 8.1e5
+//! # <TIME> <X_M> <Y_M> <Z_M>
+//! 1297526432 -2.6e6 5.6e6 -2.9e6
+//! ```
+
+use std::{
+    f64::consts::{PI, TAU},
+    fs,
+    path::Path,
+};
+
+use marlu::{
+    hifitime::Epoch, pos::ErfaError, AzEl, LatLngHeight, RADec, XyzGeocentric, XyzGeodetic, ENH,
+};
+use thiserror::Error;
+
+/// Errors when reading or interpolating an ephemeris.
+#[derive(Error, Debug)]
+pub enum EphemerisError {
+    #[error("Could not read ephemeris file {path}: {source}")]
+    /// The ephemeris file could not be read
+    Unreadable {
+        /// The path to the ephemeris file
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error("{path}:{line_num}: expected {expected}, found {line:?}")]
+    /// A line of the ephemeris file could not be parsed
+    BadLine {
+        /// The path to the ephemeris file
+        path: String,
+        /// The line number (starting from 1)
+        line_num: usize,
+        /// The contents of the line
+        line: String,
+        /// The expected format of a line
+        expected: &'static str,
+    },
+
+    #[error("{path}:{line_num}: ephemeris times must be increasing")]
+    /// The times of the ephemeris are not in order
+    Unordered {
+        /// The path to the ephemeris file
+        path: String,
+        /// The line number (starting from 1)
+        line_num: usize,
+    },
+
+    #[error("An ephemeris needs at least two positions, found {0}")]
+    /// There are not enough positions to interpolate between
+    TooShort(usize),
+
+    #[error("GPS time {gps_time_s}s is outside of the ephemeris, which covers {start_gps_time_s}s to {end_gps_time_s}s")]
+    /// A position was requested outside of the times covered by the ephemeris
+    OutOfRange {
+        /// The requested GPS time in seconds
+        gps_time_s: f64,
+        /// The GPS time of the first position in seconds
+        start_gps_time_s: f64,
+        /// The GPS time of the last position in seconds
+        end_gps_time_s: f64,
+    },
+
+    #[error(transparent)]
+    /// A near-field position could not be converted to the array's frame
+    Erfa(#[from] ErfaError),
+}
+
+/// The position of a moving target at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EphemerisEntry {
+    /// The time of the position
+    pub epoch: Epoch,
+    /// The J2000 position of the target
+    pub radec: RADec,
+}
+
+/// A table of positions of a moving target over time, which are linearly interpolated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ephemeris {
+    entries: Vec<EphemerisEntry>,
+}
+
+impl Ephemeris {
+    /// Create an ephemeris from its positions.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`EphemerisError::TooShort`] if there are less than two entries, or
+    /// [`EphemerisError::Unordered`] (with a line number of the entry index plus one) if their
+    /// times are not increasing.
+    pub fn new(entries: Vec<EphemerisEntry>) -> Result<Self, EphemerisError> {
+        check_epochs(&entries.iter().map(|entry| entry.epoch).collect::<Vec<_>>())?;
+        Ok(Self { entries })
+    }
+
+    /// Read an ephemeris file (see the [module documentation](self)).
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`EphemerisError`] if the file can't be read or parsed, or it has less than
+    /// two positions, which are not in order.
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, EphemerisError> {
+        let rows = read_table(
+            path.as_ref(),
+            "<TIME> <RA_DEG> <DEC_DEG> [<DISTANCE_M>]",
+            |values| match *values {
+                [ra_deg, dec_deg] | [ra_deg, dec_deg, _] => Some((ra_deg, dec_deg)),
+                _ => None,
+            },
+        )?;
+        let line_nums = rows.iter().map(|row| row.0).collect::<Vec<_>>();
+        let entries = rows
+            .into_iter()
+            .map(|(_, epoch, (ra_deg, dec_deg))| EphemerisEntry {
+                epoch,
+                radec: RADec::new(ra_deg.to_radians(), dec_deg.to_radians()),
+            })
+            .collect();
+        Self::new(entries).map_err(|err| at_line(err, path.as_ref(), &line_nums))
+    }
+
+    /// The positions of the ephemeris, in order of time.
+    pub fn entries(&self) -> &[EphemerisEntry] {
+        &self.entries
+    }
+
+    /// The position of the target at `epoch`, linearly interpolated in RA (the short way around)
+    /// and Dec.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use birli::{
+    ///     ephemeris::{Ephemeris, EphemerisEntry},
+    ///     marlu::{hifitime::Epoch, RADec},
+    /// };
+    ///
+    /// let entry = |gps_time_s: f64, ra_deg: f64| EphemerisEntry {
+    ///     epoch: Epoch::from_gpst_seconds(gps_time_s),
+    ///     radec: RADec::new(ra_deg.to_radians(), 0.),
+    /// };
+    /// let ephemeris = Ephemeris::new(vec![entry(0., 359.), entry(10., 1.)]).unwrap();
+    ///
+    /// // halfway between 359° and 1° is 0°, not 180°.
+    /// let radec = ephemeris.radec_at(Epoch::from_gpst_seconds(5.)).unwrap();
+    /// assert!(radec.ra.to_degrees().abs() < 1e-9);
+    /// assert!(ephemeris.radec_at(Epoch::from_gpst_seconds(11.)).is_err());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [`EphemerisError::OutOfRange`] if `epoch` is not covered by the ephemeris.
+    pub fn radec_at(&self, epoch: Epoch) -> Result<RADec, EphemerisError> {
+        let (before, after, fraction) = bracket(&self.entries, |entry| entry.epoch, epoch)?;
+        let ra_step = (after.radec.ra - before.radec.ra + PI).rem_euclid(TAU) - PI;
+        Ok(RADec::new(
+            (before.radec.ra + fraction * ra_step).rem_euclid(TAU),
+            before.radec.dec + fraction * (after.radec.dec - before.radec.dec),
+        ))
+    }
+}
+
+/// The coordinate frame of the positions in a near-field track file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NearFieldFrame {
+    /// Azimuth and elevation in degrees, and range in metres, from the array position
+    AzElRange,
+    /// Earth-centred, earth-fixed (ITRF) coordinates in metres
+    Ecef,
+}
+
+/// The position of a near-field target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NearFieldPosition {
+    /// Horizon coordinates and range in metres, from the array position
+    AzElRange {
+        /// The direction of the target
+        azel: AzEl,
+        /// The distance to the target in metres
+        range_m: f64,
+    },
+    /// Earth-centred, earth-fixed (ITRF) coordinates
+    Ecef(XyzGeocentric),
+}
+
+impl NearFieldPosition {
+    /// The position relative to `array_pos`, in the same frame as the tile positions from
+    /// [`XyzGeodetic::get_tiles`].
+    ///
+    /// # Errors
+    ///
+    /// Will return [`EphemerisError::Erfa`] if `array_pos` can't be converted to ECEF.
+    pub fn to_geodetic(self, array_pos: LatLngHeight) -> Result<XyzGeodetic, EphemerisError> {
+        Ok(match self {
+            Self::AzElRange { azel, range_m } => {
+                let (sin_az, cos_az) = azel.az.sin_cos();
+                let (sin_el, cos_el) = azel.el.sin_cos();
+                ENH {
+                    e: range_m * cos_el * sin_az,
+                    n: range_m * cos_el * cos_az,
+                    h: range_m * sin_el,
+                }
+                .to_xyz(array_pos.latitude_rad)
+            }
+            Self::Ecef(xyz) => xyz.to_geodetic(array_pos)?,
+        })
+    }
+}
+
+/// The position of a near-field target at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NearFieldEntry {
+    /// The time of the position
+    pub epoch: Epoch,
+    /// The position of the target
+    pub position: NearFieldPosition,
+}
+
+/// A table of positions of a near-field target over time, e.g. a satellite, which are linearly
+/// interpolated in cartesian coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearFieldTrack {
+    entries: Vec<NearFieldEntry>,
+}
+
+impl NearFieldTrack {
+    /// Create a near-field track from its positions.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`Ephemeris::new`].
+    pub fn new(entries: Vec<NearFieldEntry>) -> Result<Self, EphemerisError> {
+        check_epochs(&entries.iter().map(|entry| entry.epoch).collect::<Vec<_>>())?;
+        Ok(Self { entries })
+    }
+
+    /// Read a near-field track file (see the [module documentation](self)), with positions in
+    /// `frame`.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`Ephemeris::from_file`].
+    pub fn from_file<T: AsRef<Path>>(
+        path: T,
+        frame: NearFieldFrame,
+    ) -> Result<Self, EphemerisError> {
+        let expected = match frame {
+            NearFieldFrame::AzElRange => "<TIME> <AZ_DEG> <EL_DEG> <RANGE_M>",
+            NearFieldFrame::Ecef => "<TIME> <X_M> <Y_M> <Z_M>",
+        };
+        let rows = read_table(path.as_ref(), expected, |values| match *values {
+            [a, b, c] => Some((a, b, c)),
+            _ => None,
+        })?;
+        let line_nums = rows.iter().map(|row| row.0).collect::<Vec<_>>();
+        let entries = rows
+            .into_iter()
+            .map(|(_, epoch, (a, b, c))| NearFieldEntry {
+                epoch,
+                position: match frame {
+                    NearFieldFrame::AzElRange => NearFieldPosition::AzElRange {
+                        azel: AzEl::new_degrees(a, b),
+                        range_m: c,
+                    },
+                    NearFieldFrame::Ecef => {
+                        NearFieldPosition::Ecef(XyzGeocentric { x: a, y: b, z: c })
+                    }
+                },
+            })
+            .collect();
+        Self::new(entries).map_err(|err| at_line(err, path.as_ref(), &line_nums))
+    }
+
+    /// The positions of the track, in order of time.
+    pub fn entries(&self) -> &[NearFieldEntry] {
+        &self.entries
+    }
+
+    /// The position of the target at `epoch` relative to `array_pos` (see
+    /// [`NearFieldPosition::to_geodetic`]), linearly interpolated.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`EphemerisError::OutOfRange`] if `epoch` is not covered by the track.
+    pub fn position_at(
+        &self,
+        epoch: Epoch,
+        array_pos: LatLngHeight,
+    ) -> Result<XyzGeodetic, EphemerisError> {
+        let (before, after, fraction) = bracket(&self.entries, |entry| entry.epoch, epoch)?;
+        let before = before.position.to_geodetic(array_pos)?;
+        let after = after.position.to_geodetic(array_pos)?;
+        Ok(XyzGeodetic {
+            x: before.x + fraction * (after.x - before.x),
+            y: before.y + fraction * (after.y - before.y),
+            z: before.z + fraction * (after.z - before.z),
+        })
+    }
+}
+
+/// Check that there are at least two epochs, which are increasing.
+fn check_epochs(epochs: &[Epoch]) -> Result<(), EphemerisError> {
+    if epochs.len() < 2 {
+        return Err(EphemerisError::TooShort(epochs.len()));
+    }
+    if let Some(entry_idx) = epochs.windows(2).position(|pair| pair[1] <= pair[0]) {
+        return Err(EphemerisError::Unordered {
+            path: String::new(),
+            line_num: entry_idx + 2,
+        });
+    }
+    Ok(())
+}
+
+/// Point an [`EphemerisError::Unordered`] from an entry index to the line of `path` it was read
+/// from.
+fn at_line(err: EphemerisError, path: &Path, line_nums: &[usize]) -> EphemerisError {
+    match err {
+        EphemerisError::Unordered { line_num, .. } => EphemerisError::Unordered {
+            path: path.display().to_string(),
+            line_num: line_nums[line_num - 1],
+        },
+        err => err,
+    }
+}
+
+/// Read the time and values of each line of a table, skipping blank lines and comments, where
+/// `parse_values` converts the values after the time. Each row is returned with its line number.
+fn read_table<T>(
+    path: &Path,
+    expected: &'static str,
+    parse_values: impl Fn(&[f64]) -> Option<T>,
+) -> Result<Vec<(usize, Epoch, T)>, EphemerisError> {
+    let path_str = path.display().to_string();
+    let contents = fs::read_to_string(path).map_err(|source| EphemerisError::Unreadable {
+        path: path_str.clone(),
+        source,
+    })?;
+    let mut rows = vec![];
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || EphemerisError::BadLine {
+            path: path_str.clone(),
+            line_num: line_idx + 1,
+            line: line.into(),
+            expected,
+        };
+        let fields: Vec<_> = line.split_whitespace().collect();
+        // a Gregorian time must have a time system, which may be a separate field. Without
+        // one, hifitime assumes TAI.
+        let gregorian = |time: &str| {
+            Epoch::from_gregorian_str(time)
+                .ok()
+                .filter(|_| time.ends_with(char::is_alphabetic))
+        };
+        let (epoch, values) = match fields[..] {
+            [time, ref values @ ..] if time.parse::<f64>().is_ok() => {
+                (Epoch::from_gpst_seconds(time.parse().unwrap()), values)
+            }
+            [date, system, ref values @ ..]
+                if gregorian(&format!("{} {}", date, system)).is_some() =>
+            {
+                (gregorian(&format!("{} {}", date, system)).unwrap(), values)
+            }
+            [time, ref values @ ..] => (gregorian(time).ok_or_else(bad_line)?, values),
+            _ => return Err(bad_line()),
+        };
+        let values = values
+            .iter()
+            .map(|value| value.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| bad_line())?;
+        rows.push((
+            line_idx + 1,
+            epoch,
+            parse_values(&values).ok_or_else(bad_line)?,
+        ));
+    }
+    Ok(rows)
+}
+
+/// The entries either side of `epoch`, and the fraction of the way from the first to the
+/// second.
+fn bracket<T>(
+    entries: &[T],
+    epoch_of: impl Fn(&T) -> Epoch,
+    epoch: Epoch,
+) -> Result<(&T, &T, f64), EphemerisError> {
+    let gps_time_s = epoch.as_gpst_seconds();
+    let (first, last) = (&entries[0], &entries[entries.len() - 1]);
+    if epoch < epoch_of(first) || epoch > epoch_of(last) {
+        return Err(EphemerisError::OutOfRange {
+            gps_time_s,
+            start_gps_time_s: epoch_of(first).as_gpst_seconds(),
+            end_gps_time_s: epoch_of(last).as_gpst_seconds(),
+        });
+    }
+    // the index of the first entry after epoch, if epoch is not the last entry.
+    let next_idx = entries
+        .partition_point(|entry| epoch_of(entry) <= epoch)
+        .clamp(1, entries.len() - 1);
+    let (before, after) = (&entries[next_idx - 1], &entries[next_idx]);
+    let start_s = epoch_of(before).as_gpst_seconds();
+    let fraction = (gps_time_s - start_s) / (epoch_of(after).as_gpst_seconds() - start_s);
+    Ok((before, after, fraction))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use approx::assert_abs_diff_eq;
+    use marlu::{hifitime::Epoch, LatLngHeight, XyzGeocentric, XyzGeodetic};
+    use tempfile::tempdir;
+
+    use super::{Ephemeris, EphemerisError, NearFieldFrame, NearFieldTrack};
+
+    #[test]
+    fn test_ephemeris_from_file() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("ephemeris.txt");
+        fs::write(
+            &path,
+            "# time ra dec distance\n1000000000 10 -20 100\n\n\
+            1000000010 12 -21 200\n2011-09-14T01:46:45 UTC 16 -23\n",
+        )
+        .unwrap();
+        let ephemeris = Ephemeris::from_file(&path).unwrap();
+        assert_eq!(ephemeris.entries().len(), 3);
+        assert_abs_diff_eq!(
+            ephemeris.entries()[2].epoch.as_gpst_seconds(),
+            1_000_000_020.,
+            epsilon = 1e-6
+        );
+
+        let at = |gps_time_s| Epoch::from_gpst_seconds(gps_time_s);
+        let radec = ephemeris.radec_at(at(1_000_000_005.)).unwrap();
+        assert_abs_diff_eq!(radec.ra.to_degrees(), 11., epsilon = 1e-9);
+        assert_abs_diff_eq!(radec.dec.to_degrees(), -20.5, epsilon = 1e-9);
+        // the last entry is included
+        let radec = ephemeris.radec_at(at(1_000_000_020.)).unwrap();
+        assert_abs_diff_eq!(radec.ra.to_degrees(), 16., epsilon = 1e-9);
+
+        assert!(matches!(
+            ephemeris.radec_at(at(999_999_999.)),
+            Err(EphemerisError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ephemeris_errors() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("ephemeris.txt");
+        for (contents, line_num) in [
+            ("0 1\n", 1),
+            ("0 1 2 3 4\n", 1),
+            ("\n0 1 abc\n", 2),
+            ("yesterday 1 2\n", 1),
+            ("2021-02-16T16:03:14 1 2\n", 1),
+        ] {
+            fs::write(&path, contents).unwrap();
+            let result = Ephemeris::from_file(&path);
+            assert!(
+                matches!(result, Err(EphemerisError::BadLine { line_num: n, .. }) if n == line_num),
+                "{:?}",
+                result
+            );
+        }
+
+        fs::write(&path, "0 1 2\n").unwrap();
+        assert!(matches!(
+            Ephemeris::from_file(&path),
+            Err(EphemerisError::TooShort(1))
+        ));
+
+        fs::write(&path, "0 1 2\n# comment\n0 1 2\n").unwrap();
+        assert!(matches!(
+            Ephemeris::from_file(&path),
+            Err(EphemerisError::Unordered { line_num: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_near_field_track_from_file() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("near_field.txt");
+        let array_pos = LatLngHeight::new_mwa();
+        let at = |gps_time_s| Epoch::from_gpst_seconds(gps_time_s);
+
+        // due east on the horizon, then straight up.
+        fs::write(&path, "# az el range\n0 90 0 1000\n10 0 90 2000\n").unwrap();
+        let track = NearFieldTrack::from_file(&path, NearFieldFrame::AzElRange).unwrap();
+        let east = track.position_at(at(0.), array_pos).unwrap();
+        assert_abs_diff_eq!(
+            east,
+            XyzGeodetic {
+                x: 0.,
+                y: 1000.,
+                z: 0.
+            },
+            epsilon = 1e-6
+        );
+        let up = track
+            .position_at(at(10.), array_pos)
+            .unwrap()
+            .to_enh(array_pos.latitude_rad);
+        assert_abs_diff_eq!(up.h, 2000., epsilon = 1e-6);
+        assert_abs_diff_eq!(up.e, 0., epsilon = 1e-6);
+        // positions are interpolated in cartesian coordinates
+        let mid = track
+            .position_at(at(5.), array_pos)
+            .unwrap()
+            .to_enh(array_pos.latitude_rad);
+        assert_abs_diff_eq!(mid.e, 500., epsilon = 1e-6);
+        assert_abs_diff_eq!(mid.h, 1000., epsilon = 1e-6);
+
+        // 1km above the array, in ECEF.
+        let XyzGeocentric { x, y, z } = LatLngHeight {
+            height_metres: array_pos.height_metres + 1000.,
+            ..array_pos
+        }
+        .to_geocentric_wgs84()
+        .unwrap();
+        fs::write(&path, format!("0 {0} {1} {2}\n10 {0} {1} {2}\n", x, y, z)).unwrap();
+        let track = NearFieldTrack::from_file(&path, NearFieldFrame::Ecef).unwrap();
+        let up = track
+            .position_at(at(5.), array_pos)
+            .unwrap()
+            .to_enh(array_pos.latitude_rad);
+        assert_abs_diff_eq!(up.h, 1000., epsilon = 1e-3);
+        assert_abs_diff_eq!(up.e, 0., epsilon = 1e-3);
+
+        fs::write(&path, "0 90 0\n10 0 90 2000\n").unwrap();
+        assert!(matches!(
+            NearFieldTrack::from_file(&path, NearFieldFrame::AzElRange),
+            Err(EphemerisError::BadLine { line_num: 1, .. })
+        ));
+    }
+}
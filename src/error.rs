@@ -3,7 +3,7 @@
 use marlu::{io::error::BadArrayShape, mwalib};
 use thiserror::Error;
 
-use crate::corrections::{DigitalGainCorrection, PassbandCorrection};
+use crate::corrections::{DigitalGainCorrection, PassbandCorrection, VanVleckCorrection};
 
 /// Errors relating to CI
 #[derive(Error, Debug)]
@@ -57,6 +57,51 @@ pub enum BirliError {
     /// Error derived from [`crate::corrections::DigitalGainCorrection`]
     DigitalGainCorrection(#[from] DigitalGainCorrection),
 
+    #[error(transparent)]
+    /// Error derived from [`crate::corrections::VanVleckCorrection`]
+    VanVleckCorrection(#[from] VanVleckCorrection),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::ephemeris::EphemerisError`]
+    EphemerisError(#[from] crate::ephemeris::EphemerisError),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::strategies::StrategyError`]
+    StrategyError(#[from] crate::strategies::StrategyError),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::cables::CableError`]
+    CableError(#[from] crate::cables::CableError),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::tiles::TileOverrideError`]
+    TileOverrideError(#[from] crate::tiles::TileOverrideError),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::passband_gains::PassbandGainsError`]
+    PassbandGainsError(#[from] crate::passband_gains::PassbandGainsError),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::preprocessing::StepOrderError`]
+    StepOrderError(#[from] crate::preprocessing::StepOrderError),
+
+    #[error(transparent)]
+    /// Error derived from [`crate::pipeline::PipelineError`]
+    PipelineError(#[from] crate::pipeline::PipelineError),
+
+    #[error(transparent)]
+    /// Error from a custom [`crate::Correction`]
+    Correction(#[from] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Couldn't write {output}: {source}")]
+    /// Error when initialising, writing or finalising an output
+    WriteError {
+        /// The output which couldn't be written
+        output: String,
+        /// The underlying error
+        source: Box<crate::io::error::IOError>,
+    },
+
     #[error("You selected dry run")]
     /// enum variant for when a dry run is selected
     DryRun {},
@@ -1,12 +1,15 @@
 //! Methods for manipulating flagmasks and flagging imagesets
 
-use std::ops::Range;
+use std::{fmt::Display, ops::Range};
 
 use crate::{
+    corrections::{scrunch_gains, ScrunchType},
     io::error::IOError,
     marlu::{
         mwalib::{CorrelatorContext, MWAVersion},
         ndarray::prelude::*,
+        rayon::prelude::*,
+        Jones,
     },
     BirliError, FlagFileSet,
 };
@@ -14,14 +17,14 @@ use cfg_if::cfg_if;
 use derive_builder::Builder;
 use itertools::izip;
 use log::trace;
-use marlu::{io::error::BadArrayShape, VisSelection};
+use marlu::{io::error::BadArrayShape, mwalib::Antenna, VisSelection};
+use prettytable::{format as prettyformat, row, table, Table};
 
 cfg_if! {
     if #[cfg(feature = "aoflagger")] {
         use aoflagger_sys::{CxxAOFlagger, flagmask_or,
             flagmask_set, CxxFlagMask, UniquePtr, CxxImageSet};
         use indicatif::{ProgressBar, ProgressStyle};
-        use marlu::{Jones, rayon::prelude::*, ndarray::{ArrayView2, Axis}};
     }
 }
 
@@ -35,7 +38,8 @@ pub struct FlagContext {
     pub coarse_chan_flags: Vec<bool>,
     /// Which fine channel indices are flagged in every coarse channel
     pub fine_chan_flags: Vec<bool>,
-    /// Which mwalib antenna indices are flagged
+    /// Which mwalib antenna indices are flagged for the whole observation, e.g. from the
+    /// metafits. Tiles detected with `bad_tile_threshold` are not added to these.
     pub antenna_flags: Vec<bool>,
     /// Whether auto-correlations are flagged
     #[builder(default = "false")]
@@ -47,6 +51,14 @@ pub struct FlagContext {
     pub flag_init: f32,
     /// How many seconds to flag from the end of the observation
     pub flag_end: f32,
+    /// If provided, flag tiles whose autocorrelations deviate from the rest of the array by more
+    /// than this many robust standard deviations, see [`detect_bad_tiles`]. Detection is done
+    /// separately for each chunk of timesteps, and a bad tile is only flagged in that chunk.
+    #[builder(default)]
+    pub bad_tile_threshold: Option<f32>,
+    /// Rules which extend flags after RFI flagging, see [`extend_flags`]
+    #[builder(default)]
+    pub extension: FlagExtension,
 }
 
 impl FlagContext {
@@ -70,6 +82,8 @@ impl FlagContext {
     ///
     /// - Timesteps are flagged in they are not provided in any of the gpubox files.
     /// - Coarse channels are flagged if they appear in the metafits, but are not provided.
+    /// - Antennas are flagged if either of their rfinputs are flagged in the metafits. Flags
+    ///   apply to every polarisation, since the outputs only have one weight per visibility.
     /// - No fine channel flags are set by default.
     /// -
     ///
@@ -162,6 +176,66 @@ impl FlagContext {
         }
     }
 
+    /// Flag fine channels whose passband gain falls below `threshold`, where `passband_gains` are
+    /// the gains of each ultrafine channel in a coarse channel (e.g.
+    /// [`crate::passband_gains::PFB_JAKE_2022_200HZ`]), scrunched to the fine channel resolution
+    /// with [`scrunch_gains`]. Unlike a fixed edge width, this tracks the filter response at any
+    /// frequency resolution. Returns the number of fine channels flagged in each coarse channel.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use birli::{corrections::ScrunchType, passband_gains::PFB_JAKE_2022_200HZ, FlagContext};
+    ///
+    /// let mut flag_ctx = FlagContext::blank_from_dimensions(1, 1, 32, 1);
+    /// let num_flagged = flag_ctx
+    ///     .flag_low_gain_chans(PFB_JAKE_2022_200HZ, &ScrunchType::CenterSymmetric, 0.6)
+    ///     .unwrap();
+    ///
+    /// // the channel on the edge of the coarse channel, and one either side.
+    /// assert_eq!(num_flagged, 3);
+    /// assert!(flag_ctx.fine_chan_flags[0]);
+    /// assert!(flag_ctx.fine_chan_flags[1]);
+    /// assert!(!flag_ctx.fine_chan_flags[2]);
+    /// assert!(flag_ctx.fine_chan_flags[31]);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [`BadArrayShape`] if the number of passband gains is not a multiple of the
+    /// number of fine channels per coarse channel.
+    pub fn flag_low_gain_chans(
+        &mut self,
+        passband_gains: &[f64],
+        scrunch_type: &ScrunchType,
+        threshold: f64,
+    ) -> Result<usize, BadArrayShape> {
+        let num_fine_chans_per_coarse = self.fine_chan_flags.len();
+        if num_fine_chans_per_coarse == 0 || passband_gains.len() % num_fine_chans_per_coarse != 0 {
+            return Err(BadArrayShape {
+                argument: "passband_gains",
+                function: "FlagContext::flag_low_gain_chans",
+                expected: format!(
+                    "a multiple of num_fine_chans_per_coarse={}",
+                    num_fine_chans_per_coarse
+                ),
+                received: format!("{}", passband_gains.len()),
+            });
+        }
+        let fscrunch = passband_gains.len() / num_fine_chans_per_coarse;
+        let mut num_flagged = 0;
+        for (flag, gain) in izip!(
+            self.fine_chan_flags.iter_mut(),
+            scrunch_gains(passband_gains, fscrunch, scrunch_type)
+        ) {
+            if gain < threshold {
+                *flag = true;
+                num_flagged += 1;
+            }
+        }
+        Ok(num_flagged)
+    }
+
     /// Set flags from this context in an existing array.
     ///
     /// # Errors
@@ -175,24 +249,8 @@ impl FlagContext {
         ant_pairs: &[(usize, usize)],
     ) -> Result<(), BirliError> {
         let timestep_flags = &self.timestep_flags[timestep_range.clone()];
-        let coarse_chan_flags = &self.coarse_chan_flags[coarse_chan_range.clone()];
+        let chan_flags = self.get_chan_flags(coarse_chan_range);
         let baseline_flags = self.get_baseline_flags(ant_pairs);
-
-        let fine_chan_count = self.fine_chan_flags.len();
-        let mut fine_chan_flags = self.fine_chan_flags.clone();
-        if self.flag_dc {
-            fine_chan_flags[fine_chan_count / 2] = true;
-        }
-        let chan_flags: Vec<_> = coarse_chan_flags
-            .iter()
-            .flat_map(|coarse_chan_flag| {
-                if *coarse_chan_flag {
-                    vec![true; fine_chan_count]
-                } else {
-                    fine_chan_flags.clone()
-                }
-            })
-            .collect();
         let shape = (timestep_range.len(), chan_flags.len(), ant_pairs.len());
 
         let flag_shape = flag_array.dim();
@@ -213,6 +271,662 @@ impl FlagContext {
 
         Ok(())
     }
+
+    /// Flags for each fine channel in the given coarse channel range, from the coarse and fine
+    /// channel flags, and DC flagging.
+    fn get_chan_flags(&self, coarse_chan_range: &Range<usize>) -> Vec<bool> {
+        let fine_chan_count = self.fine_chan_flags.len();
+        let mut fine_chan_flags = self.fine_chan_flags.clone();
+        if self.flag_dc {
+            fine_chan_flags[fine_chan_count / 2] = true;
+        }
+        self.coarse_chan_flags[coarse_chan_range.clone()]
+            .iter()
+            .flat_map(|coarse_chan_flag| {
+                if *coarse_chan_flag {
+                    vec![true; fine_chan_count]
+                } else {
+                    fine_chan_flags.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/// A threshold above which visibility amplitudes are flagged by [`flag_jones_array_sanity`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmplitudeThreshold {
+    /// Flag any visibility with an instrumental polarisation amplitude above this value.
+    Absolute(f32),
+    /// Flag any visibility with an amplitude more than this many standard deviations above the
+    /// median amplitude of its baseline, where the standard deviation is estimated from the median
+    /// absolute deviation (MAD).
+    Mad(f32),
+}
+
+impl Display for AmplitudeThreshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Absolute(amp) => write!(f, "amplitude > {}", amp),
+            Self::Mad(sigma) => write!(f, "amplitude > median + {} * 1.4826 * MAD", sigma),
+        }
+    }
+}
+
+/// Scale factor which converts a median absolute deviation into an estimate of the standard
+/// deviation of normally distributed data.
+const MAD_TO_STD: f32 = 1.4826;
+
+/// The median of a slice of floats which contains no NaNs. The slice is reordered.
+pub(crate) fn median_mut(values: &mut [f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let mid = values.len() / 2;
+    let (_, &mut median, _) = values.select_nth_unstable_by(mid, f32::total_cmp);
+    Some(median)
+}
+
+/// Flag visibilities which are obviously corrupt before any corrections are applied.
+///
+/// The following visibilities are flagged:
+/// - any visibility containing a non-finite (NaN or infinite) value.
+/// - any visibility where all elements of the Jones matrix are exactly zero.
+/// - if `amp_threshold` is provided, any visibility where the amplitude of any instrumental
+///   polarisation exceeds the threshold. For [`AmplitudeThreshold::Mad`], the median and MAD are
+///   computed separately for each baseline from visibilities that are not already flagged, and
+///   baselines where the MAD is zero or not finite are not flagged by amplitude.
+///
+/// Existing flags are always preserved.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::{flags::{flag_jones_array_sanity, AmplitudeThreshold}, Complex, Jones};
+/// use birli::ndarray::Array3;
+///
+/// let mut jones_array = Array3::from_elem((2, 2, 1), Jones::identity());
+/// jones_array[(0, 0, 0)] = Jones::nan();
+/// jones_array[(0, 1, 0)] = Jones::default();
+/// jones_array[(1, 0, 0)][0] = Complex::new(1e9, 0.);
+/// let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+///
+/// flag_jones_array_sanity(
+///     jones_array.view(),
+///     flag_array.view_mut(),
+///     Some(AmplitudeThreshold::Absolute(1e6)),
+/// );
+///
+/// assert!(flag_array[(0, 0, 0)]);
+/// assert!(flag_array[(0, 1, 0)]);
+/// assert!(flag_array[(1, 0, 0)]);
+/// assert!(!flag_array[(1, 1, 0)]);
+/// ```
+pub fn flag_jones_array_sanity(
+    jones_array: ArrayView3<Jones<f32>>,
+    mut flag_array: ArrayViewMut3<bool>,
+    amp_threshold: Option<AmplitudeThreshold>,
+) {
+    trace!("start flag_jones_array_sanity");
+
+    jones_array
+        .axis_iter(Axis(2))
+        .into_par_iter()
+        .zip(flag_array.axis_iter_mut(Axis(2)))
+        .for_each(|(jones_baseline_view, mut flag_baseline_view)| {
+            // the largest polarisation amplitude of each visibility
+            let max_amps = jones_baseline_view.map(|jones| {
+                jones
+                    .iter()
+                    .map(|c| c.norm())
+                    .fold(0_f32, |acc, amp| if amp > acc { amp } else { acc })
+            });
+
+            for (jones, flag) in izip!(jones_baseline_view.iter(), flag_baseline_view.iter_mut()) {
+                if jones.iter().any(|c| !c.re.is_finite() || !c.im.is_finite())
+                    || jones.iter().all(|c| c.re == 0. && c.im == 0.)
+                {
+                    *flag = true;
+                }
+            }
+
+            let max_amp = match amp_threshold {
+                None => return,
+                Some(AmplitudeThreshold::Absolute(amp)) => amp,
+                Some(AmplitudeThreshold::Mad(sigma)) => {
+                    let mut amps = izip!(max_amps.iter(), flag_baseline_view.iter())
+                        .filter_map(|(&amp, &flag)| if flag { None } else { Some(amp) })
+                        .collect::<Vec<_>>();
+                    let median = match median_mut(&mut amps) {
+                        Some(median) => median,
+                        None => return,
+                    };
+                    amps.iter_mut().for_each(|amp| *amp = (*amp - median).abs());
+                    // a baseline which is mostly constant (e.g. zero-filled) has no spread to
+                    // compare against, so it is left to the other tests.
+                    match median_mut(&mut amps) {
+                        Some(mad) if mad > 0. && mad.is_finite() => {
+                            median + sigma * MAD_TO_STD * mad
+                        }
+                        _ => return,
+                    }
+                }
+            };
+
+            for (&amp, flag) in izip!(max_amps.iter(), flag_baseline_view.iter_mut()) {
+                if amp > max_amp {
+                    *flag = true;
+                }
+            }
+        });
+
+    trace!("end flag_jones_array_sanity");
+}
+
+/// Statistics derived from a tile's autocorrelations by [`detect_bad_tiles`].
+///
+/// Deviations are robust z-scores relative to the other tiles in the array, using the median
+/// absolute deviation (MAD) as an estimate of the standard deviation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileDiagnostic {
+    /// The mwalib antenna index of the tile
+    pub ant_idx: usize,
+    /// The median XX autocorrelation power over unflagged channels
+    pub power_x: f32,
+    /// The median YY autocorrelation power over unflagged channels
+    pub power_y: f32,
+    /// Deviation of the (log) total power from the array median
+    pub power_dev: f32,
+    /// Deviation of the normalised spectral shape from the array median spectrum
+    pub shape_dev: f32,
+    /// Deviation of the (log) X/Y power ratio from the array median
+    pub xy_dev: f32,
+    /// Whether the tile had no unflagged autocorrelation data
+    pub no_data: bool,
+    /// Whether any deviation exceeds the threshold, or the tile is dead
+    pub bad: bool,
+}
+
+/// Robust z-scores of `values` relative to their median, ignoring non-finite values.
+///
+/// If the MAD is zero, values equal to the median get a score of zero, and anything else is
+/// infinitely deviant.
+fn robust_z_scores(values: &[f32]) -> Vec<f32> {
+    let mut finite = values
+        .iter()
+        .copied()
+        .filter(|v| v.is_finite())
+        .collect::<Vec<_>>();
+    let median = match median_mut(&mut finite) {
+        Some(median) => median,
+        None => return vec![f32::NAN; values.len()],
+    };
+    finite.iter_mut().for_each(|v| *v = (*v - median).abs());
+    let std = MAD_TO_STD * median_mut(&mut finite).unwrap_or(0.);
+    values
+        .iter()
+        .map(|&v| {
+            let dev = v - median;
+            if std > 0. {
+                dev / std
+            } else if dev == 0. {
+                0.
+            } else {
+                dev.signum() * f32::INFINITY
+            }
+        })
+        .collect()
+}
+
+/// Find tiles whose autocorrelations are anomalous relative to the rest of the array.
+///
+/// For each selected autocorrelation baseline, the unflagged XX and YY powers are averaged over
+/// time to give a spectrum for each tile. A tile is considered bad if it has no power at all, or
+/// if any of the following deviate from the array median by more than `threshold` robust
+/// standard deviations:
+/// - total power (in log space, either too high or too low)
+/// - spectral shape (the median absolute difference between the tile's spectrum, normalised by its
+///   median, and the array median normalised spectrum)
+/// - X/Y imbalance (the log ratio of XX to YY power)
+///
+/// Tiles where all autocorrelations are already flagged are reported with `no_data`, and do not
+/// contribute to the array statistics.
+///
+/// # Arguments
+///
+/// - `jones_array` - visibilities, [timestep][channel][baseline].
+/// - `flag_array` - flags with the same dimensions as `jones_array`.
+/// - `ant_pairs` - the antenna indices of each baseline in the baseline axis.
+/// - `threshold` - the number of robust standard deviations above which a tile is bad.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::{flags::detect_bad_tiles, Jones};
+/// use birli::ndarray::Array3;
+///
+/// let ant_pairs = (0..8).map(|a| (a, a)).collect::<Vec<_>>();
+/// let mut jones_array = Array3::from_shape_fn((2, 4, 8), |(_, ch, bl)| {
+///     Jones::identity() * (100. + ch as f32 + bl as f32)
+/// });
+/// // tile 3 is dead
+/// jones_array.slice_mut(birli::ndarray::s![.., .., 3]).fill(Jones::default());
+/// let flag_array = Array3::from_elem(jones_array.dim(), false);
+///
+/// let diagnostics = detect_bad_tiles(jones_array.view(), flag_array.view(), &ant_pairs, 5.);
+/// let bad_ants = diagnostics.iter().filter(|d| d.bad).map(|d| d.ant_idx).collect::<Vec<_>>();
+/// assert_eq!(bad_ants, vec![3]);
+/// ```
+pub fn detect_bad_tiles(
+    jones_array: ArrayView3<Jones<f32>>,
+    flag_array: ArrayView3<bool>,
+    ant_pairs: &[(usize, usize)],
+    threshold: f32,
+) -> Vec<TileDiagnostic> {
+    trace!("start detect_bad_tiles");
+
+    let num_chans = jones_array.dim().1;
+
+    // time-averaged XX and YY spectra for each selected autocorrelation.
+    let auto_spectra = izip!(
+        ant_pairs.iter(),
+        jones_array.axis_iter(Axis(2)),
+        flag_array.axis_iter(Axis(2))
+    )
+    .filter(|(&(ant1, ant2), ..)| ant1 == ant2)
+    .map(|(&(ant_idx, _), jones_baseline_view, flag_baseline_view)| {
+        let mut sums = vec![(0_f64, 0_f64, 0_u32); num_chans];
+        for (jones_timestep_view, flag_timestep_view) in izip!(
+            jones_baseline_view.outer_iter(),
+            flag_baseline_view.outer_iter()
+        ) {
+            for (sum, jones, &flag) in izip!(
+                sums.iter_mut(),
+                jones_timestep_view.iter(),
+                flag_timestep_view.iter()
+            ) {
+                if !flag && jones[0].re.is_finite() && jones[3].re.is_finite() {
+                    sum.0 += jones[0].re as f64;
+                    sum.1 += jones[3].re as f64;
+                    sum.2 += 1;
+                }
+            }
+        }
+        let spectrum = sums
+            .into_iter()
+            .filter(|&(.., count)| count > 0)
+            .map(|(x, y, count)| ((x / count as f64) as f32, (y / count as f64) as f32))
+            .collect::<Vec<_>>();
+        (ant_idx, spectrum)
+    })
+    .collect::<Vec<_>>();
+
+    let medians = auto_spectra
+        .iter()
+        .map(|(_, spectrum)| {
+            let mut xs = spectrum.iter().map(|&(x, _)| x).collect::<Vec<_>>();
+            let mut ys = spectrum.iter().map(|&(_, y)| y).collect::<Vec<_>>();
+            (
+                median_mut(&mut xs).unwrap_or(f32::NAN),
+                median_mut(&mut ys).unwrap_or(f32::NAN),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // tiles which contribute to the array statistics: those with some positive power.
+    let live = medians
+        .iter()
+        .map(|&(x, y)| x > 0. && y > 0.)
+        .collect::<Vec<_>>();
+    let only_live = |values: Vec<f32>| -> Vec<f32> {
+        izip!(values, live.iter())
+            .map(|(v, &l)| if l { v } else { f32::NAN })
+            .collect()
+    };
+
+    let log_powers = only_live(medians.iter().map(|&(x, y)| (x + y).ln()).collect());
+    let log_xy_ratios = only_live(medians.iter().map(|&(x, y)| (x / y).ln()).collect());
+
+    // normalised (x + y) spectra, and the median normalised spectrum over live tiles
+    let norm_spectra = izip!(auto_spectra.iter(), medians.iter())
+        .map(|((_, spectrum), &(x, y))| {
+            spectrum
+                .iter()
+                .map(|&(sx, sy)| (sx + sy) / (x + y))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let ref_spectrum = (0..norm_spectra.iter().map(Vec::len).max().unwrap_or(0))
+        .map(|chan| {
+            let mut values = izip!(norm_spectra.iter(), live.iter())
+                .filter_map(|(spectrum, &l)| if l { spectrum.get(chan).copied() } else { None })
+                .collect::<Vec<_>>();
+            median_mut(&mut values).unwrap_or(f32::NAN)
+        })
+        .collect::<Vec<_>>();
+    let shape_devs = only_live(
+        norm_spectra
+            .iter()
+            .map(|spectrum| {
+                let mut diffs = izip!(spectrum.iter(), ref_spectrum.iter())
+                    .map(|(&s, &r)| (s - r).abs())
+                    .filter(|d| d.is_finite())
+                    .collect::<Vec<_>>();
+                median_mut(&mut diffs).unwrap_or(f32::NAN)
+            })
+            .collect(),
+    );
+
+    let power_devs = robust_z_scores(&log_powers);
+    let shape_devs = robust_z_scores(&shape_devs);
+    let xy_devs = robust_z_scores(&log_xy_ratios);
+
+    let diagnostics = izip!(
+        auto_spectra.iter(),
+        medians.iter(),
+        live.iter(),
+        power_devs,
+        shape_devs,
+        xy_devs
+    )
+    .map(
+        |((ant_idx, spectrum), &(power_x, power_y), &live, power_dev, shape_dev, xy_dev)| {
+            let no_data = spectrum.is_empty();
+            // a tile with data but no power
+            let dead = !(no_data || live);
+            TileDiagnostic {
+                ant_idx: *ant_idx,
+                power_x,
+                power_y,
+                power_dev,
+                shape_dev,
+                xy_dev,
+                no_data,
+                bad: dead
+                    || power_dev.abs() > threshold
+                    || shape_dev > threshold
+                    || xy_dev.abs() > threshold,
+            }
+        },
+    )
+    .collect();
+
+    trace!("end detect_bad_tiles");
+    diagnostics
+}
+
+/// Render a table of [`TileDiagnostic`]s, labelled with tile names from the `antennas` in the
+/// metafits.
+pub fn tile_diagnostics_table(diagnostics: &[TileDiagnostic], antennas: &[Antenna]) -> Table {
+    let mut table = table!([
+        "",
+        "name",
+        "pow xx",
+        "pow yy",
+        "dev pow",
+        "dev shape",
+        "dev x/y",
+        "bad"
+    ]);
+    table.set_format(*prettyformat::consts::FORMAT_CLEAN);
+    for diagnostic in diagnostics {
+        let name = antennas
+            .get(diagnostic.ant_idx)
+            .map_or_else(String::new, |ant| ant.tile_name.clone());
+        table.add_row(row![r =>
+            format!("ant{}:", diagnostic.ant_idx),
+            name,
+            format!("{:.3e}", diagnostic.power_x),
+            format!("{:.3e}", diagnostic.power_y),
+            format!("{:.2}", diagnostic.power_dev),
+            format!("{:.2}", diagnostic.shape_dev),
+            format!("{:.2}", diagnostic.xy_dev),
+            if diagnostic.no_data { "-" } else if diagnostic.bad { "b" } else { "" }
+        ]);
+    }
+    table
+}
+
+/// Rules which extend existing flags, applied after RFI flagging to catch faint RFI that leaks
+/// around flagged regions.
+///
+/// Occupancy thresholds are the fraction of samples that must be flagged for the whole
+/// timestep, channel, baseline or antenna to be flagged. When computing the occupancy,
+/// timesteps, channels and baselines which are entirely flagged (e.g. flagged antennas or edge
+/// channels) are ignored, so they don't inflate the occupancy of everything else.
+///
+/// When processing in chunks, the rules are applied to each chunk along with its halo of extra
+/// timesteps, so time dilation is consistent across chunk boundaries as long as the halo is at
+/// least [`FlagExtension::dilate_time`]. Occupancies are still computed over the chunk and its
+/// halo rather than the whole observation, so they depend on the chunk size.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FlagExtension {
+    /// Flag samples within this many timesteps of a flagged sample
+    pub dilate_time: usize,
+    /// Flag samples within this many fine channels of a flagged sample
+    pub dilate_freq: usize,
+    /// Flag a timestep if the fraction of flagged samples exceeds this
+    pub timestep_occupancy: Option<f32>,
+    /// Flag a fine channel if the fraction of flagged samples exceeds this
+    pub channel_occupancy: Option<f32>,
+    /// Flag a baseline if the fraction of flagged samples exceeds this
+    pub baseline_occupancy: Option<f32>,
+    /// Flag an antenna if the fraction of its (cross-correlation) baselines which are entirely
+    /// flagged exceeds this
+    pub antenna_occupancy: Option<f32>,
+}
+
+impl FlagExtension {
+    /// Whether no rules are enabled.
+    pub const fn is_noop(&self) -> bool {
+        self.dilate_time == 0
+            && self.dilate_freq == 0
+            && self.timestep_occupancy.is_none()
+            && self.channel_occupancy.is_none()
+            && self.baseline_occupancy.is_none()
+            && self.antenna_occupancy.is_none()
+    }
+}
+
+impl Display for FlagExtension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rules = vec![];
+        if self.dilate_time > 0 {
+            rules.push(format!("dilate time by {}", self.dilate_time));
+        }
+        if self.dilate_freq > 0 {
+            rules.push(format!("dilate frequency by {}", self.dilate_freq));
+        }
+        for (name, occupancy) in [
+            ("timesteps", self.timestep_occupancy),
+            ("channels", self.channel_occupancy),
+            ("baselines", self.baseline_occupancy),
+            ("antennas", self.antenna_occupancy),
+        ] {
+            if let Some(occupancy) = occupancy {
+                rules.push(format!("{} > {}% flagged", name, occupancy * 100.));
+            }
+        }
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+/// Set each flag along a lane if any flag within `radius` of it was set.
+fn dilate_lane(mut lane: ArrayViewMut1<bool>, radius: usize) {
+    let orig = lane.to_vec();
+    let len = orig.len();
+    for (idx, flag) in orig.iter().enumerate() {
+        if *flag {
+            lane.slice_mut(s![idx.saturating_sub(radius)..(idx + radius + 1).min(len)])
+                .fill(true);
+        }
+    }
+}
+
+/// Extend the flags in `flag_array` according to `rules`.
+///
+/// The rules are applied in the following order: dilation in time, dilation in frequency,
+/// baseline occupancy, antenna occupancy, timestep occupancy, then channel occupancy. Returns the
+/// indices of any antennas which were flagged by the antenna occupancy rule.
+///
+/// # Arguments
+///
+/// - `flag_array` - flags, [timestep][channel][baseline].
+/// - `ant_pairs` - the antenna indices of each baseline in the baseline axis.
+/// - `rules` - which rules to apply.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::flags::{extend_flags, FlagExtension};
+/// use birli::ndarray::Array3;
+///
+/// let ant_pairs = vec![(0, 1)];
+/// let mut flag_array = Array3::from_elem((5, 1, 1), false);
+/// flag_array[(2, 0, 0)] = true;
+///
+/// let rules = FlagExtension {
+///     dilate_time: 1,
+///     ..FlagExtension::default()
+/// };
+/// extend_flags(flag_array.view_mut(), &ant_pairs, &rules);
+///
+/// assert_eq!(
+///     flag_array.iter().copied().collect::<Vec<_>>(),
+///     vec![false, true, true, true, false]
+/// );
+/// ```
+pub fn extend_flags(
+    mut flag_array: ArrayViewMut3<bool>,
+    ant_pairs: &[(usize, usize)],
+    rules: &FlagExtension,
+) -> Vec<usize> {
+    trace!("start extend_flags");
+
+    // whether each slice along `axis` is entirely flagged.
+    let all_flagged = |flag_array: &ArrayViewMut3<bool>, axis: usize| -> Vec<bool> {
+        flag_array
+            .axis_iter(Axis(axis))
+            .map(|view| view.iter().all(|&f| f))
+            .collect()
+    };
+
+    if rules.dilate_time > 0 {
+        flag_array
+            .lanes_mut(Axis(0))
+            .into_iter()
+            .for_each(|lane| dilate_lane(lane, rules.dilate_time));
+    }
+    if rules.dilate_freq > 0 {
+        flag_array
+            .lanes_mut(Axis(1))
+            .into_iter()
+            .for_each(|lane| dilate_lane(lane, rules.dilate_freq));
+    }
+
+    // the fraction of flagged samples in each slice along `axis`, counting only the samples in
+    // timesteps, channels and baselines which are not entirely flagged.
+    let occupancies = |flag_array: &ArrayViewMut3<bool>, axis: usize| -> Vec<f32> {
+        let dead_timesteps = all_flagged(flag_array, 0);
+        let dead_chans = all_flagged(flag_array, 1);
+        let dead_baselines = all_flagged(flag_array, 2);
+        flag_array
+            .indexed_iter()
+            .filter(|&((ts, ch, bl), _)| {
+                !dead_timesteps[ts] && !dead_chans[ch] && !dead_baselines[bl]
+            })
+            .fold(
+                vec![(0_usize, 0_usize); flag_array.len_of(Axis(axis))],
+                |mut counts, ((ts, ch, bl), &flag)| {
+                    let idx = [ts, ch, bl][axis];
+                    counts[idx].0 += usize::from(flag);
+                    counts[idx].1 += 1;
+                    counts
+                },
+            )
+            .into_iter()
+            .map(|(flagged, total)| {
+                if total == 0 {
+                    0.
+                } else {
+                    flagged as f32 / total as f32
+                }
+            })
+            .collect()
+    };
+
+    if let Some(threshold) = rules.baseline_occupancy {
+        for (occupancy, mut flag_baseline_view) in izip!(
+            occupancies(&flag_array, 2),
+            flag_array.axis_iter_mut(Axis(2))
+        ) {
+            if occupancy > threshold {
+                flag_baseline_view.fill(true);
+            }
+        }
+    }
+
+    let mut flagged_ants = vec![];
+    if let Some(threshold) = rules.antenna_occupancy {
+        let baseline_flagged = all_flagged(&flag_array, 2);
+        let num_ants = ant_pairs
+            .iter()
+            .map(|&(ant1, ant2)| ant1.max(ant2) + 1)
+            .max()
+            .unwrap_or(0);
+        // for each antenna, the number of flagged and total cross-correlation baselines.
+        let mut counts = vec![(0_usize, 0_usize); num_ants];
+        for (&(ant1, ant2), &flagged) in izip!(ant_pairs.iter(), baseline_flagged.iter()) {
+            if ant1 == ant2 {
+                continue;
+            }
+            for ant in [ant1, ant2] {
+                counts[ant].0 += usize::from(flagged);
+                counts[ant].1 += 1;
+            }
+        }
+        flagged_ants = counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(flagged, total))| {
+                // ignore antennas which are already entirely flagged
+                total > 0 && flagged < total && flagged as f32 / total as f32 > threshold
+            })
+            .map(|(ant, _)| ant)
+            .collect();
+        for (&(ant1, ant2), mut flag_baseline_view) in
+            izip!(ant_pairs.iter(), flag_array.axis_iter_mut(Axis(2)))
+        {
+            if flagged_ants.contains(&ant1) || flagged_ants.contains(&ant2) {
+                flag_baseline_view.fill(true);
+            }
+        }
+    }
+
+    if let Some(threshold) = rules.timestep_occupancy {
+        for (occupancy, mut flag_timestep_view) in izip!(
+            occupancies(&flag_array, 0),
+            flag_array.axis_iter_mut(Axis(0))
+        ) {
+            if occupancy > threshold {
+                flag_timestep_view.fill(true);
+            }
+        }
+    }
+
+    if let Some(threshold) = rules.channel_occupancy {
+        for (occupancy, mut flag_chan_view) in izip!(
+            occupancies(&flag_array, 1),
+            flag_array.axis_iter_mut(Axis(1))
+        ) {
+            if occupancy > threshold {
+                flag_chan_view.fill(true);
+            }
+        }
+    }
+
+    trace!("end extend_flags");
+    flagged_ants
 }
 
 /// Create an aoflagger [`CxxImageSet`] for a particular baseline from the given jones array
@@ -557,11 +1271,20 @@ pub fn write_flags(
 #[cfg(test)]
 mod tests {
     use super::write_flags;
+    use crate::corrections::ScrunchType;
     use glob::glob;
     use tempfile::tempdir;
 
     use crate::{
+        flags::{
+            detect_bad_tiles, extend_flags, flag_jones_array_sanity, AmplitudeThreshold,
+            FlagContext, FlagExtension,
+        },
         marlu::selection::SelectionError::{NoCommonTimesteps, NoProvidedTimesteps},
+        marlu::{
+            ndarray::{s, Array3},
+            Complex, Jones,
+        },
         test_common::get_mwax_context,
         test_common::{
             get_mwa_ord_dodgy_context, get_mwa_ord_no_overlap_context,
@@ -603,6 +1326,239 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_flag_jones_array_sanity_nan_zero() {
+        let mut jones_array = Array3::from_elem((2, 3, 2), Jones::identity());
+        // a single NaN element
+        jones_array[(0, 0, 0)][1] = Complex::new(f32::NAN, 0.);
+        // a single infinite element
+        jones_array[(0, 1, 1)][3] = Complex::new(0., f32::INFINITY);
+        // all-zero Jones matrix
+        jones_array[(1, 2, 0)] = Jones::default();
+        // partially zero Jones matrices are fine
+        jones_array[(1, 2, 1)][0] = Complex::new(0., 0.);
+
+        let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+        // existing flags are preserved
+        flag_array[(1, 0, 1)] = true;
+
+        flag_jones_array_sanity(jones_array.view(), flag_array.view_mut(), None);
+
+        for ((ts, ch, bl), &flag) in flag_array.indexed_iter() {
+            let expected = matches!((ts, ch, bl), (0, 0, 0) | (0, 1, 1) | (1, 2, 0) | (1, 0, 1));
+            assert_eq!(flag, expected, "at ({ts}, {ch}, {bl})");
+        }
+    }
+
+    #[test]
+    fn test_flag_jones_array_sanity_absolute() {
+        let mut jones_array = Array3::from_elem((2, 2, 1), Jones::identity());
+        jones_array[(0, 1, 0)][2] = Complex::new(3., 4.);
+
+        let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+        flag_jones_array_sanity(
+            jones_array.view(),
+            flag_array.view_mut(),
+            Some(AmplitudeThreshold::Absolute(5.)),
+        );
+        assert!(!flag_array.iter().any(|&f| f));
+
+        flag_jones_array_sanity(
+            jones_array.view(),
+            flag_array.view_mut(),
+            Some(AmplitudeThreshold::Absolute(4.9)),
+        );
+        assert!(flag_array[(0, 1, 0)]);
+        assert_eq!(flag_array.iter().filter(|&&f| f).count(), 1);
+    }
+
+    #[test]
+    fn test_flag_jones_array_sanity_mad() {
+        // two baselines with different amplitude scales
+        let mut jones_array = Array3::from_shape_fn((4, 8, 2), |(ts, ch, bl)| {
+            let amp = (1 + bl * 99) as f32 * (1. + 0.01 * ((ts * 8 + ch) % 5) as f32);
+            Jones::identity() * amp
+        });
+        // an outlier which is only anomalous relative to its own baseline
+        jones_array[(2, 3, 0)] = Jones::identity() * 50.;
+        // an outlier which is already flagged, and should not affect the statistics
+        jones_array[(3, 3, 1)] = Jones::identity() * 1e9;
+
+        let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+        flag_array[(3, 3, 1)] = true;
+
+        flag_jones_array_sanity(
+            jones_array.view(),
+            flag_array.view_mut(),
+            Some(AmplitudeThreshold::Mad(10.)),
+        );
+
+        assert!(flag_array[(2, 3, 0)]);
+        assert!(flag_array[(3, 3, 1)]);
+        assert_eq!(flag_array.iter().filter(|&&f| f).count(), 2);
+    }
+
+    #[test]
+    fn test_flag_jones_array_sanity_mad_zero() {
+        // mostly constant, with a few larger samples, so the MAD is zero.
+        let mut jones_array = Array3::from_elem((2, 8, 1), Jones::identity());
+        jones_array[(0, 3, 0)] = Jones::identity() * 2.;
+        jones_array[(1, 5, 0)] = Jones::identity() * 3.;
+
+        let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+        flag_jones_array_sanity(
+            jones_array.view(),
+            flag_array.view_mut(),
+            Some(AmplitudeThreshold::Mad(10.)),
+        );
+        assert!(!flag_array.iter().any(|&f| f));
+
+        // non-finite and zero visibilities are still flagged
+        jones_array[(0, 0, 0)] = Jones::nan();
+        jones_array[(1, 0, 0)] = Jones::default();
+        flag_jones_array_sanity(
+            jones_array.view(),
+            flag_array.view_mut(),
+            Some(AmplitudeThreshold::Mad(10.)),
+        );
+        assert!(flag_array[(0, 0, 0)]);
+        assert!(flag_array[(1, 0, 0)]);
+        assert_eq!(flag_array.iter().filter(|&&f| f).count(), 2);
+    }
+
+    #[test]
+    fn test_detect_bad_tiles() {
+        let num_ants = 16;
+        let ant_pairs = (0..num_ants)
+            .flat_map(|ant1| (ant1..num_ants).map(move |ant2| (ant1, ant2)))
+            .collect::<Vec<_>>();
+        let mut jones_array = Array3::from_shape_fn((3, 16, ant_pairs.len()), |(_, ch, bl)| {
+            let (ant1, ant2) = ant_pairs[bl];
+            if ant1 != ant2 {
+                return Jones::identity();
+            }
+            // slightly different gains for each tile, with a common sloped spectrum
+            let gain = 1. + 0.02 * (ant1 % 5) as f32;
+            let (mut xx, mut yy) = (gain * (100. + ch as f32), gain * (100. + ch as f32));
+            match ant1 {
+                // hot tile
+                3 => xx *= 20.,
+                // x/y imbalance (total power within normal range)
+                5 => {
+                    xx *= 1.6;
+                    yy *= 0.4;
+                }
+                // oscillating spectrum
+                7 => {
+                    xx *= if ch % 2 == 0 { 1.5 } else { 0.5 };
+                    yy *= if ch % 2 == 0 { 1.5 } else { 0.5 };
+                }
+                _ => {}
+            };
+            Jones::from([
+                Complex::new(xx, 0.),
+                Complex::default(),
+                Complex::default(),
+                Complex::new(yy, 0.),
+            ])
+        });
+        let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+        // tile 9 is dead
+        let auto_9 = ant_pairs.iter().position(|&p| p == (9, 9)).unwrap();
+        jones_array
+            .slice_mut(s![.., .., auto_9])
+            .fill(Jones::default());
+        // tile 11 is already flagged
+        let auto_11 = ant_pairs.iter().position(|&p| p == (11, 11)).unwrap();
+        flag_array.slice_mut(s![.., .., auto_11]).fill(true);
+
+        let diagnostics = detect_bad_tiles(jones_array.view(), flag_array.view(), &ant_pairs, 5.);
+
+        assert_eq!(diagnostics.len(), num_ants);
+        let bad_ants = diagnostics
+            .iter()
+            .filter(|d| d.bad)
+            .map(|d| d.ant_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(bad_ants, vec![3, 5, 7, 9]);
+        assert!(diagnostics[3].power_dev > 5.);
+        assert!(diagnostics[5].xy_dev > 5.);
+        assert!(diagnostics[7].shape_dev > 5.);
+        assert!(diagnostics[11].no_data);
+        assert!(!diagnostics[11].bad);
+    }
+
+    #[test]
+    fn test_extend_flags_dilate() {
+        let ant_pairs = vec![(0, 1)];
+        let mut flag_array = Array3::from_elem((5, 6, 1), false);
+        flag_array[(2, 3, 0)] = true;
+
+        let rules = FlagExtension {
+            dilate_time: 1,
+            dilate_freq: 2,
+            ..FlagExtension::default()
+        };
+        extend_flags(flag_array.view_mut(), &ant_pairs, &rules);
+
+        for ((ts, ch, _), &flag) in flag_array.indexed_iter() {
+            assert_eq!(
+                flag,
+                (1..=3).contains(&ts) && ch >= 1,
+                "ts={} ch={}",
+                ts,
+                ch
+            );
+        }
+    }
+
+    #[test]
+    fn test_extend_flags_occupancy() {
+        let ant_pairs = vec![(0, 0), (0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+        let mut flag_array = Array3::from_elem((4, 4, ant_pairs.len()), false);
+        // baseline (0, 3) is already entirely flagged, and shouldn't affect occupancy
+        flag_array.slice_mut(s![.., .., 3]).fill(true);
+        // timestep 1 is half flagged on the remaining baselines
+        flag_array.slice_mut(s![1, 0..2, ..]).fill(true);
+        // baseline (0, 2) is mostly flagged
+        flag_array.slice_mut(s![0..3, .., 2]).fill(true);
+
+        let rules = FlagExtension {
+            timestep_occupancy: Some(0.4),
+            baseline_occupancy: Some(0.6),
+            antenna_occupancy: Some(0.5),
+            ..FlagExtension::default()
+        };
+        let flagged_ants = extend_flags(flag_array.view_mut(), &ant_pairs, &rules);
+
+        // antenna 0 has two of three cross baselines flagged
+        assert_eq!(flagged_ants, vec![0]);
+        for bl in 0..4 {
+            assert!(flag_array.slice(s![.., .., bl]).iter().all(|&f| f));
+        }
+        assert!(flag_array.slice(s![1, .., ..]).iter().all(|&f| f));
+        assert!(!flag_array.slice(s![0, .., 4..]).iter().any(|&f| f));
+        assert!(!flag_array.slice(s![2.., .., 4..]).iter().any(|&f| f));
+    }
+
+    #[test]
+    fn test_flag_low_gain_chans() {
+        let passband_gains = [0.2, 0.4, 1., 1., 1., 1., 0.4, 0.2];
+        let mut flag_ctx = FlagContext::blank_from_dimensions(1, 1, 4, 1);
+        flag_ctx.fine_chan_flags[1] = true;
+        let num_flagged = flag_ctx
+            .flag_low_gain_chans(&passband_gains, &ScrunchType::Simple, 0.5)
+            .unwrap();
+        assert_eq!(num_flagged, 2);
+        // existing flags are preserved
+        assert_eq!(flag_ctx.fine_chan_flags, vec![true, true, false, true]);
+
+        let mut flag_ctx = FlagContext::blank_from_dimensions(1, 1, 3, 1);
+        assert!(flag_ctx
+            .flag_low_gain_chans(&passband_gains, &ScrunchType::Simple, 0.5)
+            .is_err());
+    }
+
     #[test]
     fn test_write_flags_mwax_minimal() {
         let flag_timestep = 1;
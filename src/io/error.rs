@@ -46,6 +46,19 @@ pub enum IOError {
         source_line: u32,
     },
 
+    /// The HDU following a visibility HDU in an MWAX gpubox file is not its weights.
+    #[error("{fits_filename} HDU {hdu_num} is not an MWAX weights HDU. Expected {expected}, received {received}")]
+    MwaxWeightsHdu {
+        /// The filename of the gpubox file
+        fits_filename: String,
+        /// The hdu number of the expected weights HDU
+        hdu_num: usize,
+        /// What the weights HDU should have
+        expected: String,
+        /// What the HDU has
+        received: String,
+    },
+
     #[error(transparent)]
     /// Error derived from [`marlu::mwalib::FitsError`]
     FitsError(#[from] mwalib::FitsError),
@@ -62,6 +75,10 @@ pub enum IOError {
     /// Error derived from [`marlu::io::error::UvfitsWriteError`]
     UvfitsWriteError(#[from] marlu::io::error::UvfitsWriteError),
 
+    #[error(transparent)]
+    /// Error derived from [`marlu::io::error::MeasurementSetWriteError`]
+    MeasurementSetWriteError(#[from] marlu::io::error::MeasurementSetWriteError),
+
     /// Error describing the number of baseline flags that were written not
     /// maching the number expected.
     #[error("Attempted to finalise mwaf files with {count} rows, but {expected} were expected")]
@@ -7,21 +7,41 @@ pub mod error;
 pub mod mwaf;
 
 use std::{
+    collections::{hash_map::Entry, HashMap},
+    f64::consts::{PI, TAU},
     ops::Range,
     path::{Path, PathBuf},
 };
 
+use itertools::izip;
 use log::trace;
 
 use crate::{
     marlu::{
-        constants::MWA_LAT_RAD,
+        constants::{MWA_LAT_RAD, VEL_C},
+        fitsio::{
+            errors::{
+                check_status as fits_check_status, Error as FitsError, FitsError as FitsErrorDetail,
+            },
+            hdu::HduInfo,
+            images::ImageType,
+            FitsFile,
+        },
+        fitsio_sys,
         hifitime::{Duration, Unit},
-        io::{ms::MeasurementSetWriter, uvfits::UvfitsWriter, VisWrite},
-        mwalib::{CorrelatorContext, MwalibError},
-        Jones, LatLngHeight, MwaObsContext, ObsContext, RADec, VisContext, ENH,
+        io::{
+            error::{BadArrayShape, MeasurementSetWriteError},
+            ms::MeasurementSetWriter,
+            uvfits::UvfitsWriter,
+            VisWrite,
+        },
+        mwalib::{CorrelatorContext, MWAVersion, MetafitsContext, MwalibError},
+        precession::precess_time,
+        rubbl_casatables::{Table, TableOpenMode},
+        Jones, LatLngHeight, MwaObsContext, ObsContext, RADec, VisContext, VisSelection,
+        XyzGeodetic, ENH, UVW,
     },
-    ndarray::{ArrayView3, ArrayViewMut3},
+    ndarray::{array, ArrayView3, ArrayViewMut3, Axis},
 };
 
 use self::error::IOError;
@@ -44,6 +64,22 @@ pub struct IOContext {
     pub ms_out: Option<PathBuf>,
     /// Optional .mwaf flag file path template (see `io::mwaf::FlagFileSet`)
     pub flag_template: Option<String>,
+    /// Optional text or FITS path for passband gains estimated once, from the uncorrected
+    /// visibilities of the first chunk (see `passband_gains::write_passband_gains`)
+    pub passband_gains_out: Option<PathBuf>,
+    /// Additional outputs of the same visibilities, each phased to a different centre
+    pub phased_outs: Vec<PhasedOutput>,
+}
+
+/// An output of the visibilities phased to a centre other than the main phase centre.
+#[derive(Debug, Clone)]
+pub struct PhasedOutput {
+    /// The phase centre of the output
+    pub phase_centre: RADec,
+    /// Optional .uvfits output path
+    pub uvfits_out: Option<PathBuf>,
+    /// Optional .ms measurement set output path
+    pub ms_out: Option<PathBuf>,
 }
 
 impl IOContext {
@@ -82,6 +118,264 @@ pub trait ReadableVis: Sync + Send {
     ) -> Result<(), IOError>;
 }
 
+/// Read the visibility weights of MWAX gpubox files into `weight_array`, and flag any visibilities
+/// with a zero weight in `flag_array`.
+///
+/// MWAX gpubox files have a weights HDU following each visibility HDU, with a weight for each
+/// baseline and polarisation giving the fraction of the data which was actually correlated, e.g.
+/// if packets were dropped. Since there is only one weight per visibility, the smallest weight of
+/// all polarisations is used. Weights are scaled by `weight_factor` (see
+/// [`crate::flags::get_weight_factor`]).
+///
+/// This does nothing for legacy correlator observations, which have no weights, and timesteps and
+/// coarse channels without data are left alone, since these are flagged by
+/// [`VisSelection::read_mwalib`]. Each gpubox file is only opened once.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::{get_weight_factor, io::read_mwax_weights, mwalib::CorrelatorContext, VisSelection};
+///
+/// let metafits_path = "tests/data/1297526432_mwax/1297526432.metafits";
+/// let gpufits_paths = vec![
+///     "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits",
+///     "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_001.fits",
+/// ];
+/// let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+/// let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+///
+/// let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+/// let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+/// let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+///
+/// let weight_factor = get_weight_factor(&corr_ctx);
+/// read_mwax_weights(
+///     &corr_ctx,
+///     &vis_sel,
+///     weight_array.view_mut(),
+///     flag_array.view_mut(),
+///     weight_factor,
+/// )
+/// .unwrap();
+///
+/// // all of the data in this observation was correlated.
+/// assert!(weight_array.iter().all(|&w| w == weight_factor as f32));
+/// ```
+///
+/// # Errors
+///
+/// Will return an [`IOError`] if the arrays are not the shape of `vis_sel`, if there is an issue
+/// reading the weights, or [`IOError::MwaxWeightsHdu`] if the HDU after a visibility HDU is not
+/// the weights of the same timestep.
+pub fn read_mwax_weights(
+    corr_ctx: &CorrelatorContext,
+    vis_sel: &VisSelection,
+    mut weight_array: ArrayViewMut3<f32>,
+    mut flag_array: ArrayViewMut3<bool>,
+    weight_factor: f64,
+) -> Result<(), IOError> {
+    if corr_ctx.mwa_version != MWAVersion::CorrMWAXv2 {
+        return Ok(());
+    }
+    trace!("start read_mwax_weights");
+
+    let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+    let num_pols = corr_ctx.metafits_context.num_visibility_pols;
+    let shape = vis_sel.get_shape(fine_chans_per_coarse);
+    for (argument, dim) in [
+        ("weight_array", weight_array.dim()),
+        ("flag_array", flag_array.dim()),
+    ] {
+        if dim != shape {
+            return Err(IOError::BadArrayShape(BadArrayShape {
+                argument,
+                function: "read_mwax_weights",
+                expected: format!("{:?}", shape),
+                received: format!("{:?}", dim),
+            }));
+        }
+    }
+
+    // each gpubox file is only opened once.
+    let mut fptrs: HashMap<&str, FitsFile> = HashMap::new();
+    for (timestep_idx, mut weight_timestep_view, mut flag_timestep_view) in izip!(
+        vis_sel.timestep_range.clone(),
+        weight_array.outer_iter_mut(),
+        flag_array.outer_iter_mut(),
+    ) {
+        let unix_time_ms = corr_ctx.timesteps[timestep_idx].unix_time_ms;
+        for (coarse_chan_idx, mut weight_coarse_chan_view, mut flag_coarse_chan_view) in izip!(
+            vis_sel.coarse_chan_range.clone(),
+            weight_timestep_view.axis_chunks_iter_mut(Axis(0), fine_chans_per_coarse),
+            flag_timestep_view.axis_chunks_iter_mut(Axis(0), fine_chans_per_coarse),
+        ) {
+            let gpubox_number = corr_ctx.coarse_chans[coarse_chan_idx].gpubox_number;
+            let (batch_idx, hdu_idx) = match corr_ctx
+                .gpubox_time_map
+                .get(&unix_time_ms)
+                .and_then(|chan_map| chan_map.get(&gpubox_number))
+            {
+                Some(&indices) => indices,
+                None => continue,
+            };
+            let gpubox = match corr_ctx.gpubox_batches[batch_idx]
+                .gpubox_files
+                .iter()
+                .find(|gpubox| gpubox.channel_identifier == gpubox_number)
+            {
+                Some(gpubox) => gpubox,
+                None => continue,
+            };
+
+            let fptr = match fptrs.entry(&gpubox.filename) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(FitsFile::open(&gpubox.filename).map_err(|fits_error| {
+                        IOError::FitsOpen {
+                            fits_error,
+                            fits_filename: gpubox.filename.clone(),
+                            source_file: file!(),
+                            source_line: line!(),
+                        }
+                    })?)
+                }
+            };
+            // the weights HDU follows the visibility HDU.
+            let weights_hdu_idx = hdu_idx + 1;
+            let weights = read_mwax_weights_hdu(
+                fptr,
+                &gpubox.filename,
+                weights_hdu_idx,
+                unix_time_ms,
+                corr_ctx.metafits_context.num_baselines,
+                num_pols,
+            )?;
+
+            for (&baseline_idx, mut weight_baseline_view, mut flag_baseline_view) in izip!(
+                vis_sel.baseline_idxs.iter(),
+                weight_coarse_chan_view.axis_iter_mut(Axis(1)),
+                flag_coarse_chan_view.axis_iter_mut(Axis(1)),
+            ) {
+                let baseline_weights = &weights[baseline_idx * num_pols..][..num_pols];
+                let weight = baseline_weights
+                    .iter()
+                    .copied()
+                    .fold(f32::INFINITY, f32::min);
+                weight_baseline_view.fill(weight * weight_factor as f32);
+                if weight <= 0. {
+                    flag_baseline_view.fill(true);
+                }
+            }
+        }
+    }
+
+    trace!("end read_mwax_weights");
+    Ok(())
+}
+
+/// Read the weights HDU `hdu_num` of the MWAX gpubox file `fptr`, checking that it is a float
+/// image of `[baseline][pol]` weights for the timestep starting at `unix_time_ms`.
+///
+/// MWAX gpubox HDUs don't have an `EXTNAME`, so the `TIME` and `MILLITIM` keys are checked
+/// instead, which both the visibility and weights HDUs of a timestep have.
+fn read_mwax_weights_hdu(
+    fptr: &mut FitsFile,
+    fits_filename: &str,
+    hdu_num: usize,
+    unix_time_ms: u64,
+    num_baselines: usize,
+    num_pols: usize,
+) -> Result<Vec<f32>, IOError> {
+    let to_error = |fits_error| IOError::FitsIO {
+        fits_error,
+        fits_filename: fits_filename.to_string(),
+        hdu_num,
+        source_file: file!(),
+        source_line: line!(),
+    };
+    let bad_hdu = |expected: String, received: String| IOError::MwaxWeightsHdu {
+        fits_filename: fits_filename.to_string(),
+        hdu_num,
+        expected,
+        received,
+    };
+    let hdu = fptr.hdu(hdu_num).map_err(to_error)?;
+    match &hdu.info {
+        HduInfo::ImageInfo {
+            shape,
+            image_type: ImageType::Float,
+        } if shape[..] == [num_baselines, num_pols] => {}
+        info => {
+            return Err(bad_hdu(
+                format!("a float image of shape {:?}", [num_baselines, num_pols]),
+                format!("{:?}", info),
+            ))
+        }
+    }
+    let time: Option<i64> = hdu.read_key(fptr, "TIME").ok();
+    let millitim: Option<i64> = hdu.read_key(fptr, "MILLITIM").ok();
+    match time.zip(millitim) {
+        Some((time, millitim)) if (time * 1000 + millitim) as u64 == unix_time_ms => {}
+        _ => {
+            return Err(bad_hdu(
+                format!("TIME and MILLITIM of {} ms", unix_time_ms),
+                format!("TIME={:?} MILLITIM={:?}", time, millitim),
+            ))
+        }
+    }
+    hdu.read_image(fptr).map_err(to_error)
+}
+
+/// Determine whether the coarse channels of an observation were produced by an oversampling
+/// coarse PFB, from the `OVERSAMP` key in the primary HDU of the metafits. Observations without
+/// this key were critically sampled.
+///
+/// Oversampled coarse channels are sampled at a higher rate than the coarse channel width, and
+/// only the central region is retained, so unlike critically sampled coarse channels, their
+/// passband is flat, and their edges are not affected by aliasing.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::{io::read_oversampled, mwalib::MetafitsContext};
+///
+/// let metafits_path = "tests/data/1297526432_mwax/1297526432.metafits";
+/// let meta_ctx = MetafitsContext::new(metafits_path, None).unwrap();
+///
+/// assert!(!read_oversampled(&meta_ctx).unwrap());
+/// ```
+///
+/// # Errors
+///
+/// Will return an [`IOError`] if the metafits can't be read, or the key can't be parsed.
+pub fn read_oversampled(meta_ctx: &MetafitsContext) -> Result<bool, IOError> {
+    let fits_filename = &meta_ctx.metafits_filename;
+    let mut fptr = FitsFile::open(fits_filename).map_err(|fits_error| IOError::FitsOpen {
+        fits_error,
+        fits_filename: fits_filename.clone(),
+        source_file: file!(),
+        source_line: line!(),
+    })?;
+    let to_error = |fits_error| IOError::FitsIO {
+        fits_error,
+        fits_filename: fits_filename.clone(),
+        hdu_num: 0,
+        source_file: file!(),
+        source_line: line!(),
+    };
+    let hdu = fptr.primary_hdu().map_err(to_error)?;
+    match hdu.read_key::<i64>(&mut fptr, "OVERSAMP") {
+        Ok(oversampled) => Ok(oversampled != 0),
+        // observations from before the key was introduced were critically sampled.
+        Err(FitsError::Fits(FitsErrorDetail { status, .. }))
+            if status == fitsio_sys::KEY_NO_EXIST as i32 =>
+        {
+            Ok(false)
+        }
+        Err(fits_error) => Err(to_error(fits_error)),
+    }
+}
+
 /// Write the given ndarrays of flags and [`Jones`] matrix visibilities to a
 /// uvfits file.
 ///
@@ -369,6 +663,187 @@ pub fn write_ms<T: AsRef<Path>>(
     Ok(())
 }
 
+/// The UVWs of each selected baseline in each (averaged) timestep of `vis_ctx`, phased to the
+/// corresponding phase centre in `phase_centres`, in the same way as marlu's writers.
+pub(crate) fn calc_rephased_uvws<'a>(
+    vis_ctx: &'a VisContext,
+    array_pos: LatLngHeight,
+    antenna_positions: &'a [XyzGeodetic],
+    dut1: Duration,
+    phase_centres: &'a [RADec],
+) -> impl Iterator<Item = UVW> + 'a {
+    vis_ctx
+        .timeseries(true, true)
+        .zip(phase_centres)
+        .flat_map(move |(epoch, &phase_centre)| {
+            let prec_info = precess_time(
+                array_pos.longitude_rad,
+                array_pos.latitude_rad,
+                phase_centre,
+                epoch,
+                dut1,
+            );
+            let tiles_xyz_precessed = prec_info.precess_xyz_parallel(antenna_positions);
+            vis_ctx
+                .sel_baselines
+                .iter()
+                .map(move |&(ant1_idx, ant2_idx)| {
+                    UVW::from_xyz(
+                        tiles_xyz_precessed[ant1_idx] - tiles_xyz_precessed[ant2_idx],
+                        prec_info.hadec_j2000,
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+}
+
+/// Check that there is a phase centre for each (averaged) timestep of `vis_ctx`.
+fn check_phase_centres(
+    vis_ctx: &VisContext,
+    phase_centres: &[RADec],
+    function: &'static str,
+) -> Result<(), IOError> {
+    if phase_centres.len() != vis_ctx.num_avg_timesteps() {
+        return Err(IOError::BadArrayShape(BadArrayShape {
+            argument: "phase_centres",
+            function,
+            expected: format!("{}", vis_ctx.num_avg_timesteps()),
+            received: format!("{}", phase_centres.len()),
+        }));
+    }
+    Ok(())
+}
+
+/// Rewrite the UVWs of a finalised uvfits file written by [`UvfitsWriter`] for a phase centre
+/// which changes with time, e.g. for a drift scan, given the phase centre of each (averaged)
+/// timestep of `vis_ctx`. uvfits only has one phase centre, so the header is left as-is.
+///
+/// `array_pos`, `antenna_positions` and `dut1` should be the same as those given to the writer.
+///
+/// # Errors
+///
+/// Will return an [`IOError`] if there isn't a phase centre for each timestep, or the file can't
+/// be written.
+pub fn rewrite_uvfits_uvws<T: AsRef<Path>>(
+    path: T,
+    vis_ctx: &VisContext,
+    array_pos: LatLngHeight,
+    antenna_positions: &[XyzGeodetic],
+    dut1: Duration,
+    phase_centres: &[RADec],
+) -> Result<(), IOError> {
+    trace!("start rewrite_uvfits_uvws to {:?}", path.as_ref());
+    check_phase_centres(vis_ctx, phase_centres, "rewrite_uvfits_uvws")?;
+
+    let mut fptr = FitsFile::edit(path)?;
+    for (row_idx, uvw) in
+        calc_rephased_uvws(vis_ctx, array_pos, antenna_positions, dut1, phase_centres).enumerate()
+    {
+        let mut uvw_s = [
+            (uvw.u / VEL_C) as f32,
+            (uvw.v / VEL_C) as f32,
+            (uvw.w / VEL_C) as f32,
+        ];
+        let mut status = 0;
+        unsafe {
+            // ffpgpe = fits_write_grppar_flt
+            fitsio_sys::ffpgpe(
+                fptr.as_raw(),      /* I - FITS file pointer                      */
+                1 + row_idx as i64, /* I - group to write(1 = 1st group)          */
+                1,                  /* I - first vector element to write(1 = 1st) */
+                3,                  /* I - number of values to write              */
+                uvw_s.as_mut_ptr(), /* I - array of values that are written       */
+                &mut status,        /* IO - error status                          */
+            );
+        }
+        fits_check_status(status)?;
+    }
+
+    trace!("end rewrite_uvfits_uvws");
+    Ok(())
+}
+
+/// Rewrite the UVWs of a measurement set written by [`MeasurementSetWriter`] for a phase centre
+/// which changes with time, like [`rewrite_uvfits_uvws`]. The phase centre of the `FIELD` table
+/// becomes a polynomial in time, which is linear between the first and last phase centres.
+///
+/// # Errors
+///
+/// Will return an [`IOError`] if there isn't a phase centre for each timestep, or the tables
+/// can't be written.
+pub fn rewrite_ms_uvws<T: AsRef<Path>>(
+    path: T,
+    vis_ctx: &VisContext,
+    array_pos: LatLngHeight,
+    antenna_positions: &[XyzGeodetic],
+    dut1: Duration,
+    phase_centres: &[RADec],
+) -> Result<(), IOError> {
+    trace!("start rewrite_ms_uvws to {:?}", path.as_ref());
+    check_phase_centres(vis_ctx, phase_centres, "rewrite_ms_uvws")?;
+    _rewrite_ms_uvws(
+        path.as_ref(),
+        vis_ctx,
+        array_pos,
+        antenna_positions,
+        dut1,
+        phase_centres,
+    )?;
+    trace!("end rewrite_ms_uvws");
+    Ok(())
+}
+
+fn _rewrite_ms_uvws(
+    path: &Path,
+    vis_ctx: &VisContext,
+    array_pos: LatLngHeight,
+    antenna_positions: &[XyzGeodetic],
+    dut1: Duration,
+    phase_centres: &[RADec],
+) -> Result<(), MeasurementSetWriteError> {
+    let mut main_table = Table::open(path, TableOpenMode::ReadWrite)?;
+    let num_rows = vis_ctx.num_avg_timesteps() * vis_ctx.sel_baselines.len();
+    if main_table.n_rows() != num_rows as u64 {
+        return Err(MeasurementSetWriteError::BadArrayShape(BadArrayShape {
+            argument: "path",
+            function: "rewrite_ms_uvws",
+            expected: format!("a main table with {} rows", num_rows),
+            received: format!("{}", main_table.n_rows()),
+        }));
+    }
+    for (row_idx, uvw) in
+        calc_rephased_uvws(vis_ctx, array_pos, antenna_positions, dut1, phase_centres).enumerate()
+    {
+        main_table.put_cell("UVW", row_idx as u64, &vec![uvw.u, uvw.v, uvw.w])?;
+    }
+
+    // the direction at the time of the field, and its rate of change per second.
+    let mut field_table = Table::open(path.join("FIELD"), TableOpenMode::ReadWrite)?;
+    let field_time_s: f64 = field_table.get_cell("TIME", 0)?;
+    let epochs = vis_ctx.timeseries(true, true).collect::<Vec<_>>();
+    let (first, last) = (phase_centres[0], phase_centres[phase_centres.len() - 1]);
+    let duration_s = (epochs[epochs.len() - 1] - epochs[0]).in_seconds();
+    let dir_info = if duration_s > 0. {
+        let ra_rate = ((last.ra - first.ra + PI).rem_euclid(TAU) - PI) / duration_s;
+        let dec_rate = (last.dec - first.dec) / duration_s;
+        let offset_s = field_time_s - epochs[0].as_mjd_utc_seconds();
+        array![
+            [
+                (first.ra + ra_rate * offset_s).rem_euclid(TAU),
+                first.dec + dec_rate * offset_s
+            ],
+            [ra_rate, dec_rate],
+        ]
+    } else {
+        array![[first.ra, first.dec]]
+    };
+    field_table.put_cell("NUM_POLY", 0, &(dir_info.dim().0 as i32 - 1))?;
+    for col_name in ["DELAY_DIR", "PHASE_DIR", "REFERENCE_DIR"] {
+        field_table.put_cell(col_name, 0, &dir_info)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 #[cfg(feature = "aoflagger")]
 /// Tests which require the use of the aoflagger feature
@@ -609,3 +1084,140 @@ mod tests_aoflagger {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, fs, ptr};
+
+    use marlu::{
+        fitsio::FitsFile,
+        fitsio_sys,
+        mwalib::{CorrelatorContext, MetafitsContext},
+    };
+    use tempfile::tempdir;
+
+    use super::{read_mwax_weights, read_oversampled, IOError};
+    use crate::{flags::get_weight_factor, test_common::get_mwax_data_paths, VisSelection};
+
+    #[test]
+    fn test_read_oversampled() {
+        let (metafits_path, _) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let tmp_metafits_path = tmp_dir.path().join("1297526432.metafits");
+        fs::copy(metafits_path, &tmp_metafits_path).unwrap();
+
+        let meta_ctx = MetafitsContext::new(&tmp_metafits_path, None).unwrap();
+        assert!(!read_oversampled(&meta_ctx).unwrap());
+
+        {
+            let mut fptr = FitsFile::edit(&tmp_metafits_path).unwrap();
+            let hdu = fptr.primary_hdu().unwrap();
+            hdu.write_key(&mut fptr, "OVERSAMP", 1).unwrap();
+        }
+        let meta_ctx = MetafitsContext::new(&tmp_metafits_path, None).unwrap();
+        assert!(read_oversampled(&meta_ctx).unwrap());
+    }
+
+    #[test]
+    fn test_read_mwax_weights_partial() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let gpufits_paths: Vec<_> = gpufits_paths
+            .iter()
+            .map(|path| {
+                let tmp_path = tmp_dir.path().join(path.rsplit('/').next().unwrap());
+                fs::copy(path, &tmp_path).unwrap();
+                tmp_path
+            })
+            .collect();
+
+        // the weights of the first timestep of the first coarse channel. Baseline 1 is half
+        // correlated, baseline 2 has no XY data.
+        {
+            let mut fptr = FitsFile::edit(&gpufits_paths[0]).unwrap();
+            let hdu = fptr.hdu(2).unwrap();
+            let weights: Vec<f32> = vec![1., 1., 1., 1., 0.5, 0.5, 0.5, 0.5, 1., 0., 1., 1.];
+            hdu.write_image(&mut fptr, &weights).unwrap();
+        }
+
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+        let weight_factor = get_weight_factor(&corr_ctx) as f32;
+
+        read_mwax_weights(
+            &corr_ctx,
+            &vis_sel,
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            weight_factor as _,
+        )
+        .unwrap();
+
+        let first_chans = 0..fine_chans_per_coarse;
+        for ((ts, ch, bl), &weight) in weight_array.indexed_iter() {
+            let flag = flag_array[(ts, ch, bl)];
+            if ts == 0 && first_chans.contains(&ch) && bl == 1 {
+                assert_eq!(weight, 0.5 * weight_factor);
+                assert!(!flag);
+            } else if ts == 0 && first_chans.contains(&ch) && bl == 2 {
+                assert_eq!(weight, 0.);
+                assert!(flag);
+            } else {
+                assert_eq!(weight, weight_factor, "at {:?}", (ts, ch, bl));
+                assert!(!flag);
+            }
+        }
+    }
+    #[test]
+    fn test_read_mwax_weights_bad_hdu() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let gpufits_paths: Vec<_> = gpufits_paths
+            .iter()
+            .map(|path| {
+                let tmp_path = tmp_dir.path().join(path.rsplit('/').next().unwrap());
+                fs::copy(path, &tmp_path).unwrap();
+                tmp_path
+            })
+            .collect();
+
+        // a weights HDU from a different timestep
+        {
+            let mut fptr = FitsFile::edit(&gpufits_paths[0]).unwrap();
+            let hdu = fptr.hdu(2).unwrap();
+            let time: i64 = hdu.read_key(&mut fptr, "TIME").unwrap();
+            // `write_key` appends a duplicate key, so update it in place.
+            let keyname = CString::new("TIME").unwrap();
+            let mut status = 0;
+            unsafe {
+                fitsio_sys::ffukyj(
+                    fptr.as_raw(),
+                    keyname.as_ptr(),
+                    time + 1,
+                    ptr::null(),
+                    &mut status,
+                );
+            }
+            assert_eq!(status, 0);
+        }
+
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+        assert!(matches!(
+            read_mwax_weights(
+                &corr_ctx,
+                &vis_sel,
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                1.,
+            ),
+            Err(IOError::MwaxWeightsHdu { hdu_num: 2, .. })
+        ));
+    }
+}
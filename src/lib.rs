@@ -169,14 +169,19 @@ use log::warn;
 
 pub mod io;
 pub use io::{mwaf::FlagFileSet, write_ms, write_uvfits};
+pub mod cables;
 pub mod corrections;
 pub use corrections::{correct_cable_lengths, correct_geometry, ScrunchType};
 pub mod calibration;
+pub mod ephemeris;
 pub mod flags;
 #[cfg(test)]
 pub use approx;
 pub use flags::{flag_to_weight_array, get_weight_factor, write_flags, FlagContext};
 pub mod passband_gains;
+pub mod pfb;
+pub mod strategies;
+pub mod tiles;
 pub use marlu;
 pub use marlu::{
     mwalib,
@@ -191,10 +196,12 @@ mod error;
 pub use error::BirliError;
 
 pub mod preprocessing;
-pub use preprocessing::PreprocessContext;
+pub use preprocessing::{Correction, PreprocessContext};
+
+pub mod pipeline;
+pub use pipeline::{BirliContext, BirliContextBuilder};
 
 pub mod cli;
-pub use cli::BirliContext;
 
 cfg_if! {
     if #[cfg(feature = "aoflagger")] {
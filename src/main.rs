@@ -2,6 +2,47 @@ use birli::{cli::BirliContext, BirliError};
 use log::{info, trace};
 use std::{env, ffi::OsString, fmt::Debug, time::Duration};
 
+/// Exit code for invalid command line arguments.
+const EXIT_INVALID_ARGS: i32 = 2;
+/// Exit code when the inputs can't be read.
+const EXIT_INPUT: i32 = 3;
+/// Exit code when calibration solutions can't be read or applied.
+const EXIT_CALIBRATION: i32 = 4;
+/// Exit code when the visibilities can't be preprocessed.
+const EXIT_PREPROCESSING: i32 = 5;
+/// Exit code when an output can't be written.
+const EXIT_OUTPUT: i32 = 6;
+/// Exit code when there isn't enough memory.
+const EXIT_MEMORY: i32 = 7;
+
+/// The process exit code for each category of error.
+const fn exit_code(err: &BirliError) -> i32 {
+    match err {
+        BirliError::DryRun {} => 0,
+        BirliError::ClapError(_)
+        | BirliError::CLIError(_)
+        | BirliError::PipelineError(_)
+        | BirliError::StepOrderError(_) => EXIT_INVALID_ARGS,
+        BirliError::IOError(_)
+        | BirliError::MwalibError(_)
+        | BirliError::SelectionError(_)
+        | BirliError::EphemerisError(_)
+        | BirliError::StrategyError(_)
+        | BirliError::CableError(_)
+        | BirliError::TileOverrideError(_)
+        | BirliError::PassbandGainsError(_)
+        | BirliError::BadMWAVersion { .. } => EXIT_INPUT,
+        BirliError::CalibrationError(_) => EXIT_CALIBRATION,
+        BirliError::PassbandCorrection(_)
+        | BirliError::DigitalGainCorrection(_)
+        | BirliError::VanVleckCorrection(_)
+        | BirliError::Correction(_)
+        | BirliError::BadArrayShape(_) => EXIT_PREPROCESSING,
+        BirliError::WriteError { .. } => EXIT_OUTPUT,
+        BirliError::InsufficientMemory { .. } => EXIT_MEMORY,
+    }
+}
+
 #[allow(clippy::field_reassign_with_default)]
 fn main_with_args<I, T>(args: I) -> i32
 where
@@ -18,7 +59,7 @@ where
         Err(BirliError::ClapError(inner)) => inner.exit(),
         Err(e) => {
             eprintln!("error parsing args: {:?}", e);
-            return 1;
+            return exit_code(&e);
         }
     };
     match birli_ctx.run() {
@@ -36,7 +77,7 @@ where
         }
         Err(e) => {
             eprintln!("preprocessing error: {}", e);
-            1
+            exit_code(&e)
         }
     }
 }
@@ -57,7 +98,9 @@ mod tests {
 
     use tempfile::tempdir;
 
-    use super::main_with_args;
+    use birli::{calibration::CalibrationError, io::error::IOError, BirliError};
+
+    use super::{exit_code, main_with_args, EXIT_CALIBRATION, EXIT_INVALID_ARGS, EXIT_OUTPUT};
 
     #[test]
     fn main_with_version_doesnt_crash() {
@@ -92,6 +135,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn main_with_unwritable_output_returns_exit_output() {
+        #[rustfmt::skip]
+        assert_eq!(
+            main_with_args(&[
+                "birli",
+                "-m", "tests/data/1297526432_mwax/1297526432.metafits",
+                "--no-draw-progress",
+                "-u", "/nonexistent/1297526432.uvfits",
+                "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits",
+            ]),
+            EXIT_OUTPUT
+        );
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let invalid_args = BirliError::StepOrderError(
+            "cable,cable"
+                .parse::<birli::preprocessing::StepOrder>()
+                .unwrap_err(),
+        );
+        let calibration =
+            BirliError::CalibrationError(CalibrationError::TimeblockCount { num_timeblocks: 2 });
+        let output = BirliError::WriteError {
+            output: "a.uvfits".into(),
+            source: Box::new(IOError::IO(std::io::ErrorKind::NotFound.into())),
+        };
+        assert_eq!(exit_code(&invalid_args), EXIT_INVALID_ARGS);
+        assert_eq!(exit_code(&calibration), EXIT_CALIBRATION);
+        assert_eq!(exit_code(&output), EXIT_OUTPUT);
+        assert_eq!(exit_code(&BirliError::DryRun {}), 0);
+    }
+
     #[test]
     fn main_succesful_writes_uvfits() {
         let tmp_dir = tempdir().unwrap();
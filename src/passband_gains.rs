@@ -1,4 +1,324 @@
 //! Possible choices for polyphase filter bank gains for the MWA.
+//!
+//! As well as the built-in gains, ultrafine gains can be read from a file with
+//! [`read_passband_gains`], or estimated from the data with [`estimate_passband_gains`].
+
+use std::{fs, path::Path};
+
+use marlu::{
+    fitsio::{self, images::ImageDescription, images::ImageType, FitsFile},
+    io::error::BadArrayShape,
+    rayon::prelude::*,
+};
+use thiserror::Error;
+
+use crate::{
+    flags::median_mut,
+    ndarray::{ArrayView3, Axis},
+    Jones,
+};
+
+/// Errors when reading, writing or estimating passband gains.
+#[derive(Error, Debug)]
+pub enum PassbandGainsError {
+    #[error("Could not read passband gains file {path}: {source}")]
+    /// The passband gains file could not be read
+    Unreadable {
+        /// The path to the passband gains file
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error("{path}:{line_num}: expected <GAIN> or <INDEX> <GAIN>..., found {line:?}")]
+    /// A line of a text passband gains file could not be parsed
+    BadLine {
+        /// The path to the passband gains file
+        path: String,
+        /// The line number (starting from 1)
+        line_num: usize,
+        /// The contents of the line
+        line: String,
+    },
+
+    #[error("Could not read passband gains from FITS file {path}: {source}")]
+    /// The primary HDU of a FITS passband gains file could not be read
+    Fits {
+        /// The path to the passband gains file
+        path: String,
+        /// The underlying fitsio error
+        source: fitsio::errors::Error,
+    },
+
+    #[error("Passband gains file {path} contains no gains")]
+    /// The passband gains file is empty
+    Empty {
+        /// The path to the passband gains file
+        path: String,
+    },
+
+    #[error("Passband gains file {path} has a gain of {gain} at index {index}, gains must be finite and positive")]
+    /// The passband gains file contains a gain which can't be divided out of the data
+    BadGain {
+        /// The path to the passband gains file
+        path: String,
+        /// The index of the gain
+        index: usize,
+        /// The gain
+        gain: f64,
+    },
+
+    #[error("Could not write passband gains file {path}: {source}")]
+    /// The passband gains file could not be written
+    Unwritable {
+        /// The path to the passband gains file
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    /// The arrays to estimate passband gains from have an unexpected shape
+    BadArrayShape(#[from] BadArrayShape),
+}
+
+/// Read the gains of each ultrafine channel in a coarse channel from a file, at the file's
+/// native resolution. The resolution of the gains is the coarse channel width divided by the
+/// number of gains.
+///
+/// Files ending in `.fits` or `.fit` are read from a one dimensional image in the primary HDU.
+/// Otherwise, the file is read as text, with either a `<GAIN>` on each line, or an `<INDEX>`
+/// followed by a gain for each polarisation, like Cotter's `-sbpassband` files. PFB gains are the
+/// same for all polarisations, so only the first gain on each line is used. Blank lines and lines
+/// starting with `#` are ignored.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::passband_gains::read_passband_gains;
+///
+/// let gains = read_passband_gains("tests/data/subband-passband-32ch-cotter.txt").unwrap();
+/// assert_eq!(gains.len(), 32);
+/// assert!((gains[0] - 0.50828905083).abs() < 1e-12);
+/// ```
+///
+/// # Errors
+///
+/// Will return a [`PassbandGainsError`] if the file can't be read or parsed, contains no gains, or
+/// contains a gain which is not finite and positive.
+pub fn read_passband_gains<T: AsRef<Path>>(path: T) -> Result<Vec<f64>, PassbandGainsError> {
+    let path_str = path.as_ref().display().to_string();
+    let is_fits = path
+        .as_ref()
+        .extension()
+        .map_or(false, |ext| ext == "fits" || ext == "fit");
+    let gains = if is_fits {
+        let to_error = |source| PassbandGainsError::Fits {
+            path: path_str.clone(),
+            source,
+        };
+        let mut fptr = FitsFile::open(&path).map_err(to_error)?;
+        let hdu = fptr.primary_hdu().map_err(to_error)?;
+        hdu.read_image(&mut fptr).map_err(to_error)?
+    } else {
+        let contents =
+            fs::read_to_string(&path).map_err(|source| PassbandGainsError::Unreadable {
+                path: path_str.clone(),
+                source,
+            })?;
+        let mut gains = vec![];
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let gain = match fields[..] {
+                [gain] | [_, gain, ..] => gain.parse::<f64>().ok(),
+                _ => None,
+            };
+            match gain {
+                Some(gain) => gains.push(gain),
+                None => {
+                    return Err(PassbandGainsError::BadLine {
+                        path: path_str,
+                        line_num: line_idx + 1,
+                        line: line.into(),
+                    })
+                }
+            }
+        }
+        gains
+    };
+    if gains.is_empty() {
+        return Err(PassbandGainsError::Empty { path: path_str });
+    }
+    // the data is divided by each gain.
+    if let Some((index, &gain)) = gains
+        .iter()
+        .enumerate()
+        .find(|(_, gain)| !gain.is_finite() || **gain <= 0.)
+    {
+        return Err(PassbandGainsError::BadGain {
+            path: path_str,
+            index,
+            gain,
+        });
+    }
+    Ok(gains)
+}
+
+/// Write the gains of each ultrafine channel in a coarse channel to a file which can be read with
+/// [`read_passband_gains`].
+///
+/// Files ending in `.fits` or `.fit` are written as a one dimensional image in the primary HDU,
+/// replacing any existing file. Otherwise, the file is written as text with a `<GAIN>` on each
+/// line.
+///
+/// # Errors
+///
+/// Will return a [`PassbandGainsError`] if the file can't be written.
+pub fn write_passband_gains<T: AsRef<Path>>(
+    path: T,
+    gains: &[f64],
+) -> Result<(), PassbandGainsError> {
+    let path_str = path.as_ref().display().to_string();
+    let is_fits = path
+        .as_ref()
+        .extension()
+        .map_or(false, |ext| ext == "fits" || ext == "fit");
+    if is_fits {
+        let to_error = |source| PassbandGainsError::Fits {
+            path: path_str.clone(),
+            source,
+        };
+        let description = ImageDescription {
+            data_type: ImageType::Double,
+            dimensions: &[gains.len()],
+        };
+        let mut fptr = FitsFile::create(&path)
+            .with_custom_primary(&description)
+            .overwrite()
+            .open()
+            .map_err(to_error)?;
+        let hdu = fptr.primary_hdu().map_err(to_error)?;
+        hdu.write_image(&mut fptr, gains).map_err(to_error)?;
+    } else {
+        let contents = gains
+            .iter()
+            .map(|gain| format!("{}\n", gain))
+            .collect::<String>();
+        fs::write(&path, contents).map_err(|source| PassbandGainsError::Unwritable {
+            path: path_str,
+            source,
+        })?;
+    }
+    Ok(())
+}
+
+/// Estimate the gains of each fine channel in a coarse channel from the data itself, for when
+/// none of the built-in gains match the data, e.g. after receiver firmware changes.
+///
+/// The amplitude of each visibility is the mean amplitude of its XX and YY polarisations. For
+/// each coarse channel, the median amplitude of each fine channel is taken over all timesteps and
+/// baselines, and normalised by the median over the fine channels of the coarse channel, which
+/// removes the digital gains and the spectral shape of the sky. The gain of each fine channel is
+/// then the median of these over all coarse channels.
+///
+/// Flagged and non-finite visibilities are excluded. Fine channels which are flagged in every
+/// coarse channel are given a gain of 1. To estimate the gains from only some baselines, e.g. the
+/// autocorrelations, pass a view of those baselines.
+///
+/// The gains are at the resolution of the data, and can be applied with
+/// [`crate::corrections::correct_coarse_passband_gains`].
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::{ndarray::Array3, passband_gains::estimate_passband_gains, Jones};
+///
+/// // 2 coarse channels of 4 fine channels, where the second coarse channel is twice as bright.
+/// let shape = [0.5_f32, 1., 1., 0.5];
+/// let jones_array = Array3::from_shape_fn((2, 8, 3), |(_, chan_idx, _)| {
+///     Jones::identity() * shape[chan_idx % 4] * (1 + chan_idx / 4) as f32
+/// });
+/// let flag_array = Array3::from_elem(jones_array.dim(), false);
+///
+/// let gains = estimate_passband_gains(jones_array.view(), flag_array.view(), 4).unwrap();
+/// assert_eq!(gains, vec![0.5, 1., 1., 0.5]);
+/// ```
+///
+/// # Errors
+///
+/// Will return [`PassbandGainsError::BadArrayShape`] if `num_fine_chans_per_coarse` is zero or
+/// doesn't divide the number of channels in `jones_array`, or the shape of `flag_array` doesn't
+/// match `jones_array`.
+pub fn estimate_passband_gains(
+    jones_array: ArrayView3<Jones<f32>>,
+    flag_array: ArrayView3<bool>,
+    num_fine_chans_per_coarse: usize,
+) -> Result<Vec<f64>, PassbandGainsError> {
+    if num_fine_chans_per_coarse == 0 || jones_array.dim().1 % num_fine_chans_per_coarse != 0 {
+        return Err(PassbandGainsError::BadArrayShape(BadArrayShape {
+            argument: "jones_array",
+            function: "estimate_passband_gains",
+            expected: format!(
+                "(_, n, _), where n is a multiple of num_fine_chans_per_coarse={}",
+                num_fine_chans_per_coarse
+            ),
+            received: format!("{:?}", jones_array.dim()),
+        }));
+    }
+    if flag_array.dim() != jones_array.dim() {
+        return Err(PassbandGainsError::BadArrayShape(BadArrayShape {
+            argument: "flag_array",
+            function: "estimate_passband_gains",
+            expected: format!("same as jones_array.dim()={:?}", jones_array.dim()),
+            received: format!("{:?}", flag_array.dim()),
+        }));
+    }
+
+    // the normalised median amplitude of each fine channel, for each coarse channel.
+    let coarse_shapes: Vec<Vec<Option<f32>>> = jones_array
+        .axis_chunks_iter(Axis(1), num_fine_chans_per_coarse)
+        .into_par_iter()
+        .zip(flag_array.axis_chunks_iter(Axis(1), num_fine_chans_per_coarse))
+        .map(|(jones_coarse_view, flag_coarse_view)| {
+            let medians: Vec<_> = jones_coarse_view
+                .axis_iter(Axis(1))
+                .zip(flag_coarse_view.axis_iter(Axis(1)))
+                .map(|(jones_chan_view, flag_chan_view)| {
+                    let mut amps = jones_chan_view
+                        .iter()
+                        .zip(flag_chan_view.iter())
+                        .filter(|(_, &flag)| !flag)
+                        .map(|(jones, _)| (jones[0].norm() + jones[3].norm()) / 2.)
+                        .filter(|amp| amp.is_finite())
+                        .collect::<Vec<_>>();
+                    median_mut(&mut amps)
+                })
+                .collect();
+            let mut chan_medians = medians.iter().flatten().copied().collect::<Vec<_>>();
+            match median_mut(&mut chan_medians) {
+                Some(norm) if norm > 0. => medians
+                    .iter()
+                    .map(|median| median.map(|median| median / norm))
+                    .collect(),
+                _ => vec![None; num_fine_chans_per_coarse],
+            }
+        })
+        .collect();
+
+    Ok((0..num_fine_chans_per_coarse)
+        .map(|fine_chan_idx| {
+            let mut gains = coarse_shapes
+                .iter()
+                .filter_map(|shape| shape[fine_chan_idx])
+                .collect::<Vec<_>>();
+            median_mut(&mut gains).map_or(1., f64::from)
+        })
+        .collect())
+}
 
 /// These gains are derived from `MWARX_RRI_PrototypeFilter_512x8.dat` using the method described
 /// in this wiki page <https://wiki.mwatelescope.org/display/MP/RRI+Receiver+PFB+Filter>
@@ -6547,3 +6867,117 @@ pub const PFB_COTTER_2014_10KHZ: &[f64] = &[
     0.5095006003,
     0.5025463233,
 ];
+
+#[cfg(test)]
+mod tests {
+    use marlu::fitsio::{images::ImageDescription, images::ImageType, FitsFile};
+    use tempfile::tempdir;
+
+    use super::{
+        estimate_passband_gains, read_passband_gains, write_passband_gains, PassbandGainsError,
+    };
+    use crate::{
+        ndarray::{s, Array3},
+        Jones,
+    };
+
+    #[test]
+    fn test_read_passband_gains_text() {
+        let gains = read_passband_gains("tests/data/subband-passband-128ch-unitary.txt").unwrap();
+        assert_eq!(gains, vec![1.; 128]);
+
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("gains.txt");
+        std::fs::write(&path, "# gain\n0.5\n\n1.0\n").unwrap();
+        assert_eq!(read_passband_gains(&path).unwrap(), vec![0.5, 1.0]);
+
+        std::fs::write(&path, "0.5\n0 abc\n").unwrap();
+        assert!(matches!(
+            read_passband_gains(&path),
+            Err(PassbandGainsError::BadLine { line_num: 2, .. })
+        ));
+
+        std::fs::write(&path, "# no gains\n").unwrap();
+        assert!(matches!(
+            read_passband_gains(&path),
+            Err(PassbandGainsError::Empty { .. })
+        ));
+
+        for bad_gain in ["0", "-0.5", "nan", "inf"] {
+            std::fs::write(&path, format!("0.5\n{}\n", bad_gain)).unwrap();
+            assert!(matches!(
+                read_passband_gains(&path),
+                Err(PassbandGainsError::BadGain { index: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_read_passband_gains_fits() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("gains.fits");
+        let expected = vec![0.5, 0.75, 1.0, 0.75];
+        {
+            let description = ImageDescription {
+                data_type: ImageType::Double,
+                dimensions: &[expected.len()],
+            };
+            let mut fptr = FitsFile::create(&path)
+                .with_custom_primary(&description)
+                .open()
+                .unwrap();
+            let hdu = fptr.primary_hdu().unwrap();
+            hdu.write_image(&mut fptr, &expected).unwrap();
+        }
+        assert_eq!(read_passband_gains(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_passband_gains_round_trip() {
+        let tmp_dir = tempdir().unwrap();
+        let gains = vec![0.5, 0.9921875, 1.0, 0.75];
+        for name in ["gains.txt", "gains.fits"] {
+            let path = tmp_dir.path().join(name);
+            write_passband_gains(&path, &gains).unwrap();
+            // existing files are replaced
+            write_passband_gains(&path, &gains).unwrap();
+            assert_eq!(read_passband_gains(&path).unwrap(), gains);
+        }
+    }
+
+    #[test]
+    fn test_estimate_passband_gains_flags() {
+        let shape = [0.5_f32, 0.75, 1., 1., 1., 0.75];
+        let mut jones_array = Array3::from_shape_fn((3, 12, 2), |(_, chan_idx, _)| {
+            Jones::identity() * shape[chan_idx % 6] * (1 + chan_idx / 6) as f32
+        });
+        let mut flag_array = Array3::from_elem(jones_array.dim(), false);
+        // a flagged outlier, and a non-finite visibility are ignored
+        jones_array[(0, 2, 0)] = Jones::identity() * 1e6;
+        flag_array[(0, 2, 0)] = true;
+        jones_array[(1, 2, 1)] = Jones::nan();
+        // a minority of outliers don't affect the median
+        jones_array[(2, 3, 0)] = Jones::identity() * 1e6;
+        // the first channel is flagged in every coarse channel
+        for coarse_idx in 0..2 {
+            flag_array.slice_mut(s![.., coarse_idx * 6, ..]).fill(true);
+        }
+
+        let gains = estimate_passband_gains(jones_array.view(), flag_array.view(), 6).unwrap();
+        assert_eq!(gains, vec![1., 0.75, 1., 1., 1., 0.75]);
+
+        assert!(matches!(
+            estimate_passband_gains(jones_array.view(), flag_array.view(), 5),
+            Err(PassbandGainsError::BadArrayShape(_))
+        ));
+        assert!(matches!(
+            estimate_passband_gains(jones_array.view(), flag_array.view(), 0),
+            Err(PassbandGainsError::BadArrayShape(_))
+        ));
+        let flag_array = Array3::from_elem((3, 12, 1), false);
+        assert!(matches!(
+            estimate_passband_gains(jones_array.view(), flag_array.view(), 6),
+            Err(PassbandGainsError::BadArrayShape(_))
+        ));
+    }
+}
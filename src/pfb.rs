@@ -0,0 +1,298 @@
+//! Compute coarse polyphase filter bank passband gains from a prototype filter.
+//!
+//! The MWA receivers channelise the band into coarse channels with a polyphase filter bank (PFB).
+//! Each coarse channel's response is shaped by the PFB's prototype filter, and since the output
+//! of each channel is decimated, power from outside the channel is aliased into it. This module
+//! computes the aliased passband gains of a prototype filter, in the same layout as the constants
+//! in [`crate::passband_gains`], so that gains for other filters and oversampling factors can be
+//! derived directly.
+//!
+//! Frequencies in this module are in units of the coarse channel spacing, relative to the centre
+//! of the coarse channel.
+//!
+//! The receivers' prototype filter, `MWARX_RRI_PrototypeFilter_512x8.dat`, isn't distributed with
+//! Birli, so the comparison with [`crate::passband_gains::PFB_JAKE_2022_200HZ`] and
+//! [`crate::passband_gains::PFB_COTTER_2014_10KHZ`] is an ignored test, which can be run with
+//! `cargo test -- --ignored` once the file is copied into `tests/data`. Until then, the constants
+//! remain the reference for the MWA's filter, and this module is only checked against filters with
+//! known responses.
+
+use std::{f64::consts::TAU, fs, path::Path};
+
+use marlu::{rayon::prelude::*, Complex};
+use thiserror::Error;
+
+/// Errors when reading prototype filter coefficients.
+#[derive(Error, Debug)]
+pub enum PfbError {
+    #[error("Could not read prototype filter {path}: {source}")]
+    /// The coefficients file could not be read
+    Unreadable {
+        /// The path to the coefficients file
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error("{path}: could not parse coefficient {value:?}")]
+    /// A coefficient could not be parsed
+    BadCoefficient {
+        /// The path to the coefficients file
+        path: String,
+        /// The value which could not be parsed
+        value: String,
+    },
+
+    #[error(
+        "Expected a multiple of {num_chans} prototype filter coefficients, found {num_coeffs}"
+    )]
+    /// The number of coefficients is not a multiple of the number of channels
+    BadLength {
+        /// The number of channels in the filter bank
+        num_chans: usize,
+        /// The number of coefficients
+        num_coeffs: usize,
+    },
+}
+
+/// The prototype filter of a polyphase filter bank.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrototypeFilter {
+    /// The filter coefficients, of length `num_chans * num_taps`
+    pub coeffs: Vec<f64>,
+    /// The number of channels in the filter bank
+    pub num_chans: usize,
+}
+
+impl PrototypeFilter {
+    /// Create a prototype filter for a filter bank with `num_chans` channels.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`PfbError::BadLength`] if the number of coefficients is not a non-zero
+    /// multiple of `num_chans`.
+    pub fn new(coeffs: Vec<f64>, num_chans: usize) -> Result<Self, PfbError> {
+        if num_chans == 0 || coeffs.is_empty() || coeffs.len() % num_chans != 0 {
+            return Err(PfbError::BadLength {
+                num_chans,
+                num_coeffs: coeffs.len(),
+            });
+        }
+        Ok(Self { coeffs, num_chans })
+    }
+
+    /// Read whitespace separated filter coefficients from a file, such as
+    /// `MWARX_RRI_PrototypeFilter_512x8.dat`.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`PfbError`] if the file can't be read or parsed, or the number of
+    /// coefficients is not a multiple of `num_chans`.
+    pub fn from_file<T: AsRef<Path>>(path: T, num_chans: usize) -> Result<Self, PfbError> {
+        let path_str = path.as_ref().display().to_string();
+        let contents = fs::read_to_string(&path).map_err(|source| PfbError::Unreadable {
+            path: path_str.clone(),
+            source,
+        })?;
+        let coeffs = contents
+            .split_whitespace()
+            .map(|value| {
+                value.parse::<f64>().map_err(|_| PfbError::BadCoefficient {
+                    path: path_str.clone(),
+                    value: value.into(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(coeffs, num_chans)
+    }
+
+    /// The number of taps in each branch of the filter bank.
+    pub fn num_taps(&self) -> usize {
+        self.coeffs.len() / self.num_chans
+    }
+
+    /// The power response of a channel at `freq`, in units of the channel spacing from the
+    /// channel centre.
+    pub fn power_response(&self, freq: f64) -> f64 {
+        let step = Complex::from_polar(1., -TAU * freq / self.num_chans as f64);
+        let mut phasor = Complex::new(1., 0.);
+        let mut response = Complex::new(0., 0.);
+        for &coeff in &self.coeffs {
+            response += phasor * coeff;
+            phasor *= step;
+        }
+        response.norm_sqr()
+    }
+
+    /// The power of a channel at `freq`, including the power aliased from its images at
+    /// `freq ± oversampling` when the channel is decimated to `oversampling` times the channel
+    /// spacing. Only the images from adjacent channels are included, since the stopband of the
+    /// prototype filter suppresses the rest.
+    pub fn aliased_power_response(&self, freq: f64, oversampling: f64) -> f64 {
+        (-1..=1)
+            .map(|image| self.power_response(freq + image as f64 * oversampling))
+            .sum()
+    }
+
+    /// Compute the passband gains of `num_gains` equally spaced ultrafine channels across a
+    /// coarse channel, starting at the lower edge, normalised to the centre of the channel. This
+    /// is the same layout as the constants in [`crate::passband_gains`], which can be applied with
+    /// [`crate::corrections::correct_coarse_passband_gains`].
+    ///
+    /// `oversampling` is the ratio of the output sample rate of each channel to the channel
+    /// spacing, 1.0 for a critically sampled filter bank.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use birli::pfb::PrototypeFilter;
+    ///
+    /// // a boxcar filter with a single tap has a sinc response.
+    /// let filter = PrototypeFilter::new(vec![1.; 4], 4).unwrap();
+    /// let gains = filter.aliased_passband_gains(1., 8);
+    ///
+    /// assert_eq!(gains.len(), 8);
+    /// assert!((gains[4] - 1.).abs() < 1e-12);
+    /// assert!((gains[1] - gains[7]).abs() < 1e-12);
+    /// ```
+    pub fn aliased_passband_gains(&self, oversampling: f64, num_gains: usize) -> Vec<f64> {
+        let centre_power = self.aliased_power_response(0., oversampling);
+        (0..num_gains)
+            .into_par_iter()
+            .map(|gain_idx| {
+                let freq = gain_idx as f64 / num_gains as f64 - 0.5;
+                self.aliased_power_response(freq, oversampling) / centre_power
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f64::consts::PI, fs};
+
+    use approx::assert_abs_diff_eq;
+    use tempfile::tempdir;
+
+    use super::{PfbError, PrototypeFilter};
+    use crate::{
+        corrections::{scrunch_gains, ScrunchType},
+        passband_gains::{PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ},
+    };
+
+    /// A Hamming windowed sinc with a cutoff at the channel edges.
+    fn windowed_sinc(num_chans: usize, num_taps: usize) -> PrototypeFilter {
+        let len = num_chans * num_taps;
+        let coeffs = (0..len)
+            .map(|n| {
+                let x = (n as f64 - (len - 1) as f64 / 2.) / num_chans as f64;
+                let sinc = if x == 0. {
+                    1.
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let window = 0.54 - 0.46 * (2. * PI * (n as f64 + 0.5) / len as f64).cos();
+                sinc * window
+            })
+            .collect();
+        PrototypeFilter::new(coeffs, num_chans).unwrap()
+    }
+
+    #[test]
+    fn test_prototype_filter_from_file() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("filter.dat");
+        fs::write(&path, "1\n2\n3 4\n").unwrap();
+        let filter = PrototypeFilter::from_file(&path, 2).unwrap();
+        assert_eq!(filter.coeffs, vec![1., 2., 3., 4.]);
+        assert_eq!(filter.num_taps(), 2);
+
+        assert!(matches!(
+            PrototypeFilter::from_file(&path, 3),
+            Err(PfbError::BadLength {
+                num_chans: 3,
+                num_coeffs: 4
+            })
+        ));
+
+        fs::write(&path, "1\nx\n").unwrap();
+        assert!(matches!(
+            PrototypeFilter::from_file(&path, 2),
+            Err(PfbError::BadCoefficient { .. })
+        ));
+    }
+
+    #[test]
+    fn test_power_response_symmetric_filter() {
+        let filter = windowed_sinc(8, 4);
+        for freq in [0.1, 0.25, 0.5, 1.3] {
+            assert_abs_diff_eq!(
+                filter.power_response(freq),
+                filter.power_response(-freq),
+                epsilon = 1e-9
+            );
+        }
+        // the power response at the channel edge is a quarter of the centre (-6dB amplitude).
+        let ratio = filter.power_response(0.5) / filter.power_response(0.);
+        assert_abs_diff_eq!(ratio, 0.25, epsilon = 0.01);
+    }
+
+    #[test]
+    fn test_aliased_passband_gains_oversampled() {
+        let filter = windowed_sinc(64, 8);
+        let critical = filter.aliased_passband_gains(1., 64);
+        let oversampled = filter.aliased_passband_gains(32. / 25., 64);
+        // critically sampled channels receive the same power from their adjacent images at the
+        // edges, so the edge gain is doubled.
+        assert_abs_diff_eq!(critical[0], 0.5, epsilon = 0.01);
+        assert_abs_diff_eq!(oversampled[0], 0.25, epsilon = 0.02);
+        assert_abs_diff_eq!(critical[32], 1.);
+        assert_abs_diff_eq!(oversampled[32], 1.);
+    }
+
+    /// A boxcar filter with a single tap has the power response of a Dirichlet kernel,
+    /// `sin²(πf) / sin²(πf / n)`, so the aliased gains can be computed exactly.
+    #[test]
+    fn test_aliased_passband_gains_boxcar() {
+        let num_chans = 16;
+        let filter = PrototypeFilter::new(vec![1.; num_chans], num_chans).unwrap();
+        let dirichlet = |freq: f64| {
+            if freq == 0. {
+                (num_chans * num_chans) as f64
+            } else {
+                (PI * freq).sin().powi(2) / (PI * freq / num_chans as f64).sin().powi(2)
+            }
+        };
+        let centre_power = dirichlet(-1.) + dirichlet(0.) + dirichlet(1.);
+        let gains = filter.aliased_passband_gains(1., 40);
+        for (idx, &gain) in gains.iter().enumerate() {
+            let freq = idx as f64 / 40. - 0.5;
+            let expected =
+                (dirichlet(freq - 1.) + dirichlet(freq) + dirichlet(freq + 1.)) / centre_power;
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-9);
+        }
+    }
+
+    /// The gains of the receivers' critically sampled prototype filter reproduce the Jake gains
+    /// to within 1e-3, and the Cotter gains to within 1e-2 once scrunched to 10kHz, since those
+    /// come from an older memo.
+    #[test]
+    #[ignore = "needs tests/data/MWARX_RRI_PrototypeFilter_512x8.dat, which isn't distributed"]
+    fn test_aliased_passband_gains_reproduce_constants() {
+        let filter =
+            PrototypeFilter::from_file("tests/data/MWARX_RRI_PrototypeFilter_512x8.dat", 512)
+                .unwrap();
+        assert_eq!(filter.num_taps(), 8);
+
+        let gains = filter.aliased_passband_gains(1., PFB_JAKE_2022_200HZ.len());
+        for (&gain, &expected) in gains.iter().zip(PFB_JAKE_2022_200HZ) {
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-3);
+        }
+
+        let fscrunch = PFB_JAKE_2022_200HZ.len() / PFB_COTTER_2014_10KHZ.len();
+        let scrunched = scrunch_gains(&gains, fscrunch, &ScrunchType::CenterSymmetric);
+        for (&gain, &expected) in scrunched.iter().zip(PFB_COTTER_2014_10KHZ) {
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-2);
+        }
+    }
+}
@@ -0,0 +1,2612 @@
+//! The preprocessing pipeline: reading, preprocessing, averaging and writing chunks of
+//! correlator data, independent of the command line.
+
+use crate::{
+    calibration::CalibrationError,
+    corrections::drift_phase_centre,
+    ephemeris::EphemerisError,
+    error::BirliError,
+    flags::{detect_bad_tiles, extend_flags, tile_diagnostics_table, FlagContext},
+    io::{
+        aocal::AOCalSols, calc_rephased_uvws, error::IOError, read_mwax_weights, rewrite_ms_uvws,
+        rewrite_uvfits_uvws, IOContext, PhasedOutput,
+    },
+    marlu::{
+        average_chunk_f64,
+        built_info::PKG_VERSION as MARLU_PKG_VERSION,
+        hifitime::{self, Epoch, Unit},
+        io::{error::BadArrayShape, ms::MeasurementSetWriter, uvfits::UvfitsWriter, VisWrite},
+        mwalib,
+        ndarray::{s, Array2, Array3, ArrayView3, ArrayViewMut3},
+        precession::{precess_time, PrecessionInfo},
+        History, Jones, LatLngHeight, MwaObsContext, ObsContext, RADec, VisContext, XyzGeodetic,
+        UVW,
+    },
+    passband_gains::{estimate_passband_gains, write_passband_gains},
+    preprocessing::StepRange,
+    with_increment_duration, Axis, Complex, FlagFileSet, PreprocessContext, VisSelection,
+};
+use cfg_if::cfg_if;
+use itertools::{izip, Itertools};
+use log::{info, warn};
+use mwalib::{
+    built_info::PKG_VERSION as MWALIB_PKG_VERSION, fitsio_sys::CFITSIO_VERSION, CorrelatorContext,
+};
+use prettytable::{format as prettyformat, row, table};
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{Debug, Display},
+    ops::Range,
+    path::PathBuf,
+    sync::PoisonError,
+    time::Duration,
+};
+use thiserror::Error;
+
+cfg_if! {
+    if #[cfg(feature = "aoflagger")] {
+        use crate::strategies::AOFlaggerStrategy;
+        use aoflagger_sys::{cxx_aoflagger_new};
+    }
+}
+
+const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Args for preprocessing a correlator context.
+pub struct BirliContext<'a> {
+    /// mwalib::CorrelatorContext
+    pub corr_ctx: CorrelatorContext,
+    /// Preprocessing parameters
+    pub prep_ctx: PreprocessContext<'a>,
+    /// selected visibility indices
+    pub vis_sel: VisSelection,
+    /// Flagging Parameters
+    pub flag_ctx: FlagContext,
+    /// Input / output paths
+    pub io_ctx: IOContext,
+    /// temporal averaging factor
+    pub avg_time: usize,
+    /// spectral averaging factor
+    pub avg_freq: usize,
+    /// temporal chunking factor
+    pub num_timesteps_per_chunk: Option<usize>,
+    /// extra timesteps of context read either side of each chunk for RFI flagging and flag
+    /// extension only
+    pub num_halo_timesteps: usize,
+    /// Are we ignoring DUT1?
+    pub ignore_dut1: bool,
+}
+
+/// Errors when building a [`BirliContext`] with a [`BirliContextBuilder`].
+#[derive(Error, Debug)]
+pub enum PipelineError {
+    #[error("Invalid {setting}: expected {expected}, received {received}")]
+    /// A setting of the pipeline is invalid
+    InvalidSetting {
+        /// The setting which is invalid
+        setting: &'static str,
+        /// What was expected
+        expected: String,
+        /// The value which was received instead
+        received: String,
+    },
+}
+
+/// A builder for a [`BirliContext`], which configures the whole pipeline with types instead of
+/// command line arguments, so that other programs can embed Birli. Anything which isn't set has
+/// the same default as the command line.
+///
+/// # Examples
+///
+/// ```rust
+/// use birli::{io::IOContext, BirliContextBuilder};
+/// use tempfile::tempdir;
+///
+/// let tmp_dir = tempdir().unwrap();
+/// let uvfits_path = tmp_dir.path().join("1297526432.uvfits");
+/// let io_ctx = IOContext {
+///     metafits_in: "tests/data/1297526432_mwax/1297526432.metafits".into(),
+///     gpufits_in: vec![
+///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits".into(),
+///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_001.fits".into(),
+///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_000.fits".into(),
+///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_001.fits".into(),
+///     ],
+///     ..IOContext::default()
+/// };
+/// let builder = BirliContextBuilder::new(io_ctx).unwrap();
+/// let num_fine_chans = builder.corr_ctx().metafits_context.num_corr_fine_chans_per_coarse;
+/// let birli_ctx = builder
+///     .avg_freq(num_fine_chans)
+///     .uvfits_out(&uvfits_path)
+///     .build()
+///     .unwrap();
+/// birli_ctx.run().unwrap();
+/// assert!(uvfits_path.exists());
+/// ```
+pub struct BirliContextBuilder<'a> {
+    corr_ctx: CorrelatorContext,
+    io_ctx: IOContext,
+    prep_ctx: Option<PreprocessContext<'a>>,
+    vis_sel: Option<VisSelection>,
+    flag_ctx: Option<FlagContext>,
+    avg_time: usize,
+    avg_freq: usize,
+    num_timesteps_per_chunk: Option<usize>,
+    num_halo_timesteps: usize,
+    ignore_dut1: bool,
+}
+
+impl<'a> BirliContextBuilder<'a> {
+    /// Start building a pipeline which reads the metafits and gpufits files of `io_ctx`, and
+    /// writes any outputs it has.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BirliError::MwalibError`] if mwalib can't open the input files.
+    pub fn new(io_ctx: IOContext) -> Result<Self, BirliError> {
+        let corr_ctx = io_ctx.get_corr_ctx()?;
+        Ok(Self {
+            corr_ctx,
+            io_ctx,
+            prep_ctx: None,
+            vis_sel: None,
+            flag_ctx: None,
+            avg_time: 1,
+            avg_freq: 1,
+            num_timesteps_per_chunk: None,
+            num_halo_timesteps: 0,
+            ignore_dut1: false,
+        })
+    }
+
+    /// The correlator context of the inputs, for deriving settings from the observation.
+    pub const fn corr_ctx(&self) -> &CorrelatorContext {
+        &self.corr_ctx
+    }
+
+    /// Set the preprocessing options. Defaults to [`PreprocessContext::from_mwalib`].
+    #[must_use]
+    pub fn prep_ctx(mut self, prep_ctx: PreprocessContext<'a>) -> Self {
+        self.prep_ctx = Some(prep_ctx);
+        self
+    }
+
+    /// Set the selection of visibilities to process. Defaults to [`VisSelection::from_mwalib`].
+    #[must_use]
+    pub fn vis_sel(mut self, vis_sel: VisSelection) -> Self {
+        self.vis_sel = Some(vis_sel);
+        self
+    }
+
+    /// Set the flagging options. Defaults to [`FlagContext::from_mwalib`].
+    #[must_use]
+    pub fn flag_ctx(mut self, flag_ctx: FlagContext) -> Self {
+        self.flag_ctx = Some(flag_ctx);
+        self
+    }
+
+    /// Set the number of timesteps to average together. Defaults to 1.
+    #[must_use]
+    pub const fn avg_time(mut self, avg_time: usize) -> Self {
+        self.avg_time = avg_time;
+        self
+    }
+
+    /// Set the number of fine channels to average together. Defaults to 1.
+    #[must_use]
+    pub const fn avg_freq(mut self, avg_freq: usize) -> Self {
+        self.avg_freq = avg_freq;
+        self
+    }
+
+    /// Set the number of timesteps to process in each chunk, which must be a multiple of
+    /// `avg_time`. Defaults to processing all timesteps at once.
+    #[must_use]
+    pub const fn num_timesteps_per_chunk(mut self, num_timesteps_per_chunk: Option<usize>) -> Self {
+        self.num_timesteps_per_chunk = num_timesteps_per_chunk;
+        self
+    }
+
+    /// Set the number of extra timesteps read either side of each chunk as context for RFI
+    /// flagging and flag extension. Only the preprocessing steps up to and including RFI
+    /// flagging are applied to the halo. Defaults to 0.
+    #[must_use]
+    pub const fn num_halo_timesteps(mut self, num_halo_timesteps: usize) -> Self {
+        self.num_halo_timesteps = num_halo_timesteps;
+        self
+    }
+
+    /// Set whether to ignore the DUT1 of the metafits in the outputs. Defaults to `false`.
+    #[must_use]
+    pub const fn ignore_dut1(mut self, ignore_dut1: bool) -> Self {
+        self.ignore_dut1 = ignore_dut1;
+        self
+    }
+
+    /// Apply the calibration solutions in an aocal file.
+    #[must_use]
+    pub fn aocalsols_in<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.io_ctx.aocalsols_in = Some(path.into());
+        self
+    }
+
+    /// Write a uvfits output.
+    #[must_use]
+    pub fn uvfits_out<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.io_ctx.uvfits_out = Some(path.into());
+        self
+    }
+
+    /// Write a measurement set output.
+    #[must_use]
+    pub fn ms_out<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.io_ctx.ms_out = Some(path.into());
+        self
+    }
+
+    /// Write mwaf flag files, named with `template` (see [`FlagFileSet`]).
+    #[must_use]
+    pub fn flag_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.io_ctx.flag_template = Some(template.into());
+        self
+    }
+
+    /// Write passband gains estimated from the uncorrected visibilities of the first chunk,
+    /// including its halo. These are only written once; when the passband is estimated from the
+    /// data ([`PreprocessContext::empirical_passband`]), the gains applied are estimated
+    /// separately for each chunk, so they only match the written gains for the first chunk.
+    #[must_use]
+    pub fn passband_gains_out<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.io_ctx.passband_gains_out = Some(path.into());
+        self
+    }
+
+    /// Also write the visibilities phased to another centre.
+    #[must_use]
+    pub fn phased_out(mut self, phased_out: PhasedOutput) -> Self {
+        self.io_ctx.phased_outs.push(phased_out);
+        self
+    }
+
+    /// Validate the settings, and fill in any defaults.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`PipelineError`] if the averaging factors are zero, the chunk size is not a
+    /// multiple of the temporal averaging factor, or if there are outputs phased to other centres
+    /// but the preprocessing steps or custom stages after geometric corrections could change the
+    /// weights or flags (see [`PreprocessContext::can_rephase`]). Also returns a
+    /// [`PipelineError`] if the visibility selection or the flags don't match the dimensions of
+    /// the observation. Can also raise errors from the default settings.
+    pub fn build(self) -> Result<BirliContext<'a>, BirliError> {
+        let Self {
+            corr_ctx,
+            io_ctx,
+            prep_ctx,
+            vis_sel,
+            flag_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_halo_timesteps,
+            ignore_dut1,
+        } = self;
+
+        for (setting, factor) in [("avg_time", avg_time), ("avg_freq", avg_freq)] {
+            if factor == 0 {
+                return Err(PipelineError::InvalidSetting {
+                    setting,
+                    expected: "a positive, non-zero integer".into(),
+                    received: format!("{}", factor),
+                }
+                .into());
+            }
+        }
+        if let Some(chunk_size) = num_timesteps_per_chunk {
+            if chunk_size == 0 || chunk_size % avg_time != 0 {
+                return Err(PipelineError::InvalidSetting {
+                    setting: "num_timesteps_per_chunk",
+                    expected: format!("a multiple of the temporal averaging factor, {}", avg_time),
+                    received: format!("{}", chunk_size),
+                }
+                .into());
+            }
+        }
+        let prep_ctx = match prep_ctx {
+            Some(prep_ctx) => prep_ctx,
+            None => PreprocessContext::from_mwalib(&corr_ctx)?,
+        };
+        // outputs phased to other centres share the weights and flags of the main outputs.
+        if !io_ctx.phased_outs.is_empty() && !prep_ctx.step_order.can_rephase() {
+            return Err(PipelineError::InvalidSetting {
+                setting: "step_order",
+                expected: "flag and passband before geometry when there are phased outputs".into(),
+                received: prep_ctx.step_order.to_string(),
+            }
+            .into());
+        }
+        if !io_ctx.phased_outs.is_empty() && !prep_ctx.can_rephase() {
+            return Err(PipelineError::InvalidSetting {
+                setting: "stages",
+                expected: "custom stages before geometry when there are phased outputs".into(),
+                received: "custom stages from geometry onwards".into(),
+            }
+            .into());
+        }
+        let vis_sel = match vis_sel {
+            Some(vis_sel) => vis_sel,
+            None => VisSelection::from_mwalib(&corr_ctx)?,
+        };
+        for (setting, range, len) in [
+            (
+                "vis_sel.timestep_range",
+                &vis_sel.timestep_range,
+                corr_ctx.num_timesteps,
+            ),
+            (
+                "vis_sel.coarse_chan_range",
+                &vis_sel.coarse_chan_range,
+                corr_ctx.num_coarse_chans,
+            ),
+        ] {
+            if range.is_empty() || range.end > len {
+                return Err(PipelineError::InvalidSetting {
+                    setting,
+                    expected: format!("a non-empty range within 0..{}", len),
+                    received: format!("{:?}", range),
+                }
+                .into());
+            }
+        }
+        let num_baselines = corr_ctx.metafits_context.num_baselines;
+        if vis_sel.baseline_idxs.is_empty()
+            || vis_sel
+                .baseline_idxs
+                .iter()
+                .any(|&idx| idx >= num_baselines)
+        {
+            return Err(PipelineError::InvalidSetting {
+                setting: "vis_sel.baseline_idxs",
+                expected: format!("baseline indices less than {}", num_baselines),
+                received: format!("{:?}", vis_sel.baseline_idxs),
+            }
+            .into());
+        }
+
+        let mut flag_ctx = flag_ctx.unwrap_or_else(|| FlagContext::from_mwalib(&corr_ctx));
+        for (setting, flags, len) in [
+            (
+                "flag_ctx.timestep_flags",
+                &flag_ctx.timestep_flags,
+                corr_ctx.num_timesteps,
+            ),
+            (
+                "flag_ctx.coarse_chan_flags",
+                &flag_ctx.coarse_chan_flags,
+                corr_ctx.num_coarse_chans,
+            ),
+            (
+                "flag_ctx.fine_chan_flags",
+                &flag_ctx.fine_chan_flags,
+                corr_ctx.metafits_context.num_corr_fine_chans_per_coarse,
+            ),
+            (
+                "flag_ctx.antenna_flags",
+                &flag_ctx.antenna_flags,
+                corr_ctx.metafits_context.num_ants,
+            ),
+        ] {
+            if flags.len() != len {
+                return Err(PipelineError::InvalidSetting {
+                    setting,
+                    expected: format!("{} flags", len),
+                    received: format!("{} flags", flags.len()),
+                }
+                .into());
+            }
+        }
+        flag_ctx.finalise_flag_settings(&corr_ctx);
+
+        Ok(BirliContext {
+            corr_ctx,
+            prep_ctx,
+            vis_sel,
+            flag_ctx,
+            io_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_halo_timesteps,
+            ignore_dut1,
+        })
+    }
+}
+
+// Add build-time information from the "built" crate.
+include!(concat!(env!("OUT_DIR"), "/built.rs"));
+
+/// stolen from hyperdrive
+/// Write many info-level log lines of how this executable was compiled.
+///
+/// # Errors
+///
+/// propagates writeln! fails
+pub fn fmt_build_info(f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match GIT_HEAD_REF {
+        Some(hr) => {
+            let dirty = GIT_DIRTY.unwrap_or(false);
+            writeln!(
+                f,
+                "Compiled on git commit hash: {}{}",
+                GIT_COMMIT_HASH.unwrap(),
+                if dirty { " (dirty)" } else { "" }
+            )?;
+            writeln!(f, "            git head ref: {}", hr)?;
+        }
+        None => writeln!(f, "Compiled on git commit hash: <no git info>")?,
+    }
+    writeln!(f, "            {}", BUILT_TIME_UTC)?;
+    writeln!(f, "         with compiler {}", RUSTC_VERSION)?;
+    writeln!(f, "libraries:")?;
+    writeln!(f, "- marlu v{}", MARLU_PKG_VERSION)?;
+    writeln!(f, "- mwalib v{}", MWALIB_PKG_VERSION)?;
+    writeln!(f, "- cfitsio (bindings) v{}", CFITSIO_VERSION)?;
+
+    cfg_if! {
+        if #[cfg(feature = "aoflagger")] {
+            use std::os::raw::c_short;
+            let mut major: c_short = -1;
+            let mut minor: c_short = -1;
+            let mut sub_minor: c_short = -1;
+            let aoflagger = unsafe { cxx_aoflagger_new() };
+            aoflagger.GetVersion(&mut major, &mut minor, &mut sub_minor);
+            assert!(major >= 3);
+            assert!(minor >= 0);
+            assert!(sub_minor >= 0);
+            writeln!(f, "- aoflagger v{}.{}.{}", major, minor, sub_minor)?;
+        }
+    }
+    writeln!(f)?;
+    Ok(())
+}
+
+fn time_details(
+    gps_time_ms: u64,
+    dut1: hifitime::Duration,
+    phase_centre: RADec,
+    array_pos: LatLngHeight,
+) -> (String, String, f64, PrecessionInfo) {
+    let epoch = Epoch::from_gpst_seconds(gps_time_ms as f64 / 1e3);
+    let (y, mo, d, h, mi, s, ms) = epoch.as_gregorian_utc();
+    let precession_info = precess_time(
+        array_pos.longitude_rad,
+        array_pos.latitude_rad,
+        phase_centre,
+        epoch,
+        dut1,
+    );
+    (
+        format!("{:02}-{:02}-{:02}", y, mo, d),
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            h,
+            mi,
+            s,
+            (ms as f64 / 1e6).round()
+        ),
+        epoch.as_mjd_utc_seconds(),
+        precession_info,
+    )
+}
+
+impl Display for BirliContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} version {}", PKG_NAME, PKG_VERSION,)?;
+
+        fmt_build_info(f)?;
+
+        writeln!(
+            f,
+            "observation name:     {}",
+            self.corr_ctx.metafits_context.obs_name
+        )?;
+
+        writeln!(f, "Array position:       {}", &self.prep_ctx.array_pos)?;
+        writeln!(f, "Phase centre:         {}", &self.prep_ctx.phase_centre)?;
+        for phased_out in &self.io_ctx.phased_outs {
+            writeln!(
+                f,
+                "Extra phase centre:   {} ({})",
+                &phased_out.phase_centre,
+                phased_out
+                    .uvfits_out
+                    .as_ref()
+                    .or(phased_out.ms_out.as_ref())
+                    .map_or_else(String::new, |path| path.display().to_string())
+            )?;
+        }
+        let pointing_centre = RADec::from_mwalib_tile_pointing(&self.corr_ctx.metafits_context);
+        if pointing_centre != self.prep_ctx.phase_centre {
+            writeln!(f, "Pointing centre:      {}", &pointing_centre)?;
+        }
+
+        let coarse_chan_flag_idxs: Vec<usize> = self
+            .flag_ctx
+            .coarse_chan_flags
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
+            .collect();
+        // TODO: actually display this.
+        let _fine_chan_flag_idxs: Vec<usize> = self
+            .flag_ctx
+            .fine_chan_flags
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
+            .collect();
+        let timestep_flag_idxs: Vec<usize> = self
+            .flag_ctx
+            .timestep_flags
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
+            .collect();
+        let ant_pairs = self.vis_sel.get_ant_pairs(&self.corr_ctx.metafits_context);
+        #[allow(clippy::needless_collect)]
+        let baseline_flag_idxs: Vec<usize> = self
+            .flag_ctx
+            .get_baseline_flags(&ant_pairs)
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
+            .collect();
+
+        let dut1 = if self.ignore_dut1 {
+            hifitime::Duration::from_total_nanoseconds(0)
+        } else {
+            hifitime::Duration::from_f64(
+                self.corr_ctx.metafits_context.dut1.unwrap_or(0.0),
+                Unit::Second,
+            )
+        };
+        let (sched_start_date, sched_start_time, sched_start_mjd_s, sched_start_prec) =
+            time_details(
+                self.corr_ctx.metafits_context.sched_start_gps_time_ms,
+                dut1,
+                self.prep_ctx.phase_centre,
+                self.prep_ctx.array_pos,
+            );
+        writeln!(
+            f,
+            "Scheduled start:      {} {} UTC, unix={:.3}, gps={:.3}, mjd={:.3}, lmst={:7.4}°, lmst2k={:7.4}°, lat2k={:7.4}°",
+            sched_start_date, sched_start_time,
+            self.corr_ctx.metafits_context.sched_start_unix_time_ms as f64 / 1e3,
+            self.corr_ctx.metafits_context.sched_start_gps_time_ms as f64 / 1e3,
+            sched_start_mjd_s,
+            sched_start_prec.lmst.to_degrees(),
+            sched_start_prec.lmst_j2000.to_degrees(),
+            sched_start_prec.array_latitude_j2000.to_degrees(),
+        )?;
+        let (sched_end_date, sched_end_time, sched_end_mjd_s, sched_end_prec) = time_details(
+            self.corr_ctx.metafits_context.sched_end_gps_time_ms,
+            dut1,
+            self.prep_ctx.phase_centre,
+            self.prep_ctx.array_pos,
+        );
+        writeln!(
+            f,
+
+            "Scheduled end:        {} {} UTC, unix={:.3}, gps={:.3}, mjd={:.3}, lmst={:7.4}°, lmst2k={:7.4}°, lat2k={:7.4}°",
+            sched_end_date, sched_end_time,
+            self.corr_ctx.metafits_context.sched_end_unix_time_ms as f64 / 1e3,
+            self.corr_ctx.metafits_context.sched_end_gps_time_ms as f64 / 1e3,
+            sched_end_mjd_s,
+            sched_end_prec.lmst.to_degrees(),
+            sched_end_prec.lmst_j2000.to_degrees(),
+            sched_end_prec.array_latitude_j2000.to_degrees(),
+        )?;
+        let int_time_s = self.corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
+        let sched_duration_s = self.corr_ctx.metafits_context.sched_duration_ms as f64 / 1e3;
+        writeln!(
+            f,
+            "Scheduled duration:   {:.3}s = {:3} * {:.3}s",
+            sched_duration_s,
+            (sched_duration_s / int_time_s).ceil(),
+            int_time_s
+        )?;
+        let quack_duration_s = self.corr_ctx.metafits_context.quack_time_duration_ms as f64 / 1e3;
+        writeln!(
+            f,
+            "Quack duration:       {:.3}s = {:3} * {:.3}s",
+            quack_duration_s,
+            (quack_duration_s / int_time_s).ceil(),
+            int_time_s
+        )?;
+        let num_avg_timesteps =
+            (self.vis_sel.timestep_range.len() as f64 / self.avg_time as f64).ceil() as usize;
+        let avg_int_time_s = int_time_s * self.avg_time as f64;
+        writeln!(
+            f,
+            "Output duration:      {:.3}s = {:3} * {:.3}s{}",
+            num_avg_timesteps as f64 * avg_int_time_s,
+            num_avg_timesteps,
+            avg_int_time_s,
+            if self.avg_time == 1 {
+                "".into()
+            } else {
+                format!(" ({}x)", self.avg_time)
+            }
+        )?;
+
+        let total_bandwidth_mhz = self.corr_ctx.metafits_context.obs_bandwidth_hz as f64 / 1e6;
+        let fine_chan_width_khz =
+            self.corr_ctx.metafits_context.corr_fine_chan_width_hz as f64 / 1e3;
+        let fine_chans_per_coarse = self
+            .corr_ctx
+            .metafits_context
+            .num_corr_fine_chans_per_coarse;
+
+        writeln!(
+            f,
+            "Scheduled Bandwidth:  {:.3}MHz = {:3} * {:3} * {:.3}kHz",
+            total_bandwidth_mhz,
+            self.corr_ctx.metafits_context.num_metafits_coarse_chans,
+            fine_chans_per_coarse,
+            fine_chan_width_khz
+        )?;
+
+        let out_bandwidth_mhz = self.vis_sel.coarse_chan_range.len() as f64
+            * fine_chans_per_coarse as f64
+            * fine_chan_width_khz
+            / 1e3;
+        let num_avg_chans = (self.vis_sel.coarse_chan_range.len() as f64
+            * fine_chans_per_coarse as f64
+            / self.avg_freq as f64)
+            .ceil() as usize;
+        let avg_fine_chan_width_khz = fine_chan_width_khz * self.avg_freq as f64;
+        writeln!(
+            f,
+            "Output Bandwidth:     {:.3}MHz = {:9} * {:.3}kHz{}",
+            out_bandwidth_mhz,
+            num_avg_chans,
+            avg_fine_chan_width_khz,
+            if self.avg_freq == 1 {
+                "".into()
+            } else {
+                format!(" ({}x)", self.avg_freq)
+            }
+        )?;
+
+        let first_epoch =
+            Epoch::from_gpst_seconds(self.corr_ctx.timesteps[0].gps_time_ms as f64 / 1e3);
+        let (y, mo, d, ..) = first_epoch.as_gregorian_utc();
+
+        let mut timestep_table = table!([
+            "",
+            format!("{:02}-{:02}-{:02} UTC +", y, mo, d),
+            "unix [s]",
+            "gps [s]",
+            "p",
+            "c",
+            "g",
+            "s",
+            "f"
+        ]);
+        timestep_table.set_format(*prettyformat::consts::FORMAT_CLEAN);
+
+        let provided_timestep_indices = &self.corr_ctx.provided_timestep_indices;
+        let common_timestep_indices = &self.corr_ctx.common_timestep_indices;
+        let common_good_timestep_indices = &self.corr_ctx.common_good_timestep_indices;
+        for (timestep_idx, timestep) in self.corr_ctx.timesteps.iter().enumerate() {
+            let provided = provided_timestep_indices.contains(&timestep_idx);
+            let selected = self.vis_sel.timestep_range.contains(&timestep_idx);
+            let common = common_timestep_indices.contains(&timestep_idx);
+            let good = common_good_timestep_indices.contains(&timestep_idx);
+            let flagged = timestep_flag_idxs.contains(&timestep_idx);
+
+            let (_, time, ..) = time_details(
+                timestep.gps_time_ms,
+                dut1,
+                self.prep_ctx.phase_centre,
+                self.prep_ctx.array_pos,
+            );
+            let row = row![r =>
+                format!("ts{}:", timestep_idx),
+                time,
+                format!("{:.3}", timestep.unix_time_ms as f64 / 1e3),
+                format!("{:.3}", timestep.gps_time_ms as f64 / 1e3),
+                if provided {"p"} else {""},
+                if common {"c"} else {""},
+                if good {"g"} else {""},
+                if selected {"s"} else {""},
+                if flagged {"f"} else {""}
+            ];
+            timestep_table.add_row(row);
+        }
+
+        writeln!(
+            f,
+            "Timestep details (all={}, provided={}, common={}, good={}, select={}, flag={}):\n{}",
+            self.corr_ctx.num_timesteps,
+            self.corr_ctx.num_provided_timesteps,
+            self.corr_ctx.num_common_timesteps,
+            self.corr_ctx.num_common_good_timesteps,
+            self.vis_sel.timestep_range.len(),
+            timestep_flag_idxs.len(),
+            timestep_table
+        )?;
+
+        let mut coarse_chan_table = table!([
+            "",
+            "gpu",
+            "corr",
+            "rec",
+            "cen [MHz]",
+            "p",
+            "c",
+            "g",
+            "s",
+            "f"
+        ]);
+        coarse_chan_table.set_format(*prettyformat::consts::FORMAT_CLEAN);
+        // coarse_chan_table
+        let provided_coarse_chan_indices = &self.corr_ctx.provided_coarse_chan_indices;
+        let common_coarse_chan_indices = &self.corr_ctx.common_coarse_chan_indices;
+        let common_good_coarse_chan_indices = &self.corr_ctx.common_good_coarse_chan_indices;
+        for (chan_idx, chan) in self.corr_ctx.coarse_chans.iter().enumerate() {
+            let provided = provided_coarse_chan_indices.contains(&chan_idx);
+            let selected = self.vis_sel.coarse_chan_range.contains(&chan_idx);
+            let common = common_coarse_chan_indices.contains(&chan_idx);
+            let good = common_good_coarse_chan_indices.contains(&chan_idx);
+            let flagged = coarse_chan_flag_idxs.contains(&chan_idx);
+            let row = row![r =>
+                format!("cc{}:", chan_idx),
+                chan.gpubox_number,
+                chan.corr_chan_number,
+                chan.rec_chan_number,
+                format!("{:.4}", chan.chan_centre_hz as f64 / 1e6),
+                if provided {"p"} else {""},
+                if common {"c"} else {""},
+                if good {"g"} else {""},
+                if selected {"s"} else {""},
+                if flagged {"f"} else {""}
+            ];
+            coarse_chan_table.add_row(row);
+        }
+
+        writeln!(
+            f,
+            "Coarse channel details (metafits={}, provided={}, common={}, good={}, select={}, flag={}):\n{}",
+            self.corr_ctx.num_coarse_chans,
+            self.corr_ctx.num_provided_coarse_chans,
+            self.corr_ctx.num_common_coarse_chans,
+            self.corr_ctx.num_common_good_coarse_chans,
+            self.vis_sel.coarse_chan_range.len(),
+            coarse_chan_flag_idxs.len(),
+            coarse_chan_table
+        )?;
+
+        writeln!(
+            f,
+            "Antenna details (all={}, flag={})",
+            self.corr_ctx.metafits_context.num_ants,
+            self.flag_ctx
+                .antenna_flags
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
+                .count(),
+            // format!("\n{}", ant_table)
+        )?;
+
+        writeln!(
+            f,
+            "Baseline Details (all={}, auto={}, select={}, flag={}):",
+            self.corr_ctx.metafits_context.num_baselines,
+            self.corr_ctx.metafits_context.num_ants,
+            self.vis_sel.baseline_idxs.len(),
+            baseline_flag_idxs.len(),
+        )?;
+
+        // TODO: show free memory with https://docs.rs/sys-info/latest/sys_info/fn.mem_info.html
+
+        let num_sel_timesteps = self.vis_sel.timestep_range.len();
+        let num_sel_chans = self.vis_sel.coarse_chan_range.len() * fine_chans_per_coarse;
+        let num_sel_baselines = self.vis_sel.baseline_idxs.len();
+        let num_sel_pols = self.corr_ctx.metafits_context.num_visibility_pols;
+        let mem_selected_bytes = self.vis_sel.estimate_bytes_best(fine_chans_per_coarse);
+        let mem_per_timestep_gib =
+            mem_selected_bytes as f64 / num_sel_timesteps as f64 / 1024.0_f64.powi(3);
+
+        writeln!(
+            f,
+            "Estimated memory usage per timestep =           {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
+            num_sel_chans,
+            num_sel_baselines,
+            std::mem::size_of::<Jones<f32>>(),
+            std::mem::size_of::<f32>(),
+            std::mem::size_of::<bool>(),
+            mem_per_timestep_gib,
+        )?;
+
+        if let Some(num_timesteps) = self.num_timesteps_per_chunk {
+            let num_timesteps =
+                (num_timesteps + 2 * self.num_halo_timesteps).min(num_sel_timesteps);
+            writeln!(
+                f,
+                "Estimated memory per chunk          = {:5}ts * {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
+                num_timesteps,
+                num_sel_chans,
+                num_sel_baselines,
+                std::mem::size_of::<Jones<f32>>(),
+                std::mem::size_of::<f32>(),
+                std::mem::size_of::<bool>(),
+                mem_per_timestep_gib * num_timesteps as f64,
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Estimated memory selected           = {:5}ts * {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
+            num_sel_timesteps,
+            num_sel_chans,
+            num_sel_baselines,
+            std::mem::size_of::<Jones<f32>>(),
+            std::mem::size_of::<f32>(),
+            std::mem::size_of::<bool>(),
+            mem_per_timestep_gib * num_sel_timesteps as f64,
+        )?;
+
+        let avg_mem_per_timestep_gib = (num_avg_chans
+            * num_sel_baselines
+            * num_sel_pols
+            * (std::mem::size_of::<Complex<f32>>()
+                + std::mem::size_of::<f32>()
+                + std::mem::size_of::<bool>())) as f64
+            / 1024.0_f64.powi(3);
+
+        writeln!(
+            f,
+            "Estimated output size               = {:5}ts * {:6}ch * {:6}bl * {:1}pol * ({}<c32> + {}<f32> + {}<bool>) = {:7.02} GiB",
+            num_avg_timesteps,
+            num_avg_chans,
+            num_sel_baselines,
+            num_sel_pols,
+            std::mem::size_of::<Complex<f32>>(),
+            std::mem::size_of::<f32>(),
+            std::mem::size_of::<bool>(),
+            avg_mem_per_timestep_gib * num_avg_timesteps as f64,
+        )?;
+
+        writeln!(f, "Preprocessing Context: \n{}", &self.prep_ctx)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> BirliContext<'a> {
+    /// Prepare to read the selected visibilities in chunks, loading any calibration solutions and
+    /// allocating the arrays which are reused for each chunk. Also returns the outputs of the
+    /// [`IOContext`] which haven't been used.
+    fn into_chunks(self) -> Result<(Chunks<'a>, IOContext), BirliError> {
+        let Self {
+            corr_ctx,
+            mut prep_ctx,
+            vis_sel,
+            flag_ctx,
+            mut io_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_halo_timesteps,
+            ignore_dut1,
+        } = self;
+
+        prep_ctx.calsols = if let Some(ref calsol_file) = io_ctx.aocalsols_in {
+            if corr_ctx
+                .metafits_context
+                .calibration_delays_and_gains_applied
+            {
+                warn!(
+                    "The correlator has already applied calibration delays and gains, applying \
+                    calibration solutions from {} may double-calibrate the data!",
+                    calsol_file.display()
+                );
+            }
+            let calsols = AOCalSols::read_andre_binary(calsol_file)
+                .map_err(CalibrationError::ReadSolutions)?;
+            if calsols.di_jones.dim().0 != 1 {
+                return Err(CalibrationError::TimeblockCount {
+                    num_timeblocks: calsols.di_jones.dim().0,
+                }
+                .into());
+            }
+            let calsol_chans = calsols.di_jones.dim().2;
+            if calsol_chans % corr_ctx.num_coarse_chans != 0 {
+                return Err(BirliError::BadArrayShape(BadArrayShape {
+                    argument: "input AO calibration solutions",
+                    function: "BirliContext::run",
+                    expected: format!(
+                        "a multiple of metafits_num_coarse_chans={}",
+                        corr_ctx.metafits_context.num_metafits_coarse_chans
+                    ),
+                    received: format!("{}", calsol_chans),
+                }));
+            }
+            let num_calsol_fine_chans_per_coarse = calsol_chans / corr_ctx.num_coarse_chans;
+            Some(
+                calsols
+                    .di_jones
+                    .index_axis(Axis(0), 0)
+                    .slice(s![
+                        ..,
+                        (vis_sel.coarse_chan_range.start * num_calsol_fine_chans_per_coarse)
+                            ..(vis_sel.coarse_chan_range.end * num_calsol_fine_chans_per_coarse)
+                    ])
+                    .to_owned(),
+            )
+        } else {
+            None
+        };
+
+        // write a bundled aoflagger strategy once, rather than for every chunk.
+        #[cfg(feature = "aoflagger")]
+        {
+            prep_ctx.aoflagger_strategy = prep_ctx
+                .aoflagger_strategy
+                .take()
+                .map(AOFlaggerStrategy::written)
+                .transpose()?;
+        }
+
+        let antenna_positions = prep_ctx.get_tiles(&corr_ctx.metafits_context);
+        let dut1 = if ignore_dut1 {
+            hifitime::Duration::from_total_nanoseconds(0)
+        } else {
+            hifitime::Duration::from_f64(
+                corr_ctx.metafits_context.dut1.unwrap_or(0.0),
+                Unit::Second,
+            )
+        };
+
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let chunk_size = if let Some(steps) = num_timesteps_per_chunk {
+            steps
+        } else {
+            vis_sel.timestep_range.len()
+        };
+
+        // Allocate our big arrays once, reuse them for each chunk unless the chunk shape changes.
+        // Each chunk may be read with a halo of extra timesteps either side, which are only used
+        // for context during RFI flagging and flag extension.
+        let chunk_vis_sel = VisSelection {
+            timestep_range: (vis_sel.timestep_range.start
+                ..(vis_sel.timestep_range.start + chunk_size + 2 * num_halo_timesteps)
+                    .min(vis_sel.timestep_range.end)),
+            ..vis_sel.clone()
+        };
+        let jones_array = chunk_vis_sel.allocate_jones(fine_chans_per_coarse)?;
+        let flag_array = chunk_vis_sel.allocate_flags(fine_chans_per_coarse)?;
+        let weight_array = chunk_vis_sel.allocate_weights(fine_chans_per_coarse)?;
+
+        let chunks = Chunks {
+            next_timestep: vis_sel.timestep_range.start,
+            passband_gains_out: io_ctx.passband_gains_out.take(),
+            corr_ctx,
+            prep_ctx,
+            vis_sel,
+            flag_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_halo_timesteps,
+            chunk_size,
+            antenna_positions,
+            dut1,
+            jones_array,
+            flag_array,
+            weight_array,
+        };
+        Ok((chunks, io_ctx))
+    }
+
+    /// Read and preprocess the selected visibilities in chunks of
+    /// [`BirliContext::num_timesteps_per_chunk`] timesteps, yielding each chunk averaged by
+    /// [`BirliContext::avg_time`] and [`BirliContext::avg_freq`] instead of writing it. The
+    /// outputs of the [`IOContext`] are ignored, except for the passband gains.
+    ///
+    /// # Errors
+    ///
+    /// can raise:
+    /// - `BadArrayShape` if the calibration solutions don't match the visibilities.
+    /// - `InsufficientMemory` if the arrays for a chunk can't be allocated.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use birli::{io::IOContext, BirliContextBuilder};
+    ///
+    /// let io_ctx = IOContext {
+    ///     metafits_in: "tests/data/1297526432_mwax/1297526432.metafits".into(),
+    ///     gpufits_in: vec![
+    ///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits".into(),
+    ///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_001.fits".into(),
+    ///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_000.fits".into(),
+    ///         "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_001.fits".into(),
+    ///     ],
+    ///     ..IOContext::default()
+    /// };
+    /// let birli_ctx = BirliContextBuilder::new(io_ctx)
+    ///     .unwrap()
+    ///     .num_timesteps_per_chunk(Some(1))
+    ///     .build()
+    ///     .unwrap();
+    /// for chunk in birli_ctx.chunks().unwrap() {
+    ///     let chunk = chunk.unwrap();
+    ///     assert_eq!(chunk.timestamps.len(), 1);
+    ///     assert_eq!(chunk.jones_array.dim().1, chunk.frequencies_hz.len());
+    ///     assert_eq!(chunk.uvws.dim(), (1, chunk.ant_pairs.len()));
+    /// }
+    /// ```
+    pub fn chunks(self) -> Result<Chunks<'a>, BirliError> {
+        Ok(self.into_chunks()?.0)
+    }
+
+    /// Read, Preprocess and write corrected visibilities chunks.
+    ///
+    /// # Errors
+    ///
+    /// can raise:
+    /// - `BadArrayShape` if the shape of the calibration solutions
+    ///     is incompatible with the visibility shape.
+    /// - `CalibrationError` if the calibration solutions can't be read, or have more than one
+    ///   timeblock.
+    /// - `WriteError` if an output can't be initialised, written or finalised.
+    /// - preprocessing errors
+    pub fn run(self) -> Result<HashMap<String, Duration>, BirliError> {
+        let (mut chunks, io_ctx) = self.into_chunks()?;
+        let Chunks {
+            ref corr_ctx,
+            ref prep_ctx,
+            ref vis_sel,
+            avg_time,
+            avg_freq,
+            ref antenna_positions,
+            dut1,
+            ..
+        } = chunks;
+
+        // ////////// //
+        // Prepare IO //
+        // ////////// //
+
+        let vis_ctx = VisContext::from_mwalib(
+            corr_ctx,
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            &vis_sel.baseline_idxs,
+            avg_time,
+            avg_freq,
+        );
+
+        // TODO: move phase_centre, array_pos out of prep_ctx
+        let obs_ctx = ObsContext {
+            phase_centre: prep_ctx.phase_centre,
+            array_pos: prep_ctx.array_pos,
+            ..ObsContext::from_mwalib(&corr_ctx.metafits_context)
+        };
+
+        let mwa_ctx = MwaObsContext::from_mwalib(&corr_ctx.metafits_context);
+
+        let args_strings = env::args().collect_vec();
+        let cmd_line = shlex::join(args_strings.iter().map(String::as_str));
+        let application = format!("{} {}", PKG_NAME, PKG_VERSION);
+        let message = prep_ctx.as_comment();
+        let history = History {
+            cmd_line: Some(&cmd_line),
+            application: Some(&application),
+            message: Some(&message),
+        };
+        let antenna_names = corr_ctx
+            .metafits_context
+            .antennas
+            .iter()
+            .map(|a| a.tile_name.clone())
+            .collect_vec();
+        let antenna_positions = antenna_positions.clone();
+        let draw_progress = prep_ctx.draw_progress;
+        // ephemerides and drift scans are phased to a different centre in each timestep, which
+        // the writers don't support, so the UVWs of the main outputs are rewritten once they are
+        // finalised.
+        let tracked_outs = chunks
+            .tracks_phase_centre()
+            .then(|| (io_ctx.uvfits_out.clone(), io_ctx.ms_out.clone()));
+        // the main outputs, followed by any outputs phased to other centres.
+        let phased_outs = std::iter::once(PhasedOutput {
+            phase_centre: obs_ctx.phase_centre,
+            uvfits_out: io_ctx.uvfits_out,
+            ms_out: io_ctx.ms_out,
+        })
+        .chain(io_ctx.phased_outs);
+        let mut writers = phased_outs
+            .map(|phased_out| {
+                let obs_ctx = ObsContext {
+                    phase_centre: phased_out.phase_centre,
+                    ..obs_ctx.clone()
+                };
+                let uvfits_writer = phased_out.uvfits_out.map(|uvfits_out| {
+                    let output = uvfits_out.display().to_string();
+                    let writer = with_increment_duration!("init", {
+                        UvfitsWriter::from_marlu(
+                            &uvfits_out,
+                            &vis_ctx,
+                            obs_ctx.array_pos,
+                            obs_ctx.phase_centre,
+                            dut1,
+                            obs_ctx.name.as_deref(),
+                            antenna_names.clone(),
+                            antenna_positions.clone(),
+                            Some(&history),
+                        )
+                        .map_err(write_error(&output))?
+                    });
+                    Ok::<_, BirliError>((writer, output))
+                });
+                let ms_writer = phased_out.ms_out.map(|ms_out| {
+                    let output = ms_out.display().to_string();
+                    let writer = MeasurementSetWriter::new(
+                        &ms_out,
+                        obs_ctx.phase_centre,
+                        obs_ctx.array_pos,
+                        antenna_positions.clone(),
+                        dut1,
+                    );
+                    with_increment_duration!("init", {
+                        writer
+                            .initialize_mwa(
+                                &vis_ctx,
+                                &obs_ctx,
+                                &mwa_ctx,
+                                Some(&history),
+                                &vis_sel.coarse_chan_range,
+                            )
+                            .map_err(write_error(&output))?;
+                    });
+                    Ok::<_, BirliError>((writer, output))
+                });
+                Ok::<_, BirliError>((
+                    obs_ctx.phase_centre,
+                    uvfits_writer.transpose()?,
+                    ms_writer.transpose()?,
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        #[cfg(feature = "aoflagger")]
+        let (aoflagger_version, aoflagger_strategy) = {
+            let mut major = 0;
+            let mut minor = 0;
+            let mut subminor = 0;
+            unsafe {
+                aoflagger_sys::cxx_aoflagger_new().GetVersion(
+                    &mut major,
+                    &mut minor,
+                    &mut subminor,
+                );
+            }
+            (
+                Some(format!("v{major}.{minor}.{subminor}")),
+                prep_ctx
+                    .aoflagger_strategy
+                    .as_ref()
+                    .map(ToString::to_string),
+            )
+        };
+        #[cfg(not(feature = "aoflagger"))]
+        let (aoflagger_version, aoflagger_strategy) = (None, None);
+
+        let mut flag_file_set = io_ctx
+            .flag_template
+            .map(|flag_template| {
+                FlagFileSet::new(
+                    &flag_template,
+                    corr_ctx,
+                    vis_sel,
+                    aoflagger_version,
+                    aoflagger_strategy,
+                )
+                .map(|flag_file_set| (flag_file_set, flag_template.clone()))
+                .map_err(write_error(&flag_template))
+            })
+            .transpose()?;
+
+        // //////// //
+        // Chunking //
+        // //////// //
+
+        while let Some(chunk) = chunks.read_next(writers.len() > 1) {
+            let ChunkViews {
+                corr_ctx,
+                prep_ctx,
+                vis_sel: chunk_vis_sel,
+                mut jones_array,
+                mut weight_array,
+                mut flag_array,
+                unphased_jones_array,
+            } = chunk?;
+
+            // output flags (before averaging)
+            if let Some((flag_file_set, flag_template)) = flag_file_set.as_mut() {
+                with_increment_duration!(
+                    "write",
+                    flag_file_set
+                        .write_flag_array(flag_array.view(), draw_progress)
+                        .map_err(write_error(flag_template))?
+                );
+            }
+
+            let chunk_vis_ctx = VisContext::from_mwalib(
+                corr_ctx,
+                &chunk_vis_sel.timestep_range,
+                &chunk_vis_sel.coarse_chan_range,
+                &chunk_vis_sel.baseline_idxs,
+                avg_time,
+                avg_freq,
+            );
+
+            for (out_idx, (phase_centre, uvfits_writer, ms_writer)) in
+                writers.iter_mut().enumerate()
+            {
+                // the first outputs are already phased to the main phase centre. Flags and
+                // weights do not depend on the phase centre, so they are reused.
+                if let Some(unphased_jones_array) =
+                    unphased_jones_array.as_ref().filter(|_| out_idx > 0)
+                {
+                    jones_array.assign(unphased_jones_array);
+                    prep_ctx.preprocess_phased(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        weight_array.view_mut(),
+                        flag_array.view_mut(),
+                        &chunk_vis_sel,
+                        Some(*phase_centre),
+                    )?;
+                }
+
+                // output uvfits
+                if let Some((uvfits_writer, output)) = uvfits_writer.as_mut() {
+                    with_increment_duration!(
+                        "write",
+                        uvfits_writer
+                            .write_vis(
+                                jones_array.view(),
+                                weight_array.view(),
+                                &chunk_vis_ctx,
+                                draw_progress,
+                            )
+                            .map_err(write_error(output))?
+                    );
+                }
+
+                // output ms
+                if let Some((ms_writer, output)) = ms_writer.as_mut() {
+                    with_increment_duration!(
+                        "write",
+                        ms_writer
+                            .write_vis(
+                                jones_array.view(),
+                                weight_array.view(),
+                                &chunk_vis_ctx,
+                                draw_progress,
+                            )
+                            .map_err(write_error(output))?
+                    );
+                }
+            }
+        }
+
+        for (_, uvfits_writer, ms_writer) in &mut writers {
+            // Finalise the uvfits writer.
+            if let Some((uvfits_writer, output)) = uvfits_writer.as_mut() {
+                with_increment_duration!(
+                    "write",
+                    uvfits_writer.finalise().map_err(write_error(output))?
+                );
+            };
+
+            // Finalise the MS writer.
+            if let Some((ms_writer, output)) = ms_writer.as_mut() {
+                with_increment_duration!(
+                    "write",
+                    ms_writer.finalise().map_err(write_error(output))?
+                );
+            };
+        }
+
+        if let Some((uvfits_out, ms_out)) = tracked_outs {
+            let phase_centres = vis_ctx
+                .timeseries(true, true)
+                .map(|epoch| chunks.phase_centre_at(epoch))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(uvfits_out) = uvfits_out {
+                let output = uvfits_out.display().to_string();
+                with_increment_duration!(
+                    "write",
+                    rewrite_uvfits_uvws(
+                        uvfits_out,
+                        &vis_ctx,
+                        obs_ctx.array_pos,
+                        &antenna_positions,
+                        dut1,
+                        &phase_centres,
+                    )
+                    .map_err(write_error(&output))?
+                );
+            }
+            if let Some(ms_out) = ms_out {
+                let output = ms_out.display().to_string();
+                with_increment_duration!(
+                    "write",
+                    rewrite_ms_uvws(
+                        ms_out,
+                        &vis_ctx,
+                        obs_ctx.array_pos,
+                        &antenna_positions,
+                        dut1,
+                        &phase_centres,
+                    )
+                    .map_err(write_error(&output))?
+                );
+            }
+        }
+
+        // Finalise the mwaf files.
+        if let Some((flag_file_set, flag_template)) = flag_file_set {
+            flag_file_set
+                .finalise()
+                .map_err(write_error(&flag_template))?;
+        }
+
+        // Copy the global durations out to the caller. They are only timings, so they are still
+        // usable if another thread panicked while holding the lock.
+        let durations = crate::DURATIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        Ok(durations)
+    }
+}
+
+/// Wrap an error from writing `output` in a [`BirliError::WriteError`].
+fn write_error<E: Into<IOError>>(output: &str) -> impl FnOnce(E) -> BirliError + '_ {
+    move |err| BirliError::WriteError {
+        output: output.to_string(),
+        source: Box::new(err.into()),
+    }
+}
+
+/// A chunk of preprocessed visibilities, averaged by [`BirliContext::avg_time`] and
+/// [`BirliContext::avg_freq`], yielded by [`Chunks`].
+///
+/// The arrays are owned, so they are always a copy of the visibilities in [`Chunks`], even
+/// without averaging.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// The mwalib timestep indices of the chunk, before averaging
+    pub timestep_range: Range<usize>,
+    /// Visibilities, `[timestep][channel][baseline]`
+    pub jones_array: Array3<Jones<f32>>,
+    /// Weights, `[timestep][channel][baseline]`, which are not positive where flagged
+    pub weight_array: Array3<f32>,
+    /// Flags, `[timestep][channel][baseline]`
+    pub flag_array: Array3<bool>,
+    /// The UVWs of each baseline at each timestep in metres, `[timestep][baseline]`
+    pub uvws: Array2<UVW>,
+    /// The centroid of each timestep
+    pub timestamps: Vec<Epoch>,
+    /// The centre frequency of each channel in Hz
+    pub frequencies_hz: Vec<f64>,
+    /// The antenna indices of each baseline
+    pub ant_pairs: Vec<(usize, usize)>,
+}
+
+/// An iterator over chunks of preprocessed visibilities, from [`BirliContext::chunks`].
+///
+/// The visibilities of each chunk are read into the same arrays, so only one chunk (and its halo)
+/// is held in memory at a time, along with the [`Chunk`]s which haven't been dropped.
+pub struct Chunks<'a> {
+    corr_ctx: CorrelatorContext,
+    prep_ctx: PreprocessContext<'a>,
+    vis_sel: VisSelection,
+    flag_ctx: FlagContext,
+    avg_time: usize,
+    avg_freq: usize,
+    num_timesteps_per_chunk: Option<usize>,
+    num_halo_timesteps: usize,
+    chunk_size: usize,
+    next_timestep: usize,
+    passband_gains_out: Option<PathBuf>,
+    antenna_positions: Vec<XyzGeodetic>,
+    dut1: hifitime::Duration,
+    jones_array: Array3<Jones<f32>>,
+    flag_array: Array3<bool>,
+    weight_array: Array3<f32>,
+}
+
+/// A chunk which has been read into the arrays of [`Chunks`], before it is averaged or written.
+struct ChunkViews<'b, 'a> {
+    corr_ctx: &'b CorrelatorContext,
+    prep_ctx: &'b PreprocessContext<'a>,
+    /// The timesteps of the chunk, without its halo
+    vis_sel: VisSelection,
+    jones_array: ArrayViewMut3<'b, Jones<f32>>,
+    /// Weights, with the flags baked in
+    weight_array: ArrayViewMut3<'b, f32>,
+    flag_array: ArrayViewMut3<'b, bool>,
+    /// A copy of the visibilities before any phased preprocessing, if requested
+    unphased_jones_array: Option<Array3<Jones<f32>>>,
+}
+
+impl<'a> Chunks<'a> {
+    /// The correlator context of the observation.
+    pub const fn corr_ctx(&self) -> &CorrelatorContext {
+        &self.corr_ctx
+    }
+
+    /// The preprocessing options the chunks are preprocessed with.
+    pub const fn prep_ctx(&self) -> &PreprocessContext<'a> {
+        &self.prep_ctx
+    }
+
+    /// Whether the main phase centre changes with time, i.e. the geometric corrections track an
+    /// ephemeris or a drift scan.
+    const fn tracks_phase_centre(&self) -> bool {
+        let prep_ctx = &self.prep_ctx;
+        prep_ctx.correct_geometry
+            && (prep_ctx.ephemeris.is_some()
+                || (prep_ctx.near_field.is_none() && prep_ctx.drift.is_some()))
+    }
+
+    /// The main phase centre at `epoch`, which is interpolated from the ephemeris or follows the
+    /// drift scan (using the DUT1 of the metafits) if [`Self::tracks_phase_centre`].
+    fn phase_centre_at(&self, epoch: Epoch) -> Result<RADec, EphemerisError> {
+        let prep_ctx = &self.prep_ctx;
+        if !self.tracks_phase_centre() {
+            return Ok(prep_ctx.phase_centre);
+        }
+        match (prep_ctx.ephemeris.as_ref(), prep_ctx.drift) {
+            (Some(ephemeris), _) => ephemeris.radec_at(epoch),
+            (None, Some(azel)) => {
+                let meta_dut1 = hifitime::Duration::from_f64(
+                    self.corr_ctx.metafits_context.dut1.unwrap_or(0.0),
+                    Unit::Second,
+                );
+                Ok(drift_phase_centre(
+                    azel,
+                    epoch,
+                    prep_ctx.array_pos,
+                    meta_dut1,
+                ))
+            }
+            (None, None) => Ok(prep_ctx.phase_centre),
+        }
+    }
+
+    /// Read and preprocess the next chunk, keeping a copy of the unphased visibilities if
+    /// `keep_unphased`.
+    fn read_next(&mut self, keep_unphased: bool) -> Option<Result<ChunkViews<'_, 'a>, BirliError>> {
+        if self.next_timestep >= self.vis_sel.timestep_range.end {
+            return None;
+        }
+        let chunk_vis_sel = VisSelection {
+            timestep_range: self.next_timestep
+                ..(self.next_timestep + self.chunk_size).min(self.vis_sel.timestep_range.end),
+            ..self.vis_sel.clone()
+        };
+        self.next_timestep = chunk_vis_sel.timestep_range.end;
+        Some(self.read_chunk(chunk_vis_sel, keep_unphased))
+    }
+
+    fn read_chunk(
+        &mut self,
+        chunk_vis_sel: VisSelection,
+        keep_unphased: bool,
+    ) -> Result<ChunkViews<'_, 'a>, BirliError> {
+        let Self {
+            corr_ctx,
+            prep_ctx,
+            vis_sel,
+            flag_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_halo_timesteps,
+            chunk_size,
+            passband_gains_out,
+            jones_array,
+            flag_array,
+            weight_array,
+            ..
+        } = self;
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let weight_factor = VisContext::from_mwalib(
+            corr_ctx,
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            &vis_sel.baseline_idxs,
+            *avg_time,
+            *avg_freq,
+        )
+        .weight_factor();
+
+        if num_timesteps_per_chunk.is_some() {
+            info!(
+                "processing timestep chunk {:?} of {:?} % {}",
+                chunk_vis_sel.timestep_range,
+                vis_sel.timestep_range.clone(),
+                chunk_size
+            );
+        }
+        let (halo_vis_sel, halo_core) =
+            halo_selection(vis_sel, &chunk_vis_sel, *num_halo_timesteps);
+
+        // only reallocate arrays if the chunk dimensions have changed.
+        let chunk_dims = halo_vis_sel.get_shape(fine_chans_per_coarse);
+        let (mut jones_array, mut flag_array, mut weight_array) = if jones_array.dim() == chunk_dims
+        {
+            (
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                weight_array.view_mut(),
+            )
+        } else {
+            (
+                jones_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
+                flag_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
+                weight_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
+            )
+        };
+
+        read_vis_flags_weights(
+            corr_ctx,
+            flag_ctx,
+            &halo_vis_sel,
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            weight_array.view_mut(),
+            weight_factor,
+            prep_ctx.draw_progress,
+        )?;
+
+        // the passband gains written out are estimated once, from the uncorrected visibilities of
+        // the first chunk. With `empirical_passband`, the gains applied to each chunk are
+        // estimated separately by `prep_ctx`.
+        if let Some(path) = passband_gains_out.take() {
+            let gains = with_increment_duration!(
+                "estimate_passband",
+                estimate_passband_gains(
+                    jones_array.view(),
+                    flag_array.view(),
+                    fine_chans_per_coarse
+                )?
+            );
+            write_passband_gains(&path, &gains)?;
+            info!(
+                "wrote passband gains estimated from timesteps {:?} to {}",
+                halo_vis_sel.timestep_range,
+                path.display()
+            );
+        }
+
+        // the halo only provides context for RFI flagging, so only the steps up to and including
+        // flagging are applied to it.
+        prep_ctx.preprocess_unphased_steps(
+            corr_ctx,
+            jones_array.view_mut(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            &halo_vis_sel,
+            StepRange::UntilFlag,
+        )?;
+        prep_ctx.preprocess_phased_steps(
+            corr_ctx,
+            jones_array.view_mut(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            &halo_vis_sel,
+            None,
+            StepRange::UntilFlag,
+        )?;
+
+        let unphased_jones_array = {
+            let (mut jones_array, mut weight_array, mut flag_array) = (
+                jones_array.slice_mut(s![halo_core.clone(), .., ..]),
+                weight_array.slice_mut(s![halo_core.clone(), .., ..]),
+                flag_array.slice_mut(s![halo_core.clone(), .., ..]),
+            );
+            prep_ctx.preprocess_unphased_steps(
+                corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                &chunk_vis_sel,
+                StepRange::AfterFlag,
+            )?;
+            // keep a copy of the unphased visibilities of the chunk for any other phase centres.
+            let unphased_jones_array = keep_unphased.then(|| jones_array.to_owned());
+            prep_ctx.preprocess_phased_steps(
+                corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                &chunk_vis_sel,
+                None,
+                StepRange::AfterFlag,
+            )?;
+
+            // detect bad tiles from the autocorrelations of this chunk (without its halo), and
+            // flag them in this chunk only, so that earlier chunks which have already been
+            // written are consistent.
+            if let Some(threshold) = flag_ctx.bad_tile_threshold {
+                flag_bad_tiles(
+                    corr_ctx,
+                    flag_ctx,
+                    &chunk_vis_sel,
+                    jones_array.view(),
+                    flag_array.view_mut(),
+                    threshold,
+                );
+            }
+            unphased_jones_array
+        };
+
+        // extend flags over the halo, so that flags are dilated across chunk boundaries.
+        if !flag_ctx.extension.is_noop() {
+            let flagged_ants = with_increment_duration!(
+                "extend_flags",
+                extend_flags(
+                    flag_array.view_mut(),
+                    &halo_vis_sel.get_ant_pairs(&corr_ctx.metafits_context),
+                    &flag_ctx.extension,
+                )
+            );
+            if !flagged_ants.is_empty() {
+                info!(
+                    "flagged antennas {:?} in timesteps {:?} by occupancy",
+                    flagged_ants, halo_vis_sel.timestep_range
+                );
+            }
+        }
+
+        // discard the halo
+        let (jones_array, flag_array, mut weight_array) = (
+            jones_array.slice_move(s![halo_core.clone(), .., ..]),
+            flag_array.slice_move(s![halo_core.clone(), .., ..]),
+            weight_array.slice_move(s![halo_core, .., ..]),
+        );
+
+        // bake flags into weights
+        for (weight, flag) in izip!(weight_array.iter_mut(), flag_array.iter()) {
+            *weight = if *flag {
+                -(*weight).abs()
+            } else {
+                (*weight).abs()
+            } as f32;
+        }
+
+        Ok(ChunkViews {
+            corr_ctx,
+            prep_ctx,
+            vis_sel: chunk_vis_sel,
+            jones_array,
+            weight_array,
+            flag_array,
+            unphased_jones_array,
+        })
+    }
+}
+
+/// The timesteps of a chunk, extended by `num_halo_timesteps` either side within the selection,
+/// and the range of the chunk's own timesteps within the extended selection.
+fn halo_selection(
+    vis_sel: &VisSelection,
+    chunk_vis_sel: &VisSelection,
+    num_halo_timesteps: usize,
+) -> (VisSelection, Range<usize>) {
+    let halo_vis_sel = VisSelection {
+        timestep_range: (chunk_vis_sel
+            .timestep_range
+            .start
+            .saturating_sub(num_halo_timesteps)
+            .max(vis_sel.timestep_range.start)
+            ..(chunk_vis_sel.timestep_range.end + num_halo_timesteps)
+                .min(vis_sel.timestep_range.end)),
+        ..vis_sel.clone()
+    };
+    let halo_start = chunk_vis_sel.timestep_range.start - halo_vis_sel.timestep_range.start;
+    let halo_core = halo_start..halo_start + chunk_vis_sel.timestep_range.len();
+    (halo_vis_sel, halo_core)
+}
+
+/// Populate the flags, visibilities and weights of `vis_sel`.
+#[allow(clippy::too_many_arguments)]
+fn read_vis_flags_weights(
+    corr_ctx: &CorrelatorContext,
+    flag_ctx: &FlagContext,
+    vis_sel: &VisSelection,
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    mut flag_array: ArrayViewMut3<bool>,
+    mut weight_array: ArrayViewMut3<f32>,
+    weight_factor: f64,
+    draw_progress: bool,
+) -> Result<(), BirliError> {
+    flag_ctx.set_flags(
+        flag_array.view_mut(),
+        &vis_sel.timestep_range,
+        &vis_sel.coarse_chan_range,
+        &vis_sel.get_ant_pairs(&corr_ctx.metafits_context),
+    )?;
+    with_increment_duration!(
+        "read",
+        vis_sel.read_mwalib(
+            corr_ctx,
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            draw_progress,
+        )?
+    );
+    weight_array.fill(weight_factor as f32);
+    with_increment_duration!(
+        "read",
+        read_mwax_weights(corr_ctx, vis_sel, weight_array, flag_array, weight_factor)?
+    );
+    Ok(())
+}
+
+/// Detect bad tiles from the autocorrelations of `chunk_vis_sel` and flag all of their baselines
+/// in `flag_array`. The detections only apply to this chunk, so they are kept apart from
+/// [`FlagContext::antenna_flags`], which is only used to avoid warning about tiles which are
+/// already flagged.
+fn flag_bad_tiles(
+    corr_ctx: &CorrelatorContext,
+    flag_ctx: &FlagContext,
+    chunk_vis_sel: &VisSelection,
+    jones_array: ArrayView3<Jones<f32>>,
+    mut flag_array: ArrayViewMut3<bool>,
+    threshold: f32,
+) {
+    let ant_pairs = chunk_vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+    let diagnostics = with_increment_duration!(
+        "flag_bad_tiles",
+        detect_bad_tiles(jones_array, flag_array.view(), &ant_pairs, threshold)
+    );
+    info!(
+        "tile diagnostics for timesteps {:?}:\n{}",
+        chunk_vis_sel.timestep_range,
+        tile_diagnostics_table(&diagnostics, &corr_ctx.metafits_context.antennas)
+    );
+    let mut bad_ants = vec![false; corr_ctx.metafits_context.num_ants];
+    for diagnostic in diagnostics.iter().filter(|d| d.bad) {
+        if !flag_ctx.antenna_flags[diagnostic.ant_idx] {
+            warn!(
+                "flagging bad tile {} ({}) in timesteps {:?}",
+                diagnostic.ant_idx,
+                corr_ctx.metafits_context.antennas[diagnostic.ant_idx].tile_name,
+                chunk_vis_sel.timestep_range
+            );
+        }
+        bad_ants[diagnostic.ant_idx] = true;
+    }
+    for (&(ant1, ant2), mut flag_baseline_view) in
+        izip!(&ant_pairs, flag_array.axis_iter_mut(Axis(2)))
+    {
+        if bad_ants[ant1] || bad_ants[ant2] {
+            flag_baseline_view.fill(true);
+        }
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Chunk, BirliError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (avg_time, avg_freq, dut1) = (self.avg_time, self.avg_freq, self.dut1);
+        let array_pos = self.prep_ctx.array_pos;
+        let antenna_positions = self.antenna_positions.clone();
+        let phase_centres = |chunks: &Self, epochs: &[Epoch]| {
+            epochs
+                .iter()
+                .map(|&epoch| chunks.phase_centre_at(epoch))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let ChunkViews {
+            corr_ctx,
+            vis_sel: chunk_vis_sel,
+            jones_array,
+            weight_array,
+            flag_array,
+            ..
+        } = match self.read_next(false)? {
+            Ok(chunk) => chunk,
+            Err(err) => return Some(Err(err)),
+        };
+        let vis_ctx = VisContext::from_mwalib(
+            corr_ctx,
+            &chunk_vis_sel.timestep_range,
+            &chunk_vis_sel.coarse_chan_range,
+            &chunk_vis_sel.baseline_idxs,
+            avg_time,
+            avg_freq,
+        );
+
+        // average, in the same way as the writers. Without averaging, the chunk is still copied
+        // out of the arrays which the next chunk is read into.
+        let (jones_array, weight_array, flag_array) = if vis_ctx.trivial_averaging() {
+            (
+                jones_array.to_owned(),
+                weight_array.to_owned(),
+                flag_array.to_owned(),
+            )
+        } else {
+            let avg_dims = vis_ctx.avg_dims();
+            let mut avg_jones_array = Array3::<Jones<f32>>::zeros(avg_dims);
+            let mut avg_weight_array = Array3::<f32>::zeros(avg_dims);
+            let mut avg_flag_array = Array3::<bool>::from_elem(avg_dims, false);
+            let (num_timesteps, num_chans, _) = jones_array.dim();
+            for ((timestep_idx, chan_idx, bl_idx), avg_jones) in avg_jones_array.indexed_iter_mut()
+            {
+                let window = s![
+                    (timestep_idx * avg_time)..((timestep_idx + 1) * avg_time).min(num_timesteps),
+                    (chan_idx * avg_freq)..((chan_idx + 1) * avg_freq).min(num_chans),
+                    bl_idx..=bl_idx
+                ];
+                let (jones_chunk, weight_chunk) =
+                    (jones_array.slice(window), weight_array.slice(window));
+                average_chunk_f64!(
+                    jones_chunk,
+                    weight_chunk,
+                    *avg_jones,
+                    avg_weight_array[[timestep_idx, chan_idx, bl_idx]],
+                    avg_flag_array[[timestep_idx, chan_idx, bl_idx]]
+                );
+            }
+            (avg_jones_array, avg_weight_array, avg_flag_array)
+        };
+        let timestamps = vis_ctx.timeseries(true, true).collect_vec();
+        let frequencies_hz = vis_ctx.avg_frequencies_hz();
+        let ant_pairs = vis_ctx.sel_baselines.clone();
+
+        let phase_centres = match phase_centres(self, &timestamps) {
+            Ok(phase_centres) => phase_centres,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let uvws = calc_rephased_uvws(
+            &vis_ctx,
+            array_pos,
+            &antenna_positions,
+            dut1,
+            &phase_centres,
+        )
+        .collect::<Vec<_>>();
+        let num_uvws = uvws.len();
+        let uvws = match Array2::from_shape_vec((timestamps.len(), ant_pairs.len()), uvws) {
+            Ok(uvws) => uvws,
+            Err(_) => {
+                return Some(Err(BirliError::BadArrayShape(BadArrayShape {
+                    argument: "uvws",
+                    function: "Chunks::next",
+                    expected: format!(
+                        "a UVW for each of {} baselines of {} timesteps",
+                        ant_pairs.len(),
+                        timestamps.len()
+                    ),
+                    received: format!("{} UVWs", num_uvws),
+                })))
+            }
+        };
+
+        Some(Ok(Chunk {
+            timestep_range: chunk_vis_sel.timestep_range,
+            jones_array,
+            weight_array,
+            flag_array,
+            uvws,
+            timestamps,
+            frequencies_hz,
+            ant_pairs,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use tempfile::tempdir;
+
+    use super::{halo_selection, BirliContextBuilder, PipelineError};
+    use crate::{
+        calibration::CalibrationError,
+        ephemeris::{Ephemeris, EphemerisEntry},
+        flags::FlagExtension,
+        io::{IOContext, PhasedOutput},
+        marlu::{constants::VEL_C, fitsio::FitsFile, fitsio_sys, hifitime::Epoch, RADec},
+        marlu::{
+            mwalib::CorrelatorContext,
+            ndarray::{Array3, ArrayViewMut3},
+            Jones,
+        },
+        preprocessing::{Correction, PreprocessStep, StagePosition, StepOrder},
+        test_common::{get_mwax_context, get_mwax_data_paths},
+        Axis, BirliContext, BirliError, FlagContext, PreprocessContext, VisSelection,
+    };
+    use itertools::Itertools;
+    use std::{
+        ops::Range,
+        sync::{Arc, Mutex},
+    };
+
+    fn get_mwax_io_ctx() -> IOContext {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        IOContext {
+            metafits_in: metafits_path.into(),
+            gpufits_in: gpufits_paths.iter().map(Into::into).collect(),
+            ..IOContext::default()
+        }
+    }
+
+    #[test]
+    fn test_builder_defaults_match_cli() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let cli_ctx = BirliContext::from_args(&args).unwrap();
+
+        let birli_ctx = BirliContextBuilder::new(get_mwax_io_ctx())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            format!("{}", birli_ctx.prep_ctx),
+            format!("{}", cli_ctx.prep_ctx)
+        );
+        assert_eq!(
+            birli_ctx.prep_ctx.as_comment(),
+            cli_ctx.prep_ctx.as_comment()
+        );
+        assert_eq!(
+            birli_ctx.vis_sel.timestep_range,
+            cli_ctx.vis_sel.timestep_range
+        );
+        assert_eq!(birli_ctx.flag_ctx.flag_dc, cli_ctx.flag_ctx.flag_dc);
+        assert_eq!(birli_ctx.avg_time, cli_ctx.avg_time);
+        assert_eq!(birli_ctx.avg_freq, cli_ctx.avg_freq);
+        assert_eq!(
+            birli_ctx.num_timesteps_per_chunk,
+            cli_ctx.num_timesteps_per_chunk
+        );
+    }
+
+    #[test]
+    fn test_builder_run_matches_cli() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let cli_path = tmp_dir.path().join("cli.uvfits");
+        let builder_path = tmp_dir.path().join("builder.uvfits");
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli", "-m", metafits_path,
+            "--no-draw-progress",
+            "-u", cli_path.to_str().unwrap(),
+            "--avg-freq-factor", "2",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        BirliContext::from_args(&args).unwrap().run().unwrap();
+
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        builder
+            .prep_ctx(prep_ctx)
+            .avg_freq(2)
+            .uvfits_out(&builder_path)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        // compare the primary HDU, which has the visibilities. The comments of the antenna table
+        // header can contain junk.
+        let read = |path| {
+            let bytes = std::fs::read(path).unwrap();
+            let antenna_table_start = bytes
+                .windows(8)
+                .position(|window| window == b"XTENSION")
+                .unwrap();
+            bytes[..antenna_table_start].to_vec()
+        };
+        assert!(read(&cli_path) == read(&builder_path));
+    }
+
+    #[test]
+    fn test_builder_invalid_settings() {
+        let result = BirliContextBuilder::new(get_mwax_io_ctx())
+            .unwrap()
+            .avg_time(0)
+            .build();
+        assert!(matches!(
+            result,
+            Err(BirliError::PipelineError(PipelineError::InvalidSetting {
+                setting: "avg_time",
+                ..
+            }))
+        ));
+
+        let result = BirliContextBuilder::new(get_mwax_io_ctx())
+            .unwrap()
+            .avg_time(2)
+            .num_timesteps_per_chunk(Some(3))
+            .build();
+        assert!(matches!(
+            result,
+            Err(BirliError::PipelineError(PipelineError::InvalidSetting {
+                setting: "num_timesteps_per_chunk",
+                ..
+            }))
+        ));
+
+        // flags can't depend on the phase centre when there are several
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            step_order: "cable,digital,passband,geometry,flag,calibrate"
+                .parse::<StepOrder>()
+                .unwrap(),
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        let result = builder
+            .prep_ctx(prep_ctx)
+            .phased_out(PhasedOutput {
+                phase_centre: RADec::new(0., 0.),
+                uvfits_out: Some("a.uvfits".into()),
+                ms_out: None,
+            })
+            .build();
+        assert!(matches!(
+            result,
+            Err(BirliError::PipelineError(PipelineError::InvalidSetting {
+                setting: "step_order",
+                ..
+            }))
+        ));
+
+        // nor can custom stages after geometric corrections change them
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            stages: vec![(
+                StagePosition::After(PreprocessStep::Calibration),
+                Arc::new(FlagTimestep(0)) as Arc<dyn Correction>,
+            )],
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        assert!(prep_ctx.step_order.can_rephase());
+        assert!(!prep_ctx.can_rephase());
+        let result = builder
+            .prep_ctx(prep_ctx)
+            .phased_out(PhasedOutput {
+                phase_centre: RADec::new(0., 0.),
+                uvfits_out: Some("a.uvfits".into()),
+                ms_out: None,
+            })
+            .build();
+        assert!(matches!(
+            result,
+            Err(BirliError::PipelineError(PipelineError::InvalidSetting {
+                setting: "stages",
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_builder_selection_and_flags_must_match_observation() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let build = |vis_sel: VisSelection, flag_ctx: FlagContext| {
+            BirliContextBuilder::new(get_mwax_io_ctx())
+                .unwrap()
+                .vis_sel(vis_sel)
+                .flag_ctx(flag_ctx)
+                .build()
+        };
+        build(vis_sel.clone(), FlagContext::from_mwalib(&corr_ctx)).unwrap();
+
+        let bad_sels = [
+            (
+                "vis_sel.timestep_range",
+                VisSelection {
+                    timestep_range: 0..corr_ctx.num_timesteps + 1,
+                    ..vis_sel.clone()
+                },
+            ),
+            (
+                "vis_sel.coarse_chan_range",
+                VisSelection {
+                    coarse_chan_range: 0..0,
+                    ..vis_sel.clone()
+                },
+            ),
+            (
+                "vis_sel.baseline_idxs",
+                VisSelection {
+                    baseline_idxs: vec![0, corr_ctx.metafits_context.num_baselines],
+                    ..vis_sel.clone()
+                },
+            ),
+        ];
+        for (expected_setting, bad_sel) in bad_sels {
+            assert!(matches!(
+                build(bad_sel, FlagContext::from_mwalib(&corr_ctx)),
+                Err(BirliError::PipelineError(PipelineError::InvalidSetting { setting, .. }))
+                    if setting == expected_setting
+            ));
+        }
+
+        let mut bad_flags = [
+            "flag_ctx.timestep_flags",
+            "flag_ctx.coarse_chan_flags",
+            "flag_ctx.fine_chan_flags",
+            "flag_ctx.antenna_flags",
+        ]
+        .map(|setting| (setting, FlagContext::from_mwalib(&corr_ctx)));
+        bad_flags[0].1.timestep_flags.push(false);
+        bad_flags[1].1.coarse_chan_flags.pop();
+        bad_flags[2].1.fine_chan_flags.push(false);
+        bad_flags[3].1.antenna_flags.clear();
+        for (expected_setting, bad_flag_ctx) in bad_flags {
+            assert!(matches!(
+                build(vis_sel.clone(), bad_flag_ctx),
+                Err(BirliError::PipelineError(PipelineError::InvalidSetting { setting, .. }))
+                    if setting == expected_setting
+            ));
+        }
+    }
+
+    #[test]
+    fn test_chunks_match_uvfits() {
+        let tmp_dir = tempdir().unwrap();
+        let uvfits_path = tmp_dir.path().join("chunks.uvfits");
+        let avg_freq = 2;
+
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        builder
+            .prep_ctx(prep_ctx)
+            .avg_freq(avg_freq)
+            .uvfits_out(&uvfits_path)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        let chunks = builder
+            .prep_ctx(prep_ctx)
+            .avg_freq(avg_freq)
+            .num_timesteps_per_chunk(Some(1))
+            .build()
+            .unwrap()
+            .chunks()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let corr_ctx = get_mwax_context();
+        let num_chans = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse
+            * corr_ctx.num_common_coarse_chans
+            / avg_freq;
+        assert_eq!(chunks.len(), corr_ctx.num_common_timesteps);
+
+        let mut fptr = FitsFile::open(&uvfits_path).unwrap();
+        let mut row_idx = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.timestep_range.len(), 1);
+            assert_eq!(
+                chunk.jones_array.dim(),
+                (1, num_chans, chunk.ant_pairs.len())
+            );
+            assert_eq!(chunk.frequencies_hz.len(), num_chans);
+            for (bl_idx, uvw) in chunk.uvws.iter().enumerate() {
+                let mut params = [0_f32; 3];
+                let mut vis = vec![0_f32; num_chans * 4 * 3];
+                let mut status = 0;
+                let mut anynul = 0;
+                unsafe {
+                    // ffggpe = fits_read_grppar_flt
+                    fitsio_sys::ffggpe(
+                        fptr.as_raw(),
+                        1 + row_idx as i64,
+                        1,
+                        3,
+                        params.as_mut_ptr(),
+                        &mut status,
+                    );
+                    // ffgpve = fits_read_img_flt
+                    fitsio_sys::ffgpve(
+                        fptr.as_raw(),
+                        1 + row_idx as i64,
+                        1,
+                        vis.len() as i64,
+                        0.,
+                        vis.as_mut_ptr(),
+                        &mut anynul,
+                        &mut status,
+                    );
+                }
+                assert_eq!(status, 0);
+                assert_abs_diff_eq!(params[0] as f64 * VEL_C, uvw.u, epsilon = 1e-3);
+                assert_abs_diff_eq!(params[1] as f64 * VEL_C, uvw.v, epsilon = 1e-3);
+                assert_abs_diff_eq!(params[2] as f64 * VEL_C, uvw.w, epsilon = 1e-3);
+                for chan_idx in 0..num_chans {
+                    let jones = chunk.jones_array[[0, chan_idx, bl_idx]];
+                    let weight = chunk.weight_array[[0, chan_idx, bl_idx]];
+                    // uvfits polarisations are XX,YY,XY,YX
+                    let xx = &vis[chan_idx * 12..chan_idx * 12 + 3];
+                    assert_abs_diff_eq!(xx[0], jones[0].re);
+                    assert_abs_diff_eq!(xx[1], jones[0].im);
+                    assert_abs_diff_eq!(xx[2], weight);
+                    assert_eq!(chunk.flag_array[[0, chan_idx, bl_idx]], weight <= 0.);
+                }
+                row_idx += 1;
+            }
+        }
+    }
+
+    /// The UVWs of each timestep of an ephemeris are those of a fixed phase centre at the target's
+    /// position at that time, in both the chunks and the outputs.
+    #[test]
+    fn test_ephemeris_uvws_follow_target() {
+        let tmp_dir = tempdir().unwrap();
+        let uvfits_path = tmp_dir.path().join("ephemeris.uvfits");
+
+        let corr_ctx = get_mwax_context();
+        let int_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
+        let gps_time_s = |timestep_idx: usize| {
+            corr_ctx.timesteps[timestep_idx].gps_time_ms as f64 / 1e3 + int_time_s / 2.
+        };
+        let pointing = RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context);
+        // a target moving by five degrees in RA over the observation.
+        let entry = |gps_time_s: f64, offset_deg: f64| EphemerisEntry {
+            epoch: Epoch::from_gpst_seconds(gps_time_s),
+            radec: RADec::new(pointing.ra + offset_deg.to_radians(), pointing.dec),
+        };
+        let ephemeris = Ephemeris::new(vec![
+            entry(gps_time_s(corr_ctx.common_timestep_indices[0]) - 1., 0.),
+            entry(
+                gps_time_s(*corr_ctx.common_timestep_indices.last().unwrap()) + 1.,
+                5.,
+            ),
+        ])
+        .unwrap();
+
+        let build = |ephemeris: Option<Ephemeris>, phase_centre: RADec| {
+            let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+            let prep_ctx = PreprocessContext {
+                draw_progress: false,
+                phase_centre,
+                ephemeris,
+                ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+            };
+            builder.prep_ctx(prep_ctx).num_timesteps_per_chunk(Some(1))
+        };
+        build(Some(ephemeris.clone()), pointing)
+            .uvfits_out(&uvfits_path)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        let chunks = build(Some(ephemeris.clone()), pointing)
+            .build()
+            .unwrap()
+            .chunks()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut fptr = FitsFile::open(&uvfits_path).unwrap();
+        let mut row_idx = 0;
+        for chunk in &chunks {
+            let phase_centre = ephemeris.radec_at(chunk.timestamps[0]).unwrap();
+            let builder = build(None, phase_centre);
+            let vis_sel = VisSelection {
+                timestep_range: chunk.timestep_range.clone(),
+                ..VisSelection::from_mwalib(builder.corr_ctx()).unwrap()
+            };
+            let fixed_chunk = builder
+                .vis_sel(vis_sel)
+                .build()
+                .unwrap()
+                .chunks()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            for (uvw, fixed_uvw) in chunk.uvws.iter().zip(fixed_chunk.uvws.iter()) {
+                assert_abs_diff_eq!(uvw.u, fixed_uvw.u, epsilon = 1e-9);
+                assert_abs_diff_eq!(uvw.v, fixed_uvw.v, epsilon = 1e-9);
+                assert_abs_diff_eq!(uvw.w, fixed_uvw.w, epsilon = 1e-9);
+
+                let mut params = [0_f32; 3];
+                let mut status = 0;
+                unsafe {
+                    // ffggpe = fits_read_grppar_flt
+                    fitsio_sys::ffggpe(
+                        fptr.as_raw(),
+                        1 + row_idx as i64,
+                        1,
+                        3,
+                        params.as_mut_ptr(),
+                        &mut status,
+                    );
+                }
+                assert_eq!(status, 0);
+                assert_abs_diff_eq!(params[0] as f64 * VEL_C, uvw.u, epsilon = 1e-3);
+                assert_abs_diff_eq!(params[1] as f64 * VEL_C, uvw.v, epsilon = 1e-3);
+                assert_abs_diff_eq!(params[2] as f64 * VEL_C, uvw.w, epsilon = 1e-3);
+                row_idx += 1;
+            }
+        }
+    }
+
+    /// A custom stage which flags every sample in one timestep of the observation.
+    #[derive(Debug)]
+    struct FlagTimestep(usize);
+
+    impl Correction for FlagTimestep {
+        fn describe(&self, _prep_ctx: &PreprocessContext) -> Vec<String> {
+            vec![format!("flag timestep {}", self.0)]
+        }
+
+        fn apply(
+            &self,
+            _prep_ctx: &PreprocessContext,
+            _corr_ctx: &CorrelatorContext,
+            _jones_array: ArrayViewMut3<Jones<f32>>,
+            _weight_array: ArrayViewMut3<f32>,
+            mut flag_array: ArrayViewMut3<bool>,
+            vis_sel: &VisSelection,
+            _phase_centre: Option<RADec>,
+        ) -> Result<(), BirliError> {
+            if vis_sel.timestep_range.contains(&self.0) {
+                flag_array
+                    .index_axis_mut(Axis(0), self.0 - vis_sel.timestep_range.start)
+                    .fill(true);
+            }
+            Ok(())
+        }
+    }
+
+    /// The flags of each timestep, dilated by one timestep, with the given chunking.
+    fn dilated_chunk_flags(
+        flagged_timestep: usize,
+        num_timesteps_per_chunk: Option<usize>,
+        num_halo_timesteps: usize,
+    ) -> Vec<Array3<bool>> {
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            stages: vec![(
+                StagePosition::Before(PreprocessStep::Flag),
+                Arc::new(FlagTimestep(flagged_timestep)) as Arc<dyn Correction>,
+            )],
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        let flag_ctx = FlagContext {
+            extension: FlagExtension {
+                dilate_time: 1,
+                ..FlagExtension::default()
+            },
+            ..FlagContext::from_mwalib(builder.corr_ctx())
+        };
+        builder
+            .prep_ctx(prep_ctx)
+            .flag_ctx(flag_ctx)
+            .num_timesteps_per_chunk(num_timesteps_per_chunk)
+            .num_halo_timesteps(num_halo_timesteps)
+            .build()
+            .unwrap()
+            .chunks()
+            .unwrap()
+            .flat_map(|chunk| {
+                let chunk = chunk.unwrap();
+                chunk
+                    .flag_array
+                    .axis_iter(Axis(0))
+                    .map(|flags| flags.insert_axis(Axis(0)).to_owned())
+                    .collect_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_flag_extension_over_halo() {
+        let corr_ctx = get_mwax_context();
+        let timestep_range = VisSelection::from_mwalib(&corr_ctx).unwrap().timestep_range;
+        assert!(timestep_range.len() >= 3);
+        // flag the second last timestep, since the first may be flagged by the quack time.
+        let flagged_timestep = timestep_range.end - 2;
+
+        let unchunked_flags = dilated_chunk_flags(flagged_timestep, None, 0);
+        // the flagged timestep and its neighbours are flagged.
+        for flags in &unchunked_flags[unchunked_flags.len() - 3..] {
+            assert!(flags.iter().all(|&flag| flag));
+        }
+
+        // without a halo, the last chunk never sees the flagged timestep.
+        let chunked_flags = dilated_chunk_flags(flagged_timestep, Some(1), 0);
+        assert!(!chunked_flags.last().unwrap().iter().all(|&flag| flag));
+
+        let halo_flags = dilated_chunk_flags(flagged_timestep, Some(1), 1);
+        assert_eq!(unchunked_flags, halo_flags);
+    }
+
+    #[test]
+    fn test_halo_selection() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection {
+            timestep_range: 10..15,
+            ..VisSelection::from_mwalib(&corr_ctx).unwrap()
+        };
+        let chunk = |timestep_range| VisSelection {
+            timestep_range,
+            ..vis_sel.clone()
+        };
+        // the halo is clipped to the selection at either end.
+        for (chunk_range, num_halo_timesteps, halo_range, halo_core) in [
+            (10..12, 2, 10..14, 0..2),
+            (12..14, 2, 10..15, 2..4),
+            (14..15, 2, 12..15, 2..3),
+            (12..14, 0, 12..14, 0..2),
+            (10..15, 1, 10..15, 0..5),
+        ] {
+            let (halo_vis_sel, core) =
+                halo_selection(&vis_sel, &chunk(chunk_range.clone()), num_halo_timesteps);
+            assert_eq!(halo_vis_sel.timestep_range, halo_range, "{:?}", chunk_range);
+            assert_eq!(core, halo_core, "{:?}", chunk_range);
+            assert_eq!(halo_vis_sel.coarse_chan_range, vis_sel.coarse_chan_range);
+            assert_eq!(halo_vis_sel.baseline_idxs, vis_sel.baseline_idxs);
+        }
+    }
+
+    /// A custom stage which records the timesteps it is applied to.
+    #[derive(Debug)]
+    struct RecordTimesteps(Arc<Mutex<Vec<Range<usize>>>>);
+
+    impl Correction for RecordTimesteps {
+        fn describe(&self, _prep_ctx: &PreprocessContext) -> Vec<String> {
+            vec![]
+        }
+
+        fn apply(
+            &self,
+            _prep_ctx: &PreprocessContext,
+            _corr_ctx: &CorrelatorContext,
+            jones_array: ArrayViewMut3<Jones<f32>>,
+            _weight_array: ArrayViewMut3<f32>,
+            _flag_array: ArrayViewMut3<bool>,
+            vis_sel: &VisSelection,
+            _phase_centre: Option<RADec>,
+        ) -> Result<(), BirliError> {
+            assert_eq!(jones_array.dim().0, vis_sel.timestep_range.len());
+            self.0.lock().unwrap().push(vis_sel.timestep_range.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_halo_only_until_flag() {
+        let before_flag = Arc::new(Mutex::new(vec![]));
+        let after_flag = Arc::new(Mutex::new(vec![]));
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            stages: vec![
+                (
+                    StagePosition::Before(PreprocessStep::Flag),
+                    Arc::new(RecordTimesteps(before_flag.clone())) as Arc<dyn Correction>,
+                ),
+                (
+                    StagePosition::After(PreprocessStep::Flag),
+                    Arc::new(RecordTimesteps(after_flag.clone())) as Arc<dyn Correction>,
+                ),
+            ],
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        let mut vis_sel = VisSelection::from_mwalib(builder.corr_ctx()).unwrap();
+        let t = vis_sel.timestep_range.start;
+        vis_sel.timestep_range = t..t + 3;
+        let chunks = builder
+            .prep_ctx(prep_ctx)
+            .vis_sel(vis_sel)
+            .num_timesteps_per_chunk(Some(1))
+            .num_halo_timesteps(1)
+            .build()
+            .unwrap()
+            .chunks()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.timestep_range.clone())
+                .collect_vec(),
+            vec![t..t + 1, t + 1..t + 2, t + 2..t + 3]
+        );
+        // the steps up to flagging see each chunk with its halo, later steps only the chunk.
+        assert_eq!(
+            *before_flag.lock().unwrap(),
+            vec![t..t + 2, t..t + 3, t + 1..t + 3]
+        );
+        assert_eq!(
+            *after_flag.lock().unwrap(),
+            vec![t..t + 1, t + 1..t + 2, t + 2..t + 3]
+        );
+    }
+
+    #[test]
+    fn test_run_munted_calsols_is_error() {
+        let tmp_dir = tempdir().unwrap();
+        let calsols_path = tmp_dir.path().join("munted.bin");
+        std::fs::write(&calsols_path, b"MWAOCAX not a calibration solution").unwrap();
+
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        let result = builder
+            .prep_ctx(prep_ctx)
+            .aocalsols_in(&calsols_path)
+            .build()
+            .unwrap()
+            .run();
+        assert!(matches!(
+            result,
+            Err(BirliError::CalibrationError(
+                CalibrationError::ReadSolutions(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_run_unwritable_output_is_error() {
+        let builder = BirliContextBuilder::new(get_mwax_io_ctx()).unwrap();
+        let prep_ctx = PreprocessContext {
+            draw_progress: false,
+            ..PreprocessContext::from_mwalib(builder.corr_ctx()).unwrap()
+        };
+        let result = builder
+            .prep_ctx(prep_ctx)
+            .uvfits_out("/nonexistent/1297526432.uvfits")
+            .build()
+            .unwrap()
+            .run();
+        assert!(matches!(result, Err(BirliError::WriteError { .. })));
+    }
+}
@@ -1,28 +1,384 @@
 //! Crate for preprocessing visibilities
 use crate::{
+    cables::CableModel,
     calibration::apply_di_calsol,
-    correct_cable_lengths, correct_geometry,
-    corrections::{correct_coarse_passband_gains, correct_digital_gains, ScrunchType},
-    marlu::{mwalib::CorrelatorContext, ndarray::prelude::*, Jones, LatLngHeight, RADec},
+    correct_cable_lengths,
+    corrections::{
+        correct_cable_model, correct_coarse_passband_gains, correct_digital_gains,
+        correct_geometry_drift, correct_geometry_ephemeris, correct_geometry_near_field,
+        correct_geometry_with_tiles, correct_van_vleck, reverse_geometric_delays, ScrunchType,
+    },
+    ephemeris::{Ephemeris, NearFieldTrack},
+    flags::{flag_jones_array_sanity, AmplitudeThreshold},
+    io::read_oversampled,
+    marlu::{
+        mwalib::{
+            CableDelaysApplied, CorrelatorContext, GeometricDelaysApplied, MWAVersion,
+            MetafitsContext,
+        },
+        ndarray::prelude::*,
+        AzEl, Jones, LatLngHeight, RADec, XyzGeodetic,
+    },
+    passband_gains::{estimate_passband_gains, PFB_JAKE_2022_200HZ},
+    tiles::TileOverrides,
     with_increment_duration, BirliError, VisSelection,
 };
 use cfg_if::cfg_if;
 use derive_builder::Builder;
-use log::trace;
+use log::{debug, trace};
 use std::{
+    borrow::Cow,
     fmt::{Debug, Display},
+    str::FromStr,
+    sync::Arc,
     time::Duration,
 };
+use thiserror::Error;
 
 cfg_if! {
     if #[cfg(feature = "aoflagger")] {
         use crate::{
             flags::flag_jones_array_existing,
+            strategies::{AOFlaggerStrategy, BundledStrategy},
         };
         use aoflagger_sys::{cxx_aoflagger_new};
     }
 }
 
+/// A preprocessing step whose position in a [`StepOrder`] can be changed.
+///
+/// Sanity flagging and Van Vleck corrections always happen first, since they need the raw
+/// visibilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreprocessStep {
+    /// Cable length corrections
+    CableLengths,
+    /// Digital gain corrections
+    DigitalGains,
+    /// Coarse pfb passband gain corrections
+    PassbandGains,
+    /// RFI flagging with aoflagger
+    Flag,
+    /// Geometric corrections, including reversing the correlator's geometric delays
+    Geometry,
+    /// Applying calibration solutions
+    Calibration,
+}
+
+impl PreprocessStep {
+    /// All of the steps, in the default order.
+    pub const ALL: [Self; 6] = [
+        Self::CableLengths,
+        Self::DigitalGains,
+        Self::PassbandGains,
+        Self::Flag,
+        Self::Geometry,
+        Self::Calibration,
+    ];
+
+    /// The name of the step in a [`StepOrder`] string.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::CableLengths => "cable",
+            Self::DigitalGains => "digital",
+            Self::PassbandGains => "passband",
+            Self::Flag => "flag",
+            Self::Geometry => "geometry",
+            Self::Calibration => "calibrate",
+        }
+    }
+}
+
+impl Display for PreprocessStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Errors when specifying the order of preprocessing steps.
+#[derive(Error, Debug)]
+pub enum StepOrderError {
+    #[error("Unknown preprocessing step {0:?}, expected one of cable, digital, passband, flag, geometry, calibrate")]
+    /// A step name could not be parsed
+    UnknownStep(String),
+
+    #[error("Preprocessing step {0} appears more than once")]
+    /// A step was given more than once
+    Duplicate(PreprocessStep),
+
+    #[error("Preprocessing step {0} is missing, all steps must be given")]
+    /// A step was not given
+    Missing(PreprocessStep),
+
+    #[error("Preprocessing step {step} must come after {before}, {reason}")]
+    /// A step was given before another which it depends on
+    OutOfOrder {
+        /// The step which was given too early
+        step: PreprocessStep,
+        /// The step which it must come after
+        before: PreprocessStep,
+        /// Why the order matters
+        reason: &'static str,
+    },
+}
+
+/// The order in which [`PreprocessContext`] performs each [`PreprocessStep`]. Every step is
+/// always present, and is skipped at its position if it is disabled.
+///
+/// The steps before [`PreprocessStep::Geometry`] do not depend on the phase centre, and are
+/// performed by [`PreprocessContext::preprocess_unphased`]. Geometric corrections and the steps
+/// after them are performed by [`PreprocessContext::preprocess_phased`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepOrder(Vec<PreprocessStep>);
+
+impl Default for StepOrder {
+    fn default() -> Self {
+        Self(PreprocessStep::ALL.to_vec())
+    }
+}
+
+impl StepOrder {
+    /// Create a step order from a list of every step.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`StepOrderError`] if a step is missing or duplicated, or if calibration
+    /// comes before cable length or digital gain corrections. These are different for each
+    /// rfInput, and calibration solutions are full Jones matrices, so the two don't commute.
+    pub fn new(steps: Vec<PreprocessStep>) -> Result<Self, StepOrderError> {
+        for (idx, step) in steps.iter().enumerate() {
+            if steps[..idx].contains(step) {
+                return Err(StepOrderError::Duplicate(*step));
+            }
+        }
+        if let Some(&step) = PreprocessStep::ALL.iter().find(|s| !steps.contains(s)) {
+            return Err(StepOrderError::Missing(step));
+        }
+        let order = Self(steps);
+        for before in [PreprocessStep::CableLengths, PreprocessStep::DigitalGains] {
+            if order.position(PreprocessStep::Calibration) < order.position(before) {
+                return Err(StepOrderError::OutOfOrder {
+                    step: PreprocessStep::Calibration,
+                    before,
+                    reason: "since calibration solutions are derived from corrected visibilities",
+                });
+            }
+        }
+        Ok(order)
+    }
+
+    /// The steps, in order.
+    pub fn steps(&self) -> &[PreprocessStep] {
+        &self.0
+    }
+
+    fn position(&self, step: PreprocessStep) -> usize {
+        self.0
+            .iter()
+            .position(|&s| s == step)
+            .expect("all steps are present")
+    }
+
+    /// The steps performed by [`PreprocessContext::preprocess_unphased`].
+    pub fn unphased_steps(&self) -> &[PreprocessStep] {
+        &self.0[..self.position(PreprocessStep::Geometry)]
+    }
+
+    /// The steps performed by [`PreprocessContext::preprocess_phased`], starting with
+    /// [`PreprocessStep::Geometry`].
+    pub fn phased_steps(&self) -> &[PreprocessStep] {
+        &self.0[self.position(PreprocessStep::Geometry)..]
+    }
+
+    /// Whether the steps from geometric corrections onwards leave weights and flags the same for
+    /// any phase centre, so that [`PreprocessContext::preprocess_phased`] can be applied to a copy
+    /// of the same visibilities for several phase centres. Flagging and passband corrections,
+    /// which scale the weights, must come before geometric corrections.
+    pub fn can_rephase(&self) -> bool {
+        !self
+            .phased_steps()
+            .iter()
+            .any(|step| matches!(step, PreprocessStep::Flag | PreprocessStep::PassbandGains))
+    }
+}
+
+impl Display for StepOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.0
+                .iter()
+                .map(|step| step.name())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+impl FromStr for StepOrder {
+    type Err = StepOrderError;
+
+    /// Parse a comma-separated list of step names, e.g.
+    /// `cable,digital,passband,flag,geometry,calibrate`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split(',')
+            .map(|name| {
+                let name = name.trim();
+                PreprocessStep::ALL
+                    .into_iter()
+                    .find(|step| step.name() == name)
+                    .ok_or_else(|| StepOrderError::UnknownStep(name.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(steps)
+    }
+}
+
+/// A stage of preprocessing which operates on a chunk of visibilities.
+///
+/// Each built-in [`PreprocessStep`] is a correction whose options are taken from the
+/// [`PreprocessContext`], and library users can add their own corrections to
+/// [`PreprocessContext::stages`], before or after any built-in step.
+///
+/// Corrections which come after geometric corrections are applied by
+/// [`PreprocessContext::preprocess_phased`], once for each phase centre. These must leave the
+/// weights and flags unchanged if the visibilities are phased to several centres.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use birli::{
+///     marlu::{mwalib::CorrelatorContext, ndarray::ArrayViewMut3, Jones, RADec},
+///     preprocessing::{PreprocessStep, StagePosition},
+///     BirliError, Correction, PreprocessContext, VisSelection,
+/// };
+///
+/// /// Scale every visibility by a constant factor.
+/// #[derive(Debug)]
+/// struct Scale(f32);
+///
+/// impl Correction for Scale {
+///     fn describe(&self, _prep_ctx: &PreprocessContext) -> Vec<String> {
+///         vec![format!("scaling by {}", self.0)]
+///     }
+///
+///     fn apply(
+///         &self,
+///         _prep_ctx: &PreprocessContext,
+///         _corr_ctx: &CorrelatorContext,
+///         mut jones_array: ArrayViewMut3<Jones<f32>>,
+///         _weight_array: ArrayViewMut3<f32>,
+///         _flag_array: ArrayViewMut3<bool>,
+///         _vis_sel: &VisSelection,
+///         _phase_centre: Option<RADec>,
+///     ) -> Result<(), BirliError> {
+///         jones_array.mapv_inplace(|jones| jones * self.0);
+///         Ok(())
+///     }
+/// }
+///
+/// let prep_ctx = PreprocessContext {
+///     correct_geometry: true,
+///     stages: vec![(StagePosition::After(PreprocessStep::Geometry), Arc::new(Scale(2.)))],
+///     ..PreprocessContext::default()
+/// };
+/// assert_eq!(prep_ctx.as_comment(), "geometric corrections, scaling by 2");
+/// ```
+pub trait Correction: Debug + Send + Sync {
+    /// A short description of each task the correction will do, for the history of the outputs.
+    /// This is empty if the correction is disabled.
+    fn describe(&self, prep_ctx: &PreprocessContext) -> Vec<String>;
+
+    /// Apply the correction to a chunk of visibilities selected by `vis_sel`.
+    ///
+    /// `phase_centre` is the phase centre which the visibilities are being corrected to by
+    /// [`PreprocessContext::preprocess_phased`], or `None` for the main phase centre of
+    /// `prep_ctx`, which may be tracking a moving target.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BirliError`] if the correction fails. Custom corrections can wrap any
+    /// error in [`BirliError::Correction`].
+    #[allow(clippy::too_many_arguments)]
+    fn apply(
+        &self,
+        prep_ctx: &PreprocessContext,
+        corr_ctx: &CorrelatorContext,
+        jones_array: ArrayViewMut3<Jones<f32>>,
+        weight_array: ArrayViewMut3<f32>,
+        flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+        phase_centre: Option<RADec>,
+    ) -> Result<(), BirliError>;
+}
+
+impl Correction for PreprocessStep {
+    fn describe(&self, prep_ctx: &PreprocessContext) -> Vec<String> {
+        prep_ctx
+            .describe_step(*self)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn apply(
+        &self,
+        prep_ctx: &PreprocessContext,
+        corr_ctx: &CorrelatorContext,
+        jones_array: ArrayViewMut3<Jones<f32>>,
+        weight_array: ArrayViewMut3<f32>,
+        flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+        phase_centre: Option<RADec>,
+    ) -> Result<(), BirliError> {
+        prep_ctx.preprocess_step(
+            *self,
+            corr_ctx,
+            jones_array,
+            weight_array,
+            flag_array,
+            vis_sel,
+            phase_centre,
+        )
+    }
+}
+
+/// Where a custom [`Correction`] is inserted in [`PreprocessContext::stages`], relative to a
+/// built-in step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StagePosition {
+    /// Immediately before the step
+    Before(PreprocessStep),
+    /// Immediately after the step
+    After(PreprocessStep),
+}
+
+/// Which preprocessing steps to perform, relative to RFI flagging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StepRange {
+    /// All of the steps
+    All,
+    /// The steps up to and including RFI flagging, which is all that the halo of extra
+    /// timesteps around a chunk needs
+    UntilFlag,
+    /// The steps after RFI flagging, which are only applied to the chunk itself
+    AfterFlag,
+}
+
+impl StepRange {
+    /// Whether a step which comes after RFI flagging if `after_flag` is in the range.
+    const fn contains(self, after_flag: bool) -> bool {
+        match self {
+            Self::All => true,
+            Self::UntilFlag => !after_flag,
+            Self::AfterFlag => after_flag,
+        }
+    }
+}
+
 /// Options for preprocessing a chunk of correlator data
 #[derive(Builder, Debug, Default)]
 pub struct PreprocessContext<'a> {
@@ -30,25 +386,86 @@ pub struct PreprocessContext<'a> {
     pub array_pos: LatLngHeight,
     /// The phase centre used for geometric corrections
     pub phase_centre: RADec,
+    /// A moving target to track with geometric corrections instead of `phase_centre`. The UVWs of
+    /// the outputs follow the target, but their headers are labelled with `phase_centre`, which
+    /// should be the position of the target near the middle of the observation.
+    #[builder(default)]
+    pub ephemeris: Option<Ephemeris>,
+    /// A target in the near field, e.g. a satellite, to correct for the delays to instead of
+    /// phasing to `phase_centre`. The outputs are still labelled with `phase_centre`.
+    #[builder(default)]
+    pub near_field: Option<NearFieldTrack>,
+    /// A fixed azimuth and elevation to phase each timestep to instead of `phase_centre`, e.g.
+    /// zenith for a drift scan. The outputs are labelled with `phase_centre`, which should be the
+    /// J2000 position of `drift` near the middle of the observation.
+    #[builder(default)]
+    pub drift: Option<AzEl>,
+
+    /// Whether to flag non-finite, zero and high-amplitude visibilities before corrections
+    #[builder(default)]
+    pub flag_sanity: bool,
+    /// The amplitude threshold used when flagging visibilities before corrections
+    #[builder(default)]
+    pub amp_threshold: Option<AmplitudeThreshold>,
+
+    /// Whether to correct the quantisation (Van Vleck) effects of legacy correlator data
+    #[builder(default)]
+    pub correct_van_vleck: bool,
 
     /// Whether cable length corrections are enabled
     #[builder(default = "true")]
     pub correct_cable_lengths: bool,
+    /// The cable model used for cable length corrections, instead of the metafits lengths
+    #[builder(default)]
+    pub cable_model: Option<CableModel>,
+    /// Overrides of the cable delays and positions of tiles in the metafits
+    #[builder(default)]
+    pub tile_overrides: Option<TileOverrides>,
     /// Whether digital gain corrections are enabled
     #[builder(default = "true")]
     pub correct_digital_gains: bool,
     /// the pfb passband gains to use for corrections
-    pub passband_gains: Option<&'a [f64]>,
+    pub passband_gains: Option<Cow<'a, [f64]>>,
+    /// Whether to estimate the pfb passband gains with [`estimate_passband_gains`], and use them
+    /// for corrections instead of `passband_gains`. The gains are estimated separately for the
+    /// visibilities of each call to [`PreprocessContext::preprocess`], i.e. each chunk and its
+    /// halo when chunking, from the flags set before the passband gains step.
+    #[builder(default)]
+    pub empirical_passband: bool,
     /// The calibration solutions to apply
     pub calsols: Option<Array2<Jones<f64>>>,
     /// Whether geometric corrections are enabled
     #[builder(default = "true")]
     pub correct_geometry: bool,
+    /// Whether to reverse the geometric delays applied by the correlator before geometric
+    /// corrections
+    #[builder(default)]
+    pub reverse_geometric_delays: bool,
+
+    /// The cable delays already applied by the correlator, if any
+    #[builder(default)]
+    pub correlator_cable_delays: Option<CableDelaysApplied>,
+    /// The geometric delays already applied by the correlator, if any
+    #[builder(default)]
+    pub correlator_geometric_delays: Option<GeometricDelaysApplied>,
 
-    /// AOFlagger strategy path for flagging
+    /// AOFlagger strategy for flagging. A bundled strategy is written to a temporary file each
+    /// time a chunk is flagged, unless it has already been [`AOFlaggerStrategy::written`], as
+    /// [`crate::BirliContext`] does.
     #[builder(default)]
     #[cfg(feature = "aoflagger")]
-    pub aoflagger_strategy: Option<String>,
+    pub aoflagger_strategy: Option<AOFlaggerStrategy>,
+
+    /// The order of the preprocessing steps
+    #[builder(default)]
+    pub step_order: StepOrder,
+    /// Custom corrections to insert between the preprocessing steps, in the order they are
+    /// applied at each position. A correction before geometric corrections is applied before the
+    /// correlator's geometric delays are reversed. Corrections from geometric corrections onwards
+    /// could change the weights and flags which outputs phased to other centres share, so they
+    /// can't be used with phased outputs (see [`PreprocessContext::can_rephase`]).
+    #[builder(default)]
+    pub stages: Vec<(StagePosition, Arc<dyn Correction>)>,
 
     /// Whether to draw progress bars
     #[builder(default = "true")]
@@ -57,15 +474,52 @@ pub struct PreprocessContext<'a> {
 
 impl Display for PreprocessContext<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.flag_sanity {
+            writeln!(
+                f,
+                "Will flag non-finite and zero visibilities{}.",
+                self.amp_threshold
+                    .map_or_else(String::new, |threshold| format!(
+                        ", and where {}",
+                        threshold
+                    ))
+            )?;
+        }
+        if self.correct_van_vleck {
+            writeln!(f, "Will correct Van Vleck quantisation effects.")?;
+        }
         writeln!(
             f,
-            "{} correct cable lengths.",
+            "{} correct cable lengths{}.",
             if self.correct_cable_lengths {
                 "Will"
             } else {
                 "Will not"
+            },
+            match &self.cable_model {
+                Some(CableModel {
+                    attenuations_db: Some(_),
+                    ..
+                }) if self.correct_cable_lengths => " and attenuation from cable flavours",
+                Some(_) if self.correct_cable_lengths => " from cable flavours",
+                _ => "",
             }
         )?;
+        if let Some(tile_overrides) = &self.tile_overrides {
+            writeln!(
+                f,
+                "Will override the cable delays of {} tiles and the positions of {} tiles.",
+                tile_overrides.num_delays(),
+                tile_overrides.num_positions()
+            )?;
+        }
+        if let Some(cable_delays) = self.correlator_cable_delays {
+            writeln!(
+                f,
+                "Cable delays were already applied by the correlator ({}).",
+                cable_delays
+            )?;
+        }
         writeln!(
             f,
             "{} correct digital gains.",
@@ -77,11 +531,16 @@ impl Display for PreprocessContext<'_> {
         )?;
         writeln!(
             f,
-            "{} correct coarse pfb passband gains.",
-            if self.passband_gains.is_some() {
+            "{} correct coarse pfb passband gains{}.",
+            if self.empirical_passband || self.passband_gains.is_some() {
                 "Will"
             } else {
                 "Will not"
+            },
+            if self.empirical_passband {
+                " estimated from the data"
+            } else {
+                ""
             }
         )?;
         cfg_if! {
@@ -93,56 +552,312 @@ impl Display for PreprocessContext<'_> {
                 }
             }
         }
+        if let Some(geometric_delays) = self.correlator_geometric_delays {
+            writeln!(
+                f,
+                "Geometric delays were already applied by the correlator ({}){}.",
+                geometric_delays,
+                if self.correct_geometry && self.reverse_geometric_delays {
+                    ", and will be reversed"
+                } else {
+                    ""
+                }
+            )?;
+        }
         writeln!(
             f,
-            "{} correct geometry.",
+            "{} correct geometry{}.",
             if self.correct_geometry {
                 "Will"
             } else {
                 "Will not"
+            },
+            match (
+                self.correct_geometry,
+                self.ephemeris.as_ref(),
+                self.near_field.as_ref(),
+                self.drift
+            ) {
+                (true, Some(ephemeris), ..) => format!(
+                    ", tracking an ephemeris of {} positions",
+                    ephemeris.entries().len()
+                ),
+                (true, _, Some(near_field), _) => format!(
+                    ", for a near-field target at {} positions",
+                    near_field.entries().len()
+                ),
+                (true, .., Some(azel)) => format!(", drifting at az/el {}", azel),
+                _ => "".into(),
             }
         )?;
+        if self.step_order != StepOrder::default() {
+            writeln!(f, "Will preprocess in the order {}.", self.step_order)?;
+        }
+        for (position, stage) in &self.stages {
+            writeln!(
+                f,
+                "Will apply a custom correction {:?}: {}.",
+                position,
+                stage.describe(self).join(", ")
+            )?;
+        }
         Ok(())
     }
 }
 
 impl<'a> PreprocessContext<'a> {
-    /// A one line description of the tasks preprocessing will do.
-    pub fn as_comment(&self) -> String {
-        [
-            if self.correct_cable_lengths {
-                Some("cable length corrections".to_string())
+    /// The default preprocessing options for an observation, which are the defaults of the
+    /// command line: all corrections are enabled, except for those the correlator has already
+    /// applied, whose geometric delays are reversed. The passband gains are
+    /// [`PFB_JAKE_2022_200HZ`] unless the coarse channels are oversampled, and the bundled
+    /// aoflagger strategy is used.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the metafits can't be read.
+    pub fn from_mwalib(corr_ctx: &CorrelatorContext) -> Result<Self, BirliError> {
+        let meta_ctx = &corr_ctx.metafits_context;
+        let cable_delays_applied = !matches!(
+            meta_ctx.cable_delays_applied,
+            CableDelaysApplied::NoCableDelaysApplied
+        );
+        let geometric_delays_applied = !matches!(
+            meta_ctx.geometric_delays_applied,
+            GeometricDelaysApplied::No
+        );
+        Ok(Self {
+            array_pos: LatLngHeight::new_mwa(),
+            phase_centre: RADec::from_mwalib_phase_or_pointing(meta_ctx),
+            correct_van_vleck: matches!(
+                meta_ctx.mwa_version,
+                Some(MWAVersion::CorrLegacy | MWAVersion::CorrOldLegacy)
+            ),
+            correct_cable_lengths: !cable_delays_applied,
+            correlator_cable_delays: if cable_delays_applied {
+                Some(meta_ctx.cable_delays_applied)
             } else {
                 None
             },
-            if self.correct_digital_gains {
-                Some("digital gains".to_string())
-            } else {
+            correct_digital_gains: true,
+            passband_gains: if read_oversampled(meta_ctx)? {
                 None
+            } else {
+                Some(PFB_JAKE_2022_200HZ.into())
             },
-            if self.passband_gains.is_some() {
-                Some("pfb gains".to_string())
+            correct_geometry: true,
+            reverse_geometric_delays: geometric_delays_applied,
+            correlator_geometric_delays: if geometric_delays_applied {
+                Some(meta_ctx.geometric_delays_applied)
             } else {
                 None
             },
             #[cfg(feature = "aoflagger")]
-            self.aoflagger_strategy
-                .as_ref()
-                .map(|strategy| format!("aoflagging with {}", strategy)),
-            if self.correct_geometry {
-                Some("geometric corrections".to_string())
+            aoflagger_strategy: Some(AOFlaggerStrategy::Bundled(BundledStrategy::MwaDefault)),
+            draw_progress: true,
+            ..Self::default()
+        })
+    }
+
+    /// The cable model to use for cable length corrections, with any tile overrides applied, or
+    /// `None` if the metafits lengths should be used as-is.
+    pub fn get_cable_model(&self, meta_ctx: &MetafitsContext) -> Option<CableModel> {
+        match (&self.cable_model, &self.tile_overrides) {
+            (None, None) => None,
+            (cable_model, tile_overrides) => {
+                let mut cable_model = cable_model
+                    .clone()
+                    .unwrap_or_else(|| CableModel::from_metafits(meta_ctx));
+                if let Some(tile_overrides) = tile_overrides {
+                    tile_overrides.apply_to_cable_model(&mut cable_model);
+                }
+                Some(cable_model)
+            }
+        }
+    }
+
+    /// The geodetic position of each antenna relative to the array position, with any tile
+    /// overrides applied.
+    pub fn get_tiles(&self, meta_ctx: &MetafitsContext) -> Vec<XyzGeodetic> {
+        self.tile_overrides.as_ref().map_or_else(
+            || XyzGeodetic::get_tiles(meta_ctx, self.array_pos.latitude_rad),
+            |tile_overrides| tile_overrides.get_tiles(meta_ctx, self.array_pos.latitude_rad),
+        )
+    }
+
+    /// A one line description of the tasks preprocessing will do, in the order they are done.
+    pub fn as_comment(&self) -> String {
+        let tasks = vec![
+            if self.flag_sanity {
+                Some("sanity flagging".to_string())
+            } else {
+                None
+            },
+            if self.correct_van_vleck {
+                Some("van vleck corrections".to_string())
             } else {
                 None
             },
-        ]
-        .into_iter()
-        .flatten()
-        .collect::<Vec<String>>()
-        .join(", ")
+        ];
+        tasks
+            .into_iter()
+            .flatten()
+            .chain({
+                let (unphased, phased) = self.pipeline(StepRange::All);
+                unphased
+                    .into_iter()
+                    .chain(phased)
+                    .flat_map(|stage| stage.describe(self))
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Whether [`PreprocessContext::preprocess_phased`] leaves weights and flags the same for any
+    /// phase centre, like [`StepOrder::can_rephase`]. Custom `stages` may change them too, so none
+    /// may be applied from geometric corrections onwards.
+    pub fn can_rephase(&self) -> bool {
+        let (_, phased) = self.pipeline(StepRange::All);
+        self.step_order.can_rephase() && phased.len() == self.step_order.phased_steps().len()
+    }
+
+    /// The built-in steps of `step_order` within `steps`, with any custom `stages` inserted
+    /// around them, split into the stages before geometric corrections, and the stages from
+    /// geometric corrections onwards.
+    fn pipeline(&self, steps: StepRange) -> (Vec<&dyn Correction>, Vec<&dyn Correction>) {
+        let custom_stages = |position| {
+            self.stages
+                .iter()
+                .filter(move |(stage_position, _)| *stage_position == position)
+                .map(|(_, stage)| stage.as_ref())
+        };
+        let (mut unphased, mut phased) = (vec![], vec![]);
+        let (mut is_phased, mut after_flag) = (false, false);
+        for step in self.step_order.steps() {
+            if steps.contains(after_flag) {
+                let stages = if is_phased {
+                    &mut phased
+                } else {
+                    &mut unphased
+                };
+                stages.extend(custom_stages(StagePosition::Before(*step)));
+            }
+            is_phased |= *step == PreprocessStep::Geometry;
+            let stages = if is_phased {
+                &mut phased
+            } else {
+                &mut unphased
+            };
+            if steps.contains(after_flag) {
+                stages.push(step as &dyn Correction);
+            }
+            after_flag |= *step == PreprocessStep::Flag;
+            if steps.contains(after_flag) {
+                stages.extend(custom_stages(StagePosition::After(*step)));
+            }
+        }
+        (unphased, phased)
+    }
+
+    /// The tasks [`PreprocessContext::as_comment`] describes for `step`.
+    fn describe_step(&self, step: PreprocessStep) -> Vec<Option<String>> {
+        match step {
+            PreprocessStep::CableLengths => vec![
+                self.correlator_cable_delays
+                    .map(|cable_delays| format!("correlator cable delays ({})", cable_delays)),
+                match &self.cable_model {
+                    _ if !self.correct_cable_lengths => None,
+                    Some(CableModel {
+                        attenuations_db: Some(_),
+                        ..
+                    }) => {
+                        Some("cable length and attenuation corrections from flavours".to_string())
+                    }
+                    Some(_) => Some("cable length corrections from flavours".to_string()),
+                    None => Some("cable length corrections".to_string()),
+                },
+                self.tile_overrides.as_ref().map(|tile_overrides| {
+                    format!(
+                        "tile overrides ({} delays, {} positions)",
+                        tile_overrides.num_delays(),
+                        tile_overrides.num_positions()
+                    )
+                }),
+            ],
+            PreprocessStep::DigitalGains => vec![if self.correct_digital_gains {
+                Some("digital gains".to_string())
+            } else {
+                None
+            }],
+            PreprocessStep::PassbandGains => vec![if self.empirical_passband {
+                Some("empirical pfb gains".to_string())
+            } else if self.passband_gains.is_some() {
+                Some("pfb gains".to_string())
+            } else {
+                None
+            }],
+            PreprocessStep::Flag => {
+                cfg_if! {
+                    if #[cfg(feature = "aoflagger")] {
+                        vec![self
+                            .aoflagger_strategy
+                            .as_ref()
+                            .map(|strategy| format!("aoflagging with {}", strategy))]
+                    } else {
+                        vec![]
+                    }
+                }
+            }
+            PreprocessStep::Geometry => vec![
+                self.correlator_geometric_delays.map(|geometric_delays| {
+                    format!("correlator geometric delays ({})", geometric_delays)
+                }),
+                if self.correct_geometry && self.reverse_geometric_delays {
+                    Some("reversed correlator geometric delays".to_string())
+                } else {
+                    None
+                },
+                match (
+                    self.correct_geometry,
+                    self.ephemeris.as_ref(),
+                    self.near_field.as_ref(),
+                    self.drift,
+                ) {
+                    (true, Some(ephemeris), ..) => {
+                        let entries = ephemeris.entries();
+                        Some(format!(
+                            "geometric corrections tracking an ephemeris (gps {:.0}s to {:.0}s)",
+                            entries[0].epoch.as_gpst_seconds(),
+                            entries[entries.len() - 1].epoch.as_gpst_seconds()
+                        ))
+                    }
+                    (true, _, Some(near_field), _) => {
+                        let entries = near_field.entries();
+                        Some(format!(
+                            "near-field geometric corrections (gps {:.0}s to {:.0}s)",
+                            entries[0].epoch.as_gpst_seconds(),
+                            entries[entries.len() - 1].epoch.as_gpst_seconds()
+                        ))
+                    }
+                    (true, .., Some(azel)) => Some(format!(
+                        "geometric corrections drifting at az {:.4}° el {:.4}°",
+                        azel.az.to_degrees(),
+                        azel.el.to_degrees()
+                    )),
+                    (true, ..) => Some("geometric corrections".to_string()),
+                    _ => None,
+                },
+            ],
+            PreprocessStep::Calibration => {
+                vec![self.calsols.as_ref().map(|_| "di calibration".to_string())]
+            }
+        }
     }
 
     /// Preprocess visibilities for a chunk of correlator data
     ///
+    /// This is [`PreprocessContext::preprocess_unphased`] followed by
+    /// [`PreprocessContext::preprocess_phased`] to the main phase centre.
+    ///
     /// # Arguments
     /// * `corr_ctx` - [`marlu::mwalib::CorrelatorContext`]
     /// * `jones_array` - Array of Jones visibilties
@@ -163,101 +878,387 @@ impl<'a> PreprocessContext<'a> {
         mut flag_array: ArrayViewMut3<bool>,
         vis_sel: &VisSelection,
     ) -> Result<(), BirliError> {
-        if self.correct_cable_lengths {
-            trace!("correcting cable lengths");
+        self.preprocess_unphased(
+            corr_ctx,
+            jones_array.view_mut(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            vis_sel,
+        )?;
+        self.preprocess_phased(
+            corr_ctx,
+            jones_array,
+            weight_array,
+            flag_array,
+            vis_sel,
+            None,
+        )
+    }
+
+    /// The preprocessing steps which do not depend on the phase centre: sanity flagging, Van
+    /// Vleck corrections, the steps before geometric corrections in `step_order` with any custom
+    /// `stages` among them, and reversing the correlator's geometric delays.
+    ///
+    /// When the same visibilities are phased to several centres, these steps only need to be
+    /// performed once, and [`PreprocessContext::preprocess_phased`] can be applied to a copy of
+    /// the result for each phase centre.
+    ///
+    /// # Errors
+    /// will wrap errors from `correct_digital_gains`, `correct_coarse_passband_gains`
+    pub fn preprocess_unphased(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        jones_array: ArrayViewMut3<Jones<f32>>,
+        weight_array: ArrayViewMut3<f32>,
+        flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+    ) -> Result<(), BirliError> {
+        self.preprocess_unphased_steps(
+            corr_ctx,
+            jones_array,
+            weight_array,
+            flag_array,
+            vis_sel,
+            StepRange::All,
+        )
+    }
+
+    /// The steps of [`PreprocessContext::preprocess_unphased`] within `steps`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn preprocess_unphased_steps(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+        steps: StepRange,
+    ) -> Result<(), BirliError> {
+        if self.flag_sanity && steps.contains(false) {
+            trace!("flagging corrupt visibilities");
             with_increment_duration!(
-                "correct_cable",
-                correct_cable_lengths(
-                    corr_ctx,
-                    jones_array.view_mut(),
-                    &vis_sel.coarse_chan_range,
-                    self.draw_progress
+                "flag_sanity",
+                flag_jones_array_sanity(
+                    jones_array.view(),
+                    flag_array.view_mut(),
+                    self.amp_threshold
                 )
             );
         }
 
-        let sel_ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+        if self.correct_van_vleck && steps.contains(false) {
+            trace!("correcting van vleck");
+            let sel_ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+            with_increment_duration!(
+                "correct_van_vleck",
+                correct_van_vleck(corr_ctx, jones_array.view_mut(), &sel_ant_pairs)?
+            );
+        }
+
+        for stage in self.pipeline(steps).0 {
+            stage.apply(
+                self,
+                corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                vis_sel,
+                None,
+            )?;
+        }
 
-        if self.correct_digital_gains {
-            trace!("correcting digital gains");
+        // the correlator's delays are reversed after the last unphased step.
+        let flag_unphased = self
+            .step_order
+            .unphased_steps()
+            .contains(&PreprocessStep::Flag);
+        if self.correct_geometry && self.reverse_geometric_delays && steps.contains(flag_unphased) {
+            trace!("reversing correlator geometric delays");
             with_increment_duration!(
-                "correct_digital",
-                correct_digital_gains(
+                "correct_geom",
+                reverse_geometric_delays(
                     corr_ctx,
                     jones_array.view_mut(),
+                    &vis_sel.timestep_range,
                     &vis_sel.coarse_chan_range,
-                    &sel_ant_pairs,
-                )?
+                )
             );
         }
 
-        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        Ok(())
+    }
 
-        // perform pfb passband gain corrections
-        if let Some(passband_gains) = self.passband_gains {
-            trace!("correcting pfb gains");
-            with_increment_duration!(
-                "correct_passband",
-                correct_coarse_passband_gains(
-                    jones_array.view_mut(),
-                    weight_array.view_mut(),
-                    passband_gains,
-                    fine_chans_per_coarse,
-                    &ScrunchType::from_mwa_version(corr_ctx.metafits_context.mwa_version.unwrap())?,
-                )?
-            );
+    /// The preprocessing steps which depend on the phase centre: geometric corrections to
+    /// `phase_centre`, and the steps and custom `stages` which follow them in `step_order`. If
+    /// `phase_centre` is `None`, the main phase centre is used, which tracks `self.ephemeris`,
+    /// corrects for the delays to `self.near_field` or drifts with `self.drift` if any are set.
+    ///
+    /// With the default step order, only calibration follows geometric corrections, which only
+    /// flags visibilities which are made non-finite by the calibration solutions, so
+    /// `weight_array` and `flag_array` are the same for any phase centre (see
+    /// [`PreprocessContext::can_rephase`]).
+    ///
+    /// # Errors
+    /// will wrap errors from `correct_geometry_ephemeris`, `correct_geometry_near_field`,
+    /// `apply_di_calsol`
+    #[allow(clippy::too_many_arguments)]
+    pub fn preprocess_phased(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        jones_array: ArrayViewMut3<Jones<f32>>,
+        weight_array: ArrayViewMut3<f32>,
+        flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+        phase_centre: Option<RADec>,
+    ) -> Result<(), BirliError> {
+        self.preprocess_phased_steps(
+            corr_ctx,
+            jones_array,
+            weight_array,
+            flag_array,
+            vis_sel,
+            phase_centre,
+            StepRange::All,
+        )
+    }
+
+    /// The steps of [`PreprocessContext::preprocess_phased`] within `steps`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn preprocess_phased_steps(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+        phase_centre: Option<RADec>,
+        steps: StepRange,
+    ) -> Result<(), BirliError> {
+        for stage in self.pipeline(steps).1 {
+            stage.apply(
+                self,
+                corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                vis_sel,
+                phase_centre,
+            )?;
         }
+        Ok(())
+    }
 
-        cfg_if! {
-            if #[cfg(feature = "aoflagger")] {
-                if let Some(strategy) = self.aoflagger_strategy.as_ref() {
-                    trace!("using aoflagger");
-                    let aoflagger = unsafe { cxx_aoflagger_new() };
+    /// Perform a single step of preprocessing, if it is enabled. `phase_centre` is only used for
+    /// geometric corrections, see [`PreprocessContext::preprocess_phased`].
+    #[allow(clippy::too_many_arguments)]
+    fn preprocess_step(
+        &self,
+        step: PreprocessStep,
+        corr_ctx: &CorrelatorContext,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+        phase_centre: Option<RADec>,
+    ) -> Result<(), BirliError> {
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        match step {
+            PreprocessStep::CableLengths if self.correct_cable_lengths => {
+                trace!("correcting cable lengths");
+                if let Some(cable_model) = self.get_cable_model(&corr_ctx.metafits_context) {
                     with_increment_duration!(
-                        "flag",
-                        flag_jones_array_existing(
-                            &aoflagger,
-                            strategy,
-                            jones_array.view(),
-                            flag_array.view_mut(),
-                            true,
-                            self.draw_progress,
+                        "correct_cable",
+                        correct_cable_model(
+                            corr_ctx,
+                            jones_array.view_mut(),
+                            &vis_sel.coarse_chan_range,
+                            &cable_model,
+                            self.draw_progress
+                        )
+                    );
+                } else {
+                    with_increment_duration!(
+                        "correct_cable",
+                        correct_cable_lengths(
+                            corr_ctx,
+                            jones_array.view_mut(),
+                            &vis_sel.coarse_chan_range,
+                            self.draw_progress
                         )
                     );
                 }
             }
-        }
+            PreprocessStep::DigitalGains if self.correct_digital_gains => {
+                trace!("correcting digital gains");
+                with_increment_duration!(
+                    "correct_digital",
+                    correct_digital_gains(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        &vis_sel.coarse_chan_range,
+                        &vis_sel.get_ant_pairs(&corr_ctx.metafits_context),
+                    )?
+                );
+            }
+            PreprocessStep::PassbandGains => {
+                let empirical_gains = if self.empirical_passband {
+                    trace!("estimating pfb gains");
+                    let gains = with_increment_duration!(
+                        "estimate_passband",
+                        estimate_passband_gains(
+                            jones_array.view(),
+                            flag_array.view(),
+                            fine_chans_per_coarse
+                        )?
+                    );
+                    debug!("estimated pfb gains: {:?}", gains);
+                    Some(gains)
+                } else {
+                    None
+                };
 
-        if self.correct_geometry {
-            trace!("correcting geometric delays");
-            with_increment_duration!(
-                "correct_geom",
-                correct_geometry(
-                    corr_ctx,
-                    jones_array.view_mut(),
-                    &vis_sel.timestep_range,
-                    &vis_sel.coarse_chan_range,
-                    Some(self.array_pos),
-                    Some(self.phase_centre),
-                    self.draw_progress,
-                )
-            );
+                // perform pfb passband gain corrections
+                if let Some(passband_gains) = empirical_gains
+                    .as_deref()
+                    .or(self.passband_gains.as_deref())
+                {
+                    trace!("correcting pfb gains");
+                    with_increment_duration!(
+                        "correct_passband",
+                        correct_coarse_passband_gains(
+                            jones_array.view_mut(),
+                            weight_array.view_mut(),
+                            passband_gains,
+                            fine_chans_per_coarse,
+                            &ScrunchType::from_mwa_version(corr_ctx.mwa_version)?,
+                        )?
+                    );
+                }
+            }
+            PreprocessStep::Flag => {
+                cfg_if! {
+                    if #[cfg(feature = "aoflagger")] {
+                        if let Some(strategy) = self.aoflagger_strategy.as_ref() {
+                            trace!("using aoflagger");
+                            let aoflagger = unsafe { cxx_aoflagger_new() };
+                            with_increment_duration!(
+                                "flag",
+                                strategy.with_path(|strategy| flag_jones_array_existing(
+                                    &aoflagger,
+                                    strategy,
+                                    jones_array.view(),
+                                    flag_array.view_mut(),
+                                    true,
+                                    self.draw_progress,
+                                ))?
+                            );
+                        }
+                    }
+                }
+            }
+            PreprocessStep::Geometry if self.correct_geometry => {
+                self.correct_geometry_to(corr_ctx, jones_array, vis_sel, phase_centre)?;
+            }
+            PreprocessStep::Calibration => {
+                if let Some(ref calsols) = self.calsols {
+                    trace!("applying calibration solutions");
+                    let sel_ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+                    with_increment_duration!(
+                        "calibrate",
+                        apply_di_calsol(
+                            calsols.view(),
+                            jones_array.view_mut(),
+                            weight_array.view_mut(),
+                            flag_array.view_mut(),
+                            &sel_ant_pairs,
+                        )?
+                    );
+                }
+            }
+            _ => {}
         }
+        Ok(())
+    }
 
-        if let Some(ref calsols) = self.calsols {
-            trace!("applying calibration solutions");
-            with_increment_duration!(
-                "calibrate",
-                apply_di_calsol(
-                    calsols.view(),
-                    jones_array.view_mut(),
-                    weight_array.view_mut(),
-                    flag_array.view_mut(),
-                    &sel_ant_pairs,
-                )?
-            );
+    /// Geometric corrections to `phase_centre`, or the main phase centre if it is `None`.
+    fn correct_geometry_to(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        vis_sel: &VisSelection,
+        phase_centre: Option<RADec>,
+    ) -> Result<(), BirliError> {
+        match (
+            phase_centre,
+            self.ephemeris.as_ref(),
+            self.near_field.as_ref(),
+            self.drift,
+        ) {
+            (None, Some(ephemeris), ..) => {
+                trace!("correcting geometric delays with ephemeris");
+                with_increment_duration!(
+                    "correct_geom",
+                    correct_geometry_ephemeris(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        &vis_sel.timestep_range,
+                        &vis_sel.coarse_chan_range,
+                        self.array_pos,
+                        ephemeris,
+                        &self.get_tiles(&corr_ctx.metafits_context),
+                        self.draw_progress,
+                    )?
+                );
+            }
+            (None, _, Some(near_field), _) => {
+                trace!("correcting near-field geometric delays");
+                with_increment_duration!(
+                    "correct_geom",
+                    correct_geometry_near_field(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        &vis_sel.timestep_range,
+                        &vis_sel.coarse_chan_range,
+                        self.array_pos,
+                        near_field,
+                        &self.get_tiles(&corr_ctx.metafits_context),
+                        self.draw_progress,
+                    )?
+                );
+            }
+            (None, .., Some(azel)) => {
+                trace!("correcting geometric delays for drift");
+                with_increment_duration!(
+                    "correct_geom",
+                    correct_geometry_drift(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        &vis_sel.timestep_range,
+                        &vis_sel.coarse_chan_range,
+                        self.array_pos,
+                        azel,
+                        &self.get_tiles(&corr_ctx.metafits_context),
+                        self.draw_progress,
+                    )
+                );
+            }
+            (phase_centre, ..) => {
+                trace!("correcting geometric delays");
+                with_increment_duration!(
+                    "correct_geom",
+                    correct_geometry_with_tiles(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        &vis_sel.timestep_range,
+                        &vis_sel.coarse_chan_range,
+                        self.array_pos,
+                        phase_centre.unwrap_or(self.phase_centre),
+                        &self.get_tiles(&corr_ctx.metafits_context),
+                        self.draw_progress,
+                    )
+                );
+            }
         }
-
         Ok(())
     }
 }
@@ -266,7 +1267,10 @@ impl<'a> PreprocessContext<'a> {
 mod tests {
     use std::path::PathBuf;
 
+    use approx::assert_abs_diff_eq;
+
     use float_cmp::F32Margin;
+    use itertools::{izip, Itertools};
     use marlu::{
         constants::{
             COTTER_MWA_HEIGHT_METRES, COTTER_MWA_LATITUDE_RADIANS, COTTER_MWA_LONGITUDE_RADIANS,
@@ -276,10 +1280,14 @@ mod tests {
     use tempfile::tempdir;
 
     use crate::{
+        corrections::{quantised_power, van_vleck_sigma},
         flag_to_weight_array,
         flags::get_weight_factor,
         passband_gains::PFB_JAKE_2022_200HZ,
-        test_common::{compare_uvfits_with_csv, get_1254670392_avg_paths},
+        test_common::{
+            compare_uvfits_with_csv, get_1254670392_avg_paths, get_mwa_ord_context,
+            get_mwax_data_paths,
+        },
         write_uvfits, FlagContext, VisSelection,
     };
 
@@ -396,7 +1404,7 @@ mod tests {
         prep_ctx.correct_digital_gains = false;
         prep_ctx.correct_geometry = false;
         prep_ctx.draw_progress = false;
-        prep_ctx.passband_gains = Some(PFB_JAKE_2022_200HZ);
+        prep_ctx.passband_gains = Some(PFB_JAKE_2022_200HZ.into());
 
         let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
         let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
@@ -437,4 +1445,477 @@ mod tests {
 
         assert!(matches!(result, Err(BirliError::BadMWAVersion { .. })));
     }
+
+    #[test]
+    fn test_correlator_delays_comment() {
+        let prep_ctx = PreprocessContext {
+            correct_cable_lengths: false,
+            correct_geometry: true,
+            reverse_geometric_delays: true,
+            correlator_cable_delays: Some(CableDelaysApplied::CableAndRecClock),
+            correlator_geometric_delays: Some(GeometricDelaysApplied::TilePointing),
+            ..PreprocessContext::default()
+        };
+
+        let comment = prep_ctx.as_comment();
+        assert!(!comment.contains("cable length corrections"));
+        assert!(comment.contains("correlator cable delays"));
+        assert!(comment.contains("correlator geometric delays (Tile Pointing)"));
+        assert!(comment.contains("reversed correlator geometric delays, geometric corrections"));
+
+        let display = format!("{}", prep_ctx);
+        assert!(display
+            .contains("already applied by the correlator (Tile Pointing), and will be reversed"));
+    }
+
+    #[test]
+    fn test_reverse_geometric_delays_round_trip() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let mut corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        // pretend the correlator tracked the pointing centre.
+        corr_ctx.metafits_context.geometric_delays_applied = GeometricDelaysApplied::AzElTracking;
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+
+        let prep_ctx = PreprocessContext {
+            correct_cable_lengths: false,
+            correct_digital_gains: false,
+            correct_geometry: true,
+            reverse_geometric_delays: true,
+            array_pos: LatLngHeight::new_mwa(),
+            phase_centre: RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context),
+            draw_progress: false,
+            ..PreprocessContext::default()
+        };
+
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        vis_sel
+            .read_mwalib(
+                &corr_ctx,
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                false,
+            )
+            .unwrap();
+        let raw_jones_array = jones_array.clone();
+        let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+        weight_array.fill(get_weight_factor(&corr_ctx) as _);
+
+        prep_ctx
+            .preprocess(
+                &corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                &vis_sel,
+            )
+            .unwrap();
+
+        // reversing the correlator's delays and re-phasing to the pointing centre does nothing.
+        // visibilities are ~4e6, so allow for f32 rounding in both rotations.
+        for (jones, raw_jones) in izip!(jones_array.iter(), raw_jones_array.iter()) {
+            assert_abs_diff_eq!(*jones, *raw_jones, epsilon = 2.);
+        }
+    }
+
+    #[test]
+    fn test_empirical_passband() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+
+        let prep_ctx = PreprocessContext {
+            correct_cable_lengths: false,
+            correct_digital_gains: false,
+            correct_geometry: false,
+            empirical_passband: true,
+            draw_progress: false,
+            ..PreprocessContext::default()
+        };
+        assert!(prep_ctx.as_comment().contains("empirical pfb gains"));
+        assert!(format!("{}", prep_ctx)
+            .contains("Will correct coarse pfb passband gains estimated from the data"));
+
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        vis_sel
+            .read_mwalib(
+                &corr_ctx,
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                false,
+            )
+            .unwrap();
+        let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+        weight_array.fill(get_weight_factor(&corr_ctx) as _);
+
+        // attenuate the lower fine channel of each coarse channel
+        for (chan_idx, mut jones_chan_view) in jones_array.axis_iter_mut(Axis(1)).enumerate() {
+            if chan_idx % fine_chans_per_coarse == 0 {
+                jones_chan_view.mapv_inplace(|jones| jones * 0.5);
+            }
+        }
+        let raw_gains =
+            estimate_passband_gains(jones_array.view(), flag_array.view(), fine_chans_per_coarse)
+                .unwrap();
+        assert!((raw_gains[0] - 0.5).abs() < 1e-3, "{}", raw_gains[0]);
+
+        prep_ctx
+            .preprocess(
+                &corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                &vis_sel,
+            )
+            .unwrap();
+
+        // the estimated passband has been removed from the data
+        let gains =
+            estimate_passband_gains(jones_array.view(), flag_array.view(), fine_chans_per_coarse)
+                .unwrap();
+        for gain in gains {
+            assert!((gain - 1.).abs() < 1e-3, "{}", gain);
+        }
+    }
+
+    #[test]
+    fn test_parse_step_order() {
+        let default = StepOrder::default();
+        assert_eq!(
+            default.to_string(),
+            "cable,digital,passband,flag,geometry,calibrate"
+        );
+        assert_eq!(default.to_string().parse::<StepOrder>().unwrap(), default);
+        assert!(default.can_rephase());
+
+        let order: StepOrder = "digital, cable, geometry, flag, calibrate, passband"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            order.unphased_steps(),
+            &[PreprocessStep::DigitalGains, PreprocessStep::CableLengths]
+        );
+        assert_eq!(order.phased_steps()[0], PreprocessStep::Geometry);
+        assert!(!order.can_rephase());
+
+        assert!(matches!(
+            "cable,digital,passband,flag,geometry,calibrate,bandpass".parse::<StepOrder>(),
+            Err(StepOrderError::UnknownStep(name)) if name == "bandpass"
+        ));
+        assert!(matches!(
+            "cable,digital,passband,flag,geometry,cable,calibrate".parse::<StepOrder>(),
+            Err(StepOrderError::Duplicate(PreprocessStep::CableLengths))
+        ));
+        assert!(matches!(
+            "cable,digital,passband,geometry,calibrate".parse::<StepOrder>(),
+            Err(StepOrderError::Missing(PreprocessStep::Flag))
+        ));
+        assert!(matches!(
+            "cable,calibrate,digital,passband,flag,geometry".parse::<StepOrder>(),
+            Err(StepOrderError::OutOfOrder {
+                step: PreprocessStep::Calibration,
+                before: PreprocessStep::DigitalGains,
+                ..
+            })
+        ));
+    }
+
+    /// Corrections which scale every polarisation the same way commute, so they can be done in
+    /// any order, and the comment lists them in the order they are done.
+    #[test]
+    fn test_preprocess_step_order() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+
+        let preprocess = |step_order: StepOrder| {
+            let prep_ctx = PreprocessContext {
+                phase_centre: RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context),
+                array_pos: LatLngHeight::new_mwa(),
+                correct_cable_lengths: true,
+                correct_digital_gains: true,
+                passband_gains: Some(PFB_JAKE_2022_200HZ.into()),
+                correct_geometry: true,
+                step_order,
+                draw_progress: false,
+                ..PreprocessContext::default()
+            };
+            let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+            let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+            vis_sel
+                .read_mwalib(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    flag_array.view_mut(),
+                    false,
+                )
+                .unwrap();
+            let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+            weight_array.fill(get_weight_factor(&corr_ctx) as _);
+            prep_ctx
+                .preprocess(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &vis_sel,
+                )
+                .unwrap();
+            (prep_ctx.as_comment(), jones_array, weight_array)
+        };
+
+        let (default_comment, default_jones, default_weights) = preprocess(StepOrder::default());
+        let (comment, jones_array, weight_array) = preprocess(
+            "geometry,cable,passband,flag,digital,calibrate"
+                .parse::<StepOrder>()
+                .unwrap(),
+        );
+        assert!(
+            default_comment.find("pfb gains").unwrap()
+                < default_comment.find("geometric corrections").unwrap()
+        );
+        assert!(
+            comment.find("geometric corrections").unwrap() < comment.find("pfb gains").unwrap()
+        );
+        assert!(comment.find("pfb gains").unwrap() < comment.find("digital gains").unwrap());
+        assert_eq!(weight_array, default_weights);
+        for (jones, default_jones) in jones_array.iter().zip_eq(default_jones.iter()) {
+            for pol_idx in 0..4 {
+                let diff = (jones[pol_idx] - default_jones[pol_idx]).norm();
+                assert!(
+                    diff <= 1e-4 * default_jones[pol_idx].norm().max(1.),
+                    "{}",
+                    diff
+                );
+            }
+        }
+    }
+
+    /// Legacy observations are Van Vleck corrected by default, before any other corrections. The
+    /// corrected autocorrelations are the power of the inputs whose quantised power is the raw
+    /// autocorrelation.
+    #[test]
+    fn test_default_van_vleck_legacy() {
+        let corr_ctx = get_mwa_ord_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+        let num_samples = 2.
+            * corr_ctx.metafits_context.corr_fine_chan_width_hz as f64
+            * corr_ctx.metafits_context.corr_int_time_ms as f64
+            / 1000.;
+
+        let default_ctx = || {
+            #[allow(unused_mut)]
+            let mut prep_ctx = PreprocessContext {
+                draw_progress: false,
+                ..PreprocessContext::from_mwalib(&corr_ctx).unwrap()
+            };
+            #[cfg(feature = "aoflagger")]
+            {
+                prep_ctx.aoflagger_strategy = None;
+            }
+            prep_ctx
+        };
+        assert!(default_ctx().correct_van_vleck);
+
+        let read = || {
+            let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+            let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+            vis_sel
+                .read_mwalib(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    flag_array.view_mut(),
+                    false,
+                )
+                .unwrap();
+            let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+            weight_array.fill(get_weight_factor(&corr_ctx) as _);
+            (jones_array, weight_array, flag_array)
+        };
+        let preprocess = |prep_ctx: &PreprocessContext,
+                          (mut jones_array, mut weight_array, mut flag_array): (
+            Array3<Jones<f32>>,
+            Array3<f32>,
+            Array3<bool>,
+        )| {
+            prep_ctx
+                .preprocess(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &vis_sel,
+                )
+                .unwrap();
+            jones_array
+        };
+
+        let raw = read();
+        let raw_jones = raw.0.clone();
+
+        // the Van Vleck corrections alone
+        let van_vleck_ctx = PreprocessContext {
+            correct_van_vleck: true,
+            draw_progress: false,
+            ..PreprocessContext::default()
+        };
+        let van_vleck_jones = preprocess(&van_vleck_ctx, raw);
+        let mut num_corrected = 0;
+        for (bl_idx, &(ant1, ant2)) in ant_pairs.iter().enumerate() {
+            if ant1 != ant2 {
+                continue;
+            }
+            for (raw, corrected) in izip!(
+                raw_jones.index_axis(Axis(2), bl_idx).iter(),
+                van_vleck_jones.index_axis(Axis(2), bl_idx).iter()
+            ) {
+                for pol_idx in [0, 3] {
+                    let raw_power = f64::from(raw[pol_idx].re) / num_samples;
+                    let corrected_power = f64::from(corrected[pol_idx].re) / num_samples;
+                    if van_vleck_sigma(raw_power).is_some() {
+                        assert_abs_diff_eq!(
+                            quantised_power(corrected_power.sqrt()),
+                            raw_power,
+                            epsilon = 1e-4 * raw_power.max(1.)
+                        );
+                        num_corrected += 1;
+                    } else {
+                        assert_eq!(raw[pol_idx], corrected[pol_idx]);
+                    }
+                }
+            }
+        }
+        assert!(num_corrected > 0);
+
+        // the default corrections are the Van Vleck corrections, then everything else.
+        let default_jones = preprocess(&default_ctx(), read());
+        let mut reference = read();
+        reference.0.assign(&van_vleck_jones);
+        let reference_jones = preprocess(
+            &PreprocessContext {
+                correct_van_vleck: false,
+                ..default_ctx()
+            },
+            reference,
+        );
+        assert_eq!(default_jones, reference_jones);
+    }
+
+    /// A custom correction which records when it is applied, and fails if it has no name.
+    #[derive(Debug)]
+    struct RecordStage(&'static str, Arc<std::sync::Mutex<Vec<&'static str>>>);
+
+    impl Correction for RecordStage {
+        fn describe(&self, _prep_ctx: &PreprocessContext) -> Vec<String> {
+            vec![self.0.to_string()]
+        }
+
+        fn apply(
+            &self,
+            _prep_ctx: &PreprocessContext,
+            _corr_ctx: &CorrelatorContext,
+            _jones_array: ArrayViewMut3<Jones<f32>>,
+            _weight_array: ArrayViewMut3<f32>,
+            _flag_array: ArrayViewMut3<bool>,
+            _vis_sel: &VisSelection,
+            _phase_centre: Option<RADec>,
+        ) -> Result<(), BirliError> {
+            if self.0.is_empty() {
+                return Err(BirliError::Correction("no name".into()));
+            }
+            self.1.lock().unwrap().push(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_stages() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+        let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let stage = |name| -> Arc<dyn Correction> { Arc::new(RecordStage(name, log.clone())) };
+        let mut prep_ctx = PreprocessContext {
+            correct_digital_gains: true,
+            stages: vec![
+                (
+                    StagePosition::After(PreprocessStep::Geometry),
+                    stage("after geometry"),
+                ),
+                (
+                    StagePosition::Before(PreprocessStep::CableLengths),
+                    stage("first"),
+                ),
+                (
+                    StagePosition::Before(PreprocessStep::Geometry),
+                    stage("before geometry"),
+                ),
+            ],
+            draw_progress: false,
+            ..PreprocessContext::default()
+        };
+        assert_eq!(
+            prep_ctx.as_comment(),
+            "first, digital gains, before geometry, after geometry"
+        );
+
+        prep_ctx
+            .preprocess_unphased(
+                &corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                &vis_sel,
+            )
+            .unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["first", "before geometry"]);
+
+        // stages after geometric corrections are applied for each phase centre
+        for phase_centre in [None, Some(RADec::new(0., 0.))] {
+            prep_ctx
+                .preprocess_phased(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &vis_sel,
+                    phase_centre,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "first",
+                "before geometry",
+                "after geometry",
+                "after geometry"
+            ]
+        );
+
+        prep_ctx
+            .stages
+            .push((StagePosition::After(PreprocessStep::Calibration), stage("")));
+        assert!(matches!(
+            prep_ctx.preprocess(
+                &corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                &vis_sel,
+            ),
+            Err(BirliError::Correction(_))
+        ));
+    }
 }
@@ -0,0 +1,295 @@
+//! Aoflagger strategy files bundled with Birli, and validation of user-supplied strategies.
+//!
+//! Aoflagger can find its own default MWA strategy on disk, but this isn't always installed (e.g.
+//! in minimal containers). Birli embeds a copy of the MWA strategy which ships with aoflagger,
+//! `data/strategies/mwa-default.lua` from <https://gitlab.com/aroffringa/aoflagger>, and writes
+//! it to a temporary file when aoflagger needs to load it.
+//!
+//! Aoflagger only ships the one MWA strategy, which it uses for both legacy and MWAX correlators
+//! and every frequency band, so Birli doesn't choose a strategy by `MWAVersion` or frequency.
+//! Tuned strategies without a reference in aoflagger would be guesswork, so these are left to
+//! `--aoflagger-strategy`.
+
+use std::{fmt::Display, fs, io::Write, path::Path, sync::Arc};
+
+use tempfile::TempPath;
+use thiserror::Error;
+
+const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Errors when validating or writing an aoflagger strategy.
+#[derive(Error, Debug)]
+pub enum StrategyError {
+    #[error("Could not read aoflagger strategy file {path}: {source}")]
+    /// The strategy file could not be read
+    Unreadable {
+        /// The path to the strategy file
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error("aoflagger strategy file {path} is not a lua strategy. Only lua strategies are supported by aoflagger 3")]
+    /// The strategy file does not have a `.lua` extension
+    NotLua {
+        /// The path to the strategy file
+        path: String,
+    },
+
+    #[error("aoflagger strategy file {path} does not define an execute function")]
+    /// The strategy file does not define `function execute(input)`
+    NoExecute {
+        /// The path to the strategy file
+        path: String,
+    },
+
+    #[error(
+        "Could not write bundled aoflagger strategy {file_name} to a temporary file: {source}"
+    )]
+    /// A bundled strategy could not be written to disk
+    WriteBundled {
+        /// The file name of the bundled strategy
+        file_name: &'static str,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+}
+
+/// An aoflagger strategy which is embedded in the Birli binary, copied unmodified from aoflagger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundledStrategy {
+    /// The default MWA strategy from aoflagger, `data/strategies/mwa-default.lua`
+    MwaDefault,
+}
+
+impl BundledStrategy {
+    /// All of the strategies bundled with Birli.
+    pub const ALL: [Self; 1] = [Self::MwaDefault];
+
+    /// The filename of the strategy.
+    pub const fn file_name(&self) -> &'static str {
+        match self {
+            Self::MwaDefault => "mwa-default.lua",
+        }
+    }
+
+    /// The lua source of the strategy.
+    pub const fn contents(&self) -> &'static str {
+        match self {
+            Self::MwaDefault => include_str!("mwa-default.lua"),
+        }
+    }
+
+    /// Write the strategy to a new temporary file so that it can be loaded by aoflagger. The file
+    /// is only readable by the current user, and is removed when the returned path is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`StrategyError::WriteBundled`] if the strategy can't be written.
+    pub fn write(&self) -> Result<TempPath, StrategyError> {
+        let to_error = |source| StrategyError::WriteBundled {
+            file_name: self.file_name(),
+            source,
+        };
+        let mut file = tempfile::Builder::new()
+            .prefix("birli-")
+            .suffix(&format!("-{}", self.file_name()))
+            .tempfile()
+            .map_err(to_error)?;
+        file.write_all(self.contents().as_bytes())
+            .map_err(to_error)?;
+        Ok(file.into_temp_path())
+    }
+}
+
+impl Display for BundledStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (bundled with Birli {})",
+            self.file_name(),
+            PKG_VERSION
+        )
+    }
+}
+
+/// An aoflagger strategy to flag with.
+#[derive(Debug, Clone)]
+pub enum AOFlaggerStrategy {
+    /// A strategy bundled with Birli, which is written to a temporary file while flagging
+    Bundled(BundledStrategy),
+    /// A strategy bundled with Birli which has already been written to a temporary file by
+    /// [`AOFlaggerStrategy::written`], which is removed once the last clone is dropped
+    Written(BundledStrategy, Arc<TempPath>),
+    /// The path to a lua strategy file
+    File(String),
+}
+
+impl AOFlaggerStrategy {
+    /// Write a bundled strategy to a temporary file now, so that it can be reused for every
+    /// chunk instead of being written each time it is used. Other strategies are unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`StrategyError::WriteBundled`] if a bundled strategy can't be written.
+    pub fn written(self) -> Result<Self, StrategyError> {
+        match self {
+            Self::Bundled(strategy) => Ok(Self::Written(strategy, Arc::new(strategy.write()?))),
+            strategy => Ok(strategy),
+        }
+    }
+
+    /// Call `f` with the path to the strategy file. Bundled strategies which haven't been
+    /// [`written`](AOFlaggerStrategy::written) are written to a temporary file, which is removed
+    /// once `f` returns.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`StrategyError::WriteBundled`] if a bundled strategy can't be written.
+    pub fn with_path<T>(&self, f: impl FnOnce(&str) -> T) -> Result<T, StrategyError> {
+        match self {
+            Self::Bundled(strategy) => {
+                let path = strategy.write()?;
+                Ok(f(&path.to_string_lossy()))
+            }
+            Self::Written(_, path) => Ok(f(&path.to_string_lossy())),
+            Self::File(path) => Ok(f(path)),
+        }
+    }
+}
+
+/// Strategies are equal if they have the same source, wherever a bundled strategy is written.
+impl PartialEq for AOFlaggerStrategy {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Bundled(strategy) | Self::Written(strategy, _),
+                Self::Bundled(other) | Self::Written(other, _),
+            ) => strategy == other,
+            (Self::File(path), Self::File(other)) => path == other,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AOFlaggerStrategy {}
+
+/// A description of the strategy for history and metadata, which names the bundled strategy if
+/// it is one, otherwise just the path.
+impl Display for AOFlaggerStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bundled(strategy) | Self::Written(strategy, _) => write!(f, "{}", strategy),
+            Self::File(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+/// Check that a user-supplied strategy file can be used by aoflagger, before any data is read.
+///
+/// Aoflagger aborts the whole process if it can't load a strategy, so this catches the common
+/// problems up front: the file must be readable, be a lua strategy, and define an `execute`
+/// function.
+///
+/// # Errors
+///
+/// Will return a [`StrategyError`] describing the first problem found.
+pub fn validate_strategy_file(path: &str) -> Result<(), StrategyError> {
+    if Path::new(path).extension().and_then(|ext| ext.to_str()) != Some("lua") {
+        return Err(StrategyError::NotLua { path: path.into() });
+    }
+    let contents = fs::read_to_string(path).map_err(|source| StrategyError::Unreadable {
+        path: path.into(),
+        source,
+    })?;
+    if !contents
+        .lines()
+        .any(|line| line.trim_start().starts_with("function execute"))
+    {
+        return Err(StrategyError::NoExecute { path: path.into() });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_strategy_file, AOFlaggerStrategy, BundledStrategy, StrategyError};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_bundled_strategies_valid() {
+        for strategy in BundledStrategy::ALL {
+            let path = strategy.write().unwrap();
+            validate_strategy_file(path.to_str().unwrap()).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), strategy.contents());
+            assert!(AOFlaggerStrategy::Bundled(strategy)
+                .to_string()
+                .starts_with(strategy.file_name()));
+        }
+    }
+
+    #[test]
+    fn test_bundled_strategy_removed_after_use() {
+        let strategy = AOFlaggerStrategy::Bundled(BundledStrategy::MwaDefault);
+        let path = strategy
+            .with_path(|path| {
+                validate_strategy_file(path).unwrap();
+                path.to_string()
+            })
+            .unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_written_strategy_reused() {
+        let strategy = AOFlaggerStrategy::Bundled(BundledStrategy::MwaDefault)
+            .written()
+            .unwrap();
+        assert_eq!(
+            strategy,
+            AOFlaggerStrategy::Bundled(BundledStrategy::MwaDefault)
+        );
+        let first = strategy.with_path(ToString::to_string).unwrap();
+        let second = strategy.clone().with_path(ToString::to_string).unwrap();
+        assert_eq!(first, second);
+        validate_strategy_file(&first).unwrap();
+
+        // the file is kept until the last clone is dropped
+        drop(strategy);
+        assert!(!std::path::Path::new(&first).exists());
+    }
+
+    #[test]
+    fn test_describe_user_strategy() {
+        assert_eq!(
+            AOFlaggerStrategy::File("/tmp/custom.lua".into()).to_string(),
+            "/tmp/custom.lua"
+        );
+    }
+
+    #[test]
+    fn test_validate_strategy_file_errors() {
+        let tmp_dir = tempdir().unwrap();
+
+        let missing = tmp_dir.path().join("missing.lua");
+        assert!(matches!(
+            validate_strategy_file(missing.to_str().unwrap()),
+            Err(StrategyError::Unreadable { .. })
+        ));
+
+        let rfis = tmp_dir.path().join("old.rfis");
+        fs::write(&rfis, "<rfi-strategy/>").unwrap();
+        assert!(matches!(
+            validate_strategy_file(rfis.to_str().unwrap()),
+            Err(StrategyError::NotLua { .. })
+        ));
+
+        let no_execute = tmp_dir.path().join("no_execute.lua");
+        fs::write(&no_execute, "aoflagger.require_min_version(\"3.0\")\n").unwrap();
+        assert!(matches!(
+            validate_strategy_file(no_execute.to_str().unwrap()),
+            Err(StrategyError::NoExecute { .. })
+        ));
+    }
+}
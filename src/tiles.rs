@@ -0,0 +1,298 @@
+//! Per-tile overrides of the cable delays and positions in the metafits.
+//!
+//! The metafits `TILEDATA` table can be out of date or less precise than a curated table of
+//! measured cable delays and surveyed tile positions. A tile override file is a whitespace
+//! separated text file with one override per line, keyed by tile name:
+//!
+//! ```text
+//! # <TILE_NAME> <KIND> <VALUES...>
+//! Tile011 delay_m 150.2
+//! Tile011 enh 12.3 -45.6 377.8
+//! Tile012 delay_ns 501.3 501.9
+//! Tile012 xyz 1.2 3.4 5.6
+//! ```
+//!
+//! Where `<KIND>` is one of:
+//! - `delay_m`: electrical length in metres
+//! - `delay_ns`: delay in nanoseconds
+//! - `enh`: East, North, Height position in metres, relative to the array centre
+//! - `xyz`: geodetic X, Y, Z position in metres, relative to the array centre
+//!
+//! Delays take either a single value for both rfinputs, or separate values for the X and Y
+//! rfinputs. Tiles and values which are not overridden are taken from the metafits.
+
+use std::{fs, path::Path};
+
+use marlu::{constants::VEL_C, mwalib::MetafitsContext, XyzGeodetic, ENH};
+use thiserror::Error;
+
+use crate::cables::CableModel;
+
+/// Errors when reading a tile override file.
+#[derive(Error, Debug)]
+pub enum TileOverrideError {
+    #[error("Could not read tile override file {path}: {source}")]
+    /// The override file could not be read
+    Unreadable {
+        /// The path to the override file
+        path: String,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    #[error("{path}:{line_num}: expected <TILE_NAME> <KIND> <VALUES...>, found {line:?}")]
+    /// A line of the override file could not be parsed
+    BadLine {
+        /// The path to the override file
+        path: String,
+        /// The line number (starting from 1)
+        line_num: usize,
+        /// The contents of the line
+        line: String,
+    },
+
+    #[error("{path}:{line_num}: tile {tile_name} is not in the metafits")]
+    /// A tile in the override file is not in the metafits
+    UnknownTile {
+        /// The path to the override file
+        path: String,
+        /// The line number (starting from 1)
+        line_num: usize,
+        /// The tile name
+        tile_name: String,
+    },
+}
+
+/// An overridden tile position, relative to the array centre.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TilePosition {
+    /// East, North, Height coordinates, which depend on the latitude of the array centre.
+    Enh(ENH),
+    /// Geodetic X, Y, Z coordinates.
+    Xyz(XyzGeodetic),
+}
+
+impl TilePosition {
+    /// The geodetic position of the tile, given the latitude of the array centre.
+    pub fn to_xyz(self, latitude_rad: f64) -> XyzGeodetic {
+        match self {
+            Self::Enh(enh) => enh.to_xyz(latitude_rad),
+            Self::Xyz(xyz) => xyz,
+        }
+    }
+}
+
+/// Overrides of the cable delays and positions of tiles, indexed by mwalib antenna index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TileOverrides {
+    /// The overridden electrical length in metres of the `[X, Y]` rfinputs of each antenna.
+    pub electrical_lengths_m: Vec<Option<[f64; 2]>>,
+    /// The overridden position of each antenna.
+    pub positions: Vec<Option<TilePosition>>,
+}
+
+impl TileOverrides {
+    /// Read a tile override file (see the [module documentation](self)), resolving tile names
+    /// to antennas in `meta_ctx`.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`TileOverrideError`] if the file can't be read or parsed, or a tile isn't
+    /// in the metafits.
+    pub fn from_file<T: AsRef<Path>>(
+        path: T,
+        meta_ctx: &MetafitsContext,
+    ) -> Result<Self, TileOverrideError> {
+        let path_str = path.as_ref().display().to_string();
+        let contents =
+            fs::read_to_string(&path).map_err(|source| TileOverrideError::Unreadable {
+                path: path_str.clone(),
+                source,
+            })?;
+        let mut overrides = Self {
+            electrical_lengths_m: vec![None; meta_ctx.antennas.len()],
+            positions: vec![None; meta_ctx.antennas.len()],
+        };
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || TileOverrideError::BadLine {
+                path: path_str.clone(),
+                line_num: line_idx + 1,
+                line: line.into(),
+            };
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (tile_name, kind, values) = match fields[..] {
+                [tile_name, kind, ref values @ ..] => (
+                    tile_name,
+                    kind,
+                    values
+                        .iter()
+                        .map(|value| value.parse::<f64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| bad_line())?,
+                ),
+                _ => return Err(bad_line()),
+            };
+            let ant_idx = meta_ctx
+                .antennas
+                .iter()
+                .position(|antenna| antenna.tile_name == tile_name)
+                .ok_or_else(|| TileOverrideError::UnknownTile {
+                    path: path_str.clone(),
+                    line_num: line_idx + 1,
+                    tile_name: tile_name.into(),
+                })?;
+            let metres_per_unit = match kind {
+                "delay_m" => Some(1.),
+                "delay_ns" => Some(VEL_C * 1e-9),
+                _ => None,
+            };
+            match (kind, metres_per_unit, &values[..]) {
+                (_, Some(scale), &[delay]) => {
+                    overrides.electrical_lengths_m[ant_idx] = Some([delay * scale; 2]);
+                }
+                (_, Some(scale), &[delay_x, delay_y]) => {
+                    overrides.electrical_lengths_m[ant_idx] =
+                        Some([delay_x * scale, delay_y * scale]);
+                }
+                ("enh", _, &[e, n, h]) => {
+                    overrides.positions[ant_idx] = Some(TilePosition::Enh(ENH { e, n, h }));
+                }
+                ("xyz", _, &[x, y, z]) => {
+                    overrides.positions[ant_idx] = Some(TilePosition::Xyz(XyzGeodetic { x, y, z }));
+                }
+                _ => return Err(bad_line()),
+            }
+        }
+        Ok(overrides)
+    }
+
+    /// The number of tiles with overridden cable delays.
+    pub fn num_delays(&self) -> usize {
+        self.electrical_lengths_m.iter().flatten().count()
+    }
+
+    /// The number of tiles with overridden positions.
+    pub fn num_positions(&self) -> usize {
+        self.positions.iter().flatten().count()
+    }
+
+    /// Replace the electrical lengths in `cable_model` with any overridden lengths.
+    pub fn apply_to_cable_model(&self, cable_model: &mut CableModel) {
+        for (lengths, &overridden) in cable_model
+            .electrical_lengths_m
+            .iter_mut()
+            .zip(self.electrical_lengths_m.iter())
+        {
+            if let Some(overridden) = overridden {
+                *lengths = overridden;
+            }
+        }
+    }
+
+    /// The geodetic position of each antenna in `meta_ctx`, given the latitude of the array
+    /// centre, using the overridden positions where available.
+    pub fn get_tiles(&self, meta_ctx: &MetafitsContext, latitude_rad: f64) -> Vec<XyzGeodetic> {
+        XyzGeodetic::get_tiles(meta_ctx, latitude_rad)
+            .into_iter()
+            .zip(self.positions.iter())
+            .map(|(xyz, position)| position.map_or(xyz, |position| position.to_xyz(latitude_rad)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use marlu::{constants::VEL_C, XyzGeodetic, ENH};
+    use tempfile::tempdir;
+
+    use super::{TileOverrideError, TileOverrides};
+    use crate::{cables::CableModel, test_common::get_mwax_context};
+
+    #[test]
+    fn test_tile_overrides_from_file() {
+        let corr_ctx = get_mwax_context();
+        let meta_ctx = &corr_ctx.metafits_context;
+        let tile0 = &meta_ctx.antennas[0].tile_name;
+        let tile1 = &meta_ctx.antennas[1].tile_name;
+
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("tiles.txt");
+        fs::write(
+            &path,
+            format!(
+                "# tile kind values\n{tile0} delay_m 100\n\n{tile1} delay_ns 1 2\n\
+                {tile0} enh 1 2 3\n{tile1} xyz 4 5 6\n"
+            ),
+        )
+        .unwrap();
+        let overrides = TileOverrides::from_file(&path, meta_ctx).unwrap();
+        assert_eq!(overrides.num_delays(), 2);
+        assert_eq!(overrides.num_positions(), 2);
+
+        let mut cable_model = CableModel::from_metafits(meta_ctx);
+        overrides.apply_to_cable_model(&mut cable_model);
+        assert_eq!(cable_model.electrical_lengths_m[0], [100., 100.]);
+        assert!((cable_model.electrical_lengths_m[1][0] - VEL_C * 1e-9).abs() < 1e-12);
+        assert!((cable_model.electrical_lengths_m[1][1] - 2. * VEL_C * 1e-9).abs() < 1e-12);
+
+        let latitude_rad = -0.5;
+        let tiles_xyz = overrides.get_tiles(meta_ctx, latitude_rad);
+        let metafits_xyz = XyzGeodetic::get_tiles(meta_ctx, latitude_rad);
+        assert_eq!(
+            tiles_xyz[0],
+            ENH {
+                e: 1.,
+                n: 2.,
+                h: 3.
+            }
+            .to_xyz(latitude_rad)
+        );
+        assert_eq!(
+            tiles_xyz[1],
+            XyzGeodetic {
+                x: 4.,
+                y: 5.,
+                z: 6.
+            }
+        );
+        assert_eq!(tiles_xyz[2..], metafits_xyz[2..]);
+    }
+
+    #[test]
+    fn test_tile_overrides_errors() {
+        let corr_ctx = get_mwax_context();
+        let meta_ctx = &corr_ctx.metafits_context;
+        let tile0 = &meta_ctx.antennas[0].tile_name;
+
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("tiles.txt");
+        for (contents, line_num) in [
+            (format!("{tile0} delay_m\n"), 1),
+            (format!("{tile0} delay_m 1 2 3\n"), 1),
+            (format!("\n{tile0} enh 1 2\n"), 2),
+            (format!("{tile0} xyz 1 2 abc\n"), 1),
+            (format!("{tile0} position 1 2 3\n"), 1),
+            (tile0.to_string(), 1),
+        ] {
+            fs::write(&path, contents).unwrap();
+            let result = TileOverrides::from_file(&path, meta_ctx);
+            assert!(
+                matches!(result, Err(TileOverrideError::BadLine { line_num: n, .. }) if n == line_num),
+                "{:?}",
+                result
+            );
+        }
+
+        fs::write(&path, "NotATile delay_m 1\n").unwrap();
+        assert!(matches!(
+            TileOverrides::from_file(&path, meta_ctx),
+            Err(TileOverrideError::UnknownTile { .. })
+        ));
+    }
+}